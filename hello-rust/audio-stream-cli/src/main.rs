@@ -0,0 +1,13 @@
+// Audio stream client binary; all work is done by audio-stream-core.
+
+use audio_stream_core::cli::Config;
+use audio_stream_core::{client, logger};
+
+#[tokio::main]
+async fn main() {
+    let config = Config::parse();
+    if let Err(e) = client::run(&config).await {
+        logger::log_error(&format!("Client failed: {}", e));
+        std::process::exit(1);
+    }
+}
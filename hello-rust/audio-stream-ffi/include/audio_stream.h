@@ -0,0 +1,87 @@
+#ifndef AUDIO_STREAM_H
+#define AUDIO_STREAM_H
+
+/* Generated by cbindgen from src/lib.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A connection to an audio stream server.
+ */
+typedef struct AudioStreamClient AudioStreamClient;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Connect to the server at `uri`, e.g. `ws://localhost:8080/audio`.
+ * Returns NULL on failure.
+ *
+ * # Safety
+ *
+ * `uri` must be a NUL-terminated string.
+ */
+AudioStreamClient *audio_stream_connect(const char *uri);
+
+/**
+ * Upload the file at `path` as a new stream and write its NUL-terminated ID
+ * into `stream_id`, a buffer of `stream_id_len` bytes.
+ *
+ * # Safety
+ *
+ * `client` must come from `audio_stream_connect`, `path` must be a
+ * NUL-terminated string, and `stream_id` must point to `stream_id_len`
+ * writable bytes.
+ */
+int audio_stream_upload(AudioStreamClient *client,
+                        const char *path,
+                        char *stream_id,
+                        size_t stream_id_len);
+
+/**
+ * Download the stream `stream_id` to the file at `path`.
+ *
+ * # Safety
+ *
+ * `client` must come from `audio_stream_connect`; `stream_id` and `path`
+ * must be NUL-terminated strings.
+ */
+int audio_stream_download(AudioStreamClient *client, const char *stream_id, const char *path);
+
+/**
+ * Compare the size and SHA-256 of the file at `path` with those the server
+ * reports for the stream `stream_id`. Returns 1 when they match, 0 when they
+ * differ, and -1 on failure.
+ *
+ * # Safety
+ *
+ * `client` must come from `audio_stream_connect`; `stream_id` and `path`
+ * must be NUL-terminated strings.
+ */
+int audio_stream_verify(AudioStreamClient *client, const char *stream_id, const char *path);
+
+/**
+ * Get the message of the last failure on this thread, or NULL. The string
+ * stays valid until the next call on this thread.
+ */
+const char *audio_stream_last_error(void);
+
+/**
+ * Close the connection and free the client. NULL is ignored.
+ *
+ * # Safety
+ *
+ * `client` must come from `audio_stream_connect` and not be used afterwards.
+ */
+void audio_stream_close(AudioStreamClient *client);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* AUDIO_STREAM_H */
@@ -0,0 +1,266 @@
+// C ABI of the audio stream client, for native applications that embed it.
+// A client is opened with audio_stream_connect and freed with
+// audio_stream_close. Every call blocks until it is done and returns 0 on
+// success or -1 on failure, after which audio_stream_last_error describes the
+// failure. A panic inside a call is reported the same way rather than
+// unwinding into the caller. The header include/audio_stream.h is generated
+// from this file, see cbindgen.toml.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use audio_stream_core::client::websocket_client::WebSocketClient;
+use audio_stream_core::client::{
+    download_manager, file_manager, upload_manager, verification_module, ClientError,
+};
+use tokio::runtime::Runtime;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A connection to an audio stream server.
+pub struct AudioStreamClient {
+    runtime: Runtime,
+    ws_client: WebSocketClient,
+}
+
+/// Connect to the server at `uri`, e.g. `ws://localhost:8080/audio`.
+/// Returns NULL on failure.
+///
+/// # Safety
+///
+/// `uri` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn audio_stream_connect(uri: *const c_char) -> *mut AudioStreamClient {
+    guarded(ptr::null_mut(), || {
+        let uri = str_arg(uri, "uri")?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut ws_client = WebSocketClient::new(uri);
+        runtime
+            .block_on(ws_client.connect(uri))
+            .map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(AudioStreamClient {
+            runtime,
+            ws_client,
+        })))
+    })
+}
+
+/// Upload the file at `path` as a new stream and write its NUL-terminated ID
+/// into `stream_id`, a buffer of `stream_id_len` bytes.
+///
+/// # Safety
+///
+/// `client` must come from `audio_stream_connect`, `path` must be a
+/// NUL-terminated string, and `stream_id` must point to `stream_id_len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn audio_stream_upload(
+    client: *mut AudioStreamClient,
+    path: *const c_char,
+    stream_id: *mut c_char,
+    stream_id_len: usize,
+) -> c_int {
+    guarded(-1, || {
+        let client = client_arg(client)?;
+        let path = str_arg(path, "path")?;
+        if stream_id.is_null() {
+            return Err("stream_id must not be NULL".to_string());
+        }
+        let result = client.runtime.block_on(async {
+            let size = file_manager::get_file_size(path)?;
+            upload_manager::upload(&mut client.ws_client, path, size).await
+        });
+        let id = result.map_err(|e| e.to_string())?.stream_id;
+        if id.len() >= stream_id_len {
+            return Err(format!(
+                "stream ID {} needs a buffer of {} bytes",
+                id,
+                id.len() + 1
+            ));
+        }
+        ptr::copy_nonoverlapping(id.as_ptr(), stream_id.cast::<u8>(), id.len());
+        *stream_id.add(id.len()) = 0;
+        Ok(0)
+    })
+}
+
+/// Download the stream `stream_id` to the file at `path`.
+///
+/// # Safety
+///
+/// `client` must come from `audio_stream_connect`; `stream_id` and `path`
+/// must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn audio_stream_download(
+    client: *mut AudioStreamClient,
+    stream_id: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    guarded(-1, || {
+        let client = client_arg(client)?;
+        let stream_id = str_arg(stream_id, "stream_id")?;
+        let path = str_arg(path, "path")?;
+        client
+            .runtime
+            .block_on(download_manager::download(
+                &mut client.ws_client,
+                stream_id,
+                path,
+                None,
+            ))
+            .map(|_| 0)
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Compare the size and SHA-256 of the file at `path` with those the server
+/// reports for the stream `stream_id`. Returns 1 when they match, 0 when they
+/// differ, and -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from `audio_stream_connect`; `stream_id` and `path`
+/// must be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn audio_stream_verify(
+    client: *mut AudioStreamClient,
+    stream_id: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    guarded(-1, || {
+        let client = client_arg(client)?;
+        let stream_id = str_arg(stream_id, "stream_id")?;
+        let path = str_arg(path, "path")?;
+        let result = client.runtime.block_on(async {
+            let local = file_manager::compute_checksum(path).await?;
+            let stored = download_manager::query_checksum(&mut client.ws_client, stream_id).await?;
+            Ok::<_, ClientError>(verification_module::verify_remote(&local, &stored).passed)
+        });
+        result.map(c_int::from).map_err(|e| e.to_string())
+    })
+}
+
+/// Get the message of the last failure on this thread, or NULL. The string
+/// stays valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn audio_stream_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| {
+        error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Close the connection and free the client. NULL is ignored.
+///
+/// # Safety
+///
+/// `client` must come from `audio_stream_connect` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn audio_stream_close(client: *mut AudioStreamClient) {
+    if client.is_null() {
+        return;
+    }
+    let mut client = Box::from_raw(client);
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        let AudioStreamClient { runtime, ws_client } = &mut *client;
+        let _ = runtime.block_on(ws_client.close());
+    }));
+}
+
+unsafe fn str_arg<'a>(value: *const c_char, name: &str) -> Result<&'a str, String> {
+    if value.is_null() {
+        return Err(format!("{} must not be NULL", name));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+unsafe fn client_arg<'a>(
+    client: *mut AudioStreamClient,
+) -> Result<&'a mut AudioStreamClient, String> {
+    client
+        .as_mut()
+        .ok_or_else(|| "client must not be NULL".to_string())
+}
+
+/// Run the body of an exported function, returning `failed` and recording the
+/// message when it fails or panics.
+fn guarded<T>(failed: T, body: impl FnOnce() -> Result<T, String>) -> T {
+    let message = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(message)) => message,
+        Err(payload) => format!("internal error: {}", panic_message(payload.as_ref())),
+    };
+    set_last_error(message);
+    failed
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panic")
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs cannot be represented in a C string
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(audio_stream_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn null_arguments_fail_with_a_message() {
+        assert!(unsafe { audio_stream_connect(ptr::null()) }.is_null());
+        assert_eq!(last_error(), "uri must not be NULL");
+
+        let path = CString::new("in.mp3").unwrap();
+        let mut id = [0 as c_char; 16];
+        let code = unsafe {
+            audio_stream_upload(ptr::null_mut(), path.as_ptr(), id.as_mut_ptr(), id.len())
+        };
+        assert_eq!(code, -1);
+        assert_eq!(last_error(), "client must not be NULL");
+        unsafe { audio_stream_close(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn unreachable_servers_fail_to_connect() {
+        // Nothing listens on a port once its listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("ws://{}/audio", listener.local_addr().unwrap());
+        drop(listener);
+
+        let c_uri = CString::new(uri.as_str()).unwrap();
+        assert!(unsafe { audio_stream_connect(c_uri.as_ptr()) }.is_null());
+        assert!(last_error().contains(&uri), "{}", last_error());
+    }
+
+    #[test]
+    fn panics_are_reported_as_failures() {
+        let code = guarded(-1, || -> Result<c_int, String> { panic!("boom") });
+        assert_eq!(code, -1);
+        assert_eq!(last_error(), "internal error: boom");
+    }
+}
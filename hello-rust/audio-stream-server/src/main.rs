@@ -0,0 +1,13 @@
+// Audio stream server binary; all work is done by audio-stream-core.
+
+use audio_stream_core::cli::ServerConfig;
+use audio_stream_core::{logger, server};
+
+#[tokio::main]
+async fn main() {
+    let config = ServerConfig::parse();
+    if let Err(e) = server::run(&config).await {
+        logger::log_error(&format!("Server failed: {}", e));
+        std::process::exit(1);
+    }
+}
@@ -1,33 +1,543 @@
 use clap::Parser;
+#[cfg(feature = "client")]
 use std::path::PathBuf;
 
+#[cfg(feature = "client")]
+use crate::client::download_manager::TeeTarget;
+#[cfg(any(feature = "client", feature = "server"))]
+use crate::protocol::is_valid_namespace;
+use crate::protocol::FaultConfig;
+#[cfg(feature = "client")]
+use crate::protocol::PcmFormat;
+#[cfg(feature = "server")]
+use crate::server::network::CidrBlock;
+
+#[cfg(feature = "client")]
 #[derive(Parser, Debug)]
 #[command(name = "audio_stream_client")]
 #[command(about = "Audio Stream Cache Client - Rust Implementation", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Config {
+    /// Run a tool instead of the default upload/download/verify cycle
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
+
     /// Input audio file path
-    #[arg(long, value_name = "FILE")]
+    #[arg(long, value_name = "FILE", required = true, default_value = "", hide_default_value = true)]
     pub input: String,
 
+    /// Upload the file at this HTTP(S) URL instead of --input, streaming it
+    /// from the remote server as it is uploaded; s3://BUCKET/KEY with the s3
+    /// feature
+    #[arg(long, value_name = "URL", conflicts_with_all = ["input", "append_to", "resume"])]
+    pub input_url: Option<String>,
+
     /// WebSocket server URI
     #[arg(long, default_value = "ws://localhost:8080/audio")]
     pub server: String,
 
+    /// Access token sent with AUTH on every connection, for servers started
+    /// with --token
+    #[arg(long, value_name = "SECRET", global = true)]
+    pub token: Option<String>,
+
     /// Output file path
     #[arg(long, value_name = "FILE", default_value = "")]
     pub output: String,
 
+    /// Also write the download to this target in the same transfer: a file,
+    /// `-` for stdout (logs then go to stderr), or `|COMMAND` for a player
+    /// reading stdin, e.g. `|ffplay -nodisp -autoexit -` (repeatable)
+    #[arg(
+        long,
+        value_name = "TARGET",
+        value_parser = parse_tee,
+        conflicts_with_all = ["no_verify", "append_to"]
+    )]
+    pub tee: Vec<TeeTarget>,
+
+    /// Control message encoding to request from the server (json, cbor, msgpack)
+    #[arg(long, default_value = "json")]
+    pub encoding: String,
+
+    /// How to check the upload: download it back, or compare against the
+    /// checksum the server reports for the stored stream
+    #[arg(long, value_enum, default_value_t = VerifyMode::Download)]
+    pub verify: VerifyMode,
+
+    /// Upload only, skipping download and verification
+    #[arg(long, conflicts_with = "verify")]
+    pub no_verify: bool,
+
+    /// Append the input to this finalized stream instead of creating a new
+    /// one; only the resulting stream size is verified
+    #[arg(long, value_name = "STREAM_ID", conflicts_with = "verify")]
+    pub append_to: Option<String>,
+
+    /// Resume an interrupted upload of the input to this stream, sending
+    /// only the byte ranges the server does not have yet
+    #[arg(long, value_name = "STREAM_ID", conflicts_with = "append_to")]
+    pub resume: Option<String>,
+
+    /// Keep the progress of the upload in this file, so running the client
+    /// again after a crash resumes the upload where the server left off
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["append_to", "resume", "input_url"])]
+    pub upload_checkpoint: Option<String>,
+
+    /// Bytes committed between rewrites of the --upload-checkpoint file
+    #[arg(long, value_name = "SIZE", default_value = "4M", value_parser = parse_size)]
+    pub upload_checkpoint_interval: u64,
+
+    /// Play the download on the local audio device as it arrives, through
+    /// ffplay or aplay found on PATH
+    #[cfg(feature = "audio-playback")]
+    #[arg(long, conflicts_with_all = ["no_verify", "append_to"])]
+    #[cfg_attr(feature = "opus", arg(conflicts_with = "opus"))]
+    pub play: bool,
+
+    /// Declare the input as raw little-endian PCM (e.g. 44100:2:16), so the
+    /// server can serve it as WAV
+    #[arg(long, value_name = "RATE:CHANNELS:BITS", value_parser = parse_pcm_format)]
+    pub pcm_format: Option<PcmFormat>,
+
+    /// Tag the uploaded stream, e.g. project=podcast42, so `list --tag` can
+    /// find it (repeatable)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Create streams in this namespace, with IDs like NAMESPACE/stream-1a2b3c4d,
+    /// as servers confining the access token to it require
+    #[arg(long, value_name = "NAMESPACE", value_parser = parse_namespace_name)]
+    pub namespace: Option<String>,
+
+    /// Encode the raw PCM input to Opus before upload and decode it on
+    /// download (16-bit, mono or stereo, at an Opus sample rate)
+    #[cfg(feature = "opus")]
+    #[arg(
+        long,
+        requires = "pcm_format",
+        conflicts_with_all = ["append_to", "resume", "input_url", "upload_checkpoint", "tee"]
+    )]
+    pub opus: bool,
+
+    /// Opus bitrate in bits per second (default: chosen by libopus)
+    #[cfg(feature = "opus")]
+    #[arg(long, value_name = "BPS", requires = "opus")]
+    pub opus_bitrate: Option<u32>,
+
+    /// Tag every uploaded chunk with its capture time so live streams can be
+    /// aligned on download (needs --encoding cbor or msgpack)
+    #[arg(long)]
+    pub capture_timestamps: bool,
+
+    /// Send upload chunks as fast as possible instead of pacing them on the
+    /// server's acknowledgements of what it has written
+    #[arg(long)]
+    pub no_pacing: bool,
+
+    /// Times a download chunk whose GET failed or came back empty is
+    /// requested again, with exponential backoff; chunks still failing are
+    /// listed when the download ends
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub get_retries: u32,
+
+    /// Have the server send a running SHA-256 every N downloaded chunks and
+    /// check it as the download goes, so corruption is noticed early
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub checkpoint_every: Option<u32>,
+
+    /// Record every connect and frame of the session to this file
+    #[arg(long, value_name = "FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Log type, size, and leading bytes (hex) of every WebSocket frame
+    #[arg(long)]
+    pub frame_dump: bool,
+
+    /// Randomly drop, delay, truncate, or duplicate the frames sent, e.g.
+    /// "drop=0.01,delay=0.05,delay-ms=500,truncate=0.01,duplicate=0.01,seed=42"
+    #[arg(long, value_name = "SPEC", value_parser = FaultConfig::parse)]
+    pub inject_faults: Option<FaultConfig>,
+
+    /// Shell command run before the transfer; a nonzero exit aborts it
+    #[arg(long, value_name = "COMMAND")]
+    pub pre_hook: Option<String>,
+
+    /// Shell command run after the transfer with AUDIO_STREAM_ID,
+    /// AUDIO_STREAM_SHA256, AUDIO_STREAM_RESULT, and related variables set
+    #[arg(long, value_name = "COMMAND")]
+    pub post_hook: Option<String>,
+
+    /// Write the end-of-run summary (phases, bytes, durations, result) to
+    /// this file as JSON
+    #[arg(long, value_name = "FILE")]
+    pub summary_file: Option<PathBuf>,
+
+    /// Push transfer metrics after each run to statsd (statsd://HOST:PORT)
+    /// or to a Prometheus Pushgateway (http://HOST:PORT)
+    #[cfg(feature = "metrics-push")]
+    #[arg(long, value_name = "URL")]
+    pub metrics_push: Option<String>,
+
+    /// Job name of pushed metrics: the Pushgateway job label, or the statsd
+    /// metric prefix
+    #[cfg(feature = "metrics-push")]
+    #[arg(long, value_name = "NAME", default_value = "audio_stream_client")]
+    pub metrics_job: String,
+
+    /// Sample the CPU during the transfer and write the profile to FILE: an
+    /// SVG flamegraph if it ends in .svg, else folded stacks
+    #[cfg(all(feature = "profile", unix))]
+    #[arg(long, value_name = "FILE")]
+    pub profile: Option<PathBuf>,
+
+    /// Show a live dashboard instead of log output during transfers
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+
     /// Enable verbose logging
     #[arg(long, short = 'v')]
     pub verbose: bool,
+
+    /// Never color log output (also set by NO_COLOR or when not on a terminal)
+    #[arg(long)]
+    pub no_color: bool,
+}
+
+/// Client subcommands.
+#[cfg(feature = "client")]
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ClientCommand {
+    /// Measure upload/download throughput and compare it with earlier runs
+    Bench(BenchConfig),
+    /// Re-drive a session recorded with --trace-file against a server
+    Replay(ReplayConfig),
+    /// Play a stream while it is being uploaded, paced by a jitter buffer
+    Listen(ListenConfig),
+    /// Upload each channel of a PCM WAV file as its own mono stream
+    SplitChannels(SplitChannelsConfig),
+    /// Interleave mono PCM streams into one multi-channel WAV file
+    MergeChannels(MergeChannelsConfig),
+    /// Download a time range of a raw PCM or WAV stream as raw samples
+    Clip(ClipConfig),
+    /// Upload many files over a pool of reused connections
+    Batch(BatchConfig),
+    /// Run the protocol conformance suite against one or more servers
+    Conformance(ConformanceConfig),
+    /// Run an upload/download/verify cycle against an in-process server
+    #[cfg(feature = "server")]
+    Selftest(SelftestConfig),
+    /// Put a server in drain mode: it refuses new uploads and exits once
+    /// its open connections close
+    Drain(DrainConfig),
+    /// Copy a stream from one server to another, piped through the client
+    Copy(CopyConfig),
+    /// Mirror a local directory to a server: upload new and changed files
+    /// and optionally delete the streams of removed ones
+    Sync(SyncConfig),
+    /// Check every stream of a manifest against its recorded size and SHA-256
+    VerifyManifest(VerifyManifestConfig),
+    /// List the streams of a server, optionally only those with given tags
+    List(ListConfig),
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchConfig {
+    /// Input audio file path
+    #[arg(long, value_name = "FILE", required_unless_present = "synthetic_size")]
+    pub input: Option<String>,
+
+    /// Benchmark with a generated sparse input file of this size instead of
+    /// --input, e.g. 512M or 20G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "input")]
+    pub synthetic_size: Option<u64>,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Upload/download cycles per run
+    #[arg(long, default_value_t = 3)]
+    pub iterations: u32,
+
+    /// JSON file holding the results of previous runs
+    #[arg(long, value_name = "FILE", default_value = "bench-history.json")]
+    pub history: PathBuf,
+
+    /// Number of most recent runs averaged into the baseline
+    #[arg(long, default_value_t = 5)]
+    pub baseline_runs: usize,
+
+    /// Fail when throughput drops more than this percentage below the baseline
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0)]
+    pub max_regression: f64,
+
+    /// Simulate a link capped at this bandwidth in each direction (kbit/s)
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub bandwidth_kbps: Option<u64>,
+
+    /// Simulate a link with this much added round-trip time (ms)
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub latency_ms: u64,
+
+    /// Add up to this much random latency to every reply (ms)
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub jitter_ms: u64,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplayConfig {
+    /// Trace file recorded with --trace-file
+    #[arg(long, value_name = "FILE")]
+    pub trace_file: PathBuf,
+
+    /// Server to replay against instead of the first recorded URI
+    #[arg(long)]
+    pub server: Option<String>,
+
+    /// Keep the recorded timing between frames instead of replaying at full speed
+    #[arg(long)]
+    pub realtime: bool,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct ListenConfig {
+    /// Stream to play
+    #[arg(long, value_name = "STREAM_ID")]
+    pub stream_id: String,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// File receiving the played audio
+    #[arg(long, value_name = "FILE")]
+    pub output: String,
+
+    /// Raw PCM format of the stream, used to pace playback
+    #[arg(long, value_name = "RATE:CHANNELS:BITS", value_parser = parse_pcm_format)]
+    pub pcm_format: PcmFormat,
+
+    /// Target delay behind the live edge, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub latency_ms: u64,
+
+    /// Audio kept buffered ahead of playback, in milliseconds; at most the
+    /// target latency
+    #[arg(long, value_name = "MS", default_value_t = 200)]
+    pub jitter_buffer_ms: u64,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct SplitChannelsConfig {
+    /// Multi-channel PCM WAV file
+    #[arg(long, value_name = "FILE")]
+    pub input: String,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct MergeChannelsConfig {
+    /// Mono PCM stream of each channel, in channel order; repeat per channel
+    #[arg(long = "stream-id", value_name = "STREAM_ID", required = true)]
+    pub stream_ids: Vec<String>,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Output WAV file path
+    #[arg(long, value_name = "FILE")]
+    pub output: String,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct ClipConfig {
+    /// Stream to cut the clip from
+    #[arg(long, value_name = "STREAM_ID")]
+    pub stream_id: String,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Output file receiving the raw samples
+    #[arg(long, value_name = "FILE")]
+    pub output: String,
+
+    /// Start of the clip, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.0)]
+    pub start: f64,
+
+    /// End of the clip, in seconds (default: end of the stream)
+    #[arg(long, value_name = "SECONDS")]
+    pub end: Option<f64>,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct BatchConfig {
+    /// File to upload; repeat per file
+    #[arg(long = "input", value_name = "FILE", required = true)]
+    pub inputs: Vec<String>,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Connections kept open and used concurrently
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub connections: u16,
+
+    /// Add the stream and SHA-256 of each uploaded file to this manifest,
+    /// for later checks with verify-manifest
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct VerifyManifestConfig {
+    /// Manifest written by batch --manifest or sync
+    #[arg(long, value_name = "FILE")]
+    pub manifest: PathBuf,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct ListConfig {
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Only list streams with this tag, e.g. project=podcast42 (repeatable;
+    /// streams must carry every tag given)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Streams requested per LIST
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..=1000))]
+    pub page_size: u64,
 }
 
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConformanceConfig {
+    /// WebSocket server URI; repeat to compare several implementations
+    #[arg(long = "server", value_name = "URI", required = true)]
+    pub servers: Vec<String>,
+
+    /// Size of the file transferred by the large-file case, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 64)]
+    pub large_file_mib: u64,
+
+    /// Time limit of each case, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 120)]
+    pub case_timeout: u64,
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+#[derive(clap::Args, Debug, Clone)]
+pub struct SelftestConfig {
+    /// Length of the generated test audio in seconds
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub duration: u32,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct DrainConfig {
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Admin token the server was started with (--admin-token)
+    #[arg(long, value_name = "TOKEN")]
+    pub token: String,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct CopyConfig {
+    /// Stream to copy
+    #[arg(long, value_name = "STREAM_ID")]
+    pub stream_id: String,
+
+    /// WebSocket URI of the server to copy from
+    #[arg(long, value_name = "URI")]
+    pub from: String,
+
+    /// WebSocket URI of the server to copy to
+    #[arg(long, value_name = "URI")]
+    pub to: String,
+
+    /// Stream ID of the copy (default: the source stream ID)
+    #[arg(long, value_name = "STREAM_ID")]
+    pub target_stream_id: Option<String>,
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, Clone)]
+pub struct SyncConfig {
+    /// Directory to mirror
+    #[arg(long, value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// WebSocket server URI
+    #[arg(long, default_value = "ws://localhost:8080/audio")]
+    pub server: String,
+
+    /// Manifest recording the stream of each file (default:
+    /// .audio-stream-sync.json in the directory)
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// Delete the streams of removed files and the old streams of changed ones
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Print the plan without uploading or deleting anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Client verification strategy.
+#[cfg(feature = "client")]
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Download the stream and compare it with the uploaded bytes
+    Download,
+    /// Compare with the size and SHA-256 reported in STOPPED or STAT
+    Remote,
+    /// Download the stream and compare audio fingerprints, so that lossy
+    /// or re-containered copies of the same audio pass (WAV or --pcm-format)
+    Fingerprint,
+    /// Download the stream and compare the files byte by byte, reporting
+    /// the first differing offset with a hexdump around it
+    Bytes,
+}
+
+#[cfg(feature = "client")]
 impl Config {
     pub fn parse() -> Self {
         let mut config = <Config as Parser>::parse();
 
         // Generate default output path if not provided
-        if config.output.is_empty() {
+        if config.command.is_none() && config.output.is_empty() {
             config.output = Self::generate_default_output(&config.input);
         }
 
@@ -45,3 +555,398 @@ impl Config {
         format!("audio/output/output-{}-{}", timestamp, filename)
     }
 }
+
+#[cfg(feature = "client")]
+fn parse_pcm_format(spec: &str) -> Result<PcmFormat, String> {
+    PcmFormat::parse(spec).ok_or_else(|| {
+        format!(
+            "invalid PCM format `{}`, expected RATE:CHANNELS:BITS with 8, 16, 24, or 32 bits",
+            spec
+        )
+    })
+}
+
+/// Parse a stream tag given as KEY=VALUE.
+#[cfg(feature = "client")]
+fn parse_tag(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid tag `{}`, expected KEY=VALUE", spec)),
+    }
+}
+
+/// Parse a download tee target: `-`, `|COMMAND`, or a file path.
+#[cfg(feature = "client")]
+fn parse_tee(spec: &str) -> Result<TeeTarget, String> {
+    TeeTarget::parse(spec).ok_or_else(|| {
+        format!(
+            "invalid tee target `{}`, expected a file, `-`, or `|COMMAND`",
+            spec
+        )
+    })
+}
+
+/// Parse a stream namespace name.
+#[cfg(feature = "client")]
+fn parse_namespace_name(name: &str) -> Result<String, String> {
+    if !is_valid_namespace(name) {
+        return Err(format!(
+            "Invalid namespace (letters, digits, '-' and '_'): {}",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Parse a byte count with an optional binary suffix (K, M, G, T).
+#[cfg(any(feature = "client", feature = "server"))]
+fn parse_size(spec: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid size `{}`, expected bytes or a number with K, M, G, or T",
+            spec
+        )
+    };
+    let upper = spec.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(['B', 'I']);
+    let (number, shift) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 10),
+        Some((i, 'M')) => (&digits[..i], 20),
+        Some((i, 'G')) => (&digits[..i], 30),
+        Some((i, 'T')) => (&digits[..i], 40),
+        _ => (digits, 0),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// Parse a cache shard name, which becomes a directory under the cache.
+#[cfg(feature = "server")]
+fn parse_cache_shard(name: &str) -> Result<String, String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid cache shard name (letters, digits, '-' and '_'): {}",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Parse an access token given as NAME=SECRET.
+#[cfg(feature = "server")]
+fn parse_token(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+            Ok((name.to_string(), secret.to_string()))
+        }
+        _ => Err(format!("invalid token `{}`, expected NAME=SECRET", spec)),
+    }
+}
+
+/// Parse a token quota given as NAME=SIZE.
+#[cfg(feature = "server")]
+fn parse_quota(spec: &str) -> Result<(String, u64), String> {
+    match spec.split_once('=') {
+        Some((name, size)) if !name.is_empty() => Ok((name.to_string(), parse_size(size)?)),
+        _ => Err(format!("invalid quota `{}`, expected NAME=SIZE", spec)),
+    }
+}
+
+/// Parse a token namespace given as NAME=NAMESPACE.
+#[cfg(feature = "server")]
+fn parse_namespace(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((name, namespace)) if !name.is_empty() && is_valid_namespace(namespace) => {
+            Ok((name.to_string(), namespace.to_string()))
+        }
+        _ => Err(format!(
+            "invalid namespace `{}`, expected NAME=NAMESPACE of letters, digits, '-' and '_'",
+            spec
+        )),
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Parser, Debug, Clone)]
+#[command(name = "audio_stream_server")]
+#[command(about = "Audio Stream Cache Server - Rust Implementation", long_about = None)]
+pub struct ServerConfig {
+    /// Port to listen on
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Also accept raw TCP clients (tcp:// URIs) on this port
+    #[arg(long, value_name = "PORT")]
+    pub tcp_port: Option<u16>,
+
+    /// WebSocket endpoint path
+    #[arg(long, default_value = "/audio")]
+    pub path: String,
+
+    /// Bind with SO_REUSEPORT, so several server processes share the port
+    /// and the kernel spreads connections across them (Unix). Every process
+    /// lists all of them with --cluster-node and has its own --cache-shard
+    /// and --node-uri, on a port only it listens on; requests for streams of
+    /// another process are redirected there with MOVED
+    #[arg(long, requires_all = ["cache_shard", "node_uri", "cluster_nodes"])]
+    pub reuse_port: bool,
+
+    /// Keep this process's streams in cache/NAME instead of cache
+    #[arg(long, value_name = "NAME", value_parser = parse_cache_shard)]
+    pub cache_shard: Option<String>,
+
+    /// Connections served at the same time, each on its own thread
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: u32,
+
+    /// Connections waiting for a free slot beyond --max-connections; further
+    /// connections are rejected
+    #[arg(long, default_value_t = 64)]
+    pub connection_queue: u32,
+
+    /// Seconds a client may take to send its HTTP request head or complete
+    /// the WebSocket handshake
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub handshake_timeout: u64,
+
+    /// Close connections that neither send nor accept data for SECS seconds
+    /// (0 never closes them)
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    pub idle_timeout: u64,
+
+    /// Largest message a connection may buffer, inbound or outbound, e.g.
+    /// "32M"; larger messages close the connection
+    #[arg(long, value_name = "BYTES", default_value = "32M", value_parser = parse_size)]
+    pub max_buffered_bytes: u64,
+
+    /// Largest data frame accepted during an upload, e.g. "1M"; advertised
+    /// in STARTED, larger frames are refused with CHUNK_TOO_LARGE
+    #[arg(long, value_name = "BYTES", default_value = "1M", value_parser = parse_size)]
+    pub max_chunk_size: u64,
+
+    /// Only serve clients within this CIDR block, e.g. 10.0.0.0/8 (repeatable)
+    #[arg(long = "allow", value_name = "CIDR", value_parser = CidrBlock::parse)]
+    pub allow: Vec<CidrBlock>,
+
+    /// Close connections from this CIDR block, even when allowed (repeatable)
+    #[arg(long = "deny", value_name = "CIDR", value_parser = CidrBlock::parse)]
+    pub deny: Vec<CidrBlock>,
+
+    /// Token authorizing admin commands such as DRAIN (admin commands are
+    /// refused without one)
+    #[arg(long, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Require clients to authenticate with this access token, given as
+    /// NAME=SECRET (repeatable); usage is accounted per NAME
+    #[arg(long = "token", value_name = "NAME=SECRET", value_parser = parse_token)]
+    pub tokens: Vec<(String, String)>,
+
+    /// Bytes the streams uploaded with a token may hold at once, e.g.
+    /// team-a=10G (repeatable)
+    #[arg(long = "storage-quota", value_name = "NAME=SIZE", value_parser = parse_quota)]
+    pub storage_quotas: Vec<(String, u64)>,
+
+    /// Bytes a token may upload and download in total, e.g. team-a=100G
+    /// (repeatable)
+    #[arg(long = "transfer-quota", value_name = "NAME=SIZE", value_parser = parse_quota)]
+    pub transfer_quotas: Vec<(String, u64)>,
+
+    /// Confine a token to a stream namespace, e.g. team-a=tenant-a
+    /// (repeatable): its stream IDs must start with `tenant-a/`, and LIST
+    /// shows it only those streams
+    #[arg(long = "namespace", value_name = "NAME=NAMESPACE", value_parser = parse_namespace)]
+    pub namespaces: Vec<(String, String)>,
+
+    /// Seconds a drain waits for open connections to close before the server
+    /// exits anyway
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    pub drain_timeout: u64,
+
+    /// Accept WebSocket upgrades from browser pages of this origin only, e.g.
+    /// https://app.example.com (repeatable); clients sending no Origin header
+    /// are always accepted
+    #[arg(long = "allowed-origin", value_name = "ORIGIN")]
+    pub allowed_origins: Vec<String>,
+
+    /// Close the connection when a client violates the protocol
+    /// (e.g. sends binary data before START)
+    #[arg(long)]
+    pub close_on_protocol_error: bool,
+
+    /// Log type, size, and leading bytes (hex) of every WebSocket frame
+    #[arg(long)]
+    pub frame_dump: bool,
+
+    /// Append one line per protocol operation (client, op, stream, status,
+    /// bytes, duration) to FILE in Common Log Format
+    #[arg(long, value_name = "FILE")]
+    pub access_log: Option<String>,
+
+    /// Randomly drop, delay, truncate, or duplicate the frames sent, e.g.
+    /// "drop=0.01,delay=0.05,delay-ms=500,truncate=0.01,duplicate=0.01,seed=42"
+    #[arg(long, value_name = "SPEC", value_parser = FaultConfig::parse)]
+    pub inject_faults: Option<FaultConfig>,
+
+    /// Journal chunk extents before acknowledging them and recover streams
+    /// from the journals on startup
+    #[arg(long)]
+    pub journal: bool,
+
+    /// Earlier generations kept of a stream ID uploaded again after it was
+    /// finalized, readable by version; 0 refuses such uploads instead
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub keep_versions: usize,
+
+    /// Back cache file mappings of 64MB or more with transparent huge pages
+    /// (Linux only)
+    #[arg(long)]
+    pub huge_pages: bool,
+
+    /// Large-file mode: preallocate cache files of any declared size up to
+    /// --max-preallocation instead of at most 8GB, and grow files of unknown
+    /// size in 64MB steps
+    #[arg(long)]
+    pub large_files: bool,
+
+    /// Preallocate at most BYTES of a cache file for the size a client
+    /// declares in START, e.g. "1G"; larger files grow as data arrives
+    #[arg(long, value_name = "BYTES", default_value = "1G", value_parser = parse_size)]
+    pub max_preallocation: u64,
+
+    /// Prefetch up to BYTES of a cache file ahead of downloads reading it
+    /// sequentially, e.g. "8M" (0 disables read-ahead)
+    #[arg(long, value_name = "BYTES", default_value = "8M", value_parser = parse_size)]
+    pub read_ahead: u64,
+
+    /// Serve raw PCM streams (content type audio/pcm with rate, channels,
+    /// and bits) as WAV files over HTTP
+    #[arg(long)]
+    pub synthesize_wav: bool,
+
+    /// Remove orphaned cache files and stale registry entries at startup and
+    /// then every SECS seconds (0 sweeps at startup only)
+    #[arg(long, value_name = "SECS")]
+    pub cache_sweep_interval: Option<u64>,
+
+    /// Re-hash finalized streams against their checksums every SECS seconds
+    /// in the background, marking corrupted streams as ERROR
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub scrub_interval: Option<u64>,
+
+    /// Delete finalized streams not read for SECS seconds; streams given a
+    /// TTL with SET_TTL expire on their own schedule, and pinned streams
+    /// never expire
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stream_ttl: Option<u64>,
+
+    /// Pause uploads that receive neither data nor pings for SECS seconds,
+    /// keeping their data for a resumed START; paused streams expire like
+    /// finalized ones
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub pause_after: Option<u64>,
+
+    /// Probe WAV/MP3 headers on finalize and store duration, sample rate,
+    /// and channels in stream metadata
+    #[arg(long)]
+    pub probe_audio: bool,
+
+    /// Cut leading and trailing silence from raw PCM and PCM WAV streams on
+    /// finalize; trimmed streams no longer match their source, so clients
+    /// should upload them with --no-verify
+    #[arg(long)]
+    pub trim_silence: bool,
+
+    /// Level below which --trim-silence treats a frame as silent, in dBFS
+    #[arg(long, value_name = "DB", default_value_t = -60.0, allow_negative_numbers = true)]
+    pub silence_threshold_db: f64,
+
+    /// Produce a derived rendition of every finalized stream (opus, mp3; repeatable)
+    #[cfg(feature = "transcode")]
+    #[arg(long = "transcode", value_name = "FORMAT")]
+    pub transcode_formats: Vec<String>,
+
+    /// Path to the ffmpeg executable used for transcoding and waveform peaks
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg_path: String,
+
+    /// Normalize finalized streams to this integrated loudness (LUFS, e.g. -23)
+    #[cfg(feature = "loudness")]
+    #[arg(long, value_name = "LUFS", allow_negative_numbers = true)]
+    pub loudness_target: Option<f64>,
+
+    /// Send finalized raw PCM and PCM WAV streams as RTP to this UDP address
+    #[cfg(feature = "rtp")]
+    #[arg(long, value_name = "HOST:PORT")]
+    pub rtp_destination: Option<String>,
+
+    /// RTP payload type of formats without a static one
+    #[cfg(feature = "rtp")]
+    #[arg(long, default_value_t = 96, value_parser = clap::value_parser!(u8).range(96..=127))]
+    pub rtp_payload_type: u8,
+
+    /// Compute waveform peaks for finalized streams, served via PEAKS
+    #[arg(long)]
+    pub waveform_peaks: bool,
+
+    /// Number of min/max buckets per waveform
+    #[arg(long, default_value_t = 1000)]
+    pub peak_buckets: usize,
+
+    /// Replicate finalized streams to a peer server (WebSocket URI)
+    #[cfg(feature = "client")]
+    #[arg(long, value_name = "URI")]
+    pub replicate_to: Option<String>,
+
+    /// Cluster member WebSocket URI, including this node (repeatable);
+    /// enables consistent-hash routing with MOVED redirects
+    #[arg(long = "cluster-node", value_name = "URI")]
+    pub cluster_nodes: Vec<String>,
+
+    /// This node's URI as listed in --cluster-node
+    /// (defaults to ws://localhost:<port><path>)
+    #[arg(long, value_name = "URI")]
+    pub node_uri: Option<String>,
+
+    /// Webhook URL notified when a stream is finalized (repeatable)
+    #[arg(long = "webhook-url", value_name = "URL")]
+    pub webhook_urls: Vec<String>,
+
+    /// Enable verbose logging
+    #[arg(long, short = 'v')]
+    pub verbose: bool,
+
+    /// Never color log output (also set by NO_COLOR or when not on a terminal)
+    #[arg(long)]
+    pub no_color: bool,
+}
+
+#[cfg(feature = "server")]
+impl ServerConfig {
+    pub fn parse() -> Self {
+        <ServerConfig as Parser>::parse()
+    }
+
+    /// Port of --node-uri, which this process listens on besides the port
+    /// it shares with --reuse-port.
+    pub fn shard_port(&self) -> Option<u16> {
+        if !self.reuse_port {
+            return None;
+        }
+        url::Url::parse(self.node_uri.as_deref()?)
+            .ok()?
+            .port_or_known_default()
+    }
+}
+
+#[cfg(feature = "server")]
+impl Default for ServerConfig {
+    /// The configuration of a server started without arguments.
+    fn default() -> Self {
+        <ServerConfig as Parser>::parse_from(["audio_stream_server"])
+    }
+}
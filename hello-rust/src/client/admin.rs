@@ -0,0 +1,35 @@
+// Admin commands sent to a server (drain).
+// The server authorizes them by the token it was started with (--admin-token).
+
+use super::error::{ClientError, Result};
+use super::websocket_client::{ControlMessage, WebSocketClient};
+use crate::cli::DrainConfig;
+use crate::logger;
+
+/// Put the server in drain mode: it refuses new uploads and exits once its
+/// open connections close.
+pub async fn run_drain(config: &DrainConfig) -> Result<()> {
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let result = drain(&mut ws_client, &config.token).await;
+    let _ = ws_client.close().await;
+
+    let uploads = result?;
+    logger::log_info(&format!(
+        "Server {} is draining with {} uploads in flight",
+        config.server, uploads
+    ));
+    Ok(())
+}
+
+async fn drain(ws_client: &mut WebSocketClient, token: &str) -> Result<usize> {
+    ws_client
+        .send_control_message(ControlMessage::Drain {
+            token: token.to_string(),
+        })
+        .await?;
+    match ws_client.receive_reply("DRAIN", &["DRAINING"]).await? {
+        ControlMessage::Draining { uploads } => Ok(uploads),
+        other => Err(ClientError::unexpected("DRAIN", other)),
+    }
+}
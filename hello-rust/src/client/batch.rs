@@ -0,0 +1,95 @@
+// Batch upload of many files (batch subcommand).
+// Files are uploaded concurrently over a pool of connections that are reused
+// from one file to the next instead of reconnecting per file. With --manifest,
+// the stream and SHA-256 of every uploaded file are added to a manifest that
+// `verify-manifest` can later check the server against.
+
+use std::time::Instant;
+
+use futures_util::stream::{self, StreamExt};
+
+use super::connection_pool::ConnectionPool;
+use super::error::{ClientError, Result};
+use super::manifest::{Manifest, ManifestEntry};
+use super::{file_manager, upload_manager};
+use crate::cli::BatchConfig;
+use crate::logger;
+
+pub async fn run(config: &BatchConfig) -> Result<()> {
+    logger::log_banner("Starting Batch Upload");
+    logger::log_info(&format!("Files: {}", config.inputs.len()));
+    logger::log_info(&format!("Connections: {}", config.connections));
+
+    let pool = ConnectionPool::new(&config.server, config.connections as usize);
+    let start = Instant::now();
+    let results: Vec<(String, Result<ManifestEntry>)> = stream::iter(&config.inputs)
+        .map(|input| async {
+            let result = upload_one(&pool, input).await;
+            (input.clone(), result)
+        })
+        .buffer_unordered(config.connections as usize)
+        .collect()
+        .await;
+    pool.close().await;
+
+    let mut manifest = match &config.manifest {
+        Some(path) => Some(Manifest::load(path)?),
+        None => None,
+    };
+    let mut failures = Vec::new();
+    let mut bytes = 0;
+    for (input, result) in &results {
+        match result {
+            Ok(entry) => {
+                bytes += entry.size;
+                logger::log_info(&format!("{} -> {}", input, entry.stream_id));
+                if let Some(manifest) = manifest.as_mut() {
+                    manifest.files.insert(input.clone(), entry.clone());
+                }
+            }
+            Err(e) => {
+                logger::log_error(&format!("{}: {}", input, e));
+                failures.push(input.as_str());
+            }
+        }
+    }
+    logger::log_info(&format!(
+        "Uploaded {} of {} files ({} bytes) in {} ms",
+        results.len() - failures.len(),
+        results.len(),
+        bytes,
+        start.elapsed().as_millis()
+    ));
+    if let (Some(manifest), Some(path)) = (&manifest, &config.manifest) {
+        manifest.save(path)?;
+        logger::log_info(&format!("Wrote manifest {}", path.display()));
+    }
+
+    if !failures.is_empty() {
+        return Err(ClientError::Batch(format!(
+            "{} of {} files failed: {}",
+            failures.len(),
+            results.len(),
+            failures.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Upload one file over a pooled connection; returns its stream ID, size,
+/// and SHA-256.
+async fn upload_one(pool: &ConnectionPool, input: &str) -> Result<ManifestEntry> {
+    let size = file_manager::get_file_size(input)?;
+    let mut connection = pool.acquire().await?;
+    match upload_manager::upload(&mut connection, input, size).await {
+        Ok(upload) => Ok(ManifestEntry {
+            stream_id: upload.stream_id,
+            size: upload.sent.size,
+            sha256: upload.sent.checksum,
+        }),
+        Err(e) => {
+            connection.discard().await;
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// Throughput benchmark with regression detection.
+// Each run averages several upload/download cycles, compares the result with a
+// rolling baseline of earlier runs stored as JSON, and appends itself to that
+// history. Runs on a simulated link are only compared with runs on the same
+// simulated link. --synthetic-size benchmarks a generated sparse file, so
+// transfers of tens of GB can be measured without such a file at hand.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::error::{ClientError, Result};
+use super::network_sim::NetworkProfile;
+use super::websocket_client::WebSocketClient;
+use super::file_manager::SpoolDir;
+use super::{download_manager, file_manager, upload_manager, verification_module};
+use crate::cli::BenchConfig;
+use crate::logger;
+
+/// Spacing of the random blocks in a synthetic input file.
+const SYNTHETIC_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// One benchmark run as stored in the history file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchRecord {
+    pub timestamp: String,
+    pub server: String,
+    pub file_size: u64,
+    pub iterations: u32,
+    pub upload_mbps: f64,
+    pub download_mbps: f64,
+    /// Simulated link of the run, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    /// Regressed runs are kept for reference but excluded from the baseline.
+    #[serde(default)]
+    pub regressed: bool,
+}
+
+/// Benchmark history file contents.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BenchHistory {
+    pub runs: Vec<BenchRecord>,
+}
+
+impl BenchHistory {
+    /// Load the history, starting empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let display = path.display().to_string();
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                ClientError::storage(
+                    &display,
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ClientError::storage(&display, e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let display = path.display().to_string();
+        let data = serde_json::to_vec_pretty(self).map_err(|e| {
+            ClientError::storage(
+                &display,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        std::fs::write(path, data).map_err(|e| ClientError::storage(&display, e))
+    }
+
+    /// Average throughput of the last `window` comparable, non-regressed runs,
+    /// as `(upload_mbps, download_mbps, runs)`.
+    pub fn baseline(
+        &self,
+        server: &str,
+        file_size: u64,
+        network: Option<&str>,
+        window: usize,
+    ) -> Option<(f64, f64, usize)> {
+        let runs: Vec<&BenchRecord> = self
+            .runs
+            .iter()
+            .rev()
+            .filter(|run| {
+                !run.regressed
+                    && run.server == server
+                    && run.file_size == file_size
+                    && run.network.as_deref() == network
+            })
+            .take(window)
+            .collect();
+        if runs.is_empty() {
+            return None;
+        }
+
+        let count = runs.len() as f64;
+        let upload = runs.iter().map(|run| run.upload_mbps).sum::<f64>() / count;
+        let download = runs.iter().map(|run| run.download_mbps).sum::<f64>() / count;
+        Some((upload, download, runs.len()))
+    }
+}
+
+/// Run the benchmark and fail with [`ClientError::Regression`] when
+/// throughput drops more than `max_regression` percent below the baseline.
+pub async fn run(config: &BenchConfig) -> Result<()> {
+    logger::log_banner("Starting Throughput Benchmark");
+    let (input, _spool) = match &config.input {
+        Some(input) => {
+            logger::log_info(&format!("Input File: {}", input));
+            (input.clone(), None)
+        }
+        None => {
+            let size = config.synthetic_size.unwrap_or(0);
+            logger::log_info(&format!("Synthetic Input: {} bytes", size));
+            let spool = SpoolDir::create("bench")?;
+            let input = spool.file("synthetic.bin");
+            write_synthetic_file(&input, size)?;
+            (input, Some(spool))
+        }
+    };
+    logger::log_info(&format!("Iterations: {}", config.iterations));
+    logger::log_info(&format!("History: {}", config.history.display()));
+    let profile = NetworkProfile {
+        bandwidth_kbps: config.bandwidth_kbps,
+        latency: Duration::from_millis(config.latency_ms),
+        jitter: Duration::from_millis(config.jitter_ms),
+    };
+    let network = (!profile.is_unshaped()).then(|| profile.to_string());
+    if let Some(network) = &network {
+        logger::log_info(&format!("Simulated Network: {}", network));
+    }
+
+    let file_size = file_manager::get_file_size(&input)?;
+    let iterations = config.iterations.max(1);
+
+    let mut ws_client = WebSocketClient::new(&config.server);
+    if network.is_some() {
+        ws_client.set_network_profile(profile);
+    }
+    ws_client.connect(&config.server).await?;
+
+    let output = std::env::temp_dir().join(format!("audio-bench-{}.bin", std::process::id()));
+    let output = output.to_string_lossy().to_string();
+
+    let mut upload_secs = 0.0;
+    let mut download_secs = 0.0;
+    for iteration in 1..=iterations {
+        let start = Instant::now();
+        let upload = upload_manager::upload(&mut ws_client, &input, file_size).await?;
+        upload_secs += start.elapsed().as_secs_f64();
+
+        let start = Instant::now();
+        let downloaded =
+            download_manager::download(&mut ws_client, &upload.stream_id, &output, Some(file_size))
+                .await?;
+        download_secs += start.elapsed().as_secs_f64();
+
+        let result = verification_module::verify(&upload.sent, &downloaded);
+        if !result.passed {
+            let _ = std::fs::remove_file(&output);
+            let _ = ws_client.close().await;
+            return Err(ClientError::Verification(format!(
+                "iteration {}: expected SHA-256 {}, got {}",
+                iteration, result.original_checksum, result.downloaded_checksum
+            )));
+        }
+        logger::log_info(&format!("Iteration {}/{} complete", iteration, iterations));
+    }
+    let _ = std::fs::remove_file(&output);
+    let _ = ws_client.close().await;
+
+    let total_bits = file_size as f64 * iterations as f64 * 8.0;
+    let upload_mbps = total_bits / upload_secs.max(f64::EPSILON) / 1_000_000.0;
+    let download_mbps = total_bits / download_secs.max(f64::EPSILON) / 1_000_000.0;
+
+    let mut history = BenchHistory::load(&config.history)?;
+    let baseline = history.baseline(
+        &config.server,
+        file_size,
+        network.as_deref(),
+        config.baseline_runs,
+    );
+
+    logger::log_banner("Benchmark Summary");
+    logger::log_info(&format!("Upload Throughput: {:.2} Mbps", upload_mbps));
+    logger::log_info(&format!("Download Throughput: {:.2} Mbps", download_mbps));
+
+    let regressions = match baseline {
+        Some((base_upload, base_download, runs)) => {
+            logger::log_info(&format!(
+                "Baseline ({} runs): upload {:.2} Mbps, download {:.2} Mbps",
+                runs, base_upload, base_download
+            ));
+            find_regressions(
+                (upload_mbps, download_mbps),
+                (base_upload, base_download),
+                config.max_regression,
+            )
+        }
+        None => {
+            logger::log_info("No baseline yet; this run starts the history");
+            Vec::new()
+        }
+    };
+
+    history.runs.push(BenchRecord {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        server: config.server.clone(),
+        file_size,
+        iterations,
+        upload_mbps,
+        download_mbps,
+        network,
+        regressed: !regressions.is_empty(),
+    });
+    history.save(&config.history)?;
+
+    if !regressions.is_empty() {
+        logger::log_error(&format!(
+            "Regression beyond {}%: {}",
+            config.max_regression,
+            regressions.join("; ")
+        ));
+        return Err(ClientError::Regression(regressions.join("; ")));
+    }
+
+    logger::log_info("Overall Result: SUCCESS");
+    Ok(())
+}
+
+/// Compare the `(upload_mbps, download_mbps)` of a run with the baseline and
+/// describe each direction more than `max_regression` percent below it.
+/// Directions without a positive baseline cannot regress and are skipped.
+fn find_regressions(current: (f64, f64), baseline: (f64, f64), max_regression: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+    for (direction, current, base) in [
+        ("upload", current.0, baseline.0),
+        ("download", current.1, baseline.1),
+    ] {
+        if !base.is_finite() || base <= 0.0 {
+            logger::log_warn(&format!(
+                "{} baseline {} Mbps is not usable, skipping comparison",
+                direction, base
+            ));
+            continue;
+        }
+        let change = (current - base) / base * 100.0;
+        logger::log_info(&format!("{} change: {:+.1}%", direction, change));
+        if change < -max_regression {
+            regressions.push(format!(
+                "{} {:.2} Mbps is {:.1}% below baseline {:.2} Mbps",
+                direction, current, -change, base
+            ));
+        }
+    }
+    regressions
+}
+
+/// Create a sparse file of `size` bytes with a random block at the start of
+/// every 64MB segment, so it takes little disk space while no two segments
+/// are alike and a misplaced chunk fails verification.
+fn write_synthetic_file(path: &str, size: u64) -> Result<()> {
+    let storage_error = |e| ClientError::storage(path, e);
+    let mut file = std::fs::File::create(path).map_err(storage_error)?;
+    file.set_len(size).map_err(storage_error)?;
+
+    let mut block = vec![0u8; file_manager::CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let length = std::cmp::min(block.len() as u64, size - offset) as usize;
+        rand::rng().fill(&mut block[..length]);
+        file.seek(SeekFrom::Start(offset)).map_err(storage_error)?;
+        file.write_all(&block[..length]).map_err(storage_error)?;
+        offset += SYNTHETIC_SEGMENT_SIZE;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "ws://localhost:8080/audio";
+
+    fn record(upload_mbps: f64, network: Option<&str>, regressed: bool) -> BenchRecord {
+        BenchRecord {
+            timestamp: String::new(),
+            server: SERVER.to_string(),
+            file_size: 1000,
+            iterations: 1,
+            upload_mbps,
+            download_mbps: upload_mbps * 2.0,
+            network: network.map(str::to_string),
+            regressed,
+        }
+    }
+
+    #[test]
+    fn baseline_averages_the_latest_runs() {
+        let history = BenchHistory {
+            runs: [10.0, 20.0, 30.0, 40.0]
+                .into_iter()
+                .map(|mbps| record(mbps, None, false))
+                .collect(),
+        };
+        assert_eq!(
+            history.baseline(SERVER, 1000, None, 2),
+            Some((35.0, 70.0, 2))
+        );
+        assert_eq!(
+            history.baseline(SERVER, 1000, None, 10),
+            Some((25.0, 50.0, 4))
+        );
+        assert_eq!(history.baseline(SERVER, 2000, None, 10), None);
+        assert_eq!(history.baseline("ws://other/audio", 1000, None, 10), None);
+    }
+
+    #[test]
+    fn baseline_skips_regressed_runs() {
+        let history = BenchHistory {
+            runs: vec![
+                record(40.0, None, false),
+                record(50.0, None, false),
+                record(5.0, None, true),
+            ],
+        };
+        assert_eq!(
+            history.baseline(SERVER, 1000, None, 2),
+            Some((45.0, 90.0, 2))
+        );
+    }
+
+    #[test]
+    fn baseline_matches_the_simulated_network() {
+        let shaped = "1000kbps 50ms";
+        let history = BenchHistory {
+            runs: vec![
+                record(1.0, Some(shaped), false),
+                record(100.0, None, false),
+                record(2.0, Some("2000kbps 50ms"), false),
+            ],
+        };
+        assert_eq!(
+            history.baseline(SERVER, 1000, Some(shaped), 5),
+            Some((1.0, 2.0, 1))
+        );
+        assert_eq!(
+            history.baseline(SERVER, 1000, None, 5),
+            Some((100.0, 200.0, 1))
+        );
+    }
+
+    #[test]
+    fn regression_beyond_the_threshold_fails() {
+        assert!(find_regressions((90.0, 180.0), (100.0, 200.0), 10.0).is_empty());
+        assert!(find_regressions((150.0, 300.0), (100.0, 200.0), 10.0).is_empty());
+
+        let regressions = find_regressions((89.0, 200.0), (100.0, 200.0), 10.0);
+        assert_eq!(
+            regressions,
+            ["upload 89.00 Mbps is 11.0% below baseline 100.00 Mbps"]
+        );
+        assert_eq!(
+            find_regressions((89.0, 100.0), (100.0, 200.0), 10.0).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn unusable_baselines_are_skipped() {
+        assert!(find_regressions((0.0, 0.0), (0.0, -1.0), 10.0).is_empty());
+        assert!(find_regressions((1.0, 1.0), (f64::NAN, f64::INFINITY), 10.0).is_empty());
+        assert_eq!(
+            find_regressions((0.0, 50.0), (0.0, 100.0), 10.0),
+            ["download 50.00 Mbps is 50.0% below baseline 100.00 Mbps"]
+        );
+    }
+}
@@ -0,0 +1,289 @@
+// Multi-channel split and merge (split-channels / merge-channels).
+// The channels of a WAV file are deinterleaved into one mono PCM stream each,
+// so they can be stored and processed independently, and mono PCM streams are
+// interleaved back into a single WAV file on download.
+
+use std::path::Path;
+
+use super::download_manager;
+use super::error::{ClientError, Result};
+use super::file_manager::{self, SpoolDir};
+use super::upload_manager::{self, UploadResult};
+use super::websocket_client::{ControlMessage, WebSocketClient};
+use crate::cli::{MergeChannelsConfig, SplitChannelsConfig};
+use crate::logger;
+use crate::protocol::PcmFormat;
+
+// Leading bytes searched for the WAV data chunk
+const WAV_HEADER_BYTES: u64 = 64 * 1024;
+
+/// Split interleaved frames into one buffer per channel.
+pub fn deinterleave(data: &[u8], format: &PcmFormat) -> Vec<Vec<u8>> {
+    let channels = format.channels as usize;
+    let sample_size = format.frame_size() / channels;
+    let mut split = vec![Vec::with_capacity(data.len() / channels); channels];
+    for frame in data.chunks_exact(format.frame_size()) {
+        for (channel, sample) in frame.chunks_exact(sample_size).enumerate() {
+            split[channel].extend_from_slice(sample);
+        }
+    }
+    split
+}
+
+/// Interleave per-channel buffers of equal length into frames.
+pub fn interleave(channels: &[Vec<u8>], sample_size: usize) -> Vec<u8> {
+    let samples = channels.iter().map(Vec::len).min().unwrap_or(0) / sample_size;
+    let mut data = Vec::with_capacity(samples * sample_size * channels.len());
+    for index in 0..samples {
+        let range = index * sample_size..(index + 1) * sample_size;
+        for channel in channels {
+            data.extend_from_slice(&channel[range.clone()]);
+        }
+    }
+    data
+}
+
+/// Upload each channel of a PCM WAV file as a mono PCM stream, in channel
+/// order.
+pub async fn split_upload(
+    ws_client: &mut WebSocketClient,
+    input: &str,
+) -> Result<Vec<UploadResult>> {
+    let file_size = file_manager::get_file_size(input)?;
+    let header = file_manager::read_chunk(
+        input,
+        0,
+        std::cmp::min(file_size, WAV_HEADER_BYTES) as usize,
+    )
+    .await?;
+    let (format, data) = PcmFormat::from_wav_header(&header, file_size).ok_or_else(|| {
+        ClientError::Protocol(format!("{} is not an integer PCM WAV file", input))
+    })?;
+    let mono = PcmFormat::new(format.sample_rate, 1, format.bits_per_sample)
+        .expect("mono variant of a valid format");
+    logger::log_info(&format!(
+        "Splitting {} channels of {} Hz, {}-bit audio ({} bytes)",
+        format.channels,
+        format.sample_rate,
+        format.bits_per_sample,
+        data.end - data.start
+    ));
+
+    // Deinterleave into per-channel spool files named after the input
+    let spool = SpoolDir::create("split")?;
+    let stem = Path::new(input)
+        .file_stem()
+        .map_or_else(|| "audio".into(), |stem| stem.to_string_lossy());
+    let paths: Vec<String> = (0..format.channels)
+        .map(|channel| spool.file(&format!("{}.ch{}.pcm", stem, channel)))
+        .collect();
+    for path in &paths {
+        file_manager::write_chunk(path, &[], false).await?;
+    }
+
+    let step = (file_manager::CHUNK_SIZE / format.frame_size()).max(1) * format.frame_size();
+    let mut offset = data.start;
+    while offset < data.end {
+        let length = std::cmp::min(step as u64, data.end - offset) as usize;
+        let frames = file_manager::read_chunk(input, offset, length).await?;
+        for (path, samples) in paths.iter().zip(deinterleave(&frames, &format)) {
+            file_manager::write_chunk(path, &samples, true).await?;
+        }
+        offset += length as u64;
+    }
+
+    let content_type = mono.content_type();
+    let mut uploads = Vec::with_capacity(paths.len());
+    for (channel, path) in paths.iter().enumerate() {
+        let size = file_manager::get_file_size(path)?;
+        let upload = upload_manager::upload_as(ws_client, path, size, &content_type).await?;
+        logger::log_info(&format!("Channel {}: stream {}", channel, upload.stream_id));
+        uploads.push(upload);
+    }
+    Ok(uploads)
+}
+
+/// Download mono PCM streams and interleave them, in the given order, into a
+/// WAV file. Returns the format written.
+pub async fn merge_download(
+    ws_client: &mut WebSocketClient,
+    stream_ids: &[String],
+    output: &str,
+) -> Result<PcmFormat> {
+    let channel_count = channel_count(stream_ids)?;
+
+    // Every channel must be finalized mono PCM of one format and length
+    let mut channels = Vec::with_capacity(stream_ids.len());
+    for stream_id in stream_ids {
+        channels.push(query_channel(ws_client, stream_id).await?);
+    }
+    let (mono, size, _) = &channels[0];
+    for ((format, channel_size, _), stream_id) in channels.iter().zip(stream_ids) {
+        if format != mono || channel_size != size {
+            return Err(ClientError::Protocol(format!(
+                "Stream {} does not match the format and length of stream {}",
+                stream_id, stream_ids[0]
+            )));
+        }
+    }
+    let format = PcmFormat::new(mono.sample_rate, channel_count, mono.bits_per_sample)
+        .ok_or_else(|| ClientError::Protocol(format!("Too many channels: {}", channel_count)))?;
+
+    let spool = SpoolDir::create("merge")?;
+    let mut paths = Vec::with_capacity(stream_ids.len());
+    for (channel, ((_, size, checksum), stream_id)) in channels.iter().zip(stream_ids).enumerate() {
+        // Named by channel, as namespaced stream IDs are not file names
+        let path = spool.file(&format!("channel-{}.pcm", channel));
+        let downloaded =
+            download_manager::download(ws_client, stream_id, &path, Some(*size)).await?;
+        if checksum
+            .as_ref()
+            .is_some_and(|checksum| *checksum != downloaded.checksum)
+        {
+            return Err(ClientError::Verification(format!(
+                "stream {} does not match its stored SHA-256",
+                stream_id
+            )));
+        }
+        paths.push(path);
+    }
+
+    let sample_size = mono.frame_size();
+    let step = (file_manager::CHUNK_SIZE / sample_size).max(1) * sample_size;
+    file_manager::write_chunk(output, &format.wav_header(size * paths.len() as u64), false).await?;
+    let mut offset = 0;
+    while offset < *size {
+        let length = std::cmp::min(step as u64, size - offset) as usize;
+        let mut samples = Vec::with_capacity(paths.len());
+        for path in &paths {
+            samples.push(file_manager::read_chunk(path, offset, length).await?);
+        }
+        file_manager::write_chunk(output, &interleave(&samples, sample_size), true).await?;
+        offset += length as u64;
+    }
+    logger::log_info(&format!(
+        "Merged {} channels into {} ({} bytes of samples)",
+        paths.len(),
+        output,
+        size * paths.len() as u64
+    ));
+    Ok(format)
+}
+
+/// Run the `split-channels` subcommand.
+pub async fn run_split(config: &SplitChannelsConfig) -> Result<()> {
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let result = split_upload(&mut ws_client, &config.input).await;
+    let _ = ws_client.close().await;
+
+    let stream_ids: Vec<String> = result?.into_iter().map(|upload| upload.stream_id).collect();
+    logger::log_info(&format!("Channel streams: {}", stream_ids.join(" ")));
+    Ok(())
+}
+
+/// Run the `merge-channels` subcommand.
+pub async fn run_merge(config: &MergeChannelsConfig) -> Result<()> {
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let result = merge_download(&mut ws_client, &config.stream_ids, &config.output).await;
+    let _ = ws_client.close().await;
+
+    let format = result?;
+    logger::log_info(&format!(
+        "Wrote {} ({} channels, {} Hz, {}-bit)",
+        config.output, format.channels, format.sample_rate, format.bits_per_sample
+    ));
+    Ok(())
+}
+
+/// Get the channel count of a merge, which must fit a WAV header.
+fn channel_count(stream_ids: &[String]) -> Result<u16> {
+    match u16::try_from(stream_ids.len()) {
+        Ok(0) => Err(ClientError::Protocol("No streams to merge".to_string())),
+        Ok(count) => Ok(count),
+        Err(_) => Err(ClientError::Protocol(format!(
+            "Too many channels: {}",
+            stream_ids.len()
+        ))),
+    }
+}
+
+/// Get the mono PCM format, size, and checksum of a finalized stream.
+async fn query_channel(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+) -> Result<(PcmFormat, u64, Option<String>)> {
+    ws_client
+        .send_control_message(ControlMessage::Stat {
+            stream_id: stream_id.to_string(),
+            version: None,
+        })
+        .await?;
+
+    let (size, status, checksum, metadata) =
+        match ws_client.receive_reply("STAT", &["STAT_RESULT"]).await? {
+            ControlMessage::StatResult {
+                size,
+                status,
+                checksum,
+                metadata,
+                ..
+            } => (size, status, checksum, metadata),
+            other => return Err(ClientError::unexpected("STAT", other)),
+        };
+    if status != "READY" {
+        return Err(ClientError::Protocol(format!(
+            "Stream {} is {}, expected READY",
+            stream_id, status
+        )));
+    }
+    match metadata
+        .get("contentType")
+        .and_then(|content_type| PcmFormat::from_content_type(content_type))
+    {
+        Some(format) if format.channels == 1 => Ok((format, size, checksum)),
+        _ => Err(ClientError::Protocol(format!(
+            "Stream {} is not mono PCM",
+            stream_id
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deinterleave_splits_frames_by_channel() {
+        let format = PcmFormat::new(8000, 2, 16).unwrap();
+        let data = [1, 1, 2, 2, 3, 3, 4, 4, 5];
+        // The trailing partial frame is dropped
+        assert_eq!(
+            deinterleave(&data, &format),
+            vec![vec![1, 1, 3, 3], vec![2, 2, 4, 4]]
+        );
+    }
+
+    #[test]
+    fn interleave_reverses_deinterleave() {
+        let format = PcmFormat::new(8000, 3, 24).unwrap();
+        let data: Vec<u8> = (0..90).collect();
+        let channels = deinterleave(&data, &format);
+        assert_eq!(interleave(&channels, 3), data);
+
+        // Channels are cut to the shortest whole sample
+        let uneven = vec![vec![1, 2, 3, 4], vec![5, 6, 7]];
+        assert_eq!(interleave(&uneven, 2), vec![1, 2, 5, 6]);
+        assert!(interleave(&[], 2).is_empty());
+    }
+
+    #[test]
+    fn channel_count_fits_a_wav_header() {
+        let ids = |count| vec!["s".to_string(); count];
+        assert!(channel_count(&ids(0)).is_err());
+        assert_eq!(channel_count(&ids(2)).unwrap(), 2);
+        assert_eq!(channel_count(&ids(65535)).unwrap(), u16::MAX);
+        assert!(channel_count(&ids(65536)).is_err());
+    }
+}
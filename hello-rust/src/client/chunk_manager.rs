@@ -1,5 +1,10 @@
 // Chunk manager for handling file chunking operations
 
+use tokio::sync::mpsc;
+
+use super::error::Result;
+use crate::protocol::PcmFormat;
+
 #[allow(dead_code)]
 pub const CHUNK_SIZE: usize = 65536; // 64KB
 
@@ -9,10 +14,88 @@ pub struct ChunkManager;
 #[allow(dead_code)]
 impl ChunkManager {
     pub fn calculate_chunk_count(file_size: u64, chunk_size: usize) -> usize {
-        ((file_size + chunk_size as u64 - 1) / chunk_size as u64) as usize
+        file_size.div_ceil(chunk_size as u64) as usize
     }
 
     pub fn get_chunk_size(file_size: u64, offset: u64, default_chunk_size: usize) -> usize {
         std::cmp::min(default_chunk_size as u64, file_size - offset) as usize
     }
 }
+
+/// How an upload splits its input into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkMode {
+    /// Chunks of the default size, regardless of content.
+    #[default]
+    Fixed,
+    /// Chunks holding whole frames of `frame_size` bytes, so live subscribers
+    /// and mid-transfer readers never see a sample torn across chunks.
+    FrameAligned { frame_size: usize },
+}
+
+impl ChunkMode {
+    /// Align chunks to the frames of a raw PCM format.
+    pub fn for_format(format: &PcmFormat) -> Self {
+        ChunkMode::FrameAligned {
+            frame_size: format.frame_size(),
+        }
+    }
+
+    /// Pick the mode for a content type: frame-aligned for raw PCM with a
+    /// declared format, fixed otherwise.
+    pub fn for_content_type(content_type: &str) -> Self {
+        PcmFormat::from_content_type(content_type)
+            .map_or(ChunkMode::Fixed, |format| Self::for_format(&format))
+    }
+
+    /// Get the chunk size to use instead of `default_chunk_size`, rounded down
+    /// to whole frames (at least one).
+    pub fn chunk_size(&self, default_chunk_size: usize) -> usize {
+        match *self {
+            ChunkMode::Fixed => default_chunk_size,
+            ChunkMode::FrameAligned { frame_size } => {
+                (default_chunk_size / frame_size).max(1) * frame_size
+            }
+        }
+    }
+}
+
+/// Receiving end of bytes produced concurrently by another task, such as an
+/// HTTP fetch or a download from another server, regrouped into chunks of
+/// the size an upload asks for.
+pub struct ChunkPipe {
+    receiver: mpsc::Receiver<Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl ChunkPipe {
+    /// Create a pipe buffering up to `capacity` pieces, and the sender the
+    /// producer writes to. Dropping the sender ends the pipe; sending an error
+    /// fails the upload reading it.
+    pub fn new(capacity: usize) -> (mpsc::Sender<Result<Vec<u8>>>, Self) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let pipe = Self {
+            receiver,
+            pending: Vec::new(),
+            finished: false,
+        };
+        (sender, pipe)
+    }
+
+    /// Read the next chunk of `max_size` bytes; only the last chunk may be
+    /// shorter. Returns `None` once the producer is done.
+    pub async fn next_chunk(&mut self, max_size: usize) -> Result<Option<Vec<u8>>> {
+        while self.pending.len() < max_size && !self.finished {
+            match self.receiver.recv().await {
+                Some(piece) => self.pending.extend_from_slice(&piece?),
+                None => self.finished = true,
+            }
+        }
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.pending.split_off(max_size.min(self.pending.len()));
+        Ok(Some(std::mem::replace(&mut self.pending, rest)))
+    }
+}
@@ -0,0 +1,370 @@
+// Cross-implementation conformance suite (conformance).
+// Scripted scenarios run against each server given, e.g. the Python, Java,
+// and C++ implementations next to this one, and the outcome of every case on
+// every server is printed as a compatibility matrix. The cases stick to the
+// core protocol all implementations share: START/STOP uploads, GET reads by
+// offset, and ERROR replies. Each case runs on a fresh connection, so one
+// failure does not cascade into the next.
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::error::{ClientError, Result};
+use super::file_manager::{self, SpoolDir};
+use super::verification_module::{self, TransferChecksum, TransferDigest};
+use super::websocket_client::{ControlMessage, Incoming, WebSocketClient};
+use super::{download_manager, stream_id_generator, upload_manager};
+use crate::cli::ConformanceConfig;
+use crate::logger;
+
+// Size of the file transferred by the happy-path and resume cases
+const SMALL_FILE_SIZE: u64 = 256 * 1024;
+
+/// Scenario of the conformance suite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    /// Connect, offering the audio-stream.v1 subprotocol.
+    Handshake,
+    /// Upload a file and download it unchanged.
+    HappyPath,
+    /// GET of a stream that does not exist is answered with ERROR.
+    UnknownStream,
+    /// STOP of a stream that was never started is answered with ERROR.
+    StopWithoutStart,
+    /// A control message that is not valid JSON is answered with ERROR.
+    MalformedMessage,
+    /// A download cut off halfway continues by offset on a new connection.
+    Resume,
+    /// Upload and download a file of `--large-file-mib`.
+    LargeFile,
+}
+
+impl Case {
+    /// Every case, in the order the suite runs them.
+    pub const ALL: [Case; 7] = [
+        Case::Handshake,
+        Case::HappyPath,
+        Case::UnknownStream,
+        Case::StopWithoutStart,
+        Case::MalformedMessage,
+        Case::Resume,
+        Case::LargeFile,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Case::Handshake => "handshake",
+            Case::HappyPath => "happy-path",
+            Case::UnknownStream => "unknown-stream",
+            Case::StopWithoutStart => "stop-without-start",
+            Case::MalformedMessage => "malformed-message",
+            Case::Resume => "resume",
+            Case::LargeFile => "large-file",
+        }
+    }
+}
+
+/// Outcome of one case on one server, with a short description.
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        matches!(self, Outcome::Pass(_))
+    }
+}
+
+/// Files shared by the cases of a run.
+struct TestFiles {
+    small: String,
+    large: String,
+    output: String,
+}
+
+/// Run the `conformance` subcommand and fail with
+/// [`ClientError::Conformance`] when any server fails a case.
+pub async fn run(config: &ConformanceConfig) -> Result<()> {
+    logger::log_banner("Starting Conformance Suite");
+    logger::log_info(&format!("Servers: {}", config.servers.join(" ")));
+
+    let spool = SpoolDir::create("conformance")?;
+    let files = TestFiles {
+        small: spool.file("small.bin"),
+        large: spool.file("large.bin"),
+        output: spool.file("download.bin"),
+    };
+    write_random_file(&files.small, SMALL_FILE_SIZE).await?;
+    write_random_file(&files.large, config.large_file_mib * 1024 * 1024).await?;
+
+    let timeout = Duration::from_secs(config.case_timeout);
+    let mut matrix = Vec::with_capacity(config.servers.len());
+    for server in &config.servers {
+        let mut outcomes = Vec::with_capacity(Case::ALL.len());
+        for case in Case::ALL {
+            logger::log_phase(&format!("{} @ {}", case.name(), server));
+            let start = Instant::now();
+            let outcome = match tokio::time::timeout(timeout, run_case(case, server, &files)).await
+            {
+                Ok(Ok(detail)) => Outcome::Pass(detail),
+                Ok(Err(e)) => Outcome::Fail(e.to_string()),
+                Err(_) => Outcome::Fail(format!("No answer within {}s", config.case_timeout)),
+            };
+            match &outcome {
+                Outcome::Pass(detail) => logger::log_info(&format!(
+                    "PASS {} ({:.2}s): {}",
+                    case.name(),
+                    start.elapsed().as_secs_f64(),
+                    detail
+                )),
+                Outcome::Fail(reason) => {
+                    logger::log_error(&format!("FAIL {}: {}", case.name(), reason))
+                }
+            }
+            outcomes.push(outcome);
+        }
+        matrix.push(outcomes);
+    }
+
+    print_matrix(&config.servers, &matrix);
+    let failures: Vec<String> = config
+        .servers
+        .iter()
+        .zip(&matrix)
+        .filter_map(|(server, outcomes)| {
+            let failed: Vec<&str> = Case::ALL
+                .iter()
+                .zip(outcomes)
+                .filter(|(_, outcome)| !outcome.passed())
+                .map(|(case, _)| case.name())
+                .collect();
+            (!failed.is_empty()).then(|| format!("{}: {}", server, failed.join(", ")))
+        })
+        .collect();
+    if !failures.is_empty() {
+        return Err(ClientError::Conformance(failures.join("; ")));
+    }
+    Ok(())
+}
+
+/// Run one case on a new connection to `server`, returning a description of
+/// what the server did.
+async fn run_case(case: Case, server: &str, files: &TestFiles) -> Result<String> {
+    let mut ws_client = WebSocketClient::new(server);
+    ws_client.connect(server).await?;
+    let result = match case {
+        Case::Handshake => Ok(match ws_client.subprotocol() {
+            Some(subprotocol) => format!("Subprotocol {} selected", subprotocol),
+            None => "No subprotocol selected".to_string(),
+        }),
+        Case::HappyPath => transfer(&mut ws_client, &files.small, &files.output).await,
+        Case::UnknownStream => {
+            let get_msg = ControlMessage::Get {
+                stream_id: stream_id_generator::generate_short(),
+                offset: 0,
+                length: file_manager::CHUNK_SIZE,
+                start_time: None,
+                end_time: None,
+                checkpoint_every: None,
+                version: None,
+            };
+            ws_client.send_control_message(get_msg).await?;
+            expect_error(&mut ws_client).await
+        }
+        Case::StopWithoutStart => {
+            let stop_msg = ControlMessage::Stop {
+                stream_id: stream_id_generator::generate_short(),
+            };
+            ws_client.send_control_message(stop_msg).await?;
+            expect_error(&mut ws_client).await
+        }
+        Case::MalformedMessage => {
+            ws_client.send_text("{\"type\": \"START\", ").await?;
+            expect_error(&mut ws_client).await
+        }
+        Case::Resume => resume(&mut ws_client, server, &files.small).await,
+        Case::LargeFile => transfer(&mut ws_client, &files.large, &files.output).await,
+    };
+    let _ = ws_client.close().await;
+    result
+}
+
+/// Upload `input`, download it to `output`, and compare the checksums.
+async fn transfer(ws_client: &mut WebSocketClient, input: &str, output: &str) -> Result<String> {
+    let size = file_manager::get_file_size(input)?;
+    let start = Instant::now();
+    let upload = upload_manager::upload(ws_client, input, size).await?;
+    let downloaded =
+        download_manager::download(ws_client, &upload.stream_id, output, Some(size)).await?;
+    let elapsed = start.elapsed().as_secs_f64();
+    compare(&upload.sent, &downloaded)?;
+    Ok(format!(
+        "{} bytes round-tripped in {:.2}s ({:.2} MB/s)",
+        size,
+        elapsed,
+        (size * 2) as f64 / 1_000_000.0 / elapsed.max(f64::EPSILON)
+    ))
+}
+
+/// Upload `input`, read the first half of the stream, reconnect, and read
+/// the rest by offset.
+async fn resume(ws_client: &mut WebSocketClient, server: &str, input: &str) -> Result<String> {
+    let size = file_manager::get_file_size(input)?;
+    let upload = upload_manager::upload(ws_client, input, size).await?;
+
+    let half = size / 2;
+    let mut digest = TransferDigest::new();
+    read_range(ws_client, &upload.stream_id, 0..half, &mut digest).await?;
+    let _ = ws_client.close().await;
+    ws_client.connect(server).await?;
+    read_range(ws_client, &upload.stream_id, half..size, &mut digest).await?;
+
+    compare(&upload.sent, &digest.finalize())?;
+    Ok(format!("Resumed at offset {} of {} bytes", half, size))
+}
+
+/// Read `range` of a stream with GET requests of at most one chunk.
+async fn read_range(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    range: Range<u64>,
+    digest: &mut TransferDigest,
+) -> Result<()> {
+    let mut offset = range.start;
+    while offset < range.end {
+        let length = std::cmp::min(file_manager::CHUNK_SIZE as u64, range.end - offset) as usize;
+        let get_msg = ControlMessage::Get {
+            stream_id: stream_id.to_string(),
+            offset,
+            length,
+            start_time: None,
+            end_time: None,
+            checkpoint_every: None,
+            version: None,
+        };
+        ws_client.send_control_message(get_msg).await?;
+
+        let data = match ws_client.receive_incoming().await? {
+            Incoming::Binary(data) if !data.is_empty() => data,
+            Incoming::Binary(_) | Incoming::Closed => {
+                return Err(ClientError::Protocol(format!(
+                    "No data at offset {} of stream {}",
+                    offset, stream_id
+                )))
+            }
+            Incoming::Control(msg) => return Err(ClientError::unexpected("GET", msg)),
+        };
+        if data.len() > length {
+            return Err(ClientError::Protocol(format!(
+                "GET of {} bytes at offset {} returned {} bytes",
+                length,
+                offset,
+                data.len()
+            )));
+        }
+        offset += data.len() as u64;
+        digest.update(data).await;
+    }
+    Ok(())
+}
+
+/// Require the next reply to be an ERROR message.
+async fn expect_error(ws_client: &mut WebSocketClient) -> Result<String> {
+    match ws_client.receive_incoming().await? {
+        Incoming::Control(ControlMessage::Error {
+            code: Some(code),
+            message,
+            ..
+        }) => Ok(format!("ERROR {}: {}", code, message)),
+        Incoming::Control(ControlMessage::Error { message, .. }) => {
+            Ok(format!("ERROR: {}", message))
+        }
+        Incoming::Control(msg) => Err(ClientError::Protocol(format!(
+            "Expected ERROR, got {}",
+            msg.type_name()
+        ))),
+        Incoming::Binary(data) => Err(ClientError::Protocol(format!(
+            "Expected ERROR, got {} bytes of data",
+            data.len()
+        ))),
+        Incoming::Closed => Err(ClientError::closed()),
+    }
+}
+
+fn compare(sent: &TransferChecksum, received: &TransferChecksum) -> Result<()> {
+    let result = verification_module::verify(sent, received);
+    if result.passed {
+        return Ok(());
+    }
+    Err(ClientError::Verification(format!(
+        "sent {} bytes ({}), received {} bytes ({})",
+        result.original_size,
+        result.original_checksum,
+        result.downloaded_size,
+        result.downloaded_checksum
+    )))
+}
+
+/// Print one row per case and one column per server, followed by the
+/// reasons of the failures.
+fn print_matrix(servers: &[String], matrix: &[Vec<Outcome>]) {
+    let case_width = Case::ALL
+        .iter()
+        .map(|case| case.name().len())
+        .max()
+        .unwrap_or(0);
+    let row = |label: &str, cells: Vec<String>| {
+        let mut line = format!("{:<width$}", label, width = case_width);
+        for (cell, server) in cells.iter().zip(servers) {
+            line.push_str(&format!("  {:<width$}", cell, width = server.len()));
+        }
+        println!("{}", line.trim_end());
+    };
+
+    println!();
+    row("case", servers.to_vec());
+    for (index, case) in Case::ALL.iter().enumerate() {
+        let cells = matrix
+            .iter()
+            .map(|outcomes| {
+                let passed = outcomes[index].passed();
+                (if passed { "PASS" } else { "FAIL" }).to_string()
+            })
+            .collect();
+        row(case.name(), cells);
+    }
+    let totals = matrix
+        .iter()
+        .map(|outcomes| {
+            let passed = outcomes.iter().filter(|outcome| outcome.passed()).count();
+            format!("{}/{}", passed, outcomes.len())
+        })
+        .collect();
+    row("passed", totals);
+
+    for (server, outcomes) in servers.iter().zip(matrix) {
+        for (case, outcome) in Case::ALL.iter().zip(outcomes) {
+            if let Outcome::Fail(reason) = outcome {
+                println!("{} {}: {}", server, case.name(), reason);
+            }
+        }
+    }
+}
+
+/// Fill a file with `size` random bytes.
+async fn write_random_file(path: &str, size: u64) -> Result<()> {
+    file_manager::write_chunk(path, &[], false).await?;
+    let mut written = 0u64;
+    while written < size {
+        let length = std::cmp::min(file_manager::CHUNK_SIZE as u64, size - written) as usize;
+        let mut chunk = vec![0u8; length];
+        rand::rng().fill(&mut chunk[..]);
+        file_manager::write_chunk(path, &chunk, true).await?;
+        written += length as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+// Pool of established connections reused across transfers.
+// A connection is checked out for one transfer at a time and returned to the
+// pool afterwards; idle connections are health-checked before reuse, and dead
+// ones are replaced by fresh connections to the same server.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::error::{ClientError, Result};
+use super::websocket_client::WebSocketClient;
+use crate::logger;
+
+/// Time an idle connection has to answer the health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Up to `size` connections to one server.
+pub struct ConnectionPool {
+    uri: String,
+    idle: Mutex<Vec<WebSocketClient>>,
+    permits: Semaphore,
+}
+
+impl ConnectionPool {
+    pub fn new(uri: &str, size: usize) -> Self {
+        Self {
+            uri: uri.to_string(),
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(size.max(1)),
+        }
+    }
+
+    /// Check out a connection, waiting while all of them are in use. An idle
+    /// connection is reused once it passed the health check; otherwise a new
+    /// one is established.
+    pub async fn acquire(&self) -> Result<PooledConnection<'_>> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| ClientError::not_connected())?;
+
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            let Some(mut client) = idle else {
+                break;
+            };
+            match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, client.check_health()).await {
+                Ok(Ok(())) => return Ok(PooledConnection::new(self, client, permit)),
+                Ok(Err(e)) => logger::log_warn(&format!("Replacing dead pooled connection: {}", e)),
+                Err(_) => logger::log_warn(&format!(
+                    "Replacing pooled connection that did not answer within {}s",
+                    HEALTH_CHECK_TIMEOUT.as_secs()
+                )),
+            }
+        }
+
+        let mut client = WebSocketClient::new(&self.uri);
+        client.connect(&self.uri).await?;
+        logger::log_debug(&format!("Opened pooled connection to {}", self.uri));
+        Ok(PooledConnection::new(self, client, permit))
+    }
+
+    /// Close the idle connections.
+    pub async fn close(&self) {
+        let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+        for mut client in idle {
+            let _ = client.close().await;
+        }
+    }
+}
+
+/// Connection checked out of a [`ConnectionPool`]; it goes back to the pool
+/// when dropped, unless discarded.
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    client: Option<WebSocketClient>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a> PooledConnection<'a> {
+    fn new(pool: &'a ConnectionPool, client: WebSocketClient, permit: SemaphorePermit<'a>) -> Self {
+        Self {
+            pool,
+            client: Some(client),
+            _permit: permit,
+        }
+    }
+
+    /// Close the connection instead of returning it, e.g. after a transfer
+    /// failed halfway and left it in an unknown state.
+    pub async fn discard(mut self) {
+        if let Some(mut client) = self.client.take() {
+            let _ = client.close().await;
+        }
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = WebSocketClient;
+
+    fn deref(&self) -> &WebSocketClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut WebSocketClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
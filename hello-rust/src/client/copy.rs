@@ -0,0 +1,98 @@
+// Server-to-server copy (the `copy` subcommand), e.g. to migrate cached audio
+// between environments.
+// The stream is downloaded from one server and uploaded to the other at the
+// same time, piped through the client without a temporary file. The copy keeps
+// the stream's content type, file name, and tags, and is checked against the
+// source's SHA-256 once the destination finalized it.
+
+use super::chunk_manager::ChunkPipe;
+use super::download_manager;
+use super::error::{ClientError, Result};
+use super::upload_manager;
+use super::verification_module::{self, TransferChecksum};
+use super::websocket_client::WebSocketClient;
+use crate::cli::CopyConfig;
+use crate::logger;
+
+/// Downloaded chunks buffered ahead of the upload.
+const PIPE_CAPACITY: usize = 8;
+
+/// Run the `copy` subcommand.
+pub async fn run(config: &CopyConfig) -> Result<()> {
+    let mut source = WebSocketClient::new(&config.from);
+    source.connect(&config.from).await?;
+    let mut destination = WebSocketClient::new(&config.to);
+    if let Err(e) = destination.connect(&config.to).await {
+        let _ = source.close().await;
+        return Err(e);
+    }
+
+    let result = copy(config, &mut source, &mut destination).await;
+    let _ = source.close().await;
+    let _ = destination.close().await;
+    result
+}
+
+async fn copy(
+    config: &CopyConfig,
+    source: &mut WebSocketClient,
+    destination: &mut WebSocketClient,
+) -> Result<()> {
+    let stat = download_manager::query_stat(source, &config.stream_id).await?;
+    let target_id = config
+        .target_stream_id
+        .as_deref()
+        .unwrap_or(&config.stream_id);
+    let content_type = stat
+        .metadata
+        .get("contentType")
+        .map_or("application/octet-stream", String::as_str);
+    logger::log_info(&format!(
+        "Copying stream {} ({} bytes, {}) from {} to {} as {}",
+        config.stream_id, stat.size, content_type, config.from, config.to, target_id
+    ));
+
+    destination.set_upload_tags(stat.tags.clone());
+    let (sender, mut pipe) = ChunkPipe::new(PIPE_CAPACITY);
+    let download =
+        download_manager::download_to_pipe(source, &config.stream_id, sender, Some(stat.size));
+    let upload = upload_manager::upload_piped(
+        destination,
+        &mut pipe,
+        target_id,
+        Some(stat.size),
+        content_type,
+        stat.metadata.get("fileName").cloned(),
+    );
+    let (downloaded, uploaded) = tokio::try_join!(download, upload)?;
+
+    // Compare the destination against the source, from STOPPED or else STAT
+    let expected = match stat.checksum {
+        Some(checksum) => TransferChecksum {
+            size: stat.size,
+            checksum,
+            blocks: None,
+        },
+        None => downloaded,
+    };
+    let stored = match uploaded.stored {
+        Some(stored) => stored,
+        None => download_manager::query_checksum(destination, target_id).await?,
+    };
+    let result = verification_module::verify_remote(&expected, &stored);
+    if !result.passed {
+        return Err(ClientError::Verification(format!(
+            "source has {} bytes with SHA-256 {}, copy has {} bytes with SHA-256 {}",
+            result.original_size,
+            result.original_checksum,
+            result.downloaded_size,
+            result.downloaded_checksum
+        )));
+    }
+
+    logger::log_info(&format!(
+        "Copied stream {} to {} as {} ({} bytes, SHA-256 {})",
+        config.stream_id, config.to, target_id, stored.size, stored.checksum
+    ));
+    Ok(())
+}
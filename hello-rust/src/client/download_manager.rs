@@ -1,52 +1,579 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Bytes;
+
+use super::progress::{self, ProgressEvent, TransferDirection};
+use super::verification_module::{TransferChecksum, TransferDigest};
 use super::{
-    file_manager,
-    websocket_client::{ControlMessage, WebSocketClient},
+    file_manager, hooks,
+    websocket_client::{ControlMessage, Incoming, WebSocketClient, MAX_REDIRECTS},
 };
+use crate::cli::ClipConfig;
 use crate::logger;
-use anyhow::Result;
+use crate::protocol::ChunkTimestamp;
+use super::error::{ClientError, Result};
+
+/// Delay before the first retry of a failed GET; doubled for each further
+/// attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Error codes of a GET that may succeed when the chunk is requested again.
+const TRANSIENT_GET_ERRORS: [&str; 2] = ["NO_DATA", "STORAGE_ERROR"];
+
+/// Query the finalized byte count of a stream.
+pub async fn query_size(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<u64> {
+    let mut redirects = 0;
+    loop {
+        ws_client
+            .send_control_message(ControlMessage::Size {
+                stream_id: stream_id.to_string(),
+            })
+            .await?;
+
+        match ws_client.receive_reply("SIZE", &["SIZE_RESULT"]).await? {
+            ControlMessage::SizeResult { size, .. } => return Ok(size),
+            ControlMessage::Moved { location, .. } if redirects < MAX_REDIRECTS => {
+                logger::log_info(&format!("Stream {} moved to {}", stream_id, location));
+                ws_client.follow_redirect(&location).await?;
+                redirects += 1;
+            }
+            other => return Err(ClientError::unexpected("SIZE", other)),
+        }
+    }
+}
+
+/// What STAT reports about a stream.
+#[derive(Debug, Clone)]
+pub struct StreamStat {
+    pub size: u64,
+    pub status: String,
+    pub checksum: Option<String>,
+    /// Metadata given at START, e.g. contentType and fileName.
+    pub metadata: HashMap<String, String>,
+    /// Tags given at START.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Query the finalized size and SHA-256 of a stream via STAT.
+pub async fn query_checksum(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+) -> Result<TransferChecksum> {
+    match query_stat(ws_client, stream_id).await? {
+        StreamStat {
+            size,
+            checksum: Some(checksum),
+            ..
+        } => Ok(TransferChecksum {
+            size,
+            checksum,
+            blocks: None,
+        }),
+        StreamStat { status, .. } => Err(ClientError::Protocol(format!(
+            "Server reported no checksum for stream {} (status {})",
+            stream_id, status
+        ))),
+    }
+}
 
+/// Query the size, status, checksum, and metadata of a stream via STAT.
+pub async fn query_stat(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<StreamStat> {
+    let mut redirects = 0;
+    loop {
+        ws_client
+            .send_control_message(ControlMessage::Stat {
+                stream_id: stream_id.to_string(),
+                version: None,
+            })
+            .await?;
+
+        match ws_client.receive_reply("STAT", &["STAT_RESULT"]).await? {
+            ControlMessage::StatResult {
+                size,
+                status,
+                checksum,
+                metadata,
+                tags,
+                ..
+            } => {
+                return Ok(StreamStat {
+                    size,
+                    status,
+                    checksum,
+                    metadata,
+                    tags,
+                })
+            }
+            ControlMessage::Moved { location, .. } if redirects < MAX_REDIRECTS => {
+                logger::log_info(&format!("Stream {} moved to {}", stream_id, location));
+                ws_client.follow_redirect(&location).await?;
+                redirects += 1;
+            }
+            other => return Err(ClientError::unexpected("STAT", other)),
+        }
+    }
+}
+
+/// Query the capture timestamps recorded for a stream's chunks.
+pub async fn query_timestamps(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+) -> Result<Vec<ChunkTimestamp>> {
+    ws_client
+        .send_control_message(ControlMessage::Timestamps {
+            stream_id: stream_id.to_string(),
+        })
+        .await?;
+
+    match ws_client
+        .receive_reply("TIMESTAMPS", &["TIMESTAMPS_RESULT"])
+        .await?
+    {
+        ControlMessage::TimestampsResult { timestamps, .. } => Ok(timestamps),
+        other => Err(ClientError::unexpected("TIMESTAMPS", other)),
+    }
+}
+
+/// Get the offset of the chunk captured at or last before `timestamp`, or 0
+/// when every chunk is later; downloading each stream from the offset for a
+/// shared timestamp aligns them.
+pub fn offset_at(timestamps: &[ChunkTimestamp], timestamp: u64) -> u64 {
+    let index = timestamps.partition_point(|entry| entry.timestamp <= timestamp);
+    index
+        .checked_sub(1)
+        .map_or(0, |index| timestamps[index].offset)
+}
+
+/// Download a stream to `output_path`.
+///
+/// With `file_size` unknown, chunks are requested until the server answers
+/// with DATA_END. Returns the size and checksum of the received bytes.
 pub async fn download(
     ws_client: &mut WebSocketClient,
     stream_id: &str,
     output_path: &str,
-    file_size: u64,
-) -> Result<u64> {
+    file_size: Option<u64>,
+) -> Result<TransferChecksum> {
     logger::log_info(&format!("Starting download: streamId={}, outputPath={}, expectedSize={}",
-        stream_id, output_path, file_size));
+        stream_id, output_path,
+        file_size.map_or_else(|| "unknown".to_string(), |size| size.to_string())));
+    let sink = ChunkSink::File(output_path);
+    download_chunks(ws_client, stream_id, sink, file_size, (None, None)).await
+}
+
+/// Download a stream to `output_path` and to every target of `tee` in one
+/// transfer, e.g. to keep a copy while playing it. Returns the size and
+/// checksum of the received bytes.
+pub async fn download_tee(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    output_path: &str,
+    tee: &[TeeTarget],
+    file_size: Option<u64>,
+) -> Result<TransferChecksum> {
+    logger::log_info(&format!(
+        "Starting download: streamId={}, outputPath={}, expectedSize={}",
+        stream_id,
+        output_path,
+        file_size.map_or_else(|| "unknown".to_string(), |size| size.to_string())
+    ));
+    for target in tee {
+        logger::log_info(&format!("Teeing download to {}", target));
+    }
+    let mut outputs = TeeSink::open(tee).await?;
+    let sink = ChunkSink::Tee(output_path, &mut outputs);
+    let result = download_chunks(ws_client, stream_id, sink, file_size, (None, None)).await;
+    outputs.finish().await;
+    result
+}
+
+/// Download a stream into `sender` instead of a file, e.g. to upload it
+/// elsewhere as it arrives. A failed download is also sent, so the reader
+/// does not mistake it for the end of the stream.
+pub async fn download_to_pipe(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    sender: mpsc::Sender<Result<Vec<u8>>>,
+    file_size: Option<u64>,
+) -> Result<TransferChecksum> {
+    let sink = ChunkSink::Pipe(&sender);
+    let result = download_chunks(ws_client, stream_id, sink, file_size, (None, None)).await;
+    if let Err(e) = &result {
+        let _ = sender
+            .send(Err(ClientError::Protocol(format!(
+                "Download of stream {} failed: {}",
+                stream_id, e
+            ))))
+            .await;
+    }
+    result
+}
+
+/// Download the samples between `start` and `end` seconds of a raw PCM or
+/// WAV stream to `output_path`; an `end` of `None` runs to the end of the
+/// stream. Returns the size and checksum of the received bytes.
+pub async fn download_time_range(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    output_path: &str,
+    start: f64,
+    end: Option<f64>,
+) -> Result<TransferChecksum> {
+    logger::log_info(&format!("Starting download: streamId={}, outputPath={}, timeRange={}s..{}",
+        stream_id, output_path, start,
+        end.map_or_else(|| "end".to_string(), |end| format!("{}s", end))));
+    let sink = ChunkSink::File(output_path);
+    download_chunks(ws_client, stream_id, sink, None, (Some(start), end)).await
+}
+
+/// Run the `clip` subcommand.
+pub async fn run_clip(config: &ClipConfig) -> Result<()> {
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let result = download_time_range(
+        &mut ws_client,
+        &config.stream_id,
+        &config.output,
+        config.start,
+        config.end,
+    )
+    .await;
+    let _ = ws_client.close().await;
+
+    let clip = result?;
+    logger::log_info(&format!("Clip SHA-256: {}", clip.checksum));
+    Ok(())
+}
+
+/// Extra destination of a download, written alongside the output file
+/// (--tee).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TeeTarget {
+    File(String),
+    /// Standard output, given as `-`.
+    Stdout,
+    /// Standard input of a player command run through the shell, given as
+    /// `|COMMAND`, e.g. `|ffplay -nodisp -autoexit -`.
+    Player(String),
+}
+
+impl TeeTarget {
+    /// Parse `-`, `|COMMAND`, or a file path.
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec {
+            "" => None,
+            "-" => Some(Self::Stdout),
+            _ => match spec.strip_prefix('|').map(str::trim) {
+                Some("") => None,
+                Some(command) => Some(Self::Player(command.to_string())),
+                None => Some(Self::File(spec.to_string())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TeeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path),
+            Self::Stdout => write!(f, "stdout"),
+            Self::Player(command) => write!(f, "player `{}`", command),
+        }
+    }
+}
+
+/// Open tee target.
+struct TeeOutput {
+    target: TeeTarget,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    /// Player process reading the writer, if any.
+    player: Option<Child>,
+}
 
+/// Tee targets receiving every chunk written to the output file. A target
+/// that stops taking data, e.g. a player that was closed, is dropped with a
+/// warning while the download goes on.
+struct TeeSink(Vec<TeeOutput>);
+
+impl TeeSink {
+    async fn open(targets: &[TeeTarget]) -> Result<Self> {
+        let mut outputs = Vec::with_capacity(targets.len());
+        for target in targets {
+            let (writer, player): (Box<dyn AsyncWrite + Unpin + Send>, _) = match target {
+                TeeTarget::File(path) => {
+                    let file = tokio::fs::File::create(path)
+                        .await
+                        .map_err(|e| ClientError::storage(path, e))?;
+                    (Box::new(file), None)
+                }
+                TeeTarget::Stdout => (Box::new(tokio::io::stdout()), None),
+                TeeTarget::Player(command) => {
+                    let mut player = tokio::process::Command::from(hooks::shell(command))
+                        .stdin(Stdio::piped())
+                        .spawn()
+                        .map_err(|e| ClientError::storage(command, e))?;
+                    let stdin = player.stdin.take().ok_or_else(|| {
+                        ClientError::Protocol(format!("No stdin for player `{}`", command))
+                    })?;
+                    (Box::new(stdin), Some(player))
+                }
+            };
+            outputs.push(TeeOutput {
+                target: target.clone(),
+                writer,
+                player,
+            });
+        }
+        Ok(Self(outputs))
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        let mut failed = Vec::new();
+        for (index, output) in self.0.iter_mut().enumerate() {
+            if let Err(e) = output.writer.write_all(data).await {
+                logger::log_warn(&format!("Dropping tee output {}: {}", output.target, e));
+                failed.push(index);
+            }
+        }
+        for index in failed.into_iter().rev() {
+            self.0.remove(index);
+        }
+    }
+
+    /// Flush and close every target, then wait for players to finish
+    /// playing what they got.
+    async fn finish(self) {
+        for mut output in self.0 {
+            let _ = output.writer.shutdown().await;
+            drop(output.writer);
+            let Some(mut player) = output.player else {
+                continue;
+            };
+            match player.wait().await {
+                Ok(status) if !status.success() => logger::log_warn(&format!(
+                    "Tee output {} exited with {}",
+                    output.target, status
+                )),
+                Ok(_) => {}
+                Err(e) => logger::log_warn(&format!(
+                    "Failed to wait for tee output {}: {}",
+                    output.target, e
+                )),
+            }
+        }
+    }
+}
+
+/// Where downloaded chunks go.
+enum ChunkSink<'a> {
+    File(&'a str),
+    Pipe(&'a mpsc::Sender<Result<Vec<u8>>>),
+    /// The output file and the tee targets.
+    Tee(&'a str, &'a mut TeeSink),
+}
+
+/// Request chunks of a stream, or of the time range `(start, end)` of it,
+/// and write them to `sink`.
+async fn download_chunks(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    mut sink: ChunkSink<'_>,
+    file_size: Option<u64>,
+    (start_time, end_time): (Option<f64>, Option<f64>),
+) -> Result<TransferChecksum> {
     let mut offset = 0u64;
-    let mut bytes_received = 0u64;
+    let mut digest = TransferDigest::new();
+    // Chunks between CHECKPOINTs, the digest they are checked against, and
+    // the chunks received
+    let mut running = ws_client
+        .checkpoint_every()
+        .filter(|_| start_time.is_none() && end_time.is_none())
+        .map(|every| (every, Sha256::new(), 0u64));
     let mut last_progress = 0;
     let mut is_first_chunk = true;
+    let mut redirects = 0;
+    let retries = ws_client.get_retries();
+    let mut attempts = 0;
+    // Offsets of chunks that failed on every attempt, with the last reason
+    let mut failures: Vec<(u64, String)> = Vec::new();
+    ws_client.report(ProgressEvent::Started {
+        direction: TransferDirection::Download,
+        stream_id: stream_id.to_string(),
+        total: file_size,
+    });
 
-    while offset < file_size {
-        let chunk_size =
-            std::cmp::min(file_manager::CHUNK_SIZE as u64, file_size - offset) as usize;
+    while file_size.is_none_or(|size| offset < size) {
+        let chunk_size = match file_size {
+            Some(size) => std::cmp::min(file_manager::CHUNK_SIZE as u64, size - offset) as usize,
+            None => file_manager::CHUNK_SIZE,
+        };
 
         // Send GET message
-        let get_msg = ControlMessage {
-            msg_type: "GET".to_string(),
-            stream_id: Some(stream_id.to_string()),
-            offset: Some(offset),
-            length: Some(chunk_size),
-            message: None,
+        let get_msg = ControlMessage::Get {
+            stream_id: stream_id.to_string(),
+            offset,
+            length: chunk_size,
+            start_time,
+            end_time,
+            checkpoint_every: running.as_ref().map(|(every, ..)| *every),
+            version: None,
         };
+        let requested_at = Instant::now();
         ws_client.send_control_message(get_msg).await?;
 
-        // Receive binary data
-        let data = ws_client.receive_binary().await?;
+        // Receive binary data, following cluster redirects
+        let received = match ws_client.receive_incoming().await? {
+            // Only a stream of unknown size ends with an empty chunk
+            Incoming::Binary(data) if data.is_empty() && file_size.is_some() => {
+                Err("empty payload".to_string())
+            }
+            // Short chunks are fine, the next GET continues after them
+            Incoming::Binary(data) if data.len() > chunk_size => {
+                return Err(ClientError::Protocol(format!(
+                    "GET of stream {} at offset {} returned {} bytes, {} requested",
+                    stream_id,
+                    offset,
+                    data.len(),
+                    chunk_size
+                )));
+            }
+            Incoming::Binary(data) => Ok(data),
+            Incoming::Control(ControlMessage::Moved { location, .. }) if redirects < MAX_REDIRECTS => {
+                logger::log_info(&format!("Stream {} moved to {}", stream_id, location));
+                ws_client.follow_redirect(&location).await?;
+                ws_client.report(ProgressEvent::Retry);
+                redirects += 1;
+                // The new server cannot continue a digest it did not start
+                if offset > 0 {
+                    running = None;
+                }
+                continue;
+            }
+            // A stream shorter than expected must not pass for a complete
+            // download
+            Incoming::Control(ControlMessage::DataEnd { size, .. })
+                if file_size.is_some_and(|expected| size != expected) =>
+            {
+                return Err(ClientError::Download(format!(
+                    "stream {} ended at {} bytes, {} expected",
+                    stream_id,
+                    size,
+                    file_size.unwrap_or_default()
+                )));
+            }
+            Incoming::Control(ControlMessage::DataEnd { size, .. }) => {
+                logger::log_info(&format!("Reached end of stream {} at {} bytes", stream_id, size));
+                break;
+            }
+            Incoming::Control(ControlMessage::Error {
+                code: Some(code),
+                message,
+                ..
+            }) if TRANSIENT_GET_ERRORS.contains(&code.as_str()) => {
+                Err(format!("{}: {}", code, message))
+            }
+            Incoming::Control(msg) => return Err(ClientError::unexpected("GET", msg)),
+            Incoming::Closed if file_size.is_some() => {
+                return Err(ClientError::Download(format!(
+                    "connection closed at offset {} of {} bytes of stream {}",
+                    offset,
+                    file_size.unwrap_or_default(),
+                    stream_id
+                )));
+            }
+            Incoming::Closed => break,
+        };
+        let data = match received {
+            Ok(data) => {
+                attempts = 0;
+                data
+            }
+            Err(reason) if attempts < retries => {
+                let delay = RETRY_BACKOFF * 2u32.pow(attempts);
+                attempts += 1;
+                logger::log_warn(&format!(
+                    "GET of stream {} at offset {} failed ({}), retrying in {} ms ({}/{})",
+                    stream_id,
+                    offset,
+                    reason,
+                    delay.as_millis(),
+                    attempts,
+                    retries
+                ));
+                ws_client.report(ProgressEvent::Retry);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(reason) => {
+                // Without a known size or with a pipe, a hole cannot be left
+                if file_size.is_none() || matches!(sink, ChunkSink::Pipe(_)) {
+                    return Err(ClientError::Download(format!(
+                        "stream {} failed at offset {}: {}",
+                        stream_id, offset, reason
+                    )));
+                }
+                logger::log_error(&format!(
+                    "Giving up on stream {} at offset {} after {} attempts: {}",
+                    stream_id,
+                    offset,
+                    attempts + 1,
+                    reason
+                ));
+                failures.push((offset, reason));
+                attempts = 0;
+                // Later checkpoints would cover the zeros filling the hole
+                running = None;
+                vec![0; chunk_size]
+            }
+        };
+        if data.is_empty() {
+            break;
+        }
 
-        // Write to file
-        file_manager::write_chunk(output_path, &data, !is_first_chunk)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to write downloaded chunk: {}", e))?;
+        let chunk_size = data.len() as u64;
+        let data = Bytes::from(data);
+        digest.update(data.clone()).await;
+        if let Some((every, hasher, chunks)) = &mut running {
+            hasher.update(&data);
+            *chunks += 1;
+            if *chunks % u64::from(*every) == 0 {
+                check_checkpoint(ws_client, stream_id, offset + chunk_size, hasher).await?;
+            }
+        }
+        match &mut sink {
+            // Write to file
+            ChunkSink::File(output_path) => {
+                file_manager::write_chunk(output_path, &data, !is_first_chunk).await?
+            }
+            ChunkSink::Tee(output_path, outputs) => {
+                file_manager::write_chunk(output_path, &data, !is_first_chunk).await?;
+                outputs.write(&data).await;
+            }
+            ChunkSink::Pipe(sender) => sender.send(Ok(Vec::from(data))).await.map_err(|_| {
+                ClientError::Protocol("Receiver of the download stopped".to_string())
+            })?,
+        }
 
         is_first_chunk = false;
-        offset += data.len() as u64;
-        bytes_received += data.len() as u64;
+        offset += chunk_size;
+        ws_client.report(ProgressEvent::Chunk {
+            bytes: chunk_size,
+            latency: requested_at.elapsed(),
+        });
+        let bytes_received = digest.size();
 
         // Report progress
-        let progress = (bytes_received * 100 / file_size) as usize;
+        let Some(file_size) = file_size else {
+            continue;
+        };
+        let progress = progress::percent(bytes_received, file_size) as usize;
         if progress >= last_progress + 25 && progress <= 100 {
             logger::log_info(&format!(
                 "Download progress: {}/{} bytes ({}%)",
@@ -56,15 +583,199 @@ pub async fn download(
         }
     }
 
+    if !failures.is_empty() {
+        let offsets = failures
+            .iter()
+            .map(|(offset, reason)| format!("{} ({})", offset, reason))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(ClientError::Download(format!(
+            "{} chunks of stream {} unrecoverable at offsets {}",
+            failures.len(),
+            stream_id,
+            offsets
+        )));
+    }
+
     // Ensure 100% is reported
-    if last_progress < 100 {
+    if let Some(file_size) = file_size.filter(|_| last_progress < 100) {
         logger::log_info(&format!(
             "Download progress: {}/{} bytes (100%)",
             file_size, file_size
         ));
     }
 
-    logger::log_info(&format!("Download completed: {} bytes downloaded", bytes_received));
+    logger::log_info(&format!("Download completed: {} bytes downloaded", digest.size()));
+    ws_client.report(ProgressEvent::Finished);
+
+    Ok(digest.finalize())
+}
 
-    Ok(bytes_received)
+/// Receive the CHECKPOINT that follows a chunk and compare it with the
+/// SHA-256 of the `offset` bytes received so far.
+async fn check_checkpoint(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    offset: u64,
+    hasher: &Sha256,
+) -> Result<()> {
+    match ws_client.receive_control_message().await? {
+        ControlMessage::Checkpoint {
+            offset: at,
+            checksum,
+            ..
+        } => {
+            let received = format!("{:x}", hasher.clone().finalize());
+            if at != offset || !checksum.eq_ignore_ascii_case(&received) {
+                return Err(ClientError::Verification(format!(
+                    "Checkpoint of stream {} at {} bytes is {}, but the {} bytes received hash to {}",
+                    stream_id, at, checksum, offset, received
+                )));
+            }
+            logger::log_debug(&format!("Checkpoint at {} bytes matches", offset));
+            Ok(())
+        }
+        other => Err(ClientError::unexpected("GET", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::file_manager::SpoolDir;
+    use crate::client::transport::MockTransport;
+    use tokio_tungstenite::tungstenite::Message;
+
+    const CHUNK: usize = file_manager::CHUNK_SIZE;
+
+    /// Answer GETs of a stream of `size` bytes with at most `max_chunk`
+    /// bytes each, failing the GET at each offset of `failures` with NO_DATA
+    /// as many times as given.
+    fn server(
+        size: u64,
+        max_chunk: usize,
+        mut failures: HashMap<u64, u32>,
+    ) -> impl FnMut(&Message) -> Vec<Message> + Send {
+        move |message| {
+            let Message::Text(text) = message else {
+                return Vec::new();
+            };
+            let ControlMessage::Get {
+                stream_id,
+                offset,
+                length,
+                ..
+            } = ControlMessage::from_json(text).unwrap()
+            else {
+                panic!("Unexpected message {}", text);
+            };
+            if let Some(left) = failures.get_mut(&offset).filter(|left| **left > 0) {
+                *left -= 1;
+                let error = ControlMessage::Error {
+                    stream_id: Some(stream_id),
+                    code: Some("NO_DATA".to_string()),
+                    message: "No data yet".to_string(),
+                };
+                return vec![Message::Text(error.to_json().unwrap().into())];
+            }
+            if offset >= size {
+                let end = ControlMessage::DataEnd { stream_id, size };
+                return vec![Message::Text(end.to_json().unwrap().into())];
+            }
+            let end = size.min(offset + length.min(max_chunk) as u64);
+            vec![Message::Binary(vec![1u8; (end - offset) as usize].into())]
+        }
+    }
+
+    fn client(server: impl FnMut(&Message) -> Vec<Message> + Send + 'static) -> WebSocketClient {
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(MockTransport::new(server)));
+        client
+    }
+
+    #[tokio::test]
+    async fn failed_chunks_are_retried() {
+        let spool = SpoolDir::create("get-retry").unwrap();
+        let output = spool.file("output.bin");
+        let mut client = client(server(100_000, CHUNK, HashMap::from([(65536, 2)])));
+
+        let checksum = download(&mut client, "s", &output, Some(100_000))
+            .await
+            .unwrap();
+        assert_eq!(checksum.size, 100_000);
+        assert_eq!(std::fs::read(&output).unwrap(), vec![1u8; 100_000]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn teed_download_reaches_every_target() {
+        let spool = SpoolDir::create("get-tee").unwrap();
+        let output = spool.file("output.bin");
+        let copy = spool.file("copy.bin");
+        let played = spool.file("played.bin");
+        let tee = [
+            TeeTarget::parse(&copy).unwrap(),
+            TeeTarget::parse(&format!("| cat > {}", played)).unwrap(),
+        ];
+        let mut client = client(server(100_000, CHUNK, HashMap::new()));
+
+        let checksum = download_tee(&mut client, "s", &output, &tee, Some(100_000))
+            .await
+            .unwrap();
+        assert_eq!(checksum.size, 100_000);
+        for path in [&output, &copy, &played] {
+            assert_eq!(std::fs::read(path).unwrap(), vec![1u8; 100_000]);
+        }
+    }
+
+    #[tokio::test]
+    async fn unrecoverable_offsets_are_listed() {
+        let spool = SpoolDir::create("get-give-up").unwrap();
+        let output = spool.file("output.bin");
+        let mut client = client(server(100_000, CHUNK, HashMap::from([(0, 2)])));
+        client.set_get_retries(1);
+
+        let error = download(&mut client, "s", &output, Some(100_000))
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("1 chunks of stream s unrecoverable at offsets 0 (NO_DATA: No data yet)"),
+            "{}",
+            error
+        );
+        // The rest of the stream was still downloaded
+        assert_eq!(std::fs::metadata(&output).unwrap().len(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn short_chunks_are_continued() {
+        let spool = SpoolDir::create("get-short").unwrap();
+        let output = spool.file("output.bin");
+        let mut client = client(server(100_000, 30_000, HashMap::new()));
+
+        let checksum = download(&mut client, "s", &output, Some(100_000))
+            .await
+            .unwrap();
+        assert_eq!(checksum.size, 100_000);
+    }
+
+    #[tokio::test]
+    async fn streams_shorter_than_expected_fail() {
+        let spool = SpoolDir::create("get-eof").unwrap();
+        let output = spool.file("output.bin");
+        let mut client = client(server(65536, CHUNK, HashMap::new()));
+
+        let error = download(&mut client, "s", &output, Some(100_000))
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("stream s ended at 65536 bytes, 100000 expected"),
+            "{}",
+            error
+        );
+    }
 }
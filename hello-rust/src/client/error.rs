@@ -0,0 +1,128 @@
+// Error type returned by the client library.
+
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+use crate::protocol::encoding::EncodingError;
+use crate::protocol::{ControlMessage, StateError};
+
+/// Client failure, grouped by category so callers can match on it.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The WebSocket connection could not be established or broke.
+    #[error("Connection error: {message}")]
+    Connection {
+        message: String,
+        #[source]
+        source: Option<tungstenite::Error>,
+    },
+    /// The server answered with something the protocol does not allow.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// The server rejected a request with an ERROR message.
+    #[error("Server error: {message}")]
+    Server {
+        code: Option<String>,
+        message: String,
+    },
+    /// The server did not answer a request in time.
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// Reading or writing a local file failed.
+    #[error("Storage error on {path}: {source}")]
+    Storage {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Fetching the input from a URL failed.
+    #[error("Fetch error: {0}")]
+    Fetch(String),
+    /// Chunks of a download failed on every attempt.
+    #[error("Download incomplete: {0}")]
+    Download(String),
+    /// The downloaded file does not match the original.
+    #[error("Verification failed: {0}")]
+    Verification(String),
+    /// Benchmark throughput fell below the historical baseline.
+    #[error("Throughput regression: {0}")]
+    Regression(String),
+    /// A server failed cases of the conformance suite.
+    #[error("Conformance failures: {0}")]
+    Conformance(String),
+    /// The transfer cycle of the self-test failed.
+    #[error("Self-test failed: {0}")]
+    Selftest(String),
+    /// Files of a batch upload failed.
+    #[error("Batch upload failed: {0}")]
+    Batch(String),
+    /// Files of a directory sync failed.
+    #[error("Sync failed: {0}")]
+    Sync(String),
+    /// A pre- or post-transfer hook failed.
+    #[error("Hook failed: {0}")]
+    Hook(String),
+    /// Audio could not be encoded or decoded.
+    #[error("Codec error: {0}")]
+    Codec(String),
+    /// Downloaded audio could not be played locally.
+    #[error("Playback error: {0}")]
+    Playback(String),
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+impl ClientError {
+    pub(crate) fn connection(message: impl Into<String>, source: tungstenite::Error) -> Self {
+        ClientError::Connection {
+            message: message.into(),
+            source: Some(source),
+        }
+    }
+
+    pub(crate) fn not_connected() -> Self {
+        ClientError::Connection {
+            message: "Not connected".to_string(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn closed() -> Self {
+        ClientError::Connection {
+            message: "Connection closed".to_string(),
+            source: None,
+        }
+    }
+
+    pub(crate) fn storage(path: &str, source: std::io::Error) -> Self {
+        ClientError::Storage {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Classify a response that does not answer `request`.
+    /// ERROR replies become [`ClientError::Server`].
+    pub(crate) fn unexpected(request: &str, response: ControlMessage) -> Self {
+        match response {
+            ControlMessage::Error { code, message, .. } => ClientError::Server { code, message },
+            other => ClientError::Protocol(format!(
+                "Unexpected response to {}: {}",
+                request,
+                other.type_name()
+            )),
+        }
+    }
+}
+
+impl From<StateError> for ClientError {
+    fn from(e: StateError) -> Self {
+        ClientError::Protocol(e.to_string())
+    }
+}
+
+impl From<EncodingError> for ClientError {
+    fn from(e: EncodingError) -> Self {
+        ClientError::Protocol(e.to_string())
+    }
+}
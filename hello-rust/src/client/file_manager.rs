@@ -1,25 +1,27 @@
-use anyhow::{Context, Result};
-use sha2::{Digest, Sha256};
-use std::path::Path;
+use super::error::{ClientError, Result};
+use super::verification_module::{TransferChecksum, TransferDigest};
+use std::path::{Path, PathBuf};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 pub const CHUNK_SIZE: usize = 65536; // 64KB
+// Read size when hashing whole files
+const HASH_BLOCK_SIZE: usize = 1024 * 1024;
 
 pub async fn read_chunk(path: &str, offset: u64, size: usize) -> Result<Vec<u8>> {
     let mut file = File::open(path)
         .await
-        .context(format!("Failed to open file: {}", path))?;
+        .map_err(|e| ClientError::storage(path, e))?;
 
     file.seek(std::io::SeekFrom::Start(offset))
         .await
-        .context("Failed to seek file")?;
+        .map_err(|e| ClientError::storage(path, e))?;
 
     let mut buffer = vec![0u8; size];
     let bytes_read = file
         .read(&mut buffer)
         .await
-        .context("Failed to read file")?;
+        .map_err(|e| ClientError::storage(path, e))?;
     buffer.truncate(bytes_read);
 
     Ok(buffer)
@@ -30,7 +32,7 @@ pub async fn write_chunk(path: &str, data: &[u8], append: bool) -> Result<()> {
     if let Some(parent) = Path::new(path).parent() {
         tokio::fs::create_dir_all(parent)
             .await
-            .context("Failed to create output directory")?;
+            .map_err(|e| ClientError::storage(&parent.to_string_lossy(), e))?;
     }
 
     let mut file = if append {
@@ -39,16 +41,20 @@ pub async fn write_chunk(path: &str, data: &[u8], append: bool) -> Result<()> {
             .append(true)
             .open(path)
             .await
-            .context(format!("Failed to open file for writing: {}", path))?
+            .map_err(|e| ClientError::storage(path, e))?
     } else {
         File::create(path)
             .await
-            .context(format!("Failed to create file: {}", path))?
+            .map_err(|e| ClientError::storage(path, e))?
     };
 
     file.write_all(data)
         .await
-        .context("Failed to write to file")?;
+        .map_err(|e| ClientError::storage(path, e))?;
+    // A tokio file finishes its last write in the background unless flushed
+    file.flush()
+        .await
+        .map_err(|e| ClientError::storage(path, e))?;
 
     Ok(())
 }
@@ -57,7 +63,7 @@ pub async fn write_chunk(path: &str, data: &[u8], append: bool) -> Result<()> {
 pub async fn read_file(path: &str) -> Result<Vec<u8>> {
     tokio::fs::read(path)
         .await
-        .context(format!("Failed to read file: {}", path))
+        .map_err(|e| ClientError::storage(path, e))
 }
 
 #[allow(dead_code)]
@@ -66,39 +72,111 @@ pub async fn write_file(path: &str, data: &[u8]) -> Result<()> {
     if let Some(parent) = Path::new(path).parent() {
         tokio::fs::create_dir_all(parent)
             .await
-            .context("Failed to create output directory")?;
+            .map_err(|e| ClientError::storage(&parent.to_string_lossy(), e))?;
     }
 
     tokio::fs::write(path, data)
         .await
-        .context(format!("Failed to write file: {}", path))
+        .map_err(|e| ClientError::storage(path, e))
 }
 
+/// Compute the SHA-256 of a file, reading it in large blocks on a blocking
+/// thread while the previous blocks are hashed on the thread pool.
 pub async fn compute_sha256(path: &str) -> Result<String> {
-    let mut file = File::open(path)
-        .await
-        .context(format!("Failed to open file for checksum: {}", path))?;
+    Ok(compute_checksum(path).await?.checksum)
+}
 
-    let mut hasher = Sha256::new();
-    let mut buffer = vec![0u8; CHUNK_SIZE];
+/// Compute the size, SHA-256 and block hashes of a file, like a transfer of
+/// it would.
+pub async fn compute_checksum(path: &str) -> Result<TransferChecksum> {
+    let owned = path.to_string();
+    tokio::task::spawn_blocking(move || hash_file(&owned))
+        .await
+        .map_err(|e| ClientError::storage(path, std::io::Error::other(e)))?
+}
 
+fn hash_file(path: &str) -> Result<TransferChecksum> {
+    let mut file = std::fs::File::open(path).map_err(|e| ClientError::storage(path, e))?;
+    let mut digest = TransferDigest::new();
     loop {
-        let bytes_read = file
-            .read(&mut buffer)
-            .await
-            .context("Failed to read file")?;
-        if bytes_read == 0 {
+        let mut block = vec![0u8; HASH_BLOCK_SIZE];
+        let filled = read_full(&mut file, &mut block).map_err(|e| ClientError::storage(path, e))?;
+        if filled == 0 {
             break;
         }
-        hasher.update(&buffer[..bytes_read]);
+        block.truncate(filled);
+        digest.blocking_update(block);
     }
+    Ok(digest.finalize())
+}
 
-    let result = hasher.finalize();
-    Ok(format!("{:x}", result))
+/// Read until `buffer` is full or the file ends; returns the bytes read.
+pub fn read_full(file: &mut std::fs::File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match std::io::Read::read(file, &mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Guess the MIME type of an audio file from its extension.
+pub fn guess_content_type(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        Some("flac") => "audio/flac",
+        Some("m4a") | Some("mp4") => "audio/mp4",
+        Some("aac") => "audio/aac",
+        Some("webm") => "audio/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Get the final path component of a file path.
+pub fn get_file_name(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
 }
 
 pub fn get_file_size(path: &str) -> Result<u64> {
-    let metadata =
-        std::fs::metadata(path).context(format!("Failed to get file metadata: {}", path))?;
+    let metadata = std::fs::metadata(path).map_err(|e| ClientError::storage(path, e))?;
     Ok(metadata.len())
 }
+
+/// Temporary directory for intermediate files, removed on drop.
+pub struct SpoolDir(PathBuf);
+
+impl SpoolDir {
+    /// Create a directory under the system temp directory, named after
+    /// `purpose` and the process.
+    pub fn create(purpose: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("audio-{}-{}", purpose, std::process::id()));
+        std::fs::create_dir_all(&path)
+            .map_err(|e| ClientError::storage(&path.to_string_lossy(), e))?;
+        Ok(Self(path))
+    }
+
+    /// Get the path of a file in the directory.
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for SpoolDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,198 @@
+// Perceptual audio fingerprints (--verify fingerprint).
+// A robust spectral hash in the style of Haitsma and Kalker: the audio is
+// mixed to mono, decimated to about 5.5 kHz, and cut into overlapping 370 ms
+// frames, and each frame becomes 32 bits, the signs of the energy differences
+// between 33 bands from 300 to 2000 Hz, taken against the previous frame.
+// Lossy coding, container changes, and trimming flip few bits, so two
+// recordings of the same audio match when their fingerprints, aligned at the
+// best offset, differ in few bits.
+
+use std::ops::Range;
+
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use super::error::{ClientError, Result};
+use super::file_manager;
+use crate::protocol::PcmFormat;
+
+/// Lowest similarity, i.e. share of equal bits, of matching fingerprints.
+pub const MATCH_THRESHOLD: f64 = 0.65;
+
+const ANALYSIS_RATE: u32 = 5512;
+const FRAME_SECONDS: f64 = 0.37;
+// Frames overlap by 31/32
+const HOPS_PER_FRAME: usize = 32;
+const BANDS: usize = 33;
+const LOWEST_BAND_HZ: f64 = 300.0;
+const HIGHEST_BAND_HZ: f64 = 2000.0;
+// Extra alignment offsets tried beyond the length difference, in frames
+const ALIGNMENT_SLACK: usize = 8;
+
+/// Fingerprint of a recording, one 32-bit hash per frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    frames: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// Fingerprint mono samples, in [-1.0, 1.0], at `sample_rate`.
+    pub fn from_samples(samples: &[f32], sample_rate: u32) -> Self {
+        let frame_len = ((sample_rate as f64 * FRAME_SECONDS) as usize)
+            .next_power_of_two()
+            .max(HOPS_PER_FRAME);
+        let hop = frame_len / HOPS_PER_FRAME;
+        if samples.len() < frame_len {
+            return Self::default();
+        }
+
+        let bins_per_hz = frame_len as f64 / sample_rate as f64;
+        let band_edges: Vec<usize> = (0..=BANDS)
+            .map(|band| {
+                let ratio = (HIGHEST_BAND_HZ / LOWEST_BAND_HZ).powf(band as f64 / BANDS as f64);
+                ((LOWEST_BAND_HZ * ratio * bins_per_hz) as usize).min(frame_len / 2)
+            })
+            .collect();
+        let window: Vec<f32> = (0..frame_len)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / frame_len as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        let fft = FftPlanner::<f32>::new().plan_fft_forward(frame_len);
+
+        let mut frames = Vec::new();
+        let mut previous: Option<[f32; BANDS]> = None;
+        let mut buffer = vec![Complex::default(); frame_len];
+        for start in (0..=samples.len() - frame_len).step_by(hop) {
+            for ((slot, sample), weight) in buffer
+                .iter_mut()
+                .zip(&samples[start..start + frame_len])
+                .zip(&window)
+            {
+                *slot = Complex::new(sample * weight, 0.0);
+            }
+            fft.process(&mut buffer);
+
+            let mut energies = [0f32; BANDS];
+            for (band, energy) in energies.iter_mut().enumerate() {
+                *energy = buffer[band_edges[band]..band_edges[band + 1].max(band_edges[band] + 1)]
+                    .iter()
+                    .map(Complex::norm_sqr)
+                    .sum();
+            }
+            if let Some(previous) = previous {
+                let mut bits = 0u32;
+                for band in 0..BANDS - 1 {
+                    let difference =
+                        energies[band] - energies[band + 1] - (previous[band] - previous[band + 1]);
+                    if difference > 0.0 {
+                        bits |= 1 << band;
+                    }
+                }
+                frames.push(bits);
+            }
+            previous = Some(energies);
+        }
+        Self { frames }
+    }
+
+    /// Fingerprint a PCM WAV file, or a raw PCM file in `raw_format`.
+    pub async fn from_file(path: &str, raw_format: Option<PcmFormat>) -> Result<Self> {
+        let (format, data) = audio_layout(path, raw_format).await?;
+        let frame_size = format.frame_size();
+        let sample_size = frame_size / format.channels as usize;
+        let factor = (format.sample_rate / ANALYSIS_RATE).max(1) as usize;
+        let step = (file_manager::CHUNK_SIZE / frame_size).max(1) * frame_size;
+
+        // Mix down to mono and average groups of `factor` frames
+        let mut samples = Vec::new();
+        let (mut sum, mut count) = (0f64, 0);
+        let mut offset = data.start;
+        while offset < data.end {
+            let length = std::cmp::min(step as u64, data.end - offset) as usize;
+            let chunk = file_manager::read_chunk(path, offset, length).await?;
+            for frame in chunk.chunks_exact(frame_size) {
+                sum += frame
+                    .chunks_exact(sample_size)
+                    .map(PcmFormat::sample_value)
+                    .sum::<f64>()
+                    / format.channels as f64;
+                count += 1;
+                if count == factor {
+                    samples.push((sum / factor as f64) as f32);
+                    (sum, count) = (0.0, 0);
+                }
+            }
+            offset += length as u64;
+        }
+        Ok(Self::from_samples(
+            &samples,
+            format.sample_rate / factor as u32,
+        ))
+    }
+
+    /// Get the number of frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Get the share of equal bits, from 0.0 to 1.0, at the alignment where
+    /// the fingerprints agree best. Recordings differing in length, e.g. by
+    /// trimmed silence, are compared over the overlap of the shorter one.
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        let (shorter, longer) = if self.len() <= other.len() {
+            (&self.frames, &other.frames)
+        } else {
+            (&other.frames, &self.frames)
+        };
+        if shorter.is_empty() {
+            return if longer.is_empty() { 1.0 } else { 0.0 };
+        }
+
+        let max_shift = (longer.len() - shorter.len() + ALIGNMENT_SLACK) as isize;
+        let min_overlap = shorter.len().div_ceil(2);
+        let mut best = 0.0;
+        for shift in -max_shift..=max_shift {
+            // Frames of the shorter fingerprint that land inside the longer one
+            let first = (-shift).max(0) as usize;
+            let last = (longer.len() as isize - shift).clamp(0, shorter.len() as isize) as usize;
+            if last < first + min_overlap {
+                continue;
+            }
+            let differing: u32 = (first..last)
+                .map(|index| {
+                    (shorter[index] ^ longer[(index as isize + shift) as usize]).count_ones()
+                })
+                .sum();
+            let similarity = 1.0 - differing as f64 / ((last - first) * (BANDS - 1)) as f64;
+            if similarity > best {
+                best = similarity;
+            }
+        }
+        best
+    }
+}
+
+/// Get the sample format and sample bytes of a PCM WAV file, or of a raw PCM
+/// file in `raw_format`.
+async fn audio_layout(
+    path: &str,
+    raw_format: Option<PcmFormat>,
+) -> Result<(PcmFormat, Range<u64>)> {
+    let size = file_manager::get_file_size(path)?;
+    let header_len = std::cmp::min(size, file_manager::CHUNK_SIZE as u64) as usize;
+    let header = file_manager::read_chunk(path, 0, header_len).await?;
+    PcmFormat::from_wav_header(&header, size)
+        .or_else(|| raw_format.map(|format| (format, 0..size)))
+        .ok_or_else(|| {
+            ClientError::Codec(format!(
+                "Cannot fingerprint {}: not a PCM WAV file and no --pcm-format given",
+                path
+            ))
+        })
+}
@@ -0,0 +1,161 @@
+// Hooks run before and after a transfer.
+// Library users implement TransferHook; the CLI wraps --pre-hook/--post-hook
+// shell commands in CommandHook, which passes the context as environment
+// variables (AUDIO_STREAM_ID, AUDIO_STREAM_SHA256, AUDIO_STREAM_RESULT, ...).
+
+use std::process::Command;
+use std::sync::Arc;
+
+use super::error::{ClientError, Result};
+use super::performance_monitor::PerformanceReport;
+use crate::logger;
+
+/// What is known about a transfer when a hook runs.
+#[derive(Debug, Clone, Default)]
+pub struct TransferContext {
+    pub input: String,
+    pub server: String,
+    /// Set once the upload has started.
+    pub stream_id: Option<String>,
+    /// Size and SHA-256 of the uploaded bytes, set once the upload completed.
+    pub size: Option<u64>,
+    pub checksum: Option<String>,
+    /// Verification outcome, unless verification was skipped.
+    pub verified: Option<bool>,
+    /// Durations and throughput of the phases completed so far.
+    pub performance: Option<PerformanceReport>,
+    /// Failure of the transfer, for post hooks.
+    pub error: Option<String>,
+}
+
+impl TransferContext {
+    pub fn new(input: &str, server: &str) -> Self {
+        Self {
+            input: input.to_string(),
+            server: server.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Callbacks around a transfer.
+///
+/// An error from `before_transfer` aborts the transfer; an error from
+/// `after_transfer` fails an otherwise successful run. Hooks are called on a
+/// blocking thread, so they may wait on commands, files, or the network.
+pub trait TransferHook: Send + Sync {
+    fn before_transfer(&self, _context: &TransferContext) -> Result<()> {
+        Ok(())
+    }
+
+    fn after_transfer(&self, _context: &TransferContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs shell commands as pre and post hooks.
+pub struct CommandHook {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+}
+
+impl CommandHook {
+    pub fn new(pre: Option<String>, post: Option<String>) -> Self {
+        Self { pre, post }
+    }
+
+    fn run(command: &str, phase: &str, context: &TransferContext) -> Result<()> {
+        logger::log_info(&format!("Running {}-hook: {}", phase, command));
+
+        let mut process = shell(command);
+        process
+            .env("AUDIO_STREAM_HOOK", phase)
+            .env("AUDIO_STREAM_INPUT", &context.input)
+            .env("AUDIO_STREAM_SERVER", &context.server);
+        if let Some(stream_id) = &context.stream_id {
+            process.env("AUDIO_STREAM_ID", stream_id);
+        }
+        if let Some(size) = context.size {
+            process.env("AUDIO_STREAM_SIZE", size.to_string());
+        }
+        if let Some(checksum) = &context.checksum {
+            process.env("AUDIO_STREAM_SHA256", checksum);
+        }
+        if let Some(verified) = context.verified {
+            process.env("AUDIO_STREAM_VERIFIED", verified.to_string());
+        }
+        if phase == "post" {
+            let result = if context.succeeded() {
+                "success"
+            } else {
+                "failure"
+            };
+            process.env("AUDIO_STREAM_RESULT", result);
+        }
+        if let Some(error) = &context.error {
+            process.env("AUDIO_STREAM_ERROR", error);
+        }
+
+        let status = process.status().map_err(|e| {
+            ClientError::Hook(format!(
+                "{}-hook `{}` failed to start: {}",
+                phase, command, e
+            ))
+        })?;
+        if !status.success() {
+            return Err(ClientError::Hook(format!(
+                "{}-hook `{}` exited with {}",
+                phase, command, status
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl TransferHook for CommandHook {
+    fn before_transfer(&self, context: &TransferContext) -> Result<()> {
+        match &self.pre {
+            Some(command) => Self::run(command, "pre", context),
+            None => Ok(()),
+        }
+    }
+
+    fn after_transfer(&self, context: &TransferContext) -> Result<()> {
+        match &self.post {
+            Some(command) => Self::run(command, "post", context),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Call one side of a hook on the blocking thread pool, off the runtime
+/// driving the transfer.
+pub(super) async fn call_blocking(
+    hook: &Arc<dyn TransferHook>,
+    context: &TransferContext,
+    call: fn(&dyn TransferHook, &TransferContext) -> Result<()>,
+) -> Result<()> {
+    let hook = Arc::clone(hook);
+    let context = context.clone();
+    tokio::task::spawn_blocking(move || call(hook.as_ref(), &context))
+        .await
+        .map_err(|e| ClientError::Hook(format!("hook panicked: {}", e)))?
+}
+
+#[cfg(windows)]
+pub(super) fn shell(command: &str) -> Command {
+    let mut process = Command::new("cmd");
+    process.args(["/C", command]);
+    process
+}
+
+#[cfg(not(windows))]
+pub(super) fn shell(command: &str) -> Command {
+    let mut process = Command::new("sh");
+    process.args(["-c", command]);
+    process
+}
@@ -0,0 +1,85 @@
+// Stream listing (the `list` subcommand).
+// Pages through LIST in stream ID order, optionally only the streams carrying
+// every --tag, and prints one line per stream, e.g.
+//   1a2b3c4d  92124  READY  project=podcast42 episode=7
+
+use std::collections::BTreeMap;
+
+use super::error::{ClientError, Result};
+use super::websocket_client::{ControlMessage, WebSocketClient};
+use crate::cli::ListConfig;
+use crate::logger;
+use crate::protocol::StreamSummary;
+
+/// Run the `list` subcommand.
+pub async fn run(config: &ListConfig) -> Result<()> {
+    let tags: BTreeMap<String, String> = config.tags.iter().cloned().collect();
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+
+    let mut after = None;
+    let mut count = 0;
+    let result = loop {
+        match list_page(
+            &mut ws_client,
+            &tags,
+            after.take(),
+            config.page_size as usize,
+        )
+        .await
+        {
+            Ok((streams, next)) => {
+                for stream in &streams {
+                    println!("{}", format_stream(stream));
+                }
+                count += streams.len();
+                match next {
+                    Some(next) => after = Some(next),
+                    None => break Ok(()),
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = ws_client.close().await;
+
+    result?;
+    logger::log_info(&format!("{} streams listed", count));
+    Ok(())
+}
+
+/// Request one page of streams; returns them with the `after` of the next
+/// page, if there is one.
+pub async fn list_page(
+    ws_client: &mut WebSocketClient,
+    tags: &BTreeMap<String, String>,
+    after: Option<String>,
+    limit: usize,
+) -> Result<(Vec<StreamSummary>, Option<String>)> {
+    ws_client
+        .send_control_message(ControlMessage::List {
+            tags: tags.clone(),
+            after,
+            limit: Some(limit),
+        })
+        .await?;
+    match ws_client.receive_reply("LIST", &["LIST_RESULT"]).await? {
+        ControlMessage::ListResult { streams, next } => Ok((streams, next)),
+        other => Err(ClientError::unexpected("LIST", other)),
+    }
+}
+
+fn format_stream(stream: &StreamSummary) -> String {
+    let tags = stream
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "{}  {}  {}  {}",
+        stream.stream_id, stream.size, stream.status, tags
+    )
+    .trim_end()
+    .to_string()
+}
@@ -0,0 +1,288 @@
+// Low-latency playback of a stream that is still being uploaded (listen).
+// The player joins the stream a target latency behind its live edge, fills a
+// jitter buffer before starting the playback clock, and then requests chunks
+// only as the clock consumes them, instead of pulling as fast as possible.
+// Played bytes go to the output file, which stands in for an audio device.
+
+use std::time::{Duration, Instant};
+
+use super::error::{ClientError, Result};
+use super::progress::{ProgressEvent, TransferDirection};
+use super::websocket_client::{ControlMessage, Incoming, WebSocketClient};
+use super::{file_manager, verification_module::TransferDigest};
+use crate::cli::ListenConfig;
+use crate::logger;
+use crate::protocol::PcmFormat;
+
+// Playback clock resolution, also the wait after reaching the live edge
+const TICK: Duration = Duration::from_millis(10);
+
+/// Playback settings of a live listener.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveOptions {
+    pub format: PcmFormat,
+    /// How far behind the live edge playback starts.
+    pub target_latency: Duration,
+    /// Audio buffered ahead of the playback clock before playing and while
+    /// playing.
+    pub jitter_buffer: Duration,
+}
+
+impl LiveOptions {
+    /// Convert a duration to whole frames of audio, in bytes.
+    fn bytes_for(&self, duration: Duration) -> u64 {
+        let frame_size = self.format.frame_size() as u64;
+        let frames = duration.as_micros() as u64 * self.format.sample_rate as u64 / 1_000_000;
+        frames * frame_size
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        self.format.sample_rate as u64 * self.format.frame_size() as u64
+    }
+}
+
+/// Outcome of a live listening session.
+#[derive(Debug, Clone)]
+pub struct LiveReport {
+    /// Stream offset playback started at.
+    pub start_offset: u64,
+    pub played: u64,
+    /// Times the buffer ran dry and playback paused to rebuffer.
+    pub underruns: u64,
+    /// SHA-256 of the played bytes.
+    pub checksum: String,
+}
+
+/// Bytes fetched ahead of the playback position.
+#[derive(Debug)]
+pub struct JitterBuffer {
+    start: u64,
+    data: Vec<u8>,
+}
+
+impl JitterBuffer {
+    /// Create an empty buffer positioned at stream offset `start`.
+    pub fn new(start: u64) -> Self {
+        Self {
+            start,
+            data: Vec::new(),
+        }
+    }
+
+    /// Get the stream offset of the next byte to fetch.
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Get the number of buffered bytes.
+    pub fn buffered(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    /// Remove up to `length` bytes from the front.
+    pub fn take(&mut self, length: u64) -> Vec<u8> {
+        let length = length.min(self.buffered()) as usize;
+        self.start += length as u64;
+        self.data.drain(..length).collect()
+    }
+}
+
+/// Playback clock; runs only while the buffer keeps up.
+struct PlaybackClock {
+    started: Instant,
+    played_before: u64,
+}
+
+/// Play a live stream into `output_path` until the uploader finishes it.
+pub async fn listen(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    output_path: &str,
+    options: LiveOptions,
+) -> Result<LiveReport> {
+    let chunk_limit = options.bytes_for(Duration::from_secs(1)).max(1) as usize;
+    let chunk_limit = std::cmp::min(file_manager::CHUNK_SIZE, chunk_limit);
+    let frame_size = options.format.frame_size() as u64;
+    let jitter_bytes = options.bytes_for(options.jitter_buffer).max(frame_size);
+
+    let live_edge = query_live_edge(ws_client, stream_id).await?;
+    let start_offset = live_edge.saturating_sub(options.bytes_for(options.target_latency));
+    let start_offset = start_offset - start_offset % frame_size;
+    logger::log_info(&format!(
+        "Joining stream {} at offset {} ({} bytes behind the live edge)",
+        stream_id,
+        start_offset,
+        live_edge - start_offset
+    ));
+    ws_client.report(ProgressEvent::Started {
+        direction: TransferDirection::Download,
+        stream_id: stream_id.to_string(),
+        total: None,
+    });
+
+    let mut buffer = JitterBuffer::new(start_offset);
+    let mut clock: Option<PlaybackClock> = None;
+    let mut digest = TransferDigest::new();
+    let mut underruns = 0;
+    let mut ended = false;
+    file_manager::write_chunk(output_path, &[], false).await?;
+
+    loop {
+        // Play out whatever the clock says is due
+        if let Some(running) = &clock {
+            let elapsed = running.started.elapsed();
+            let due =
+                (options.bytes_for(elapsed) + running.played_before).saturating_sub(digest.size());
+            let played = buffer.take(due);
+            let played_len = played.len() as u64;
+            if !played.is_empty() {
+                file_manager::write_chunk(output_path, &played, true).await?;
+                digest.update(played).await;
+            }
+            if played_len < due && !ended {
+                underruns += 1;
+                logger::log_warn(&format!(
+                    "Buffer underrun at offset {}, rebuffering",
+                    buffer.end()
+                ));
+                clock = None;
+            }
+        }
+        if ended && buffer.buffered() == 0 {
+            break;
+        }
+
+        // Keep the jitter buffer full, requesting no more than the clock needs
+        if !ended && buffer.buffered() < jitter_bytes {
+            let wanted = (jitter_bytes - buffer.buffered()).max(frame_size);
+            let length = std::cmp::min(wanted, chunk_limit as u64) as usize;
+            let requested_at = Instant::now();
+            match fetch(ws_client, stream_id, buffer.end(), length).await? {
+                Fetched::Data(data) => {
+                    buffer.push(&data);
+                    ws_client.report(ProgressEvent::Chunk {
+                        bytes: data.len() as u64,
+                        latency: requested_at.elapsed(),
+                    });
+                    // Fetch again right away while catching up
+                    if buffer.buffered() < jitter_bytes && clock.is_some() {
+                        continue;
+                    }
+                }
+                Fetched::LiveEdge => {}
+                Fetched::End => {
+                    logger::log_info(&format!(
+                        "Stream {} finalized at {} bytes",
+                        stream_id,
+                        buffer.end()
+                    ));
+                    ended = true;
+                }
+            }
+        }
+
+        if clock.is_none() && (buffer.buffered() >= jitter_bytes || ended) {
+            clock = Some(PlaybackClock {
+                started: Instant::now(),
+                played_before: digest.size(),
+            });
+        }
+        tokio::time::sleep(TICK).await;
+    }
+
+    ws_client.report(ProgressEvent::Finished);
+    let played = digest.finalize();
+    logger::log_info(&format!(
+        "Played {} bytes ({:.1}s) with {} underruns",
+        played.size,
+        played.size as f64 / options.bytes_per_second() as f64,
+        underruns
+    ));
+    Ok(LiveReport {
+        start_offset,
+        played: played.size,
+        underruns,
+        checksum: played.checksum,
+    })
+}
+
+/// Run the `listen` subcommand.
+pub async fn run(config: &ListenConfig) -> Result<()> {
+    let options = LiveOptions {
+        format: config.pcm_format,
+        target_latency: Duration::from_millis(config.latency_ms),
+        jitter_buffer: Duration::from_millis(config.jitter_buffer_ms),
+    };
+    if options.jitter_buffer > options.target_latency {
+        return Err(ClientError::Protocol(format!(
+            "Jitter buffer of {} ms exceeds the target latency of {} ms",
+            config.jitter_buffer_ms, config.latency_ms
+        )));
+    }
+
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let report = listen(&mut ws_client, &config.stream_id, &config.output, options).await;
+    let _ = ws_client.close().await;
+
+    let report = report?;
+    logger::log_info(&format!("Started at offset: {}", report.start_offset));
+    logger::log_info(&format!("Played SHA-256: {}", report.checksum));
+    Ok(())
+}
+
+enum Fetched {
+    Data(Vec<u8>),
+    /// Nothing uploaded past the offset yet.
+    LiveEdge,
+    /// The stream was finalized and the offset is at its end.
+    End,
+}
+
+async fn fetch(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    offset: u64,
+    length: usize,
+) -> Result<Fetched> {
+    ws_client
+        .send_control_message(ControlMessage::Get {
+            stream_id: stream_id.to_string(),
+            offset,
+            length,
+            start_time: None,
+            end_time: None,
+            checkpoint_every: None,
+            version: None,
+        })
+        .await?;
+
+    match ws_client.receive_incoming().await? {
+        Incoming::Binary(data) => Ok(Fetched::Data(data)),
+        Incoming::Control(ControlMessage::DataEnd { .. }) => Ok(Fetched::End),
+        Incoming::Control(ControlMessage::Error {
+            code: Some(code), ..
+        }) if code == "NO_DATA" => Ok(Fetched::LiveEdge),
+        Incoming::Control(msg) => Err(ClientError::unexpected("GET", msg)),
+        Incoming::Closed => Err(ClientError::closed()),
+    }
+}
+
+/// Get the number of bytes uploaded to a stream so far.
+async fn query_live_edge(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<u64> {
+    ws_client
+        .send_control_message(ControlMessage::Stat {
+            stream_id: stream_id.to_string(),
+            version: None,
+        })
+        .await?;
+
+    match ws_client.receive_reply("STAT", &["STAT_RESULT"]).await? {
+        ControlMessage::StatResult { size, .. } => Ok(size),
+        other => Err(ClientError::unexpected("STAT", other)),
+    }
+}
@@ -0,0 +1,113 @@
+// Manifest of uploaded files: which stream holds each file, and the file's
+// size and SHA-256 when it was uploaded. Written as JSON by `sync` and
+// `batch --manifest`, e.g.
+//   {"files": {"intro.wav": {"streamId": "1a2b3c4d", "size": 92124, "sha256": "..."}}}
+// `verify-manifest` re-checks every stream against it via STAT, e.g. for
+// periodic integrity audits of the cache.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::download_manager;
+use super::error::{ClientError, Result};
+use super::websocket_client::WebSocketClient;
+use crate::cli::VerifyManifestConfig;
+use crate::logger;
+
+/// Stream holding one uploaded file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    pub stream_id: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Manifest file contents, keyed by file name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Load a manifest; a missing file is an empty manifest.
+    pub fn load(path: &Path) -> Result<Self> {
+        let display = path.display().to_string();
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                ClientError::storage(
+                    &display,
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ClientError::storage(&display, e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let display = path.display().to_string();
+        let data = serde_json::to_vec_pretty(self).map_err(|e| {
+            ClientError::storage(
+                &display,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        std::fs::write(path, data).map_err(|e| ClientError::storage(&display, e))
+    }
+}
+
+/// Run the `verify-manifest` subcommand.
+pub async fn run_verify(config: &VerifyManifestConfig) -> Result<()> {
+    let manifest = Manifest::load(&config.manifest)?;
+    logger::log_info(&format!(
+        "Verifying {} streams of {} on {}",
+        manifest.files.len(),
+        config.manifest.display(),
+        config.server
+    ));
+
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let mut failures = Vec::new();
+    for (name, entry) in &manifest.files {
+        match download_manager::query_checksum(&mut ws_client, &entry.stream_id).await {
+            Ok(stored) if stored.size == entry.size && stored.checksum == entry.sha256 => {
+                logger::log_info(&format!("OK {} ({})", name, entry.stream_id));
+            }
+            Ok(stored) => {
+                logger::log_error(&format!(
+                    "MISMATCH {} ({}): expected {} bytes with SHA-256 {}, stored {} bytes with SHA-256 {}",
+                    name, entry.stream_id, entry.size, entry.sha256, stored.size, stored.checksum
+                ));
+                failures.push(name.as_str());
+            }
+            // A failed connection would fail every remaining stream
+            Err(e @ ClientError::Connection { .. }) => {
+                let _ = ws_client.close().await;
+                return Err(e);
+            }
+            Err(e) => {
+                logger::log_error(&format!("FAILED {} ({}): {}", name, entry.stream_id, e));
+                failures.push(name.as_str());
+            }
+        }
+    }
+    let _ = ws_client.close().await;
+
+    if !failures.is_empty() {
+        return Err(ClientError::Verification(format!(
+            "{} of {} streams do not match the manifest: {}",
+            failures.len(),
+            manifest.files.len(),
+            failures.join(", ")
+        )));
+    }
+    logger::log_info(&format!(
+        "All {} streams match the manifest",
+        manifest.files.len()
+    ));
+    Ok(())
+}
@@ -0,0 +1,294 @@
+// Transfer metrics pushed after each run (--metrics-push).
+// Scheduled transfer jobs report their outcome, durations, and throughput to
+// statsd over UDP, or to a Prometheus Pushgateway, so dashboards don't have to
+// parse the log output. A failed push is logged and never fails the transfer.
+
+use std::fmt::Write;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime};
+
+use super::error::{ClientError, Result};
+use super::hooks::{TransferContext, TransferHook};
+use super::performance_monitor::PerformanceReport;
+use crate::logger;
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where metrics are pushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MetricsTarget {
+    /// `host:port` of a statsd daemon.
+    Statsd(String),
+    /// Base URL of a Prometheus Pushgateway.
+    Pushgateway(String),
+}
+
+impl MetricsTarget {
+    fn parse(url: &str) -> Option<Self> {
+        let parsed = url::Url::parse(url).ok()?;
+        match parsed.scheme() {
+            "statsd" => {
+                let host = parsed.host_str()?;
+                Some(MetricsTarget::Statsd(format!(
+                    "{}:{}",
+                    host,
+                    parsed.port().unwrap_or(8125)
+                )))
+            }
+            "http" | "https" => Some(MetricsTarget::Pushgateway(
+                url.trim_end_matches('/').to_string(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Pushes the metrics of every transfer once it ended.
+pub struct MetricsHook {
+    target: MetricsTarget,
+    job: String,
+}
+
+impl MetricsHook {
+    /// Push to `url` (`statsd://host:port` or an http(s) Pushgateway URL)
+    /// under the job name `job`.
+    pub fn new(url: &str, job: &str) -> Result<Self> {
+        let target = MetricsTarget::parse(url).ok_or_else(|| {
+            ClientError::Hook(format!(
+                "--metrics-push {} is neither a statsd:// nor an http(s):// URL",
+                url
+            ))
+        })?;
+        Ok(Self {
+            target,
+            job: job.to_string(),
+        })
+    }
+
+    fn push(&self, context: &TransferContext) -> std::result::Result<(), String> {
+        match &self.target {
+            MetricsTarget::Statsd(address) => {
+                let packet =
+                    statsd_lines(&self.job, context.succeeded(), context.performance.as_ref());
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+                socket
+                    .send_to(packet.as_bytes(), address.as_str())
+                    .map_err(|e| e.to_string())?;
+            }
+            MetricsTarget::Pushgateway(base) => {
+                let url = job_url(base, &self.job)
+                    .ok_or_else(|| format!("{} is not a base URL", base))?;
+                let report = context.performance.clone().unwrap_or_default();
+                let body = exposition(context.succeeded(), &report);
+                let agent: ureq::Agent = ureq::Agent::config_builder()
+                    .timeout_global(Some(PUSH_TIMEOUT))
+                    .build()
+                    .into();
+                // PUT replaces the metrics of the previous run of the job
+                agent
+                    .put(&url)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .send(body)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TransferHook for MetricsHook {
+    fn after_transfer(&self, context: &TransferContext) -> Result<()> {
+        match self.push(context) {
+            Ok(()) => logger::log_info(&format!("Pushed transfer metrics to {:?}", self.target)),
+            Err(e) => logger::log_warn(&format!(
+                "Failed to push transfer metrics to {:?}: {}",
+                self.target, e
+            )),
+        }
+        Ok(())
+    }
+}
+
+/// Pushgateway URL of the metrics of `job`. The job name is one path
+/// segment, so characters such as `/` or spaces in it are percent-encoded.
+fn job_url(base: &str, job: &str) -> Option<String> {
+    let mut url = url::Url::parse(base).ok()?;
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(["metrics", "job", job]);
+    Some(url.into())
+}
+
+/// Render a transfer as statsd lines: a success or failure counter, and the
+/// byte counter, timers, and throughput gauges of the phases that completed.
+fn statsd_lines(job: &str, succeeded: bool, report: Option<&PerformanceReport>) -> String {
+    let outcome = if succeeded { "success" } else { "failure" };
+    let mut lines = vec![format!("{}.transfers.{}:1|c", job, outcome)];
+    let Some(report) = report else {
+        return lines.join("\n");
+    };
+    lines.push(format!("{}.bytes:{}|c", job, report.file_size));
+    lines.push(format!(
+        "{}.upload.duration:{}|ms",
+        job, report.upload_duration_ms
+    ));
+    lines.push(format!(
+        "{}.upload.throughput_mbps:{:.3}|g",
+        job, report.upload_throughput_mbps
+    ));
+    if report.download_duration_ms > 0 {
+        lines.push(format!(
+            "{}.download.duration:{}|ms",
+            job, report.download_duration_ms
+        ));
+        lines.push(format!(
+            "{}.download.throughput_mbps:{:.3}|g",
+            job, report.download_throughput_mbps
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Render a transfer in the Prometheus text exposition format. Gauges hold
+/// the last run of the job, as usual for batch jobs.
+fn exposition(succeeded: bool, report: &PerformanceReport) -> String {
+    let finished = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let gauges = [
+        (
+            "audio_stream_transfer_success",
+            "Whether the last transfer succeeded",
+            if succeeded { 1.0 } else { 0.0 },
+        ),
+        (
+            "audio_stream_transfer_finished_timestamp_seconds",
+            "Unix time the last transfer finished",
+            finished as f64,
+        ),
+        (
+            "audio_stream_transfer_bytes",
+            "Size of the last uploaded file in bytes",
+            report.file_size as f64,
+        ),
+        (
+            "audio_stream_upload_duration_seconds",
+            "Upload duration of the last transfer",
+            report.upload_duration_ms as f64 / 1000.0,
+        ),
+        (
+            "audio_stream_upload_throughput_mbps",
+            "Upload throughput of the last transfer in Mbps",
+            report.upload_throughput_mbps,
+        ),
+        (
+            "audio_stream_download_duration_seconds",
+            "Download duration of the last transfer",
+            report.download_duration_ms as f64 / 1000.0,
+        ),
+        (
+            "audio_stream_download_throughput_mbps",
+            "Download throughput of the last transfer in Mbps",
+            report.download_throughput_mbps,
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        let _ = writeln!(output, "{} {}", name, value);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> PerformanceReport {
+        PerformanceReport {
+            file_size: 2048,
+            upload_duration_ms: 250,
+            upload_throughput_mbps: 0.065536,
+            ..PerformanceReport::default()
+        }
+    }
+
+    #[test]
+    fn parses_statsd_and_pushgateway_targets() {
+        assert_eq!(
+            MetricsTarget::parse("statsd://metrics.local"),
+            Some(MetricsTarget::Statsd("metrics.local:8125".to_string()))
+        );
+        assert_eq!(
+            MetricsTarget::parse("statsd://127.0.0.1:9125"),
+            Some(MetricsTarget::Statsd("127.0.0.1:9125".to_string()))
+        );
+        assert_eq!(
+            MetricsTarget::parse("https://gateway:9091/"),
+            Some(MetricsTarget::Pushgateway(
+                "https://gateway:9091".to_string()
+            ))
+        );
+        for url in [
+            "udp://metrics.local:8125",
+            "ftp://gateway",
+            "gateway:9091",
+            "statsd",
+        ] {
+            assert_eq!(MetricsTarget::parse(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn job_name_is_percent_encoded() {
+        assert_eq!(
+            job_url("http://gateway:9091", "nightly/eu west?x").as_deref(),
+            Some("http://gateway:9091/metrics/job/nightly%2Feu%20west%3Fx")
+        );
+        assert_eq!(
+            job_url("http://gateway:9091/push", "audio").as_deref(),
+            Some("http://gateway:9091/push/metrics/job/audio")
+        );
+    }
+
+    #[test]
+    fn statsd_lines_cover_completed_phases() {
+        assert_eq!(
+            statsd_lines("job", false, None),
+            "job.transfers.failure:1|c"
+        );
+        assert_eq!(
+            statsd_lines("job", true, Some(&report())),
+            "job.transfers.success:1|c\n\
+             job.bytes:2048|c\n\
+             job.upload.duration:250|ms\n\
+             job.upload.throughput_mbps:0.066|g"
+        );
+
+        let mut round_trip = report();
+        round_trip.download_duration_ms = 125;
+        round_trip.download_throughput_mbps = 0.131072;
+        let lines = statsd_lines("job", true, Some(&round_trip));
+        assert!(
+            lines.ends_with("job.download.duration:125|ms\njob.download.throughput_mbps:0.131|g")
+        );
+    }
+
+    #[test]
+    fn exposition_has_one_gauge_per_metric() {
+        let output = exposition(true, &report());
+        assert!(output.contains(
+            "# HELP audio_stream_transfer_success Whether the last transfer succeeded\n\
+             # TYPE audio_stream_transfer_success gauge\n\
+             audio_stream_transfer_success 1\n"
+        ));
+        assert!(output.contains("\naudio_stream_transfer_bytes 2048\n"));
+        assert!(output.contains("\naudio_stream_upload_duration_seconds 0.25\n"));
+        assert_eq!(output.matches("# TYPE ").count(), 7);
+        assert!(exposition(false, &report()).contains("\naudio_stream_transfer_success 0\n"));
+    }
+}
@@ -1,104 +1,550 @@
+pub mod admin;
+pub mod batch;
+pub mod bench;
+pub mod channel_split;
 pub mod chunk_manager;
+pub mod conformance;
+pub mod connection_pool;
+pub mod copy;
 pub mod download_manager;
+pub mod error;
 pub mod file_manager;
+pub mod fingerprint;
+pub mod hooks;
+pub mod list;
+pub mod live_player;
+pub mod manifest;
+#[cfg(feature = "metrics-push")]
+pub mod metrics_push;
+pub mod network_sim;
+#[cfg(feature = "opus")]
+pub mod opus_codec;
 pub mod performance_monitor;
+#[cfg(feature = "audio-playback")]
+pub mod playback;
+#[cfg(all(feature = "profile", unix))]
+pub mod profiler;
+pub mod progress;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "server")]
+pub mod selftest;
 pub mod stream_id_generator;
+pub mod summary;
+pub mod sync;
+pub mod tcp_transport;
+pub mod trace;
+pub mod transport;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod upload_checkpoint;
 pub mod upload_manager;
+pub mod upload_pacer;
+pub mod url_source;
 pub mod verification_module;
 pub mod websocket_client;
 
-use super::cli::Config;
+use std::sync::Arc;
+
+use super::cli::{ClientCommand, Config, VerifyMode};
 use super::logger;
-use anyhow::Result;
+use super::protocol::Encoding;
+pub use chunk_manager::ChunkMode;
+pub use error::{ClientError, Result};
+pub use hooks::{CommandHook, TransferContext, TransferHook};
+#[cfg(feature = "metrics-push")]
+pub use metrics_push::MetricsHook;
+use performance_monitor::PerformanceMonitor;
+use summary::{RunSummary, SummaryFileHook};
+use upload_checkpoint::UploadCheckpointer;
+use upload_manager::UploadResult;
 
 pub async fn run(config: &Config) -> Result<()> {
-    logger::log_info("========================================");
-    logger::log_info("Starting Audio Stream Test");
-    logger::log_info("========================================");
-    logger::log_info(&format!("Input File: {}", config.input));
+    logger::init(config.verbose);
+    logger::init_color(config.no_color);
+    // Stdout carries the downloaded audio when teed there
+    if config.tee.contains(&download_manager::TeeTarget::Stdout) {
+        logger::set_stderr(true);
+    }
+    if let Some(token) = &config.token {
+        websocket_client::set_access_token(token.clone());
+    }
+    if let Some(ClientCommand::Bench(bench_config)) = &config.command {
+        return bench::run(bench_config).await;
+    }
+    if let Some(ClientCommand::Replay(replay_config)) = &config.command {
+        return trace::replay(replay_config).await;
+    }
+    if let Some(ClientCommand::Listen(listen_config)) = &config.command {
+        return live_player::run(listen_config).await;
+    }
+    if let Some(ClientCommand::SplitChannels(split_config)) = &config.command {
+        return channel_split::run_split(split_config).await;
+    }
+    if let Some(ClientCommand::MergeChannels(merge_config)) = &config.command {
+        return channel_split::run_merge(merge_config).await;
+    }
+    if let Some(ClientCommand::Clip(clip_config)) = &config.command {
+        return download_manager::run_clip(clip_config).await;
+    }
+    if let Some(ClientCommand::Batch(batch_config)) = &config.command {
+        return batch::run(batch_config).await;
+    }
+    if let Some(ClientCommand::Conformance(conformance_config)) = &config.command {
+        return conformance::run(conformance_config).await;
+    }
+    #[cfg(feature = "server")]
+    if let Some(ClientCommand::Selftest(selftest_config)) = &config.command {
+        return selftest::run(selftest_config).await;
+    }
+    if let Some(ClientCommand::Drain(drain_config)) = &config.command {
+        return admin::run_drain(drain_config).await;
+    }
+    if let Some(ClientCommand::Copy(copy_config)) = &config.command {
+        return copy::run(copy_config).await;
+    }
+    if let Some(ClientCommand::Sync(sync_config)) = &config.command {
+        return sync::run(sync_config).await;
+    }
+    if let Some(ClientCommand::VerifyManifest(verify_config)) = &config.command {
+        return manifest::run_verify(verify_config).await;
+    }
+    if let Some(ClientCommand::List(list_config)) = &config.command {
+        return list::run(list_config).await;
+    }
+
+    let mut hooks: Vec<Arc<dyn TransferHook>> = Vec::new();
+    if config.pre_hook.is_some() || config.post_hook.is_some() {
+        hooks.push(Arc::new(CommandHook::new(
+            config.pre_hook.clone(),
+            config.post_hook.clone(),
+        )));
+    }
+    if let Some(path) = &config.summary_file {
+        hooks.push(Arc::new(SummaryFileHook::new(path)));
+    }
+    #[cfg(feature = "metrics-push")]
+    if let Some(url) = &config.metrics_push {
+        hooks.push(Arc::new(MetricsHook::new(url, &config.metrics_job)?));
+    }
+
+    #[cfg(all(feature = "profile", unix))]
+    let profiler = match &config.profile {
+        Some(path) => Some(profiler::Profiler::start(path)?),
+        None => None,
+    };
+    let result = run_with_hooks(config, &hooks).await;
+    #[cfg(all(feature = "profile", unix))]
+    if let Some(profiler) = profiler {
+        return result.and(profiler.finish());
+    }
+    result
+}
+
+/// Run the upload/download/verify cycle with `hooks` called before and after.
+pub async fn run_with_hooks(config: &Config, hooks: &[Arc<dyn TransferHook>]) -> Result<()> {
+    let mut context = TransferContext::new(input_name(config), &config.server);
+    for hook in hooks {
+        hooks::call_blocking(hook, &context, |hook, context| {
+            hook.before_transfer(context)
+        })
+        .await?;
+    }
+
+    let mut result = run_transfer(config, &mut context).await;
+    context.error = result.as_ref().err().map(|e| e.to_string());
+    RunSummary::from_context(&context).log();
+
+    for hook in hooks {
+        if let Err(e) =
+            hooks::call_blocking(hook, &context, |hook, context| hook.after_transfer(context)).await
+        {
+            logger::log_error(&e.to_string());
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Tee targets of the download, with the local player of --play.
+fn tee_targets(config: &Config) -> Result<Vec<download_manager::TeeTarget>> {
+    #[cfg(feature = "audio-playback")]
+    if config.play {
+        let mut tee = config.tee.clone();
+        tee.push(playback::player(config.pcm_format)?);
+        return Ok(tee);
+    }
+    Ok(config.tee.clone())
+}
+
+/// Name of the upload input: the --input file or the --input-url.
+fn input_name(config: &Config) -> &str {
+    config.input_url.as_deref().unwrap_or(&config.input)
+}
+
+/// Content type to declare for a URL input: the declared PCM format, else the
+/// type the remote server reported, else a guess from the URL's file name.
+fn url_content_type(config: &Config, source: &url_source::UrlSource) -> String {
+    config
+        .pcm_format
+        .map(|format| format.content_type())
+        .or_else(|| source.content_type.clone())
+        .unwrap_or_else(|| {
+            let name = source.file_name().unwrap_or_default();
+            file_manager::guess_content_type(&name).to_string()
+        })
+}
+
+/// Pick what to upload: the input with its declared PCM format, or in Opus
+/// live mode the input encoded to a spool file next to the output.
+/// Returns the path, its size, and the content type to declare, if any.
+async fn upload_source(config: &Config, file_size: u64) -> Result<(String, u64, Option<String>)> {
+    #[cfg(feature = "opus")]
+    if let Some((format, spool)) = opus_spool(config) {
+        let size = opus_codec::encode_file(format, config.opus_bitrate, &config.input, &spool).await?;
+        logger::log_info(&format!("Encoded {} PCM bytes to {} Opus bytes", file_size, size));
+        return Ok((spool, size, Some(opus_codec::content_type(&format))));
+    }
+
+    let content_type = config.pcm_format.map(|format| format.content_type());
+    Ok((config.input.clone(), file_size, content_type))
+}
+
+/// Resume the upload recorded as `interrupted` by a checkpoint, if any. A
+/// stream the server can no longer resume is uploaded again from the start.
+async fn resume_checkpointed(
+    ws_client: &mut websocket_client::WebSocketClient,
+    interrupted: Option<(String, u64)>,
+    input: &str,
+    file_size: u64,
+    mode: ChunkMode,
+) -> Result<Option<UploadResult>> {
+    let Some((stream_id, committed)) = interrupted else {
+        return Ok(None);
+    };
+    logger::log_info(&format!(
+        "Checkpoint records {} bytes of stream {} on the server",
+        committed, stream_id
+    ));
+    match upload_manager::resume(ws_client, &stream_id, input, file_size, mode).await {
+        Ok(upload) => Ok(Some(upload)),
+        Err(ClientError::Server { message, .. }) => {
+            logger::log_warn(&format!(
+                "Cannot resume {} ({}), uploading from the start",
+                stream_id, message
+            ));
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Get the PCM format and spool file of Opus live mode, if enabled.
+#[cfg(feature = "opus")]
+fn opus_spool(config: &Config) -> Option<(crate::protocol::PcmFormat, String)> {
+    config
+        .pcm_format
+        .filter(|_| config.opus)
+        .map(|format| (format, format!("{}.opus", config.output)))
+}
+
+async fn run_transfer(config: &Config, context: &mut TransferContext) -> Result<()> {
+    logger::log_banner("Starting Audio Stream Test");
+    logger::log_info(&format!("Input File: {}", input_name(config)));
     logger::log_info(&format!("Output File: {}", config.output));
-    logger::log_info("========================================");
+    logger::log_rule();
+    // Fail before uploading when --play has no player
+    let tee = tee_targets(config)?;
+
+    // A URL input is requested now for its size; its body is read while uploading
+    let mut url_source = match &config.input_url {
+        Some(url) => {
+            if matches!(config.verify, VerifyMode::Fingerprint | VerifyMode::Bytes) {
+                return Err(ClientError::Verification(
+                    "--verify fingerprint and bytes need a local --input file".to_string(),
+                ));
+            }
+            Some(url_source::UrlSource::open(url).await?)
+        }
+        None => None,
+    };
 
     // Validate input file
-    let file_size = file_manager::get_file_size(&config.input)
-        .map_err(|e| anyhow::anyhow!("Failed to get file size: {}", e))?;
+    let file_size = match &url_source {
+        Some(source) => source.size.unwrap_or(0),
+        None => file_manager::get_file_size(&config.input)?,
+    };
 
-    logger::log_info(&format!("Input file size: {} bytes", file_size));
+    match url_source.as_ref() {
+        Some(source) if source.size.is_none() => {
+            logger::log_info("Input file size: unknown (no Content-Length)")
+        }
+        _ => logger::log_info(&format!("Input file size: {} bytes", file_size)),
+    }
 
     // Initialize components
     let mut ws_client = websocket_client::WebSocketClient::new(&config.server);
+    if let Some(trace_file) = &config.trace_file {
+        ws_client.set_trace(trace::TraceRecorder::create(trace_file)?);
+    }
+    if config.frame_dump {
+        ws_client.enable_frame_dump();
+    }
+    if let Some(faults) = &config.inject_faults {
+        logger::log_warn(&format!("Fault injection: {}", faults));
+        ws_client.set_fault_injection(faults.clone());
+    }
+    ws_client.set_capture_timestamps(config.capture_timestamps);
+    ws_client.set_checkpoint_every(config.checkpoint_every);
+    ws_client.set_get_retries(config.get_retries);
+    ws_client.set_upload_tags(config.tags.iter().cloned().collect());
+    ws_client.set_upload_pacing(!config.no_pacing);
+    ws_client.set_namespace(config.namespace.clone());
     
     // Connect to server
-    logger::log_info("========================================");
-    logger::log_info("Connecting to Server");
-    logger::log_info("========================================");
+    logger::log_banner("Connecting to Server");
     
-    ws_client.connect(&config.server).await
-        .map_err(|e| anyhow::anyhow!("Failed to connect to server: {}", e))?;
+    ws_client.connect(&config.server).await?;
     
     logger::log_info("Successfully connected to server");
 
+    #[cfg(feature = "tui")]
+    let dashboard = if config.tui {
+        let (dashboard, sender) = tui::Dashboard::start(format!("Audio Stream Client - {}", input_name(config)));
+        ws_client.set_progress(sender);
+        Some(dashboard)
+    } else {
+        None
+    };
+
+    let preferred = Encoding::parse(&config.encoding)
+        .ok_or_else(|| ClientError::Protocol(format!("Unknown encoding: {}", config.encoding)))?;
+    let encoding = ws_client.negotiate(preferred).await?;
+    logger::log_info(&format!("Control message encoding: {}", encoding.as_str()));
+    // Fail before START rather than on the first chunk
+    if config.capture_timestamps && !encoding.is_binary() {
+        return Err(ClientError::Protocol(format!(
+            "--capture-timestamps needs a binary encoding, but the server agreed to {}",
+            encoding.as_str()
+        )));
+    }
+
     // Phase 1: Upload
-    logger::log_info("========================================");
-    logger::log_info("[1/3] Uploading file...");
-    logger::log_info("========================================");
+    logger::log_banner("[1/3] Uploading file...");
     
-    let upload_start = std::time::Instant::now();
-    let stream_id = upload_manager::upload(&mut ws_client, &config.input, file_size).await
-        .map_err(|e| anyhow::anyhow!("Upload failed: {}", e))?;
+    let mut monitor = PerformanceMonitor::new(file_size);
+    monitor.start_upload();
+    let mode = config
+        .pcm_format
+        .map_or(ChunkMode::Fixed, |format| ChunkMode::for_format(&format));
+    // A checkpoint of an interrupted upload of the same input resumes it
+    let interrupted = match &config.upload_checkpoint {
+        Some(path) => {
+            let interval = config.upload_checkpoint_interval;
+            let (checkpointer, interrupted) =
+                UploadCheckpointer::open(path, &config.input, interval).await?;
+            ws_client.set_upload_checkpoint(checkpointer);
+            interrupted
+        }
+        None => None,
+    };
+    let upload = match (&config.append_to, &config.resume, url_source.as_mut()) {
+        (Some(stream_id), _, _) => {
+            upload_manager::append(&mut ws_client, stream_id, &config.input, file_size, mode)
+                .await?
+        }
+        (None, Some(stream_id), _) => {
+            upload_manager::resume(&mut ws_client, stream_id, &config.input, file_size, mode)
+                .await?
+        }
+        (None, None, Some(source)) => {
+            let content_type = url_content_type(config, source);
+            upload_manager::upload_url(&mut ws_client, source, &content_type).await?
+        }
+        (None, None, None) => {
+            let resumed =
+                resume_checkpointed(&mut ws_client, interrupted, &config.input, file_size, mode)
+                    .await?;
+            let (path, size, content_type) = upload_source(config, file_size).await?;
+            match (resumed, content_type) {
+                (Some(upload), _) => upload,
+                (None, Some(content_type)) => {
+                    upload_manager::upload_as(&mut ws_client, &path, size, &content_type).await?
+                }
+                (None, None) => upload_manager::upload(&mut ws_client, &path, size).await?,
+            }
+        }
+    };
+    monitor.end_upload();
+    if url_source.is_some() {
+        monitor.set_file_size(upload.sent.size);
+    }
+    #[cfg(feature = "opus")]
+    if let Some((_, spool)) = opus_spool(config) {
+        let _ = tokio::fs::remove_file(spool).await;
+    }
+    let stream_id = upload.stream_id.clone();
+    context.stream_id = Some(stream_id.clone());
+    context.size = Some(upload.sent.size);
+    context.checksum = Some(upload.sent.checksum.clone());
     
-    let upload_duration = upload_start.elapsed().as_millis() as f64;
-    let upload_throughput = (file_size as f64 * 8.0) / (upload_duration * 1_000_000.0);
+    let report = monitor.get_report();
+    context.performance = Some(report.clone());
+    let upload_duration = report.upload_duration_ms as f64;
+    let upload_throughput = report.upload_throughput_mbps;
     
     logger::log_info(&format!("Upload result: streamId={}, duration={}ms, throughput={} Mbps",
         stream_id, upload_duration as u64, upload_throughput));
 
-    logger::log_info("Upload successful, sleeping for 2 seconds...");
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    // An appended stream also holds earlier uploads, so only its size is checked
+    if config.append_to.is_some() {
+        let expected = upload.offset + upload.sent.size;
+        if let Some(stored) = upload.stored.as_ref().filter(|stored| stored.size != expected) {
+            return Err(ClientError::Verification(format!(
+                "stream size is {} bytes after appending at offset {}, expected {}",
+                stored.size, upload.offset, expected
+            )));
+        }
+        logger::log_info(&format!("Appended at offset {}, stream size is now {} bytes",
+            upload.offset, expected));
+    }
 
-    // Phase 2: Download
-    logger::log_info("========================================");
-    logger::log_info("[2/3] Downloading file...");
-    logger::log_info("========================================");
-    
-    let download_start = std::time::Instant::now();
-    let downloaded_size = download_manager::download(&mut ws_client, &stream_id, &config.output, file_size).await
-        .map_err(|e| anyhow::anyhow!("Download failed: {}", e))?;
-    
-    let download_duration = download_start.elapsed().as_millis() as f64;
-    let download_throughput = (downloaded_size as f64 * 8.0) / (download_duration * 1_000_000.0);
+    if config.no_verify || config.append_to.is_some() {
+        #[cfg(feature = "tui")]
+        drop(dashboard);
 
-    logger::log_info(&format!("Download result: success={}, duration={}ms, throughput={} Mbps",
-        true, download_duration as u64, download_throughput));
+        if config.no_verify {
+            logger::log_info("Verification skipped (--no-verify)");
+        }
 
-    logger::log_info("Download successful, sleeping for 2 seconds...");
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        let _ = ws_client.close().await;
+        logger::log_info("Disconnected from server");
+        return Ok(());
+    }
 
-    // Phase 3: Verification
-    logger::log_info("========================================");
-    logger::log_info("[3/3] Comparing files...");
-    logger::log_info("========================================");
-    
-    let verification_result = verification_module::verify(&config.input, &config.output).await
-        .map_err(|e| anyhow::anyhow!("Verification failed: {}", e))?;
-
-    // Performance report
-    logger::log_info("========================================");
-    logger::log_info("Operation Summary");
-    logger::log_info("========================================");
-    logger::log_info(&format!("Stream ID: {}", stream_id));
-    logger::log_info(&format!("Total Duration: {} ms", upload_duration as u64 + download_duration as u64));
-    logger::log_info(&format!("Upload Time: {} ms", upload_duration as u64));
-    logger::log_info(&format!("Download Time: {} ms", download_duration as u64));
-    logger::log_info(&format!("Upload Throughput: {} Mbps", upload_throughput));
-    logger::log_info(&format!("Download Throughput: {} Mbps", download_throughput));
-    logger::log_info(&format!("Content Match: {}", verification_result.passed));
-    logger::log_info(&format!("Overall Result: {}",
-        if verification_result.passed { "SUCCESS" } else { "FAILED" }));
-
-    logger::log_info("========================================");
-    logger::log_info("Audio stream test completed successfully!");
-    logger::log_info("========================================");
+    let verification_result = match config.verify {
+        VerifyMode::Download | VerifyMode::Fingerprint | VerifyMode::Bytes => {
+            logger::log_info("Upload successful, sleeping for 2 seconds...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+            // Phase 2: Download
+            logger::log_banner("[2/3] Downloading file...");
+
+            let stream_size = download_manager::query_size(&mut ws_client, &stream_id).await?;
+            logger::log_info(&format!("Stream size: {} bytes", stream_size));
+
+            // Opus live mode downloads the packets next to the output and decodes them later
+            #[cfg(feature = "opus")]
+            let spool = opus_spool(config);
+            #[cfg(feature = "opus")]
+            let download_path = spool.as_ref().map_or(config.output.as_str(), |(_, path)| path.as_str());
+            #[cfg(not(feature = "opus"))]
+            let download_path = config.output.as_str();
+
+            monitor.start_download();
+            let downloaded = download_manager::download_tee(
+                &mut ws_client,
+                &stream_id,
+                download_path,
+                &tee,
+                Some(stream_size),
+            )
+            .await?;
+
+            monitor.end_download();
+            let report = monitor.get_report();
+            context.performance = Some(report.clone());
+            let download_duration = report.download_duration_ms as f64;
+            let download_throughput = report.download_throughput_mbps;
+
+            logger::log_info(&format!("Download result: success={}, duration={}ms, throughput={} Mbps",
+                true, download_duration as u64, download_throughput));
+
+            logger::log_info("Download successful, sleeping for 2 seconds...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+
+            // Phase 3: Verification
+            logger::log_banner("[3/3] Comparing files...");
+
+            // Checksums were computed while the bytes were transferred
+            logger::log_info(&format!("Original file: {}", input_name(config)));
+            logger::log_info(&format!("Downloaded file: {}", download_path));
+
+            #[cfg(feature = "opus")]
+            if let Some((format, spool)) = &spool {
+                let decoded = opus_codec::decode_file(*format, spool, &config.output).await?;
+                let _ = tokio::fs::remove_file(spool).await;
+                logger::log_info(&format!("Decoded {} Opus bytes to {} PCM bytes in {}",
+                    downloaded.size, decoded, config.output));
+            }
+            // Opus transfers are lossy, so their files are not compared byte by byte
+            #[cfg(feature = "opus")]
+            let lossless = spool.is_none();
+            #[cfg(not(feature = "opus"))]
+            let lossless = true;
+            match config.verify {
+                VerifyMode::Fingerprint => {
+                    verification_module::verify_fingerprint(&config.input, &config.output,
+                        config.pcm_format, &upload.sent, &downloaded).await?
+                }
+                VerifyMode::Bytes if lossless => {
+                    verification_module::verify_bytes(&config.input, &config.output,
+                        &upload.sent, &downloaded).await?
+                }
+                _ => {
+                    let result = verification_module::verify(&upload.sent, &downloaded);
+                    if !result.passed && lossless && config.input_url.is_none() {
+                        verification_module::locate_difference(&config.input, &config.output).await?;
+                    }
+                    result
+                }
+            }
+        }
+        VerifyMode::Remote => {
+            // Phase 2: Server checksum, from STOPPED or else STAT
+            logger::log_banner("[2/3] Fetching server checksum...");
+
+            let stored = match upload.stored {
+                Some(stored) => stored,
+                None => download_manager::query_checksum(&mut ws_client, &stream_id).await?,
+            };
+
+            // Phase 3: Verification
+            logger::log_banner("[3/3] Comparing checksums...");
+
+            logger::log_info(&format!("Original file: {}", input_name(config)));
+            verification_module::verify_remote(&upload.sent, &stored)
+        }
+    };
+
+    context.verified = Some(verification_result.passed);
+    ws_client.report(progress::ProgressEvent::Verified {
+        passed: verification_result.passed,
+    });
+    #[cfg(feature = "tui")]
+    drop(dashboard);
+
+    if !verification_result.passed {
+        let _ = ws_client.close().await;
+        if let Some(similarity) = verification_result.similarity {
+            return Err(ClientError::Verification(format!(
+                "audio fingerprints are {:.1}% similar, below the {:.1}% match threshold",
+                similarity * 100.0,
+                fingerprint::MATCH_THRESHOLD * 100.0
+            )));
+        }
+        return Err(ClientError::Verification(format!(
+            "expected {} bytes with SHA-256 {}, got {} bytes with SHA-256 {}",
+            verification_result.original_size,
+            verification_result.original_checksum,
+            verification_result.downloaded_size,
+            verification_result.downloaded_checksum
+        )));
+    }
+
+    logger::log_banner("Audio stream test completed successfully!");
 
     // Disconnect from server
     let _ = ws_client.close().await;
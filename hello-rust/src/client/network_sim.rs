@@ -0,0 +1,132 @@
+// Network condition simulation for the bench subcommand.
+// ShapedTransport paces messages to a bandwidth cap in each direction and
+// holds back every reply until a round trip of added latency, plus jitter,
+// has passed since the last message sent, so throughput can be measured for
+// slow or distant links without tc/netem.
+
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use tokio::time::{sleep_until, Instant};
+use tokio_tungstenite::tungstenite::{self, Bytes, Message};
+
+use super::transport::Transport;
+
+/// Simulated link between the client and the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetworkProfile {
+    /// Bandwidth in each direction in kbit/s; `None` is unlimited.
+    pub bandwidth_kbps: Option<u64>,
+    /// Added round-trip time.
+    pub latency: Duration,
+    /// Upper bound of the random latency added on top of `latency`.
+    pub jitter: Duration,
+}
+
+impl NetworkProfile {
+    /// Whether the profile leaves the link as it is.
+    pub fn is_unshaped(&self) -> bool {
+        self.bandwidth_kbps.is_none() && self.latency.is_zero() && self.jitter.is_zero()
+    }
+
+    /// Time to put `bytes` on the link.
+    fn transmission_time(&self, bytes: usize) -> Duration {
+        match self.bandwidth_kbps {
+            Some(kbps) => Duration::from_micros(bytes as u64 * 8 * 1000 / kbps.max(1)),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for NetworkProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bandwidth_kbps {
+            Some(kbps) => write!(f, "{} kbit/s", kbps)?,
+            None => write!(f, "unlimited")?,
+        }
+        write!(
+            f,
+            ", {} ms RTT, {} ms jitter",
+            self.latency.as_millis(),
+            self.jitter.as_millis()
+        )
+    }
+}
+
+/// Transport shaping the messages of another one to a [`NetworkProfile`].
+pub struct ShapedTransport {
+    inner: Box<dyn Transport>,
+    profile: NetworkProfile,
+    /// When each direction of the link is free to carry the next message.
+    send_free: Instant,
+    receive_free: Instant,
+    last_sent: Instant,
+}
+
+impl ShapedTransport {
+    pub fn new(inner: Box<dyn Transport>, profile: NetworkProfile) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            profile,
+            send_free: now,
+            receive_free: now,
+            last_sent: now,
+        }
+    }
+
+    /// Reserve the link for `bytes` after the previous message; returns when
+    /// the transmission completes.
+    fn reserve(free: &mut Instant, profile: &NetworkProfile, bytes: usize) -> Instant {
+        *free = std::cmp::max(*free, Instant::now()) + profile.transmission_time(bytes);
+        *free
+    }
+
+    /// Wait until a message of `bytes` went out over the link.
+    async fn pace_send(&mut self, bytes: usize) {
+        sleep_until(Self::reserve(&mut self.send_free, &self.profile, bytes)).await;
+        self.last_sent = Instant::now();
+    }
+
+    /// Wait until a received message of `bytes` could have arrived.
+    async fn pace_receive(&mut self, bytes: usize) {
+        let jitter = match self.profile.jitter.as_micros() as u64 {
+            0 => Duration::ZERO,
+            max => Duration::from_micros(rand::rng().random_range(0..=max)),
+        };
+        sleep_until(self.last_sent + self.profile.latency + jitter).await;
+        sleep_until(Self::reserve(&mut self.receive_free, &self.profile, bytes)).await;
+    }
+}
+
+impl Transport for ShapedTransport {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(async move {
+            self.pace_send(text.len()).await;
+            self.inner.send_text(text).await
+        })
+    }
+
+    fn send_binary(&mut self, data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(async move {
+            self.pace_send(data.len()).await;
+            self.inner.send_binary(data).await
+        })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>> {
+        Box::pin(async move {
+            let message = self.inner.receive().await;
+            if let Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) = &message {
+                self.pace_receive(message.len()).await;
+            }
+            message
+        })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>> {
+        self.inner.close()
+    }
+}
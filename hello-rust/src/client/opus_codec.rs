@@ -0,0 +1,237 @@
+// Opus live mode (--opus, `opus` feature).
+// Raw 16-bit PCM is encoded into 20 ms Opus packets before upload and decoded
+// back to PCM on download, cutting the bandwidth of live streams by an order
+// of magnitude. The stream holds the packets back to back, each prefixed with
+// its length as a little-endian u16, so transfer chunks may split packets
+// anywhere. Checksums cover the encoded stream, since Opus is lossy.
+
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+
+use super::error::{ClientError, Result};
+use super::file_manager;
+use crate::protocol::PcmFormat;
+
+/// Content type of length-prefixed Opus packet streams, without parameters.
+pub const OPUS_FRAMES_CONTENT_TYPE: &str = "audio/x-opus-frames";
+
+const FRAME_DURATION_MS: usize = 20;
+// Largest packet libopus recommends allocating for
+const MAX_PACKET_SIZE: usize = 4000;
+// Longest frame a decoder can be handed: 120 ms at 48 kHz
+const MAX_FRAME_SAMPLES: usize = 5760;
+const LENGTH_PREFIX: usize = 2;
+
+/// Get the content type of the Opus stream encoded from `format`.
+pub fn content_type(format: &PcmFormat) -> String {
+    format!(
+        "{};rate={};channels={}",
+        OPUS_FRAMES_CONTENT_TYPE, format.sample_rate, format.channels
+    )
+}
+
+/// Streaming PCM to Opus encoder; feed it captured PCM in any slice sizes.
+pub struct OpusEncoder {
+    encoder: Encoder,
+    frame_bytes: usize,
+    pending: Vec<u8>,
+}
+
+impl OpusEncoder {
+    /// Create an encoder for `format`, at `bitrate` bits per second or the
+    /// libopus default.
+    pub fn new(format: PcmFormat, bitrate: Option<u32>) -> Result<Self> {
+        let (sample_rate, channels) = codec_params(&format)?;
+        let mut encoder =
+            Encoder::new(sample_rate, channels, Application::Audio).map_err(codec_error)?;
+        if let Some(bitrate) = bitrate {
+            encoder
+                .set_bitrate(Bitrate::BitsPerSecond(bitrate as i32))
+                .map_err(codec_error)?;
+        }
+
+        let frame_samples = format.sample_rate as usize * FRAME_DURATION_MS / 1000;
+        Ok(Self {
+            encoder,
+            frame_bytes: frame_samples * format.frame_size(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Encode every whole frame buffered so far and return the packets;
+    /// a partial frame is kept for the next call.
+    pub fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(pcm);
+        let whole = self.pending.len() - self.pending.len() % self.frame_bytes;
+
+        let mut packets = Vec::new();
+        for frame in self.pending[..whole].chunks_exact(self.frame_bytes) {
+            encode_frame(&self.encoder, frame, &mut packets)?;
+        }
+        self.pending.drain(..whole);
+        Ok(packets)
+    }
+
+    /// Encode the remaining partial frame, padded with silence.
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut packets = Vec::new();
+        if !self.pending.is_empty() {
+            self.pending.resize(self.frame_bytes, 0);
+            encode_frame(&self.encoder, &self.pending, &mut packets)?;
+            self.pending.clear();
+        }
+        Ok(packets)
+    }
+}
+
+/// Streaming Opus to PCM decoder; feed it the stream in any slice sizes.
+pub struct OpusDecoder {
+    decoder: Decoder,
+    channels: usize,
+    pending: Vec<u8>,
+    samples: Vec<i16>,
+}
+
+impl OpusDecoder {
+    pub fn new(format: PcmFormat) -> Result<Self> {
+        let (sample_rate, channels) = codec_params(&format)?;
+        Ok(Self {
+            decoder: Decoder::new(sample_rate, channels).map_err(codec_error)?,
+            channels: format.channels as usize,
+            pending: Vec::new(),
+            samples: vec![0; MAX_FRAME_SAMPLES * format.channels as usize],
+        })
+    }
+
+    /// Decode every complete packet buffered so far and return the PCM.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+
+        let mut pcm = Vec::new();
+        let mut consumed = 0;
+        while let Some(prefix) = self.pending.get(consumed..consumed + LENGTH_PREFIX) {
+            let len = u16::from_le_bytes([prefix[0], prefix[1]]) as usize;
+            let start = consumed + LENGTH_PREFIX;
+            let Some(packet) = self.pending.get(start..start + len) else {
+                break;
+            };
+
+            let packet = Packet::try_from(packet).map_err(codec_error)?;
+            let output = MutSignals::try_from(&mut self.samples[..]).map_err(codec_error)?;
+            let decoded = self
+                .decoder
+                .decode(Some(packet), output, false)
+                .map_err(codec_error)?;
+            for sample in &self.samples[..decoded * self.channels] {
+                pcm.extend_from_slice(&sample.to_le_bytes());
+            }
+            consumed = start + len;
+        }
+        self.pending.drain(..consumed);
+        Ok(pcm)
+    }
+
+    /// Fail if the stream ended inside a packet.
+    pub fn finish(&self) -> Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(ClientError::Codec(format!(
+                "Opus stream ends with {} bytes of a truncated packet",
+                self.pending.len()
+            )))
+        }
+    }
+}
+
+/// Encode a raw PCM file into an Opus stream file, chunk by chunk.
+/// Returns the size of the encoded stream.
+pub async fn encode_file(
+    format: PcmFormat,
+    bitrate: Option<u32>,
+    input: &str,
+    output: &str,
+) -> Result<u64> {
+    let mut encoder = OpusEncoder::new(format, bitrate)?;
+    let input_size = file_manager::get_file_size(input)?;
+    let mut offset = 0;
+    let mut encoded = 0;
+
+    file_manager::write_chunk(output, &[], false).await?;
+    while offset < input_size {
+        let chunk_size = std::cmp::min(file_manager::CHUNK_SIZE as u64, input_size - offset);
+        let pcm = file_manager::read_chunk(input, offset, chunk_size as usize).await?;
+        let packets = encoder.encode(&pcm)?;
+        file_manager::write_chunk(output, &packets, true).await?;
+        offset += chunk_size;
+        encoded += packets.len() as u64;
+    }
+    let packets = encoder.finish()?;
+    file_manager::write_chunk(output, &packets, true).await?;
+    Ok(encoded + packets.len() as u64)
+}
+
+/// Decode an Opus stream file into a raw PCM file, chunk by chunk.
+/// Returns the size of the decoded PCM.
+pub async fn decode_file(format: PcmFormat, input: &str, output: &str) -> Result<u64> {
+    let mut decoder = OpusDecoder::new(format)?;
+    let input_size = file_manager::get_file_size(input)?;
+    let mut offset = 0;
+    let mut decoded = 0;
+
+    file_manager::write_chunk(output, &[], false).await?;
+    while offset < input_size {
+        let chunk_size = std::cmp::min(file_manager::CHUNK_SIZE as u64, input_size - offset);
+        let data = file_manager::read_chunk(input, offset, chunk_size as usize).await?;
+        let pcm = decoder.decode(&data)?;
+        file_manager::write_chunk(output, &pcm, true).await?;
+        offset += chunk_size;
+        decoded += pcm.len() as u64;
+    }
+    decoder.finish()?;
+    Ok(decoded)
+}
+
+fn encode_frame(encoder: &Encoder, frame: &[u8], packets: &mut Vec<u8>) -> Result<()> {
+    let samples: Vec<i16> = frame
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect();
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    let len = encoder.encode(&samples, &mut packet).map_err(codec_error)?;
+
+    packets.extend_from_slice(&(len as u16).to_le_bytes());
+    packets.extend_from_slice(&packet[..len]);
+    Ok(())
+}
+
+fn codec_params(format: &PcmFormat) -> Result<(SampleRate, Channels)> {
+    if format.bits_per_sample != 16 {
+        return Err(ClientError::Codec(format!(
+            "Opus live mode needs 16-bit PCM, got {} bits",
+            format.bits_per_sample
+        )));
+    }
+    let sample_rate = SampleRate::try_from(format.sample_rate as i32).map_err(|_| {
+        ClientError::Codec(format!(
+            "Opus does not support {} Hz; use 8000, 12000, 16000, 24000, or 48000",
+            format.sample_rate
+        ))
+    })?;
+    let channels = match format.channels {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        n => {
+            return Err(ClientError::Codec(format!(
+                "Opus live mode supports mono or stereo, got {} channels",
+                n
+            )))
+        }
+    };
+    Ok((sample_rate, channels))
+}
+
+fn codec_error(e: audiopus::Error) -> ClientError {
+    ClientError::Codec(e.to_string())
+}
@@ -8,7 +8,9 @@ pub struct PerformanceMonitor {
     download_end: Option<Instant>,
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct PerformanceReport {
+    pub file_size: u64,
     pub upload_duration_ms: u64,
     pub upload_throughput_mbps: f64,
     pub download_duration_ms: u64,
@@ -28,6 +30,12 @@ impl PerformanceMonitor {
         }
     }
 
+    /// Set the size of the file once it is known, e.g. after streaming an
+    /// input of unknown length.
+    pub fn set_file_size(&mut self, file_size: u64) {
+        self.file_size = file_size;
+    }
+
     pub fn start_upload(&mut self) {
         self.upload_start = Some(Instant::now());
     }
@@ -44,36 +52,41 @@ impl PerformanceMonitor {
         self.download_end = Some(Instant::now());
     }
 
+    /// Report the phases measured so far; a phase that was not run (or not
+    /// finished) counts as zero.
     pub fn get_report(&self) -> PerformanceReport {
-        let upload_duration_ms = self
-            .upload_end
-            .unwrap()
-            .duration_since(self.upload_start.unwrap())
-            .as_millis() as u64;
-
-        let download_duration_ms = self
-            .download_end
-            .unwrap()
-            .duration_since(self.download_start.unwrap())
-            .as_millis() as u64;
-
+        let upload_duration_ms = Self::duration_ms(self.upload_start, self.upload_end);
+        let download_duration_ms = Self::duration_ms(self.download_start, self.download_end);
         let total_duration_ms = upload_duration_ms + download_duration_ms;
-
-        // Throughput (Mbps) = (file_size_bytes * 8) / (duration_ms * 1_000_000)
-        let upload_throughput_mbps =
-            (self.file_size as f64 * 8.0) / (upload_duration_ms as f64 * 1_000_000.0);
-        let download_throughput_mbps =
-            (self.file_size as f64 * 8.0) / (download_duration_ms as f64 * 1_000_000.0);
-        let average_throughput_mbps =
-            (self.file_size as f64 * 2.0 * 8.0) / (total_duration_ms as f64 * 1_000_000.0);
+        let transferred = if download_duration_ms > 0 {
+            self.file_size * 2
+        } else {
+            self.file_size
+        };
 
         PerformanceReport {
+            file_size: self.file_size,
             upload_duration_ms,
-            upload_throughput_mbps,
+            upload_throughput_mbps: Self::throughput_mbps(self.file_size, upload_duration_ms),
             download_duration_ms,
-            download_throughput_mbps,
+            download_throughput_mbps: Self::throughput_mbps(self.file_size, download_duration_ms),
             total_duration_ms,
-            average_throughput_mbps,
+            average_throughput_mbps: Self::throughput_mbps(transferred, total_duration_ms),
+        }
+    }
+
+    fn duration_ms(start: Option<Instant>, end: Option<Instant>) -> u64 {
+        match (start, end) {
+            (Some(start), Some(end)) => end.duration_since(start).as_millis() as u64,
+            _ => 0,
+        }
+    }
+
+    // Throughput (Mbps) = (bytes * 8) / (duration_ms * 1_000); 0 when unmeasured
+    fn throughput_mbps(bytes: u64, duration_ms: u64) -> f64 {
+        if duration_ms == 0 {
+            return 0.0;
         }
+        (bytes as f64 * 8.0) / (duration_ms as f64 * 1_000.0)
     }
 }
@@ -0,0 +1,68 @@
+// Local playback of downloaded audio (--play).
+// No audio library is linked in: the download is teed into the first player
+// found on PATH, ffplay for any format ffmpeg reads, or aplay for WAV and raw
+// PCM, so the client keeps building on platforms without audio toolchains.
+
+use std::path::Path;
+
+use super::download_manager::TeeTarget;
+use super::error::{ClientError, Result};
+use crate::protocol::PcmFormat;
+
+/// Tee target playing the download on the local audio device; `format`
+/// declares raw PCM input, which only aplay can be told the layout of.
+pub fn player(format: Option<PcmFormat>) -> Result<TeeTarget> {
+    let command = match format {
+        Some(format) => on_path("aplay").then(|| aplay_raw(&format)),
+        None if on_path("ffplay") => Some("ffplay -nodisp -autoexit -loglevel error -".to_string()),
+        None => on_path("aplay").then(|| "aplay -q -".to_string()),
+    };
+    command.map(TeeTarget::Player).ok_or_else(|| {
+        ClientError::Playback(match format {
+            Some(_) => "raw PCM playback needs aplay on PATH".to_string(),
+            None => "no audio player found on PATH (install ffplay or aplay)".to_string(),
+        })
+    })
+}
+
+/// Build the aplay command reading raw little-endian PCM from stdin.
+fn aplay_raw(format: &PcmFormat) -> String {
+    let sample = match format.bits_per_sample {
+        8 => "U8",
+        16 => "S16_LE",
+        24 => "S24_3LE",
+        _ => "S32_LE",
+    };
+    format!(
+        "aplay -q -t raw -f {} -r {} -c {} -",
+        sample, format.sample_rate, format.channels
+    )
+}
+
+/// Check whether `program` is an executable file in a PATH directory.
+fn on_path(program: &str) -> bool {
+    let name = if cfg!(windows) {
+        format!("{}.exe", program)
+    } else {
+        program.to_string()
+    };
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| Path::new(&dir).join(&name).is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aplay_is_told_the_raw_layout() {
+        let format = PcmFormat::new(48000, 2, 24).unwrap();
+        assert_eq!(
+            aplay_raw(&format),
+            "aplay -q -t raw -f S24_3LE -r 48000 -c 2 -"
+        );
+        let format = PcmFormat::new(8000, 1, 8).unwrap();
+        assert_eq!(aplay_raw(&format), "aplay -q -t raw -f U8 -r 8000 -c 1 -");
+    }
+}
@@ -0,0 +1,101 @@
+// Built-in CPU profiling of client transfers (--profile).
+// The process is sampled while the transfer runs and the profile is written
+// as an SVG flamegraph when the file name ends in `.svg`, or else as folded
+// stacks that inferno, flamegraph.pl, and speedscope read.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use pprof::{ProfilerGuard, ProfilerGuardBuilder, Report};
+
+use super::error::{ClientError, Result};
+use crate::logger;
+
+/// Samples per second.
+const SAMPLE_FREQUENCY: i32 = 999;
+// Frames of the sampling machinery itself
+const BLOCKLIST: [&str; 4] = ["libc", "libgcc", "pthread", "vdso"];
+
+/// Running CPU profile.
+pub struct Profiler {
+    guard: ProfilerGuard<'static>,
+    path: PathBuf,
+}
+
+impl Profiler {
+    /// Start sampling the process; the profile goes to `path` on `finish`.
+    pub fn start(path: &Path) -> Result<Self> {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_FREQUENCY)
+            .blocklist(&BLOCKLIST)
+            .build()
+            .map_err(|e| Self::error(path, e))?;
+        logger::log_info(&format!(
+            "Profiling at {} Hz into {}",
+            SAMPLE_FREQUENCY,
+            path.display()
+        ));
+        Ok(Self {
+            guard,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Stop sampling and write the profile.
+    pub fn finish(self) -> Result<()> {
+        let Self { guard, path } = self;
+        let report = guard.report().build().map_err(|e| Self::error(&path, e))?;
+        drop(guard);
+
+        let name = path.display().to_string();
+        let file = File::create(&path).map_err(|e| ClientError::storage(&name, e))?;
+        let mut writer = BufWriter::new(file);
+        let written = if path.extension().is_some_and(|ext| ext == "svg") {
+            report
+                .flamegraph(&mut writer)
+                .map_err(|e| std::io::Error::other(e.to_string()))
+        } else {
+            writer.write_all(folded_stacks(&report).as_bytes())
+        };
+        written
+            .and_then(|()| writer.flush())
+            .map_err(|e| ClientError::storage(&name, e))?;
+
+        let samples: isize = report.data.values().sum();
+        logger::log_info(&format!(
+            "Wrote CPU profile of {} samples to {}",
+            samples, name
+        ));
+        Ok(())
+    }
+
+    fn error(path: &Path, e: pprof::Error) -> ClientError {
+        ClientError::storage(&path.display().to_string(), std::io::Error::other(e))
+    }
+}
+
+/// Render a report as folded stacks: one `thread;outer;...;inner count` line
+/// per distinct stack.
+fn folded_stacks(report: &Report) -> String {
+    let mut lines: Vec<String> = report
+        .data
+        .iter()
+        .map(|(frames, count)| {
+            let mut line = frames.thread_name_or_id();
+            for frame in frames.frames.iter().rev() {
+                for symbol in frame.iter().rev() {
+                    let _ = write!(line, ";{}", symbol);
+                }
+            }
+            let _ = write!(line, " {}", count);
+            line
+        })
+        .collect();
+    lines.sort();
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
@@ -0,0 +1,46 @@
+// Transfer progress events.
+// The upload and download managers report through WebSocketClient so that
+// front ends such as the terminal dashboard can follow a transfer live.
+
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Sender half handed to [`WebSocketClient::set_progress`](super::websocket_client::WebSocketClient::set_progress).
+pub type ProgressSender = UnboundedSender<ProgressEvent>;
+
+/// Transfer direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+/// Something that happened during a transfer.
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    /// A transfer of `total` bytes (if known) started.
+    Started {
+        direction: TransferDirection,
+        stream_id: String,
+        total: Option<u64>,
+    },
+    /// A chunk was sent or received; `latency` covers the round trip for
+    /// downloads and the send for uploads.
+    Chunk { bytes: u64, latency: Duration },
+    /// A request was sent again, e.g. after a MOVED redirect.
+    Retry,
+    /// The transfer in progress completed.
+    Finished,
+    /// Verification completed.
+    Verified { passed: bool },
+}
+
+/// Whole percentage of `done` out of `total`; computed in 128 bits so it
+/// holds for transfers of any size. An empty total counts as complete.
+pub fn percent(done: u64, total: u64) -> u64 {
+    if total == 0 {
+        return 100;
+    }
+    (done as u128 * 100 / total as u128) as u64
+}
@@ -0,0 +1,160 @@
+// One-command smoke test (selftest).
+// A server started in-process on a loopback port receives a generated tone,
+// which is uploaded, downloaded, and verified like a regular transfer, so a
+// new deployment can be checked without a running server or audio files.
+
+use std::f64::consts::TAU;
+use std::time::Instant;
+
+use super::error::{ClientError, Result};
+use super::file_manager::{self, SpoolDir};
+use super::websocket_client::WebSocketClient;
+use super::{download_manager, upload_manager, verification_module};
+use crate::cli::{SelftestConfig, ServerConfig};
+use crate::logger;
+use crate::protocol::pcm_format::{PcmFormat, WAV_HEADER_LEN};
+use crate::server::memory::{MemoryPoolManager, StreamManager};
+use crate::server::network::{AudioWebSocketServer, ShutdownHandle};
+
+const SAMPLE_RATE: u32 = 48000;
+// Left and right channel tone frequencies
+const TONE_HZ: [f64; 2] = [440.0, 660.0];
+
+pub async fn run(config: &SelftestConfig) -> Result<()> {
+    logger::log_banner("Starting Self-Test");
+
+    let spool = SpoolDir::create("selftest")?;
+    let start = Instant::now();
+    match cycle(config, &spool).await {
+        Ok(size) => {
+            logger::log_info(&format!(
+                "Self-test PASSED: {} bytes uploaded, downloaded, and verified in {} ms",
+                size,
+                start.elapsed().as_millis()
+            ));
+            Ok(())
+        }
+        Err(e) => {
+            logger::log_error(&format!("Self-test FAILED: {}", e));
+            Err(ClientError::Selftest(e.to_string()))
+        }
+    }
+}
+
+/// Run the server, generate the input, and transfer it; returns its size.
+async fn cycle(config: &SelftestConfig, spool: &SpoolDir) -> Result<u64> {
+    // Stopped on return, before the spool directory is removed
+    let (uri, _server) = spawn_server(&spool.file("cache"))?;
+    logger::log_info(&format!("[1/4] In-process server listening on {}", uri));
+
+    let input = spool.file("tone.wav");
+    let output = spool.file("download.wav");
+    let size = write_tone(&input, config.duration).await?;
+    logger::log_info(&format!(
+        "[2/4] Generated {}s of test audio ({} bytes)",
+        config.duration, size
+    ));
+
+    let mut ws_client = WebSocketClient::new(&uri);
+    ws_client.connect(&uri).await?;
+    let upload = upload_manager::upload(&mut ws_client, &input, size).await?;
+    logger::log_info(&format!("[3/4] Uploaded stream {}", upload.stream_id));
+
+    let downloaded =
+        download_manager::download(&mut ws_client, &upload.stream_id, &output, Some(size)).await?;
+    let _ = ws_client.close().await;
+    let result = verification_module::verify(&upload.sent, &downloaded);
+    if !result.passed {
+        return Err(ClientError::Verification(format!(
+            "expected SHA-256 {}, got {}",
+            result.original_checksum, result.downloaded_checksum
+        )));
+    }
+    logger::log_info(&format!(
+        "[4/4] Downloaded and verified SHA-256 {}",
+        result.downloaded_checksum
+    ));
+    Ok(size)
+}
+
+/// Start a server on a loopback port with its own cache in
+/// `cache_directory`; returns its URI and the handle that stops it.
+fn spawn_server(cache_directory: &str) -> Result<(String, ShutdownHandle)> {
+    let server_config = ServerConfig::default();
+    let path = server_config.path.clone();
+    let stream_manager = StreamManager::open(cache_directory);
+    let memory_pool = MemoryPoolManager::instance(64 * 1024, 16);
+    let (addr, server) = AudioWebSocketServer::new(server_config, stream_manager, memory_pool)
+        .spawn_on_ephemeral_port()
+        .map_err(|e| ClientError::Selftest(format!("server failed to start: {}", e)))?;
+    Ok((format!("ws://{}{}", addr, path), server))
+}
+
+/// Write `seconds` of a stereo 16-bit tone as a WAV file; returns its size.
+async fn write_tone(path: &str, seconds: u32) -> Result<u64> {
+    let format = PcmFormat::new(SAMPLE_RATE, TONE_HZ.len() as u16, 16).unwrap();
+    let frames = SAMPLE_RATE as u64 * seconds as u64;
+    let data_len = frames * format.frame_size() as u64;
+    file_manager::write_chunk(path, &format.wav_header(data_len), false).await?;
+
+    // One second per write
+    let mut frame = 0u64;
+    while frame < frames {
+        let end = std::cmp::min(frame + SAMPLE_RATE as u64, frames);
+        let mut chunk = Vec::with_capacity((end - frame) as usize * format.frame_size());
+        for n in frame..end {
+            let time = n as f64 / SAMPLE_RATE as f64;
+            for hz in TONE_HZ {
+                let sample = ((TAU * hz * time).sin() * 0.5 * i16::MAX as f64) as i16;
+                chunk.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        file_manager::write_chunk(path, &chunk, true).await?;
+        frame = end;
+    }
+    Ok(WAV_HEADER_LEN as u64 + data_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ephemeral_servers_keep_separate_caches() {
+        let spool = SpoolDir::create("selftest-servers").unwrap();
+        let input = spool.file("tone.wav");
+        let size = write_tone(&input, 1).await.unwrap();
+
+        let caches = [spool.file("cache-a"), spool.file("cache-b")];
+        let mut servers = Vec::new();
+        for cache in &caches {
+            let (uri, server) = spawn_server(cache).unwrap();
+            let mut ws_client = WebSocketClient::new(&uri);
+            ws_client.connect(&uri).await.unwrap();
+            let upload = upload_manager::upload(&mut ws_client, &input, size)
+                .await
+                .unwrap();
+            let output = spool.file(&format!("{}.wav", upload.stream_id));
+            let downloaded =
+                download_manager::download(&mut ws_client, &upload.stream_id, &output, Some(size))
+                    .await
+                    .unwrap();
+            let _ = ws_client.close().await;
+            assert!(verification_module::verify(&upload.sent, &downloaded).passed);
+            servers.push((server, upload.stream_id));
+        }
+
+        // Each stream is cached by the server it was uploaded to only
+        for (cache, (_, stream_id)) in caches.iter().zip(&servers) {
+            let cached: Vec<_> = std::fs::read_dir(cache)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            assert!(cached.iter().any(|name| name.contains(stream_id.as_str())));
+            assert!(servers
+                .iter()
+                .filter(|(_, other)| other != stream_id)
+                .all(|(_, other)| !cached.iter().any(|name| name.contains(other.as_str()))));
+        }
+    }
+}
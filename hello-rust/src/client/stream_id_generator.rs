@@ -1,6 +1,8 @@
 // Stream ID generator for creating unique stream identifiers
 // Matches Java StreamIdGenerator with short UUID format
 
+use crate::protocol::NAMESPACE_SEPARATOR;
+
 pub fn generate_short() -> String {
     // Generate 8-character hex string (like Java's UUID.substring(0, 8))
     let random: String = (0..8)
@@ -9,6 +11,14 @@ pub fn generate_short() -> String {
     format!("stream-{}", random)
 }
 
+/// Generate a short stream ID in `namespace`, if given.
+pub fn generate_in(namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, generate_short()),
+        None => generate_short(),
+    }
+}
+
 pub fn generate_stream_id() -> String {
     // Legacy method for backward compatibility
     let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
@@ -0,0 +1,160 @@
+// End-of-run summary.
+// Each run ends with one aligned table of its phases (bytes, duration,
+// throughput, result), built from the transfer context; --summary-file writes
+// the same data as JSON, also for runs that failed.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::error::{ClientError, Result};
+use super::hooks::{TransferContext, TransferHook};
+use crate::logger;
+
+/// One phase of a run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhaseSummary {
+    pub phase: &'static str,
+    pub bytes: u64,
+    /// Unset for phases that are not timed.
+    pub duration_ms: Option<u64>,
+    pub throughput_mbps: Option<f64>,
+    pub result: &'static str,
+}
+
+/// Outcome of a run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSummary {
+    pub input: String,
+    pub server: String,
+    pub stream_id: Option<String>,
+    pub checksum: Option<String>,
+    pub phases: Vec<PhaseSummary>,
+    pub result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    pub fn from_context(context: &TransferContext) -> Self {
+        let report = context.performance.clone().unwrap_or_default();
+        let size = context.size.unwrap_or(0);
+        let mut phases = vec![PhaseSummary {
+            phase: "upload",
+            bytes: size,
+            duration_ms: Some(report.upload_duration_ms),
+            throughput_mbps: Some(report.upload_throughput_mbps),
+            result: if context.size.is_some() {
+                "ok"
+            } else {
+                "failed"
+            },
+        }];
+        if report.download_duration_ms > 0 {
+            phases.push(PhaseSummary {
+                phase: "download",
+                bytes: report.file_size,
+                duration_ms: Some(report.download_duration_ms),
+                throughput_mbps: Some(report.download_throughput_mbps),
+                result: "ok",
+            });
+        }
+        if let Some(verified) = context.verified {
+            phases.push(PhaseSummary {
+                phase: "verify",
+                bytes: size,
+                duration_ms: None,
+                throughput_mbps: None,
+                result: if verified { "match" } else { "mismatch" },
+            });
+        }
+
+        let succeeded = context.succeeded() && context.verified != Some(false);
+        Self {
+            input: context.input.clone(),
+            server: context.server.clone(),
+            stream_id: context.stream_id.clone(),
+            checksum: context.checksum.clone(),
+            phases,
+            result: if succeeded { "SUCCESS" } else { "FAILED" },
+            error: context.error.clone(),
+        }
+    }
+
+    /// Render the phases as aligned table rows, header first.
+    pub fn table(&self) -> Vec<String> {
+        let row = |cells: [&str; 5]| {
+            format!(
+                "{:<10} {:>12} {:>10} {:>14}  {}",
+                cells[0], cells[1], cells[2], cells[3], cells[4]
+            )
+        };
+        let mut rows = vec![row(["Phase", "Bytes", "Duration", "Throughput", "Result"])];
+        for phase in &self.phases {
+            let duration = phase
+                .duration_ms
+                .map_or("-".to_string(), |ms| format!("{} ms", ms));
+            let throughput = phase
+                .throughput_mbps
+                .map_or("-".to_string(), |mbps| format!("{:.2} Mbps", mbps));
+            rows.push(row([
+                phase.phase,
+                &phase.bytes.to_string(),
+                &duration,
+                &throughput,
+                phase.result,
+            ]));
+        }
+        rows
+    }
+
+    /// Log the summary table.
+    pub fn log(&self) {
+        logger::log_banner("Operation Summary");
+        if let Some(stream_id) = &self.stream_id {
+            logger::log_info(&format!("Stream ID: {}", stream_id));
+        }
+        if let Some(checksum) = &self.checksum {
+            logger::log_info(&format!("SHA-256: {}", checksum));
+        }
+        for row in self.table() {
+            logger::log_info(&row);
+        }
+        logger::log_info(&format!("Overall Result: {}", self.result));
+    }
+
+    /// Write the summary as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let display = path.display().to_string();
+        let data = serde_json::to_vec_pretty(self).map_err(|e| {
+            ClientError::storage(
+                &display,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        std::fs::write(path, data).map_err(|e| ClientError::storage(&display, e))
+    }
+}
+
+/// Writes the summary of every run to a file once it ended (--summary-file).
+pub struct SummaryFileHook {
+    path: PathBuf,
+}
+
+impl SummaryFileHook {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl TransferHook for SummaryFileHook {
+    fn after_transfer(&self, context: &TransferContext) -> Result<()> {
+        RunSummary::from_context(context).write(&self.path)?;
+        logger::log_info(&format!("Wrote run summary to {}", self.path.display()));
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+// Directory mirroring (the `sync` subcommand).
+// A manifest (by default .audio-stream-sync.json in the directory) records the
+// stream and SHA-256 of every file uploaded so far. Each run hashes the files
+// of the directory, checks the recorded streams on the server via STAT, and
+// plans the changes: new files are uploaded, changed files (or files whose
+// stream is gone or differs) are uploaded again, and with --delete the streams
+// of removed files and the old streams of replaced ones are deleted. The plan
+// is printed before anything is sent; --dry-run stops there.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::download_manager;
+use super::error::{ClientError, Result};
+use super::file_manager;
+use super::manifest::{Manifest, ManifestEntry};
+use super::upload_manager;
+use super::websocket_client::{ControlMessage, WebSocketClient, MAX_REDIRECTS};
+use crate::cli::SyncConfig;
+use crate::logger;
+
+/// Manifest file name used when --manifest is not given.
+pub const DEFAULT_MANIFEST: &str = ".audio-stream-sync.json";
+
+/// Size and SHA-256 of a local file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalFile {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// What a sync does with one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    /// New file: upload it.
+    Upload,
+    /// Changed file, or its stream is gone or differs: upload it again; the
+    /// old stream is deleted with --delete.
+    Replace { old: ManifestEntry },
+    /// File removed from the directory: its stream is deleted with --delete.
+    Delete { old: ManifestEntry },
+    /// Stream matches the file.
+    Unchanged,
+}
+
+/// One file of the plan.
+#[derive(Debug, Clone)]
+pub struct PlanItem {
+    pub name: String,
+    pub action: SyncAction,
+}
+
+impl fmt::Display for PlanItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.action {
+            SyncAction::Upload => write!(f, "+ upload  {}", self.name),
+            SyncAction::Replace { old } => {
+                write!(f, "~ replace {} (stream {})", self.name, old.stream_id)
+            }
+            SyncAction::Delete { old } => {
+                write!(f, "- delete  {} (stream {})", self.name, old.stream_id)
+            }
+            SyncAction::Unchanged => write!(f, "  keep    {}", self.name),
+        }
+    }
+}
+
+/// Plan a sync of the `local` files against the `manifest`. `matches`
+/// reports whether the recorded stream of an unchanged file still holds it
+/// on the server.
+pub fn plan(
+    local: &BTreeMap<String, LocalFile>,
+    manifest: &Manifest,
+    matches: impl Fn(&ManifestEntry) -> bool,
+) -> Vec<PlanItem> {
+    let mut items = Vec::new();
+    for (name, file) in local {
+        let action = match manifest.files.get(name) {
+            None => SyncAction::Upload,
+            Some(old) if old.sha256 == file.sha256 && old.size == file.size && matches(old) => {
+                SyncAction::Unchanged
+            }
+            Some(old) => SyncAction::Replace { old: old.clone() },
+        };
+        items.push(PlanItem {
+            name: name.clone(),
+            action,
+        });
+    }
+    for (name, old) in &manifest.files {
+        if !local.contains_key(name) {
+            items.push(PlanItem {
+                name: name.clone(),
+                action: SyncAction::Delete { old: old.clone() },
+            });
+        }
+    }
+    items
+}
+
+/// Run the `sync` subcommand.
+pub async fn run(config: &SyncConfig) -> Result<()> {
+    let manifest_path = config
+        .manifest
+        .clone()
+        .unwrap_or_else(|| config.dir.join(DEFAULT_MANIFEST));
+    let mut manifest = Manifest::load(&manifest_path)?;
+
+    logger::log_banner("Starting Directory Sync");
+    logger::log_info(&format!("Directory: {}", config.dir.display()));
+    logger::log_info(&format!("Manifest: {}", manifest_path.display()));
+
+    let mut local = BTreeMap::new();
+    for (name, path) in list_files(&config.dir, &manifest_path)? {
+        let path = path.to_string_lossy();
+        let size = file_manager::get_file_size(&path)?;
+        let sha256 = file_manager::compute_sha256(&path).await?;
+        local.insert(name, LocalFile { size, sha256 });
+    }
+
+    let mut ws_client = WebSocketClient::new(&config.server);
+    ws_client.connect(&config.server).await?;
+    let result = sync(
+        config,
+        &mut ws_client,
+        &local,
+        &mut manifest,
+        &manifest_path,
+    )
+    .await;
+    let _ = ws_client.close().await;
+    result
+}
+
+async fn sync(
+    config: &SyncConfig,
+    ws_client: &mut WebSocketClient,
+    local: &BTreeMap<String, LocalFile>,
+    manifest: &mut Manifest,
+    manifest_path: &Path,
+) -> Result<()> {
+    // Check the streams of files that did not change locally
+    let mut stored = BTreeMap::new();
+    for (name, entry) in &manifest.files {
+        if local
+            .get(name)
+            .is_none_or(|file| file.sha256 != entry.sha256)
+        {
+            continue;
+        }
+        let matches = match download_manager::query_checksum(ws_client, &entry.stream_id).await {
+            Ok(checksum) => checksum.size == entry.size && checksum.checksum == entry.sha256,
+            Err(e) if is_not_found(&e) => false,
+            Err(e) => return Err(e),
+        };
+        stored.insert(entry.stream_id.clone(), matches);
+    }
+    let items = plan(local, manifest, |entry| {
+        stored.get(&entry.stream_id).copied().unwrap_or(false)
+    });
+
+    logger::log_banner(if config.dry_run {
+        "Sync plan (dry run)"
+    } else {
+        "Sync plan"
+    });
+    for item in items
+        .iter()
+        .filter(|item| item.action != SyncAction::Unchanged)
+    {
+        match &item.action {
+            SyncAction::Delete { .. } if !config.delete => {
+                logger::log_info(&format!("{} (kept, pass --delete to remove)", item))
+            }
+            _ => logger::log_info(&item.to_string()),
+        }
+    }
+    let unchanged = items
+        .iter()
+        .filter(|item| item.action == SyncAction::Unchanged)
+        .count();
+    logger::log_info(&format!(
+        "{} files unchanged, {} to sync",
+        unchanged,
+        items.len() - unchanged
+    ));
+    if config.dry_run {
+        return Ok(());
+    }
+
+    let mut failures = Vec::new();
+    for item in &items {
+        match apply(config, ws_client, item, manifest).await {
+            Ok(()) => manifest.save(manifest_path)?,
+            Err(e) => {
+                logger::log_error(&format!("{}: {}", item.name, e));
+                failures.push(item.name.as_str());
+            }
+        }
+    }
+    if !failures.is_empty() {
+        return Err(ClientError::Sync(format!(
+            "{} files failed: {}",
+            failures.len(),
+            failures.join(", ")
+        )));
+    }
+    logger::log_info("Directory is in sync");
+    Ok(())
+}
+
+/// Carry out one item of the plan, updating the manifest.
+async fn apply(
+    config: &SyncConfig,
+    ws_client: &mut WebSocketClient,
+    item: &PlanItem,
+    manifest: &mut Manifest,
+) -> Result<()> {
+    let old = match &item.action {
+        SyncAction::Unchanged => return Ok(()),
+        SyncAction::Delete { old } => {
+            if config.delete {
+                delete(ws_client, &old.stream_id).await?;
+                manifest.files.remove(&item.name);
+            }
+            return Ok(());
+        }
+        SyncAction::Upload => None,
+        SyncAction::Replace { old } => Some(old),
+    };
+
+    let path = config.dir.join(&item.name).to_string_lossy().into_owned();
+    let size = file_manager::get_file_size(&path)?;
+    let upload = upload_manager::upload(ws_client, &path, size).await?;
+    logger::log_info(&format!("{} -> {}", item.name, upload.stream_id));
+    manifest.files.insert(
+        item.name.clone(),
+        ManifestEntry {
+            stream_id: upload.stream_id,
+            size: upload.sent.size,
+            sha256: upload.sent.checksum,
+        },
+    );
+
+    if let Some(old) = old.filter(|_| config.delete) {
+        delete(ws_client, &old.stream_id).await?;
+    }
+    Ok(())
+}
+
+/// Delete a stream; a stream that is already gone counts as deleted.
+pub async fn delete(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<()> {
+    let mut redirects = 0;
+    loop {
+        ws_client
+            .send_control_message(ControlMessage::Delete {
+                stream_id: stream_id.to_string(),
+            })
+            .await?;
+        match ws_client.receive_reply("DELETE", &["DELETED"]).await? {
+            ControlMessage::Deleted { .. } => {
+                logger::log_info(&format!("Deleted stream {}", stream_id));
+                return Ok(());
+            }
+            ControlMessage::Moved { location, .. } if redirects < MAX_REDIRECTS => {
+                logger::log_info(&format!("Stream {} moved to {}", stream_id, location));
+                ws_client.follow_redirect(&location).await?;
+                redirects += 1;
+            }
+            other => {
+                return match ClientError::unexpected("DELETE", other) {
+                    e if is_not_found(&e) => Ok(()),
+                    e => Err(e),
+                }
+            }
+        }
+    }
+}
+
+fn is_not_found(error: &ClientError) -> bool {
+    matches!(error, ClientError::Server { code: Some(code), .. } if code == "STREAM_NOT_FOUND")
+}
+
+/// List the files under `dir` by their path relative to it, with `/` as
+/// separator. Hidden files and directories and the manifest are skipped.
+fn list_files(dir: &Path, manifest_path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let display = current.display().to_string();
+        let entries = std::fs::read_dir(&current).map_err(|e| ClientError::storage(&display, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| ClientError::storage(&display, e))?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if hidden || path == manifest_path {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Ok(relative) = path.strip_prefix(dir) {
+                let name = relative
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((name, path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(stream_id: &str, sha256: &str) -> ManifestEntry {
+        ManifestEntry {
+            stream_id: stream_id.to_string(),
+            size: 4,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    fn file(sha256: &str) -> LocalFile {
+        LocalFile {
+            size: 4,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn plans_uploads_replacements_and_deletions() {
+        let mut manifest = Manifest::default();
+        manifest.files.insert("same.wav".into(), entry("s1", "aa"));
+        manifest
+            .files
+            .insert("changed.wav".into(), entry("s2", "bb"));
+        manifest.files.insert("lost.wav".into(), entry("s3", "cc"));
+        manifest
+            .files
+            .insert("removed.wav".into(), entry("s4", "dd"));
+        let local = BTreeMap::from([
+            ("same.wav".to_string(), file("aa")),
+            ("changed.wav".to_string(), file("b2")),
+            ("lost.wav".to_string(), file("cc")),
+            ("new.wav".to_string(), file("ee")),
+        ]);
+
+        let items = plan(&local, &manifest, |entry| entry.stream_id != "s3");
+        let actions: BTreeMap<_, _> = items
+            .into_iter()
+            .map(|item| (item.name, item.action))
+            .collect();
+        assert_eq!(actions["same.wav"], SyncAction::Unchanged);
+        assert_eq!(
+            actions["changed.wav"],
+            SyncAction::Replace {
+                old: entry("s2", "bb")
+            }
+        );
+        assert_eq!(
+            actions["lost.wav"],
+            SyncAction::Replace {
+                old: entry("s3", "cc")
+            }
+        );
+        assert_eq!(actions["new.wav"], SyncAction::Upload);
+        assert_eq!(
+            actions["removed.wav"],
+            SyncAction::Delete {
+                old: entry("s4", "dd")
+            }
+        );
+    }
+}
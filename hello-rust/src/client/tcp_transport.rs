@@ -0,0 +1,104 @@
+// Raw TCP transport of the client (tcp:// URIs).
+// Messages travel in the length-prefixed frames of the TCP framing instead of
+// WebSocket frames, without an HTTP upgrade; the protocol on top is the same.
+
+use std::io::ErrorKind;
+
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Bytes, Message, Utf8Bytes};
+
+use super::transport::Transport;
+use crate::protocol::{decode_tcp_header, encode_tcp_frame, TcpFrameKind, TCP_HEADER_LEN};
+
+/// Raw TCP connection to a server.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    /// Connect to the host and port of a `tcp://host:port` URI.
+    pub async fn connect(uri: &str) -> tungstenite::Result<Self> {
+        let url =
+            url::Url::parse(uri).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} needs a host and a port", uri),
+            )
+            .into());
+        };
+        let stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    pub async fn send(&mut self, message: Message) -> tungstenite::Result<()> {
+        let frame = match &message {
+            Message::Text(text) => encode_tcp_frame(TcpFrameKind::Text, text.as_bytes()),
+            Message::Binary(data) => encode_tcp_frame(TcpFrameKind::Binary, data),
+            Message::Close(frame) => encode_tcp_frame(
+                TcpFrameKind::Close,
+                frame
+                    .as_ref()
+                    .map_or(&[][..], |frame| frame.reason.as_bytes()),
+            ),
+            // Raw TCP has no ping or pong frames
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => return Ok(()),
+        };
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Read the next message, or `None` once the server closed the stream.
+    pub async fn next(&mut self) -> Option<tungstenite::Result<Message>> {
+        let mut header = [0u8; TCP_HEADER_LEN];
+        match self.stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        Some(self.read_payload(&header).await)
+    }
+
+    /// Send a close frame and shut down the sending side.
+    pub async fn close(&mut self) -> tungstenite::Result<()> {
+        self.send(Message::Close(None)).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn read_payload(
+        &mut self,
+        header: &[u8; TCP_HEADER_LEN],
+    ) -> tungstenite::Result<Message> {
+        let (kind, length) = decode_tcp_header(header)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut payload = vec![0u8; length];
+        self.stream.read_exact(&mut payload).await?;
+        Ok(match kind {
+            TcpFrameKind::Text => Message::Text(Utf8Bytes::try_from(payload)?),
+            TcpFrameKind::Binary => Message::Binary(Bytes::from(payload)),
+            TcpFrameKind::Close => Message::Close(None),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(self.send(Message::Text(Utf8Bytes::from(text))))
+    }
+
+    fn send_binary(&mut self, data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(self.send(Message::Binary(data)))
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>> {
+        Box::pin(self.next())
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(TcpTransport::close(self))
+    }
+}
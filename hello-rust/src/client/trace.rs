@@ -0,0 +1,236 @@
+// Protocol session recording and replay.
+// A trace file holds one JSON object per line for every connect, sent frame,
+// and received frame, with microseconds elapsed since recording started.
+// Binary payloads are base64-encoded.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::error::{ClientError, Result};
+use super::websocket_client::WebSocketClient;
+use crate::cli::ReplayConfig;
+use crate::logger;
+
+// How long replay waits for each recorded server frame
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line of a trace file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+    pub elapsed_us: u64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// Something that happened on the connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum TraceEvent {
+    Connect {
+        uri: String,
+    },
+    Send {
+        #[serde(flatten)]
+        frame: TraceFrame,
+    },
+    Recv {
+        #[serde(flatten)]
+        frame: TraceFrame,
+    },
+}
+
+/// A WebSocket frame as stored in the trace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TraceFrame {
+    Text { text: String },
+    Binary { data: String },
+    Close,
+}
+
+impl TraceFrame {
+    /// Convert a WebSocket message; ping/pong frames are not traced.
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Some(TraceFrame::Text {
+                text: text.to_string(),
+            }),
+            Message::Binary(data) => Some(TraceFrame::Binary {
+                data: BASE64.encode(data),
+            }),
+            Message::Close(_) => Some(TraceFrame::Close),
+            _ => None,
+        }
+    }
+
+    /// Short human-readable description for mismatch reports.
+    pub fn describe(&self) -> String {
+        match self {
+            TraceFrame::Text { text } => format!("text {}", text),
+            TraceFrame::Binary { data } => {
+                let len = BASE64.decode(data).map(|bytes| bytes.len()).unwrap_or(0);
+                format!("binary ({} bytes)", len)
+            }
+            TraceFrame::Close => "close".to_string(),
+        }
+    }
+}
+
+/// Appends trace entries to a file as the session runs.
+pub struct TraceRecorder {
+    file: File,
+    path: String,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let display = path.display().to_string();
+        let file = File::create(path).map_err(|e| ClientError::storage(&display, e))?;
+        logger::log_info(&format!("Recording protocol trace to {}", display));
+
+        Ok(Self {
+            file,
+            path: display,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, event: TraceEvent) {
+        let entry = TraceEntry {
+            elapsed_us: self.start.elapsed().as_micros() as u64,
+            event,
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(std::io::Error::other)
+            .and_then(|line| writeln!(self.file, "{}", line));
+        if let Err(e) = result {
+            logger::log_warn(&format!("Failed to write trace to {}: {}", self.path, e));
+        }
+    }
+
+    pub fn record_send(&mut self, message: &Message) {
+        if let Some(frame) = TraceFrame::from_message(message) {
+            self.record(TraceEvent::Send { frame });
+        }
+    }
+
+    pub fn record_recv(&mut self, message: &Message) {
+        if let Some(frame) = TraceFrame::from_message(message) {
+            self.record(TraceEvent::Recv { frame });
+        }
+    }
+}
+
+/// Read every entry of a trace file.
+pub fn load(path: &Path) -> Result<Vec<TraceEntry>> {
+    let display = path.display().to_string();
+    let file = File::open(path).map_err(|e| ClientError::storage(&display, e))?;
+
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| ClientError::storage(&display, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            ClientError::Protocol(format!("{} line {}: {}", display, index + 1, e))
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Re-drive a recorded session against a server, comparing every frame the
+/// server sends with the recording.
+pub async fn replay(config: &ReplayConfig) -> Result<()> {
+    let entries = load(&config.trace_file)?;
+    logger::log_info(&format!(
+        "Replaying {} trace entries from {}",
+        entries.len(),
+        config.trace_file.display()
+    ));
+
+    let mut ws_client = WebSocketClient::new("");
+    let mut first_uri: Option<String> = None;
+    let start = Instant::now();
+    let mut sent = 0;
+    let mut received = 0;
+    let mut mismatches = 0;
+
+    for (index, entry) in entries.iter().enumerate() {
+        if config.realtime {
+            let due = Duration::from_micros(entry.elapsed_us);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        match &entry.event {
+            TraceEvent::Connect { uri } => {
+                // The first node is replaced by --server; redirect targets are kept
+                let first = first_uri.get_or_insert_with(|| uri.clone());
+                let target = match &config.server {
+                    Some(server) if first == uri => server.clone(),
+                    _ => uri.clone(),
+                };
+                let _ = ws_client.close().await;
+                ws_client.connect(&target).await?;
+                logger::log_info(&format!("#{} connected to {}", index, target));
+            }
+            TraceEvent::Send { frame } => {
+                match frame {
+                    TraceFrame::Text { text } => ws_client.send_text(text).await?,
+                    TraceFrame::Binary { data } => {
+                        let data = BASE64.decode(data).map_err(|e| {
+                            ClientError::Protocol(format!("Entry #{}: bad base64: {}", index, e))
+                        })?;
+                        ws_client.send_raw(Message::Binary(data.into())).await?;
+                    }
+                    TraceFrame::Close => ws_client.close().await?,
+                }
+                sent += 1;
+            }
+            TraceEvent::Recv { frame: expected } => {
+                received += 1;
+                let actual = match tokio::time::timeout(RECEIVE_TIMEOUT, ws_client.receive()).await
+                {
+                    Ok(Ok(Some(message))) => TraceFrame::from_message(&message),
+                    Ok(Ok(None)) => Some(TraceFrame::Close),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => None,
+                };
+                if actual.as_ref() != Some(expected) {
+                    mismatches += 1;
+                    logger::log_warn(&format!(
+                        "#{} expected {}, got {}",
+                        index,
+                        expected.describe(),
+                        actual.map_or_else(|| "nothing".to_string(), |frame| frame.describe())
+                    ));
+                }
+            }
+        }
+    }
+    let _ = ws_client.close().await;
+
+    logger::log_info(&format!(
+        "Replay finished: {} frames sent, {} frames expected, {} mismatches",
+        sent, received, mismatches
+    ));
+    if mismatches > 0 {
+        return Err(ClientError::Verification(format!(
+            "{} of {} received frames differ from the trace",
+            mismatches, received
+        )));
+    }
+    Ok(())
+}
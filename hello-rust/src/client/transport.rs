@@ -0,0 +1,406 @@
+// Transport under WebSocketClient.
+// The client speaks the protocol over any Transport: a WebSocket, raw TCP for
+// tcp:// URIs, or the in-memory MockTransport, which answers from a closure so
+// the upload and download managers run without a server. FaultyTransport
+// wraps any of them to inject faults into the messages sent.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::BoxFuture;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Bytes, Message, Utf8Bytes};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::logger;
+use crate::protocol::{truncate_text, Fault, FaultInjector};
+
+/// Message channel to a server. Messages arrive as WebSocket messages;
+/// transports without a frame type of their own map theirs onto them.
+pub trait Transport: Send {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>>;
+
+    fn send_binary(&mut self, data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>>;
+
+    /// Receive the next message, or `None` once the connection is closed.
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>>;
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>>;
+}
+
+impl Transport for WebSocketStream<MaybeTlsStream<TcpStream>> {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(self.send(Message::Text(Utf8Bytes::from(text))))
+    }
+
+    fn send_binary(&mut self, data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(self.send(Message::Binary(data)))
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>> {
+        Box::pin(self.next())
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>> {
+        Box::pin(WebSocketStream::close(self, None))
+    }
+}
+
+/// Replies of a [`MockTransport`] to one sent message.
+pub type MockResponder = Box<dyn FnMut(&Message) -> Vec<Message> + Send>;
+
+/// In-memory transport for tests. Every sent message is logged and handed to
+/// the responder, whose replies are received in order; once no reply is
+/// pending, `receive` reports a closed connection instead of waiting.
+pub struct MockTransport {
+    responder: MockResponder,
+    pending: VecDeque<Message>,
+    sent: Arc<Mutex<Vec<Message>>>,
+}
+
+impl MockTransport {
+    pub fn new(responder: impl FnMut(&Message) -> Vec<Message> + Send + 'static) -> Self {
+        Self {
+            responder: Box::new(responder),
+            pending: VecDeque::new(),
+            sent: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Queue a message to receive before any reply.
+    pub fn push_reply(&mut self, message: Message) {
+        self.pending.push_back(message);
+    }
+
+    /// Get the log of sent messages, which stays readable after the
+    /// transport moved into a client.
+    pub fn sent(&self) -> Arc<Mutex<Vec<Message>>> {
+        self.sent.clone()
+    }
+
+    fn record(&mut self, message: Message) {
+        let replies = (self.responder)(&message);
+        self.pending.extend(replies);
+        self.sent.lock().unwrap().push(message);
+    }
+}
+
+impl Transport for MockTransport {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>> {
+        self.record(Message::Text(Utf8Bytes::from(text)));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn send_binary(&mut self, data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>> {
+        self.record(Message::Binary(data));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>> {
+        let message = self.pending.pop_front().map(Ok);
+        Box::pin(async move { message })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>> {
+        self.sent.lock().unwrap().push(Message::Close(None));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Transport dropping, delaying, truncating, or duplicating the text and
+/// binary messages sent, as chosen by its injector (--inject-faults).
+pub struct FaultyTransport {
+    inner: Box<dyn Transport>,
+    injector: FaultInjector,
+}
+
+impl FaultyTransport {
+    pub fn new(inner: Box<dyn Transport>, injector: FaultInjector) -> Self {
+        Self { inner, injector }
+    }
+
+    fn next_fault(&mut self, kind: &str, len: usize) -> Option<Fault> {
+        let fault = self.injector.next_fault(len);
+        if let Some(fault) = fault {
+            logger::log_debug(&format!(
+                "[fault] {:?} on {} frame of {} bytes",
+                fault, kind, len
+            ));
+        }
+        fault
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn send_text(&mut self, text: String) -> BoxFuture<'_, tungstenite::Result<()>> {
+        let fault = self.next_fault("text", text.len());
+        Box::pin(async move {
+            match fault {
+                None => self.inner.send_text(text).await,
+                Some(Fault::Drop) => Ok(()),
+                Some(Fault::Delay(delay)) => {
+                    tokio::time::sleep(delay).await;
+                    self.inner.send_text(text).await
+                }
+                Some(Fault::Truncate(len)) => {
+                    let truncated = truncate_text(&text, len).to_string();
+                    self.inner.send_text(truncated).await
+                }
+                Some(Fault::Duplicate) => {
+                    self.inner.send_text(text.clone()).await?;
+                    self.inner.send_text(text).await
+                }
+            }
+        })
+    }
+
+    fn send_binary(&mut self, mut data: Bytes) -> BoxFuture<'_, tungstenite::Result<()>> {
+        let fault = self.next_fault("binary", data.len());
+        Box::pin(async move {
+            match fault {
+                None => self.inner.send_binary(data).await,
+                Some(Fault::Drop) => Ok(()),
+                Some(Fault::Delay(delay)) => {
+                    tokio::time::sleep(delay).await;
+                    self.inner.send_binary(data).await
+                }
+                Some(Fault::Truncate(len)) => {
+                    data.truncate(len);
+                    self.inner.send_binary(data).await
+                }
+                Some(Fault::Duplicate) => {
+                    self.inner.send_binary(data.clone()).await?;
+                    self.inner.send_binary(data).await
+                }
+            }
+        })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<tungstenite::Result<Message>>> {
+        self.inner.receive()
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, tungstenite::Result<()>> {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::error::ClientError;
+    use crate::client::file_manager::SpoolDir;
+    use crate::client::upload_manager;
+    use crate::client::websocket_client::{ControlMessage, WebSocketClient};
+
+    /// Answer START and STOP like a server that stores the data sent, and
+    /// acknowledges it when `acks` and asked to, failing writes past
+    /// `capacity` bytes.
+    fn server(acks: bool, capacity: u64) -> impl FnMut(&Message) -> Vec<Message> + Send {
+        let mut size = 0u64;
+        let mut acked = None;
+        move |message| {
+            let reply = match message {
+                Message::Text(text) => match ControlMessage::from_json(text).unwrap() {
+                    ControlMessage::Start {
+                        stream_id,
+                        acks: asked,
+                        ..
+                    } => {
+                        acked = (acks && asked).then(|| stream_id.clone());
+                        ControlMessage::Started {
+                            stream_id,
+                            message: None,
+                            offset: None,
+                            received: None,
+                            max_chunk_size: None,
+                            acks: acked.is_some(),
+                        }
+                    }
+                    ControlMessage::Stop { stream_id } => ControlMessage::Stopped {
+                        stream_id,
+                        message: None,
+                        size: Some(size),
+                        checksum: None,
+                    },
+                    other => panic!("Unexpected {} message", other.type_name()),
+                },
+                Message::Binary(data) if size + data.len() as u64 > capacity => {
+                    ControlMessage::stream_error("s", "STORAGE_ERROR", "Disk full")
+                }
+                Message::Binary(data) => {
+                    size += data.len() as u64;
+                    match acked.clone() {
+                        Some(stream_id) => ControlMessage::Ack {
+                            stream_id,
+                            bytes: size,
+                        },
+                        None => return Vec::new(),
+                    }
+                }
+                _ => return Vec::new(),
+            };
+            vec![Message::Text(reply.to_json().unwrap().into())]
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_runs_over_mock_transport() {
+        let spool = SpoolDir::create("mock-transport").unwrap();
+        let input = spool.file("input.bin");
+        std::fs::write(&input, vec![7u8; 100_000]).unwrap();
+
+        let transport = MockTransport::new(server(false, u64::MAX));
+        let sent = transport.sent();
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(transport));
+
+        let upload = upload_manager::upload(&mut client, &input, 100_000)
+            .await
+            .unwrap();
+        assert_eq!(upload.sent.size, 100_000);
+
+        let sent = sent.lock().unwrap();
+        let data: usize = sent
+            .iter()
+            .map(|message| match message {
+                Message::Binary(data) => data.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(data, 100_000);
+        assert!(matches!(sent.first(), Some(Message::Text(text)) if text.contains("START")));
+        assert!(matches!(sent.last(), Some(Message::Text(text)) if text.contains("STOP")));
+    }
+
+    #[tokio::test]
+    async fn paced_upload_waits_for_acks() {
+        let spool = SpoolDir::create("mock-transport-acks").unwrap();
+        let input = spool.file("input.bin");
+        std::fs::write(&input, vec![7u8; 1_000_000]).unwrap();
+
+        let transport = MockTransport::new(server(true, u64::MAX));
+        let sent = transport.sent();
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(transport));
+
+        let upload = upload_manager::upload(&mut client, &input, 1_000_000)
+            .await
+            .unwrap();
+        assert_eq!(upload.sent.size, 1_000_000);
+        let sent = sent.lock().unwrap();
+        assert!(
+            matches!(sent.first(), Some(Message::Text(text)) if text.contains("\"acks\":true"))
+        );
+    }
+
+    #[tokio::test]
+    async fn paced_upload_stops_at_the_first_failed_write() {
+        let spool = SpoolDir::create("mock-transport-ack-error").unwrap();
+        let input = spool.file("input.bin");
+        std::fs::write(&input, vec![7u8; 1_000_000]).unwrap();
+
+        let transport = MockTransport::new(server(true, 200_000));
+        let sent = transport.sent();
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(transport));
+
+        let err = upload_manager::upload(&mut client, &input, 1_000_000)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, ClientError::Server { code: Some(code), .. } if code == "STORAGE_ERROR")
+        );
+        let frames = sent.lock().unwrap();
+        let data = frames
+            .iter()
+            .filter(|message| matches!(message, Message::Binary(_)))
+            .count();
+        assert!(data < 10, "{} data frames sent", data);
+    }
+
+    #[tokio::test]
+    async fn taken_stream_id_is_replaced() {
+        let spool = SpoolDir::create("mock-transport-id-taken").unwrap();
+        let input = spool.file("input.bin");
+        std::fs::write(&input, vec![7u8; 100_000]).unwrap();
+
+        // Refuse the first START as if its ID belonged to another stream
+        let mut inner = server(false, u64::MAX);
+        let mut taken = None;
+        let transport = MockTransport::new(move |message: &Message| match message {
+            Message::Text(text) if taken.is_none() && text.contains("START") => {
+                let start = ControlMessage::from_json(text).unwrap();
+                let stream_id = start.stream_id().unwrap().to_string();
+                let reply = ControlMessage::stream_error(&stream_id, "STREAM_EXISTS", "Taken");
+                taken = Some(stream_id);
+                vec![Message::Text(reply.to_json().unwrap().into())]
+            }
+            _ => inner(message),
+        });
+        let sent = transport.sent();
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(transport));
+
+        let upload = upload_manager::upload(&mut client, &input, 100_000)
+            .await
+            .unwrap();
+        assert_eq!(upload.sent.size, 100_000);
+        let started: Vec<String> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|message| match message {
+                Message::Text(text) => match ControlMessage::from_json(text).unwrap() {
+                    ControlMessage::Start { stream_id, .. } => Some(stream_id),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(started.len(), 2);
+        assert_ne!(started[0], started[1]);
+        assert_eq!(upload.stream_id, started[1]);
+    }
+
+    #[tokio::test]
+    async fn replies_are_found_among_stray_messages() {
+        let spool = SpoolDir::create("mock-transport-stray").unwrap();
+        let input = spool.file("input.bin");
+        std::fs::write(&input, vec![7u8; 100_000]).unwrap();
+
+        // Precede every reply with a ping, a late ACK, a duplicate STARTED,
+        // and leftover data
+        let mut inner = server(false, u64::MAX);
+        let transport = MockTransport::new(move |message: &Message| {
+            let stray = [
+                ControlMessage::Ack {
+                    stream_id: "old".to_string(),
+                    bytes: 65536,
+                },
+                ControlMessage::Started {
+                    stream_id: "old".to_string(),
+                    message: None,
+                    offset: None,
+                    received: None,
+                    max_chunk_size: None,
+                    acks: false,
+                },
+            ];
+            let mut replies = vec![Message::Ping(Bytes::new())];
+            replies.extend(stray.map(|stray| Message::Text(stray.to_json().unwrap().into())));
+            replies.push(Message::Binary(Bytes::from(vec![0u8; 16])));
+            replies.extend(inner(message));
+            replies
+        });
+        let mut client = WebSocketClient::new("mock://server");
+        client.connect_with("mock://server", Box::new(transport));
+
+        let upload = upload_manager::upload(&mut client, &input, 100_000)
+            .await
+            .unwrap();
+        assert_eq!(upload.sent.size, 100_000);
+    }
+}
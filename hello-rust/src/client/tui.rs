@@ -0,0 +1,329 @@
+// Terminal dashboard for live transfers (--tui).
+// Draws throughput graphs, chunk latency, retries, and ETA from the progress
+// events reported by the managers. Log output is suppressed while the
+// dashboard owns the terminal; errors are printed once it is restored.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Sparkline};
+use ratatui::Frame;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use super::progress::{ProgressEvent, ProgressSender, TransferDirection};
+use crate::logger;
+
+// Redraw interval, also the input polling timeout
+const TICK: Duration = Duration::from_millis(100);
+// Width of one throughput sample
+const SAMPLE_WINDOW: Duration = Duration::from_millis(250);
+// Throughput samples kept per direction
+const MAX_SAMPLES: usize = 240;
+
+/// Running dashboard; dropping it restores the terminal.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and return the sender to pass to
+    /// [`WebSocketClient::set_progress`](super::websocket_client::WebSocketClient::set_progress).
+    pub fn start(title: String) -> (Self, ProgressSender) {
+        let (sender, receiver) = unbounded_channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        logger::set_quiet(true);
+        let thread_stop = stop.clone();
+        let handle = std::thread::spawn(move || run(title, receiver, thread_stop));
+
+        let dashboard = Self {
+            stop,
+            handle: Some(handle),
+        };
+        (dashboard, sender)
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        logger::set_quiet(false);
+    }
+}
+
+fn run(title: String, mut receiver: UnboundedReceiver<ProgressEvent>, stop: Arc<AtomicBool>) {
+    let mut terminal = ratatui::init();
+    let mut state = DashboardState::new(title);
+
+    loop {
+        while let Ok(event) = receiver.try_recv() {
+            state.apply(event);
+        }
+        state.tick();
+        let _ = terminal.draw(|frame| state.render(frame));
+
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        // Raw mode swallows SIGINT, so handle Ctrl-C and q here
+        if event::poll(TICK).unwrap_or(false) {
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (ctrl_c || key.code == KeyCode::Char('q')) {
+                    ratatui::restore();
+                    logger::set_quiet(false);
+                    std::process::exit(130);
+                }
+            }
+        }
+    }
+
+    ratatui::restore();
+}
+
+/// Everything the dashboard shows.
+struct DashboardState {
+    title: String,
+    status: String,
+    direction: Option<TransferDirection>,
+    stream_id: String,
+    total: Option<u64>,
+    transferred: u64,
+    started: Instant,
+    finished: Option<Instant>,
+    window_bytes: u64,
+    window_start: Instant,
+    upload_samples: Vec<u64>,
+    download_samples: Vec<u64>,
+    last_latency: Duration,
+    max_latency: Duration,
+    latency_sum: Duration,
+    chunks: u64,
+    retries: u64,
+}
+
+impl DashboardState {
+    fn new(title: String) -> Self {
+        let now = Instant::now();
+        Self {
+            title,
+            status: "Connecting".to_string(),
+            direction: None,
+            stream_id: String::new(),
+            total: None,
+            transferred: 0,
+            started: now,
+            finished: None,
+            window_bytes: 0,
+            window_start: now,
+            upload_samples: Vec::new(),
+            download_samples: Vec::new(),
+            last_latency: Duration::ZERO,
+            max_latency: Duration::ZERO,
+            latency_sum: Duration::ZERO,
+            chunks: 0,
+            retries: 0,
+        }
+    }
+
+    fn apply(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Started {
+                direction,
+                stream_id,
+                total,
+            } => {
+                self.status = match direction {
+                    TransferDirection::Upload => "Uploading",
+                    TransferDirection::Download => "Downloading",
+                }
+                .to_string();
+                self.direction = Some(direction);
+                self.stream_id = stream_id;
+                self.total = total;
+                self.transferred = 0;
+                self.started = Instant::now();
+                self.finished = None;
+                self.window_bytes = 0;
+                self.window_start = self.started;
+                self.last_latency = Duration::ZERO;
+                self.max_latency = Duration::ZERO;
+                self.latency_sum = Duration::ZERO;
+                self.chunks = 0;
+            }
+            ProgressEvent::Chunk { bytes, latency } => {
+                self.transferred += bytes;
+                self.window_bytes += bytes;
+                self.last_latency = latency;
+                self.max_latency = self.max_latency.max(latency);
+                self.latency_sum += latency;
+                self.chunks += 1;
+            }
+            ProgressEvent::Retry => self.retries += 1,
+            ProgressEvent::Finished => {
+                self.tick_sample();
+                self.finished = Some(Instant::now());
+                self.status = format!("{} complete", self.status);
+                self.direction = None;
+            }
+            ProgressEvent::Verified { passed } => {
+                self.status = if passed {
+                    "Verification passed"
+                } else {
+                    "Verification FAILED"
+                }
+                .to_string();
+            }
+        }
+    }
+
+    /// Close the current throughput sample once its window has elapsed.
+    fn tick(&mut self) {
+        if self.direction.is_some() && self.window_start.elapsed() >= SAMPLE_WINDOW {
+            self.tick_sample();
+        }
+    }
+
+    fn tick_sample(&mut self) {
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        let samples = match self.direction {
+            Some(TransferDirection::Upload) => &mut self.upload_samples,
+            Some(TransferDirection::Download) => &mut self.download_samples,
+            None => return,
+        };
+        if elapsed > 0.0 {
+            // Kilobits per second keeps sub-megabit rates visible
+            samples.push((self.window_bytes as f64 * 8.0 / elapsed / 1000.0) as u64);
+            if samples.len() > MAX_SAMPLES {
+                samples.remove(0);
+            }
+        }
+        self.window_bytes = 0;
+        self.window_start = Instant::now();
+    }
+
+    /// Seconds spent on the current or last transfer.
+    fn elapsed(&self) -> f64 {
+        let end = self.finished.unwrap_or_else(Instant::now);
+        end.duration_since(self.started).as_secs_f64()
+    }
+
+    fn average_mbps(&self) -> f64 {
+        let elapsed = self.elapsed();
+        if elapsed > 0.0 {
+            self.transferred as f64 * 8.0 / elapsed / 1_000_000.0
+        } else {
+            0.0
+        }
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let total = self.total?;
+        let elapsed = self.elapsed();
+        if self.direction.is_none() || self.transferred == 0 || elapsed <= 0.0 {
+            return None;
+        }
+        let rate = self.transferred as f64 / elapsed;
+        Some(Duration::from_secs_f64(
+            total.saturating_sub(self.transferred) as f64 / rate,
+        ))
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [stats_area, gauge_area, upload_area, download_area] = Layout::vertical([
+            Constraint::Length(8),
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+        ])
+        .areas(frame.area());
+
+        let average_latency = if self.chunks > 0 {
+            self.latency_sum.div_f64(self.chunks as f64)
+        } else {
+            Duration::ZERO
+        };
+        let stats = vec![
+            Line::from(format!("Status:     {}", self.status)),
+            Line::from(format!("Stream:     {}", self.stream_id)),
+            Line::from(format!(
+                "Progress:   {} / {} bytes",
+                self.transferred,
+                self.total
+                    .map_or_else(|| "?".to_string(), |total| total.to_string())
+            )),
+            Line::from(format!(
+                "Throughput: {:.2} Mbps average",
+                self.average_mbps()
+            )),
+            Line::from(format!(
+                "Latency:    last {:.1} ms, avg {:.1} ms, max {:.1} ms",
+                self.last_latency.as_secs_f64() * 1000.0,
+                average_latency.as_secs_f64() * 1000.0,
+                self.max_latency.as_secs_f64() * 1000.0
+            )),
+            Line::from(format!(
+                "Retries:    {}    ETA: {}",
+                self.retries,
+                self.eta().map_or_else(
+                    || "-".to_string(),
+                    |eta| format!("{:.1}s", eta.as_secs_f64())
+                )
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(stats).block(Block::bordered().title(self.title.as_str())),
+            stats_area,
+        );
+
+        let ratio = match self.total {
+            Some(total) if total > 0 => (self.transferred as f64 / total as f64).min(1.0),
+            _ => 0.0,
+        };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title("Progress"))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio),
+            gauge_area,
+        );
+
+        for (area, title, samples, color) in [
+            (
+                upload_area,
+                "Upload kbps",
+                &self.upload_samples,
+                Color::Cyan,
+            ),
+            (
+                download_area,
+                "Download kbps",
+                &self.download_samples,
+                Color::Magenta,
+            ),
+        ] {
+            let visible = samples
+                .len()
+                .saturating_sub(area.width.saturating_sub(2) as usize);
+            let peak = samples.iter().max().copied().unwrap_or(0);
+            frame.render_widget(
+                Sparkline::default()
+                    .block(Block::bordered().title(format!("{} (peak {})", title, peak)))
+                    .style(Style::default().fg(color))
+                    .data(&samples[visible..]),
+                area,
+            );
+        }
+    }
+}
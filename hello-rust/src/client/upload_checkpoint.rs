@@ -0,0 +1,196 @@
+// Upload checkpoint files (--upload-checkpoint).
+// While uploading, the client keeps the stream ID, the bytes the server has
+// committed, and a fingerprint of the input in a small JSON file, rewritten
+// every --upload-checkpoint-interval bytes:
+//   {"streamId":"stream-1a2b3c4d","committed":8388608,
+//    "fingerprint":{"size":92124000,"modified":1760638000,"headSha256":"..."}}
+// A client restarted after a crash with the same checkpoint file and an
+// unchanged input resumes that upload instead of starting a new one; STARTED
+// then lists exactly what the server has. The file is removed once the
+// upload is stopped.
+
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::error::{ClientError, Result};
+use super::file_manager;
+use crate::logger;
+
+/// Leading bytes of the input hashed into its fingerprint.
+const HEAD_BYTES: usize = 1024 * 1024;
+
+/// Identity of an input file, cheap enough to take before every upload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputFingerprint {
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub modified: u64,
+    /// SHA-256 of the first MiB.
+    pub head_sha256: String,
+}
+
+impl InputFingerprint {
+    /// Take the fingerprint of the file at `path`.
+    pub async fn of(path: &str) -> Result<Self> {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| ClientError::storage(path, e))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let head_len = metadata.len().min(HEAD_BYTES as u64) as usize;
+        let head = file_manager::read_chunk(path, 0, head_len).await?;
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+            head_sha256: format!("{:x}", Sha256::digest(&head)),
+        })
+    }
+}
+
+/// Contents of a checkpoint file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadCheckpoint {
+    pub stream_id: String,
+    /// Bytes of the stream the server acknowledged, or that were sent when
+    /// the upload is not paced on ACKs.
+    pub committed: u64,
+    pub fingerprint: InputFingerprint,
+}
+
+/// Keeps the checkpoint file of one upload up to date.
+pub struct UploadCheckpointer {
+    path: String,
+    interval: u64,
+    fingerprint: InputFingerprint,
+    stream_id: Option<String>,
+    /// Committed bytes at which the file is rewritten next.
+    next: u64,
+}
+
+impl UploadCheckpointer {
+    /// Prepare checkpoints of the upload of `input` in the file at `path`,
+    /// rewritten every `interval` bytes. Returns the stream ID and committed
+    /// bytes of an interrupted upload of the same input recorded there, if
+    /// any.
+    pub async fn open(
+        path: &str,
+        input: &str,
+        interval: u64,
+    ) -> Result<(Self, Option<(String, u64)>)> {
+        let fingerprint = InputFingerprint::of(input).await?;
+        let interrupted = match tokio::fs::read(path).await {
+            Ok(data) => match serde_json::from_slice::<UploadCheckpoint>(&data) {
+                Ok(checkpoint) if checkpoint.fingerprint == fingerprint => {
+                    Some((checkpoint.stream_id, checkpoint.committed))
+                }
+                Ok(checkpoint) => {
+                    logger::log_warn(&format!(
+                        "Input changed since the upload to {} was checkpointed, starting over",
+                        checkpoint.stream_id
+                    ));
+                    None
+                }
+                Err(e) => {
+                    logger::log_warn(&format!("Ignoring unreadable checkpoint {}: {}", path, e));
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let checkpointer = Self {
+            path: path.to_string(),
+            interval: interval.max(1),
+            fingerprint,
+            stream_id: None,
+            next: 0,
+        };
+        Ok((checkpointer, interrupted))
+    }
+
+    /// Record that the upload to `stream_id` started with `committed` bytes
+    /// already on the server.
+    pub async fn start(&mut self, stream_id: &str, committed: u64) -> Result<()> {
+        self.stream_id = Some(stream_id.to_string());
+        self.next = 0;
+        self.record(committed).await
+    }
+
+    /// Record `committed` bytes on the server, rewriting the file once they
+    /// have grown by the interval since it was last written.
+    pub async fn record(&mut self, committed: u64) -> Result<()> {
+        let Some(stream_id) = self.stream_id.clone().filter(|_| committed >= self.next) else {
+            return Ok(());
+        };
+        let checkpoint = UploadCheckpoint {
+            stream_id,
+            committed,
+            fingerprint: self.fingerprint.clone(),
+        };
+        let json = serde_json::to_vec(&checkpoint)
+            .map_err(|e| ClientError::Protocol(format!("Failed to serialize checkpoint: {}", e)))?;
+        // Replace the file in one step, so a crash never leaves half of it
+        let temp = format!("{}.tmp", self.path);
+        file_manager::write_file(&temp, &json).await?;
+        tokio::fs::rename(&temp, &self.path)
+            .await
+            .map_err(|e| ClientError::storage(&self.path, e))?;
+        self.next = committed + self.interval;
+        Ok(())
+    }
+
+    /// Remove the file once the upload is stopped.
+    pub async fn finish(self) {
+        if let Err(e) = tokio::fs::remove_file(&self.path).await {
+            logger::log_warn(&format!("Failed to remove checkpoint {}: {}", self.path, e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::file_manager::SpoolDir;
+
+    #[tokio::test]
+    async fn checkpoint_is_found_again_for_the_same_input() {
+        let spool = SpoolDir::create("upload-checkpoint").unwrap();
+        let input = spool.file("input.bin");
+        let path = spool.file("upload.checkpoint");
+        std::fs::write(&input, vec![7u8; 3000]).unwrap();
+
+        let (mut checkpointer, interrupted) =
+            UploadCheckpointer::open(&path, &input, 1000).await.unwrap();
+        assert_eq!(interrupted, None);
+        checkpointer.start("stream-1", 0).await.unwrap();
+        checkpointer.record(1500).await.unwrap();
+        checkpointer.record(1999).await.unwrap();
+
+        let (checkpointer, interrupted) =
+            UploadCheckpointer::open(&path, &input, 1000).await.unwrap();
+        assert_eq!(interrupted, Some(("stream-1".to_string(), 1500)));
+        checkpointer.finish().await;
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_of_a_changed_input_is_ignored() {
+        let spool = SpoolDir::create("upload-checkpoint-changed").unwrap();
+        let input = spool.file("input.bin");
+        let path = spool.file("upload.checkpoint");
+        std::fs::write(&input, vec![7u8; 3000]).unwrap();
+
+        let (mut checkpointer, _) = UploadCheckpointer::open(&path, &input, 1000).await.unwrap();
+        checkpointer.start("stream-1", 0).await.unwrap();
+
+        std::fs::write(&input, vec![8u8; 3000]).unwrap();
+        let (_, interrupted) = UploadCheckpointer::open(&path, &input, 1000).await.unwrap();
+        assert_eq!(interrupted, None);
+    }
+}
@@ -1,66 +1,432 @@
+use std::time::Instant;
+
+use tokio_tungstenite::tungstenite::Bytes;
+
+use super::chunk_manager::{ChunkMode, ChunkPipe};
+use super::error::{ClientError, Result};
+use super::progress::{self, ProgressEvent, TransferDirection};
 use super::stream_id_generator;
+use super::upload_pacer::UploadPacer;
+use super::url_source::UrlSource;
+use super::verification_module::{TransferChecksum, TransferDigest};
 use super::{
     file_manager,
-    websocket_client::{ControlMessage, WebSocketClient},
+    websocket_client::{ControlMessage, WebSocketClient, MAX_REDIRECTS},
 };
 use crate::logger;
-use anyhow::Result;
+use crate::protocol::SessionState;
+
+/// Times a START refused because its generated stream ID is taken is
+/// repeated with a new ID.
+const MAX_ID_RETRIES: usize = 3;
+
+/// Where the bytes of an upload are read from.
+enum ChunkSource<'a> {
+    /// Local file of known size, read by offset.
+    File { path: &'a str, size: u64 },
+    /// Bytes produced concurrently by another task, of known size or not.
+    Pipe {
+        pipe: &'a mut ChunkPipe,
+        size: Option<u64>,
+    },
+}
+
+impl ChunkSource<'_> {
+    fn size(&self) -> Option<u64> {
+        match self {
+            ChunkSource::File { size, .. } => Some(*size),
+            ChunkSource::Pipe { size, .. } => *size,
+        }
+    }
+
+    /// Read the chunk at `offset` of at most `max_size` bytes, or `None` at
+    /// the end of the input.
+    async fn next_chunk(&mut self, offset: u64, max_size: usize) -> Result<Option<Vec<u8>>> {
+        match self {
+            ChunkSource::File { path, size } => {
+                if offset >= *size {
+                    return Ok(None);
+                }
+                let chunk_size = std::cmp::min(max_size as u64, *size - offset) as usize;
+                file_manager::read_chunk(path, offset, chunk_size)
+                    .await
+                    .map(Some)
+            }
+            ChunkSource::Pipe { pipe, .. } => pipe.next_chunk(max_size).await,
+        }
+    }
+}
+
+/// Outcome of an upload.
+pub struct UploadResult {
+    pub stream_id: String,
+    /// Stream offset the upload started at; nonzero when appending.
+    pub offset: u64,
+    /// Size and SHA-256 of the bytes that were sent; of the whole input for
+    /// a resumed upload.
+    pub sent: TransferChecksum,
+    /// Size and SHA-256 reported by the server in STOPPED, if any.
+    pub stored: Option<TransferChecksum>,
+}
 
 pub async fn upload(
     ws_client: &mut WebSocketClient,
     file_path: &str,
     file_size: u64,
-) -> Result<String> {
+) -> Result<UploadResult> {
+    let content_type = file_manager::guess_content_type(file_path);
+    upload_as(ws_client, file_path, file_size, content_type).await
+}
+
+/// Upload a file, declaring `content_type` instead of guessing it from the
+/// file extension. Raw PCM content types are sent in frame-aligned chunks.
+pub async fn upload_as(
+    ws_client: &mut WebSocketClient,
+    file_path: &str,
+    file_size: u64,
+    content_type: &str,
+) -> Result<UploadResult> {
     // Generate unique stream ID (using short UUID format like Java)
-    let stream_id = stream_id_generator::generate_short();
+    let stream_id = stream_id_generator::generate_in(ws_client.namespace());
+    logger::log_info(&format!("Generated stream ID: {}", stream_id));
+
+    let start_msg = ControlMessage::Start {
+        stream_id: stream_id.clone(),
+        content_type: Some(content_type.to_string()),
+        file_name: file_manager::get_file_name(file_path),
+        size: Some(file_size),
+        append: false,
+        resume: false,
+        tags: ws_client.upload_tags().clone(),
+        acks: ws_client.upload_pacing(),
+        exclusive: true,
+    };
+    let mode = ChunkMode::for_content_type(content_type);
+    let source = ChunkSource::File {
+        path: file_path,
+        size: file_size,
+    };
+    upload_stream(ws_client, start_msg, source, mode).await
+}
+
+/// Upload the body of an HTTP(S) response as it arrives, declaring
+/// `content_type`. The size is declared only when the response announced it.
+pub async fn upload_url(
+    ws_client: &mut WebSocketClient,
+    source: &mut UrlSource,
+    content_type: &str,
+) -> Result<UploadResult> {
+    let stream_id = stream_id_generator::generate_in(ws_client.namespace());
     logger::log_info(&format!("Generated stream ID: {}", stream_id));
 
+    let start_msg = ControlMessage::Start {
+        stream_id,
+        content_type: Some(content_type.to_string()),
+        file_name: source.file_name(),
+        size: source.size,
+        append: false,
+        resume: false,
+        tags: ws_client.upload_tags().clone(),
+        acks: ws_client.upload_pacing(),
+        exclusive: true,
+    };
+    let mode = ChunkMode::for_content_type(content_type);
+    let size = source.size;
+    let source = ChunkSource::Pipe {
+        pipe: &mut source.pipe,
+        size,
+    };
+    upload_stream(ws_client, start_msg, source, mode).await
+}
+
+/// Upload the bytes arriving through `pipe` as stream `stream_id`, declaring
+/// `content_type`, `file_name`, and `size` when known.
+pub async fn upload_piped(
+    ws_client: &mut WebSocketClient,
+    pipe: &mut ChunkPipe,
+    stream_id: &str,
+    size: Option<u64>,
+    content_type: &str,
+    file_name: Option<String>,
+) -> Result<UploadResult> {
+    let start_msg = ControlMessage::Start {
+        stream_id: stream_id.to_string(),
+        content_type: Some(content_type.to_string()),
+        file_name,
+        size,
+        append: false,
+        resume: false,
+        tags: ws_client.upload_tags().clone(),
+        acks: ws_client.upload_pacing(),
+        exclusive: false,
+    };
+    let mode = ChunkMode::for_content_type(content_type);
+    upload_stream(ws_client, start_msg, ChunkSource::Pipe { pipe, size }, mode).await
+}
+
+/// Append a file to the end of an existing finalized stream, e.g. the next
+/// episode of a chunked recording session.
+pub async fn append(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    file_path: &str,
+    file_size: u64,
+    mode: ChunkMode,
+) -> Result<UploadResult> {
+    logger::log_info(&format!("Appending to stream ID: {}", stream_id));
+
+    let start_msg = ControlMessage::Start {
+        stream_id: stream_id.to_string(),
+        content_type: None,
+        file_name: None,
+        size: None,
+        append: true,
+        resume: false,
+        tags: ws_client.upload_tags().clone(),
+        acks: ws_client.upload_pacing(),
+        exclusive: false,
+    };
+    let source = ChunkSource::File {
+        path: file_path,
+        size: file_size,
+    };
+    upload_stream(ws_client, start_msg, source, mode).await
+}
+
+/// Resume an interrupted upload of a file to `stream_id`, sending only the
+/// byte ranges the server does not have yet.
+pub async fn resume(
+    ws_client: &mut WebSocketClient,
+    stream_id: &str,
+    file_path: &str,
+    file_size: u64,
+    mode: ChunkMode,
+) -> Result<UploadResult> {
+    logger::log_info(&format!("Resuming upload to stream ID: {}", stream_id));
+
+    let start_msg = ControlMessage::Start {
+        stream_id: stream_id.to_string(),
+        content_type: None,
+        file_name: None,
+        size: Some(file_size),
+        append: false,
+        resume: true,
+        tags: ws_client.upload_tags().clone(),
+        acks: ws_client.upload_pacing(),
+        exclusive: false,
+    };
+    let source = ChunkSource::File {
+        path: file_path,
+        size: file_size,
+    };
+    upload_stream(ws_client, start_msg, source, mode).await
+}
+
+/// Halt the upload of `stream_id` on this connection and return the bytes
+/// the server has so far; data is refused until [`unpause`].
+pub async fn pause(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<u64> {
+    let pause_msg = ControlMessage::Pause {
+        stream_id: stream_id.to_string(),
+    };
+    ws_client.send_control_message(pause_msg).await?;
+    match ws_client.receive_reply("PAUSE", &["PAUSED"]).await? {
+        ControlMessage::Paused { offset, .. } => {
+            logger::log_info(&format!(
+                "Paused upload of {} at {} bytes",
+                stream_id, offset
+            ));
+            Ok(offset)
+        }
+        other => Err(ClientError::unexpected("PAUSE", other)),
+    }
+}
+
+/// Continue the upload of `stream_id` halted with [`pause`] and return the
+/// offset new data goes to.
+pub async fn unpause(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<u64> {
+    let resume_msg = ControlMessage::Resume {
+        stream_id: stream_id.to_string(),
+    };
+    ws_client.send_control_message(resume_msg).await?;
+    match ws_client.receive_reply("RESUME", &["RESUMED"]).await? {
+        ControlMessage::Resumed { offset, .. } => {
+            logger::log_info(&format!(
+                "Resumed upload of {} at {} bytes",
+                stream_id, offset
+            ));
+            Ok(offset)
+        }
+        other => Err(ClientError::unexpected("RESUME", other)),
+    }
+}
+
+async fn upload_stream(
+    ws_client: &mut WebSocketClient,
+    mut start_msg: ControlMessage,
+    mut source: ChunkSource<'_>,
+    mode: ChunkMode,
+) -> Result<UploadResult> {
+    let mut stream_id = start_msg.stream_id().unwrap_or_default().to_string();
+
     // Send START message
-    let start_msg = ControlMessage {
-        msg_type: "START".to_string(),
-        stream_id: Some(stream_id.clone()),
-        offset: None,
-        length: None,
-        message: None,
-    };
-    ws_client.send_control_message(start_msg).await?;
-    logger::log_info("Sent START message, waiting for STARTED response...");
-
-    // Wait for START_ACK
-    let response = ws_client.receive_control_message().await?;
+    let mut redirects = 0;
+    let mut id_retries = 0;
+    let response = loop {
+        ws_client.send_control_message(start_msg.clone()).await?;
+        logger::log_info("Sent START message, waiting for STARTED response...");
+
+        // Wait for START_ACK, following cluster redirects
+        let response = ws_client.receive_reply("START", &["STARTED"]).await?;
+        match &response {
+            ControlMessage::Moved { location, .. } if redirects < MAX_REDIRECTS => {
+                logger::log_info(&format!("Stream {} moved to {}", stream_id, location));
+                ws_client.follow_redirect(location).await?;
+                ws_client.report(ProgressEvent::Retry);
+                redirects += 1;
+            }
+            // A generated ID that is taken is replaced by a new one
+            ControlMessage::Error {
+                code: Some(code), ..
+            } if code == "STREAM_EXISTS" && id_retries < MAX_ID_RETRIES => {
+                let ControlMessage::Start {
+                    stream_id: id,
+                    exclusive: true,
+                    ..
+                } = &mut start_msg
+                else {
+                    break response;
+                };
+                stream_id = stream_id_generator::generate_in(ws_client.namespace());
+                logger::log_warn(&format!(
+                    "Stream ID {} is taken, retrying as {}",
+                    id, stream_id
+                ));
+                *id = stream_id.clone();
+                ws_client.report(ProgressEvent::Retry);
+                id_retries += 1;
+            }
+            _ => break response,
+        }
+    };
     logger::log_info(&format!(
         "Received response: msg_type='{}'",
-        response.msg_type
+        response.type_name()
     ));
-    if response.msg_type != "STARTED" {
-        anyhow::bail!("Unexpected response to START: {:?}", response);
+    let mut state = SessionState::default();
+    let (start_offset, received, chunk_limit, acks) = match response {
+        ControlMessage::Started {
+            offset,
+            received,
+            max_chunk_size,
+            acks,
+            ..
+        } => {
+            state.start(&stream_id)?;
+            (offset.unwrap_or(0), received, max_chunk_size, acks)
+        }
+        other => return Err(ClientError::unexpected("START", other)),
+    };
+
+    // A resumed upload sends only the ranges the server is missing, each
+    // after a SEEK; any other upload sends the whole input
+    let ranges = match (&received, source.size()) {
+        (Some(received), Some(size)) => received.missing(size),
+        _ => vec![(0, u64::MAX)],
+    };
+    let total = match &received {
+        Some(received) => {
+            let missing: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+            logger::log_info(&format!(
+                "Server has {} ranges of the stream, sending {} missing ranges ({} bytes)",
+                received.ranges().len(),
+                ranges.len(),
+                missing
+            ));
+            Some(missing)
+        }
+        None => source.size(),
+    };
+    // Checkpoint what the server has, including what it had before a resume
+    let committed = match (&received, source.size()) {
+        (Some(_), Some(size)) => size - total.unwrap_or(0),
+        _ => 0,
+    };
+    let mut checkpointer = ws_client.take_upload_checkpoint();
+    if let Some(checkpointer) = checkpointer.as_mut() {
+        checkpointer.start(&stream_id, committed).await?;
     }
+    ws_client.report(ProgressEvent::Started {
+        direction: TransferDirection::Upload,
+        stream_id: stream_id.clone(),
+        total,
+    });
 
-    // Upload file in chunks
-    let mut offset = 0u64;
+    // Upload file in chunks, no larger than the server accepts
+    let chunk_size = match chunk_limit {
+        Some(limit) if limit < file_manager::CHUNK_SIZE => {
+            logger::log_info(&format!("Server limits chunks to {} bytes", limit));
+            limit
+        }
+        _ => file_manager::CHUNK_SIZE,
+    };
+    let max_chunk_size = mode.chunk_size(chunk_size);
+    // Pace on the server's ACKs when it agreed to send them
+    let mut pacer = acks.then(|| UploadPacer::new(max_chunk_size));
     let mut bytes_sent = 0u64;
     let mut last_progress = 0;
+    let mut digest = TransferDigest::new();
 
-    while offset < file_size {
-        let chunk_size =
-            std::cmp::min(file_manager::CHUNK_SIZE as u64, file_size - offset) as usize;
-        let chunk = file_manager::read_chunk(file_path, offset, chunk_size)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to read file chunk: {}", e))?;
-
-        ws_client.send_binary(chunk).await?;
+    for (start, end) in ranges {
+        if received.is_some() {
+            let seek_msg = ControlMessage::Seek {
+                stream_id: stream_id.clone(),
+                offset: start,
+            };
+            ws_client.send_control_message(seek_msg).await?;
+        }
+        let mut offset = start;
+        while offset < end {
+            let max_size = std::cmp::min(max_chunk_size as u64, end - offset) as usize;
+            let Some(chunk) = source.next_chunk(offset, max_size).await? else {
+                break;
+            };
+            let chunk_size = chunk.len();
+            state.data(chunk_size)?;
+            let chunk = Bytes::from(chunk);
+            digest.update(chunk.clone()).await;
+            if let Some(pacer) = pacer.as_mut() {
+                while !pacer.can_send(chunk_size) {
+                    let bytes = receive_ack(ws_client, &stream_id).await?;
+                    pacer.on_ack(bytes, Instant::now());
+                }
+                pacer.on_send(chunk_size, Instant::now());
+            }
+            let sent_at = Instant::now();
+            ws_client.send_binary(chunk).await?;
+            ws_client.report(ProgressEvent::Chunk {
+                bytes: chunk_size as u64,
+                latency: sent_at.elapsed(),
+            });
 
-        offset += chunk_size as u64;
-        bytes_sent += chunk_size as u64;
+            offset += chunk_size as u64;
+            bytes_sent += chunk_size as u64;
+            if let Some(checkpointer) = checkpointer.as_mut() {
+                let written = pacer.as_ref().map_or(bytes_sent, UploadPacer::acked);
+                checkpointer.record(committed + written).await?;
+            }
 
-        // Report progress
-        let progress = (bytes_sent * 100 / file_size) as usize;
-        if progress >= last_progress + 25 && progress <= 100 {
-            logger::log_info(&format!(
-                "Upload progress: {}/{} bytes ({}%)",
-                bytes_sent, file_size, progress
-            ));
-            last_progress = progress;
+            // Report progress; without a known size only the total is reported
+            let Some(total) = total else {
+                continue;
+            };
+            let progress = progress::percent(bytes_sent, total) as usize;
+            if progress >= last_progress + 25 && progress <= 100 {
+                logger::log_info(&format!(
+                    "Upload progress: {}/{} bytes ({}%)",
+                    bytes_sent, total, progress
+                ));
+                last_progress = progress;
+            }
         }
     }
 
@@ -68,25 +434,75 @@ pub async fn upload(
     if last_progress < 100 {
         logger::log_info(&format!(
             "Upload progress: {}/{} bytes (100%)",
-            file_size, file_size
+            bytes_sent, bytes_sent
+        ));
+    }
+
+    // Wait until everything sent is written, so STOPPED comes next
+    if let Some(pacer) = pacer.as_mut() {
+        while pacer.in_flight() > 0 {
+            let bytes = receive_ack(ws_client, &stream_id).await?;
+            pacer.on_ack(bytes, Instant::now());
+        }
+        logger::log_debug(&format!(
+            "Upload paced with a final window of {} bytes",
+            pacer.window()
         ));
     }
 
     // Send STOP message
-    let stop_msg = ControlMessage {
-        msg_type: "STOP".to_string(),
-        stream_id: Some(stream_id.clone()),
-        offset: None,
-        length: None,
-        message: None,
+    let stop_msg = ControlMessage::Stop {
+        stream_id: stream_id.clone(),
     };
     ws_client.send_control_message(stop_msg).await?;
 
     // Wait for STOP_ACK
-    let response = ws_client.receive_control_message().await?;
-    if response.msg_type != "STOPPED" {
-        anyhow::bail!("Unexpected response to STOP: {:?}", response);
+    let response = ws_client.receive_reply("STOP", &["STOPPED"]).await?;
+    let stored = match response {
+        // STOPPED must acknowledge the stream that was started
+        ControlMessage::Stopped {
+            stream_id: stopped,
+            size,
+            checksum,
+            ..
+        } => {
+            state.stop(&stopped)?;
+            size.zip(checksum).map(|(size, checksum)| TransferChecksum {
+                size,
+                checksum,
+                blocks: None,
+            })
+        }
+        other => return Err(ClientError::unexpected("STOP", other)),
+    };
+
+    ws_client.report(ProgressEvent::Finished);
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.finish().await;
     }
 
-    Ok(stream_id)
+    // The server already had part of a resumed upload, so hash all of it
+    let sent = match (&received, &source) {
+        (Some(_), ChunkSource::File { path, .. }) => file_manager::compute_checksum(path).await?,
+        _ => digest.finalize(),
+    };
+
+    Ok(UploadResult {
+        stream_id,
+        offset: start_offset,
+        sent,
+        stored,
+    })
+}
+
+/// Wait for the ACK of data frames of `stream_id` and return the bytes
+/// written so far; a write failure arrives as an ERROR instead.
+async fn receive_ack(ws_client: &mut WebSocketClient, stream_id: &str) -> Result<u64> {
+    match ws_client.receive_control_message().await? {
+        ControlMessage::Ack {
+            stream_id: acked,
+            bytes,
+        } if acked == stream_id => Ok(bytes),
+        other => Err(ClientError::unexpected("data", other)),
+    }
 }
@@ -0,0 +1,172 @@
+// Upload pacing on the server's ACKs.
+// An upload whose STARTED echoes `acks` gets an ACK with the bytes written so
+// far after every data frame. The pacer keeps at most a window of
+// unacknowledged bytes in flight and resizes it AIMD-style: it grows by about
+// a chunk per window acknowledged while ACKs come back about as fast as the
+// fastest seen, and halves, at most once per window, when they take more
+// than twice as long, e.g. because the server's disk fell behind. Chunks the
+// server cannot write yet then wait in the input instead of piling up in the
+// socket buffers.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Chunks in flight when an upload starts.
+const INITIAL_WINDOW_CHUNKS: u64 = 4;
+
+/// Most chunks ever in flight.
+const MAX_WINDOW_CHUNKS: u64 = 64;
+
+/// ACK delay tolerated over twice the fastest one before the window
+/// shrinks, so jitter on fast links does not shrink it.
+const DELAY_SLACK: Duration = Duration::from_millis(5);
+
+/// Window of unacknowledged bytes of one upload.
+pub struct UploadPacer {
+    chunk_size: u64,
+    window: u64,
+    sent: u64,
+    acked: u64,
+    /// Bytes sent up to the end of each unacknowledged frame, with its send
+    /// time.
+    frames: VecDeque<(u64, Instant)>,
+    /// Fastest ACK seen.
+    base_delay: Option<Duration>,
+    /// Bytes sent when the window last shrank; it shrinks again only once
+    /// they are acknowledged.
+    recovery: u64,
+}
+
+impl UploadPacer {
+    /// Create a pacer for frames of at most `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1) as u64;
+        Self {
+            chunk_size,
+            window: chunk_size * INITIAL_WINDOW_CHUNKS,
+            sent: 0,
+            acked: 0,
+            frames: VecDeque::new(),
+            base_delay: None,
+            recovery: 0,
+        }
+    }
+
+    /// Get the bytes allowed in flight.
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// Get the bytes acknowledged so far.
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// Get the bytes sent and not acknowledged yet.
+    pub fn in_flight(&self) -> u64 {
+        self.sent - self.acked
+    }
+
+    /// Check whether a frame of `len` bytes fits in the window; one always
+    /// does when nothing is in flight.
+    pub fn can_send(&self, len: usize) -> bool {
+        self.in_flight() == 0 || self.in_flight() + len as u64 <= self.window
+    }
+
+    /// Record a frame of `len` bytes sent at `at`.
+    pub fn on_send(&mut self, len: usize, at: Instant) {
+        self.sent += len as u64;
+        self.frames.push_back((self.sent, at));
+    }
+
+    /// Record an ACK of `bytes` in total received at `at`, and resize the
+    /// window on how long the newest frame it covers took.
+    pub fn on_ack(&mut self, bytes: u64, at: Instant) {
+        let mut sent_at = None;
+        while let Some(&(end, time)) = self.frames.front() {
+            if end > bytes {
+                break;
+            }
+            sent_at = Some(time);
+            self.frames.pop_front();
+        }
+        self.acked = self.acked.max(bytes.min(self.sent));
+        let Some(sent_at) = sent_at else {
+            return;
+        };
+
+        let delay = at.saturating_duration_since(sent_at);
+        let base = self.base_delay.map_or(delay, |base| base.min(delay));
+        self.base_delay = Some(base);
+        if delay > base * 2 + DELAY_SLACK {
+            if self.acked > self.recovery {
+                self.window = (self.window / 2).max(self.chunk_size);
+                self.recovery = self.sent;
+            }
+        } else {
+            let step = (self.chunk_size * self.chunk_size / self.window).max(1);
+            self.window = (self.window + step).min(self.chunk_size * MAX_WINDOW_CHUNKS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a window's worth of frames and acknowledge each `delay` later.
+    fn round(pacer: &mut UploadPacer, start: Instant, delay: Duration) {
+        let mut frames = Vec::new();
+        while pacer.can_send(1000) {
+            pacer.on_send(1000, start);
+            frames.push(pacer.sent);
+        }
+        for bytes in frames {
+            pacer.on_ack(bytes, start + delay);
+        }
+    }
+
+    #[test]
+    fn window_bounds_bytes_in_flight() {
+        let mut pacer = UploadPacer::new(1000);
+        let now = Instant::now();
+        for _ in 0..4 {
+            assert!(pacer.can_send(1000));
+            pacer.on_send(1000, now);
+        }
+        assert!(!pacer.can_send(1000));
+        pacer.on_ack(1000, now);
+        assert_eq!(pacer.in_flight(), 3000);
+        assert!(pacer.can_send(1000));
+    }
+
+    #[test]
+    fn window_grows_while_acks_are_fast() {
+        let mut pacer = UploadPacer::new(1000);
+        let start = Instant::now();
+        round(&mut pacer, start, Duration::from_millis(1));
+        assert!(pacer.window() > 4000);
+        for _ in 0..200 {
+            round(&mut pacer, start, Duration::from_millis(1));
+        }
+        assert_eq!(pacer.window(), 64000);
+        assert_eq!(pacer.in_flight(), 0);
+    }
+
+    #[test]
+    fn window_halves_once_per_window_when_acks_slow_down() {
+        let mut pacer = UploadPacer::new(1000);
+        let start = Instant::now();
+        round(&mut pacer, start, Duration::from_millis(1));
+        let window = pacer.window();
+
+        round(&mut pacer, start, Duration::from_millis(50));
+        assert_eq!(pacer.window(), window / 2);
+        round(&mut pacer, start, Duration::from_millis(50));
+        assert_eq!(pacer.window(), window / 2 / 2);
+        for _ in 0..4 {
+            round(&mut pacer, start, Duration::from_millis(50));
+        }
+        assert_eq!(pacer.window(), 1000);
+    }
+}
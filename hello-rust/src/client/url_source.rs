@@ -0,0 +1,101 @@
+// Remote upload input (--input-url).
+// The file is fetched over HTTP(S) on a blocking thread and handed to the
+// upload through a bounded channel, so only a few chunks are held in memory
+// however large the file is. Its size comes from Content-Length when the
+// server sends one. s3:// URLs are fetched through presigned HTTPS URLs.
+
+use std::io::Read;
+
+use super::chunk_manager::ChunkPipe;
+use super::error::{ClientError, Result};
+use super::file_manager;
+
+/// Pieces of the body read ahead of the upload.
+const READ_AHEAD: usize = 8;
+
+/// Body of an HTTP(S) response, read as upload chunks.
+pub struct UrlSource {
+    url: String,
+    /// Size announced by Content-Length, if any.
+    pub size: Option<u64>,
+    /// Content type of the response, unless it is generic binary.
+    pub content_type: Option<String>,
+    /// Body of the response, as it arrives.
+    pub pipe: ChunkPipe,
+}
+
+impl UrlSource {
+    /// Request `url` and start reading its body in the background.
+    pub async fn open(url: &str) -> Result<Self> {
+        let request_url = request_url(url)?;
+        let response = tokio::task::spawn_blocking(move || {
+            // Ask for the plain body so Content-Length is the size uploaded
+            ureq::get(&request_url)
+                .header("Accept-Encoding", "identity")
+                .call()
+        })
+        .await
+        .map_err(|e| ClientError::Fetch(format!("{}: {}", url, e)))?
+        .map_err(|e| ClientError::Fetch(format!("{}: {}", url, e)))?;
+
+        let body = response.into_body();
+        let size = body.content_length();
+        let content_type = body
+            .mime_type()
+            .filter(|mime| *mime != "application/octet-stream")
+            .map(str::to_string);
+
+        let (sender, pipe) = ChunkPipe::new(READ_AHEAD);
+        let body_url = url.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut reader = body.into_reader();
+            loop {
+                let mut buffer = vec![0u8; file_manager::CHUNK_SIZE];
+                let piece = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.truncate(n);
+                        Ok(buffer)
+                    }
+                    Err(e) => Err(ClientError::Fetch(format!("{}: {}", body_url, e))),
+                };
+                let failed = piece.is_err();
+                // The upload stopped listening
+                if sender.blocking_send(piece).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            url: url.to_string(),
+            size,
+            content_type,
+            pipe,
+        })
+    }
+
+    /// Last segment of the URL path, used as the stream's file name.
+    pub fn file_name(&self) -> Option<String> {
+        let path = self.url.split(['?', '#']).next().unwrap_or_default();
+        let (_, path) = path.split_once("://").unwrap_or(("", path));
+        path.split_once('/')
+            .and_then(|(_, path)| path.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+    }
+}
+
+/// Get the HTTP(S) URL to request for an input URL.
+fn request_url(url: &str) -> Result<String> {
+    if !url.starts_with("s3://") {
+        return Ok(url.to_string());
+    }
+    #[cfg(feature = "s3")]
+    return super::s3::request_url(url);
+    #[cfg(not(feature = "s3"))]
+    Err(ClientError::Fetch(format!(
+        "{}: s3:// URLs need the s3 feature",
+        url
+    )))
+}
@@ -1,6 +1,18 @@
-use super::file_manager;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_tungstenite::tungstenite::Bytes;
+
+use super::error::{ClientError, Result};
+use super::file_manager::read_full;
+use super::fingerprint::{self, Fingerprint};
 use crate::logger;
-use anyhow::Result;
+use crate::protocol::PcmFormat;
 
 pub struct VerificationResult {
     pub passed: bool,
@@ -8,41 +20,492 @@ pub struct VerificationResult {
     pub downloaded_size: u64,
     pub original_checksum: String,
     pub downloaded_checksum: String,
+    /// Fingerprint similarity, when the audio was compared instead of bytes.
+    pub similarity: Option<f64>,
+}
+
+/// Chunks queued for the stream hash before `update` waits for it.
+const HASH_QUEUE_DEPTH: usize = 16;
+
+/// Bytes per block hash, the block size of the server's block index.
+pub const HASH_BLOCK_SIZE: usize = 64 * 1024;
+
+/// SHA-256 digest of one block.
+pub type BlockHash = [u8; 32];
+
+/// SHA-256 and byte count computed on the fly while a transfer runs.
+///
+/// Chunks are hashed on the rayon thread pool shared by every digest, so
+/// hashing a chunk overlaps reading or transferring the next one instead of
+/// adding to the transfer time. Every 64KB block of the stream is hashed on
+/// its own, in parallel, while the SHA-256 of the whole stream is fed the
+/// chunks in order by one pool job at a time. A bounded queue holds the
+/// transfer back when hashing falls behind, without blocking the runtime
+/// thread. The chunks are shared with the transfer, not copied, except for
+/// blocks that straddle two chunks.
+pub struct TransferDigest {
+    stream: Arc<StreamHash>,
+    permits: Arc<Semaphore>,
+    /// Hashes of the blocks by index, sent by their pool jobs.
+    block_sender: Sender<(usize, BlockHash)>,
+    block_hashes: Receiver<(usize, BlockHash)>,
+    /// Start of the block the next chunk continues.
+    partial: Vec<u8>,
+    block_count: usize,
+    size: u64,
+}
+
+/// SHA-256 of the whole stream, fed by one pool job at a time.
+struct StreamHash {
+    state: Mutex<StreamState>,
+    idle: Condvar,
+}
+
+struct StreamState {
+    /// Chunks not hashed yet, each holding its place in the queue.
+    queue: VecDeque<(Bytes, OwnedSemaphorePermit)>,
+    /// The hasher, unless a pool job is feeding it.
+    hasher: Option<Sha256>,
+}
+
+impl StreamHash {
+    /// Queue a chunk, starting a pool job unless one is running.
+    fn push(self: &Arc<Self>, chunk: Bytes, permit: OwnedSemaphorePermit) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back((chunk, permit));
+        if let Some(hasher) = state.hasher.take() {
+            let stream = self.clone();
+            rayon::spawn(move || stream.drain(hasher));
+        }
+    }
+
+    // Hash the queued chunks in order until the queue runs empty
+    fn drain(&self, mut hasher: Sha256) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let Some((chunk, _permit)) = state.queue.pop_front() else {
+                state.hasher = Some(hasher);
+                self.idle.notify_all();
+                return;
+            };
+            drop(state);
+            hasher.update(&chunk);
+        }
+    }
+
+    /// Wait for the queued chunks and return the checksum as lowercase hex.
+    fn finish(&self) -> String {
+        let mut state = self
+            .idle
+            .wait_while(self.state.lock().unwrap(), |state| state.hasher.is_none())
+            .unwrap();
+        let hasher = state.hasher.take().unwrap_or_default();
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+impl Default for TransferDigest {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub async fn verify(original_path: &str, downloaded_path: &str) -> Result<VerificationResult> {
-    logger::log_info(&format!("Original file: {}", original_path));
-    logger::log_info(&format!("Downloaded file: {}", downloaded_path));
+impl TransferDigest {
+    pub fn new() -> Self {
+        let (block_sender, block_hashes) = std::sync::mpsc::channel();
+        Self {
+            stream: Arc::new(StreamHash {
+                state: Mutex::new(StreamState {
+                    queue: VecDeque::new(),
+                    hasher: Some(Sha256::new()),
+                }),
+                idle: Condvar::new(),
+            }),
+            permits: Arc::new(Semaphore::new(HASH_QUEUE_DEPTH)),
+            block_sender,
+            block_hashes,
+            partial: Vec::new(),
+            block_count: 0,
+            size: 0,
+        }
+    }
 
-    // Get file sizes
-    let original_size = file_manager::get_file_size(original_path)?;
-    let downloaded_size = file_manager::get_file_size(downloaded_path)?;
+    /// Feed the next chunk of transferred bytes, waiting for room in the
+    /// queue when hashing falls behind.
+    pub async fn update(&mut self, chunk: impl Into<Bytes>) {
+        let permit = self.permits.clone().acquire_owned().await;
+        self.push(chunk.into(), permit.expect("hash queue is never closed"));
+    }
 
-    logger::log_info(&format!("Original size: {} bytes", original_size));
-    logger::log_info(&format!("Downloaded size: {} bytes", downloaded_size));
+    /// Feed the next chunk from a thread outside the runtime, e.g. under
+    /// `spawn_blocking`; panics when called from async code.
+    pub fn blocking_update(&mut self, chunk: impl Into<Bytes>) {
+        let permit =
+            tokio::runtime::Handle::current().block_on(self.permits.clone().acquire_owned());
+        self.push(chunk.into(), permit.expect("hash queue is never closed"));
+    }
 
-    // Compute checksums
-    let original_checksum = file_manager::compute_sha256(original_path).await?;
-    let downloaded_checksum = file_manager::compute_sha256(downloaded_path).await?;
+    /// Number of bytes fed so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 
+    /// Finish hashing and return the checksum as lowercase hex, with the
+    /// block hashes.
+    pub fn finalize(mut self) -> TransferChecksum {
+        if !self.partial.is_empty() {
+            let block = Bytes::from(std::mem::take(&mut self.partial));
+            self.hash_block(block);
+        }
+        let checksum = self.stream.finish();
+
+        // The channel closes once every block job sent its hash
+        drop(self.block_sender);
+        let mut blocks = vec![[0u8; 32]; self.block_count];
+        for (index, hash) in self.block_hashes {
+            blocks[index] = hash;
+        }
+        TransferChecksum {
+            size: self.size,
+            checksum,
+            blocks: Some(blocks),
+        }
+    }
+
+    fn push(&mut self, mut chunk: Bytes, permit: OwnedSemaphorePermit) {
+        self.size += chunk.len() as u64;
+        self.stream.push(chunk.clone(), permit);
+
+        // Complete the block the previous chunk started
+        if !self.partial.is_empty() {
+            let missing = HASH_BLOCK_SIZE - self.partial.len();
+            let head = chunk.split_to(missing.min(chunk.len()));
+            self.partial.extend_from_slice(&head);
+            if self.partial.len() < HASH_BLOCK_SIZE {
+                return;
+            }
+            let block = Bytes::from(std::mem::take(&mut self.partial));
+            self.hash_block(block);
+        }
+        while chunk.len() >= HASH_BLOCK_SIZE {
+            let block = chunk.split_to(HASH_BLOCK_SIZE);
+            self.hash_block(block);
+        }
+        self.partial.extend_from_slice(&chunk);
+    }
+
+    fn hash_block(&mut self, block: Bytes) {
+        let (index, hashes) = (self.block_count, self.block_sender.clone());
+        self.block_count += 1;
+        rayon::spawn(move || {
+            let _ = hashes.send((index, Sha256::digest(&block).into()));
+        });
+    }
+}
+
+/// Final byte count and SHA-256 of one transfer direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferChecksum {
+    pub size: u64,
+    pub checksum: String,
+    /// SHA-256 of every 64KB block, when the bytes were hashed here rather
+    /// than reported by the server.
+    pub blocks: Option<Vec<BlockHash>>,
+}
+
+impl TransferChecksum {
+    /// Offset of the first block where two transfers differ, when both were
+    /// hashed block by block; a transfer ending early differs where it ends.
+    pub fn first_difference(&self, other: &TransferChecksum) -> Option<u64> {
+        let (ours, theirs) = (self.blocks.as_ref()?, other.blocks.as_ref()?);
+        let block = ours
+            .iter()
+            .zip(theirs)
+            .position(|(ours, theirs)| ours != theirs)
+            .or_else(|| (self.size != other.size).then(|| ours.len().min(theirs.len())))?;
+        Some(block as u64 * HASH_BLOCK_SIZE as u64)
+    }
+}
+
+/// Compare the checksums computed during upload and download.
+pub fn verify(uploaded: &TransferChecksum, downloaded: &TransferChecksum) -> VerificationResult {
+    let result = compare(uploaded, downloaded, "Downloaded");
+    if let Some(offset) = uploaded
+        .first_difference(downloaded)
+        .filter(|_| !result.passed)
+    {
+        logger::log_error(&format!(
+            "Transfers first differ in the block at offset {} (0x{:x})",
+            offset, offset
+        ));
+    }
+    result
+}
+
+/// Compare the checksum computed during upload with the one the server
+/// reported for the stored stream, without downloading it back.
+pub fn verify_remote(uploaded: &TransferChecksum, stored: &TransferChecksum) -> VerificationResult {
+    compare(uploaded, stored, "Server")
+}
+
+/// Compare the audio of the original and downloaded files by fingerprint,
+/// so that they match when they sound the same even if their bytes differ.
+/// Raw PCM files are read in `raw_format`.
+pub async fn verify_fingerprint(
+    original_path: &str,
+    downloaded_path: &str,
+    raw_format: Option<PcmFormat>,
+    uploaded: &TransferChecksum,
+    downloaded: &TransferChecksum,
+) -> Result<VerificationResult> {
+    let original = Fingerprint::from_file(original_path, raw_format).await?;
+    let received = Fingerprint::from_file(downloaded_path, raw_format).await?;
+    let similarity = original.similarity(&received);
+    logger::log_info(&format!("Original fingerprint: {} frames", original.len()));
     logger::log_info(&format!(
-        "Original checksum (SHA-256): {}",
-        original_checksum
+        "Downloaded fingerprint: {} frames",
+        received.len()
     ));
     logger::log_info(&format!(
-        "Downloaded checksum (SHA-256): {}",
-        downloaded_checksum
+        "Fingerprint similarity: {:.1}% (match at {:.1}%)",
+        similarity * 100.0,
+        fingerprint::MATCH_THRESHOLD * 100.0
     ));
 
-    // Compare
-    let passed = original_size == downloaded_size
-        && original_checksum.to_lowercase() == downloaded_checksum.to_lowercase();
+    Ok(VerificationResult {
+        passed: similarity >= fingerprint::MATCH_THRESHOLD,
+        original_size: uploaded.size,
+        downloaded_size: downloaded.size,
+        original_checksum: uploaded.checksum.clone(),
+        downloaded_checksum: downloaded.checksum.clone(),
+        similarity: Some(similarity),
+    })
+}
+
+/// Compare the original and downloaded files byte by byte.
+pub async fn verify_bytes(
+    original_path: &str,
+    downloaded_path: &str,
+    uploaded: &TransferChecksum,
+    downloaded: &TransferChecksum,
+) -> Result<VerificationResult> {
+    let difference = locate_difference(original_path, downloaded_path).await?;
+    if difference.is_none() {
+        logger::log_info(&format!("Files are identical ({} bytes)", downloaded.size));
+    }
 
     Ok(VerificationResult {
-        passed,
-        original_size,
-        downloaded_size,
-        original_checksum,
-        downloaded_checksum,
+        passed: difference.is_none(),
+        original_size: uploaded.size,
+        downloaded_size: downloaded.size,
+        original_checksum: uploaded.checksum.clone(),
+        downloaded_checksum: downloaded.checksum.clone(),
+        similarity: None,
     })
 }
+
+/// Bytes of context shown before and after the first difference.
+const DIFF_CONTEXT: u64 = 32;
+/// Bytes per hexdump line.
+const DIFF_LINE: u64 = 16;
+// Read size when comparing files
+const COMPARE_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// First difference between two files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteDifference {
+    pub offset: u64,
+    /// Hexdump of both files around the offset.
+    pub context: Vec<String>,
+}
+
+/// Stream both files and log where they first differ, with a hexdump of
+/// both around that offset; returns the difference, or `None` when the
+/// files are identical. A file ending early differs where it ends.
+pub async fn locate_difference(
+    original_path: &str,
+    downloaded_path: &str,
+) -> Result<Option<ByteDifference>> {
+    let (original, downloaded) = (original_path.to_string(), downloaded_path.to_string());
+    let difference = tokio::task::spawn_blocking(move || first_difference(&original, &downloaded))
+        .await
+        .map_err(|e| ClientError::storage(downloaded_path, std::io::Error::other(e)))??;
+
+    if let Some(difference) = &difference {
+        logger::log_error(&format!(
+            "Files first differ at offset {} (0x{:x})",
+            difference.offset, difference.offset
+        ));
+        for line in &difference.context {
+            logger::log_error(line);
+        }
+    }
+    Ok(difference)
+}
+
+fn first_difference(original_path: &str, downloaded_path: &str) -> Result<Option<ByteDifference>> {
+    let open = |path: &str| File::open(path).map_err(|e| ClientError::storage(path, e));
+    let (mut original, mut downloaded) = (open(original_path)?, open(downloaded_path)?);
+    let mut left = vec![0u8; COMPARE_BLOCK_SIZE];
+    let mut right = vec![0u8; COMPARE_BLOCK_SIZE];
+
+    let mut offset = 0u64;
+    let found = loop {
+        let read_left = read_full(&mut original, &mut left)
+            .map_err(|e| ClientError::storage(original_path, e))?;
+        let read_right = read_full(&mut downloaded, &mut right)
+            .map_err(|e| ClientError::storage(downloaded_path, e))?;
+        let common = read_left.min(read_right);
+        if let Some(index) = (0..common).find(|&i| left[i] != right[i]) {
+            break Some(offset + index as u64);
+        }
+        if read_left != read_right {
+            break Some(offset + common as u64);
+        }
+        if read_left == 0 {
+            break None;
+        }
+        offset += common as u64;
+    };
+    let Some(offset) = found else {
+        return Ok(None);
+    };
+
+    let start = offset.saturating_sub(DIFF_CONTEXT) / DIFF_LINE * DIFF_LINE;
+    let end = (offset + DIFF_CONTEXT).div_ceil(DIFF_LINE) * DIFF_LINE;
+    let len = (end - start) as usize;
+    let window = |file: &mut File| -> std::io::Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut buffer = vec![0u8; len];
+        let read = read_full(file, &mut buffer)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    };
+    let left = window(&mut original).map_err(|e| ClientError::storage(original_path, e))?;
+    let right = window(&mut downloaded).map_err(|e| ClientError::storage(downloaded_path, e))?;
+    Ok(Some(ByteDifference {
+        offset,
+        context: hexdump(start, &left, &right),
+    }))
+}
+
+/// Format both windows side by side, 16 bytes per line starting at `start`;
+/// lines holding a difference are marked with `*`, and bytes past the end of
+/// a file show as `--`.
+fn hexdump(start: u64, left: &[u8], right: &[u8]) -> Vec<String> {
+    let hex = |bytes: &[u8], from: usize| {
+        (from..from + DIFF_LINE as usize)
+            .map(|i| {
+                bytes
+                    .get(i)
+                    .map_or("--".to_string(), |b| format!("{:02x}", b))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut lines = vec![format!(
+        "  {:<10}{:<49}{}",
+        "offset", "original", "downloaded"
+    )];
+    let mut from = 0;
+    while from < left.len().max(right.len()) {
+        let to = from + DIFF_LINE as usize;
+        let differs = (from..to).any(|i| left.get(i) != right.get(i));
+        lines.push(format!(
+            "{} {:08x}  {}  {}",
+            if differs { '*' } else { ' ' },
+            start + from as u64,
+            hex(left, from),
+            hex(right, from)
+        ));
+        from = to;
+    }
+    lines
+}
+
+fn compare(
+    original: &TransferChecksum,
+    other: &TransferChecksum,
+    label: &str,
+) -> VerificationResult {
+    logger::log_info(&format!("Original size: {} bytes", original.size));
+    logger::log_info(&format!("{} size: {} bytes", label, other.size));
+    logger::log_info(&format!(
+        "Original checksum (SHA-256): {}",
+        original.checksum
+    ));
+    logger::log_info(&format!("{} checksum (SHA-256): {}", label, other.checksum));
+
+    // Compare
+    let passed =
+        original.size == other.size && original.checksum.eq_ignore_ascii_case(&other.checksum);
+
+    VerificationResult {
+        passed,
+        original_size: original.size,
+        downloaded_size: other.size,
+        original_checksum: original.checksum.clone(),
+        downloaded_checksum: other.checksum.clone(),
+        similarity: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn digest_of(chunks: &[&[u8]]) -> TransferChecksum {
+        let mut digest = TransferDigest::new();
+        for chunk in chunks {
+            digest.update(chunk.to_vec()).await;
+        }
+        digest.finalize()
+    }
+
+    #[tokio::test]
+    async fn chunks_hash_like_the_whole_stream() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let (head, tail) = data.split_at(70_000);
+        let (middle, tail) = tail.split_at(1_000);
+        let checksum = digest_of(&[head, middle, b"", tail]).await;
+
+        assert_eq!(checksum.size, data.len() as u64);
+        assert_eq!(checksum.checksum, format!("{:x}", Sha256::digest(&data)));
+        let blocks: Vec<BlockHash> = data
+            .chunks(HASH_BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+        assert_eq!(checksum.blocks, Some(blocks));
+    }
+
+    #[tokio::test]
+    async fn first_difference_is_the_block_that_differs() {
+        let data = vec![7u8; 3 * HASH_BLOCK_SIZE];
+        let mut changed = data.clone();
+        changed[2 * HASH_BLOCK_SIZE + 5] = 8;
+        let original = digest_of(&[&data]).await;
+
+        assert_eq!(original.first_difference(&digest_of(&[&data]).await), None);
+        assert_eq!(
+            original.first_difference(&digest_of(&[&changed]).await),
+            Some(2 * HASH_BLOCK_SIZE as u64)
+        );
+        let truncated = digest_of(&[&data[..HASH_BLOCK_SIZE]]).await;
+        assert_eq!(
+            original.first_difference(&truncated),
+            Some(HASH_BLOCK_SIZE as u64)
+        );
+        let reported = TransferChecksum {
+            blocks: None,
+            ..original.clone()
+        };
+        assert_eq!(original.first_difference(&reported), None);
+    }
+
+    #[tokio::test]
+    async fn empty_streams_hash_to_the_empty_digest() {
+        let checksum = digest_of(&[]).await;
+        assert_eq!(checksum.checksum, format!("{:x}", Sha256::digest(b"")));
+        assert_eq!(checksum.blocks, Some(Vec::new()));
+    }
+}
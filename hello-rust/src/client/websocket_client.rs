@@ -1,83 +1,427 @@
-use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
-use tungstenite::{Bytes, Utf8Bytes};
-
-type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ControlMessage {
-    #[serde(rename = "type")]
-    pub msg_type: String,
-    #[serde(rename = "streamId")]
-    pub stream_id: Option<String>,
-    #[serde(rename = "offset")]
-    pub offset: Option<u64>,
-    #[serde(rename = "length")]
-    pub length: Option<usize>,
-    #[serde(rename = "message")]
-    pub message: Option<String>,
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::{Error as WsError, ProtocolError, SubProtocolError};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{Bytes, Message, Utf8Bytes};
+
+use super::error::{ClientError, Result};
+use super::network_sim::{NetworkProfile, ShapedTransport};
+use super::progress::{ProgressEvent, ProgressSender};
+use super::tcp_transport::TcpTransport;
+use super::trace::{TraceEvent, TraceFrame, TraceRecorder};
+use super::upload_checkpoint::UploadCheckpointer;
+use super::transport::{FaultyTransport, Transport};
+pub use crate::protocol::ControlMessage;
+use crate::logger;
+use crate::protocol::{
+    data_frame, frame_parts, timestamped_data_frame, Encoding, FaultConfig, FrameDirection,
+    FrameDump, FRAME_CONTROL, FRAME_DATA, SUBPROTOCOL, SUBPROTOCOL_HEADER, TCP_SCHEME,
+};
+
+/// Maximum number of MOVED redirects followed for a single request.
+pub const MAX_REDIRECTS: usize = 3;
+
+/// Times a failed GET is repeated unless set otherwise.
+pub const DEFAULT_GET_RETRIES: u32 = 3;
+
+/// Time a request waits for its reply among unrelated messages.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+static ACCESS_TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Authenticate every connection made from now on with an access token.
+/// Only the first call has an effect.
+pub fn set_access_token(token: String) {
+    let _ = ACCESS_TOKEN.set(token);
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct StreamMessage {
-    #[serde(rename = "type")]
-    pub msg_type: String,
-    #[serde(rename = "streamId")]
-    pub stream_id: Option<String>,
-    #[serde(rename = "offset")]
-    pub offset: Option<u64>,
-    #[serde(rename = "length")]
-    pub length: Option<usize>,
-    #[serde(rename = "message")]
-    pub message: Option<String>,
+/// A server reply that may be either a data frame or a control message.
+// Replies are matched right away, never stored, so boxing would not pay off
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Incoming {
+    Binary(Vec<u8>),
+    Control(ControlMessage),
+    Closed,
 }
 
 pub struct WebSocketClient {
-    stream: Option<WsStream>,
+    stream: Option<Box<dyn Transport>>,
+    /// Encoding requested through HELLO, re-offered after redirects.
+    preferred: Encoding,
+    /// Encoding the server acknowledged for this connection.
+    encoding: Encoding,
+    /// Records every frame when a trace file was requested.
+    trace: Option<TraceRecorder>,
+    /// Logs every frame when frame dumping is enabled.
+    frame_dump: Option<FrameDump>,
+    /// Receives transfer progress reported by the managers.
+    progress: Option<ProgressSender>,
+    /// Tag every data frame sent with the time it was sent.
+    capture_timestamps: bool,
+    /// Chunks between the CHECKPOINTs downloads ask for, if any.
+    checkpoint_every: Option<u32>,
+    /// Times a failed GET of a download is repeated.
+    get_retries: u32,
+    /// Tags sent with the START of every upload.
+    upload_tags: BTreeMap<String, String>,
+    /// Pace uploads on the server's ACKs.
+    upload_pacing: bool,
+    /// Namespace of the streams uploads create, if any.
+    namespace: Option<String>,
+    /// Checkpoints of the next upload, if requested.
+    upload_checkpoint: Option<UploadCheckpointer>,
+    /// Subprotocol the server selected in the handshake, if any.
+    subprotocol: Option<String>,
+    /// Faults injected into sent frames, and the connections made so far.
+    faults: Option<FaultConfig>,
+    connections: u64,
+    /// Simulated link every connection is shaped to.
+    network: Option<NetworkProfile>,
 }
 
 impl WebSocketClient {
     pub fn new(_uri: &str) -> Self {
         Self {
             stream: None,
+            preferred: Encoding::Json,
+            encoding: Encoding::Json,
+            trace: None,
+            frame_dump: None,
+            progress: None,
+            capture_timestamps: false,
+            checkpoint_every: None,
+            get_retries: DEFAULT_GET_RETRIES,
+            upload_tags: BTreeMap::new(),
+            upload_pacing: true,
+            namespace: None,
+            upload_checkpoint: None,
+            subprotocol: None,
+            faults: None,
+            connections: 0,
+            network: None,
+        }
+    }
+
+    /// Forward transfer progress events to `sender`.
+    pub fn set_progress(&mut self, sender: ProgressSender) {
+        self.progress = Some(sender);
+    }
+
+    /// Report a progress event if anyone is listening.
+    pub fn report(&self, event: ProgressEvent) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.send(event);
+        }
+    }
+
+    /// Tag every data frame sent with the current time as its capture time,
+    /// so the server can align it with other streams. Needs a binary control
+    /// message encoding.
+    pub fn set_capture_timestamps(&mut self, enabled: bool) {
+        self.capture_timestamps = enabled;
+    }
+
+    /// Have the server follow every `every`-th chunk of a download with a
+    /// CHECKPOINT of the bytes sent so far, checked as the download goes.
+    pub fn set_checkpoint_every(&mut self, every: Option<u32>) {
+        self.checkpoint_every = every;
+    }
+
+    /// Get the chunks between the CHECKPOINTs downloads ask for, if any.
+    pub fn checkpoint_every(&self) -> Option<u32> {
+        self.checkpoint_every
+    }
+
+    /// Repeat a GET that failed with a transient error or came back empty
+    /// up to `retries` times, with exponential backoff, before giving up on
+    /// the chunk.
+    pub fn set_get_retries(&mut self, retries: u32) {
+        self.get_retries = retries;
+    }
+
+    /// Get the times a failed GET of a download is repeated.
+    pub fn get_retries(&self) -> u32 {
+        self.get_retries
+    }
+
+    /// Tag every stream uploaded from now on, so LIST can find it.
+    pub fn set_upload_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.upload_tags = tags;
+    }
+
+    /// Get the tags sent with the START of every upload.
+    pub fn upload_tags(&self) -> &BTreeMap<String, String> {
+        &self.upload_tags
+    }
+
+    /// Ask the server to acknowledge the data frames of every upload and
+    /// keep only as much data in flight as it keeps up with. Servers that do
+    /// not acknowledge get the data as fast as it can be sent.
+    pub fn set_upload_pacing(&mut self, enabled: bool) {
+        self.upload_pacing = enabled;
+    }
+
+    /// Check whether uploads are paced on the server's ACKs.
+    pub fn upload_pacing(&self) -> bool {
+        self.upload_pacing
+    }
+
+    /// Create the streams of uploads in `namespace`, which servers confining
+    /// the access token to a namespace require.
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace;
+    }
+
+    /// Get the namespace of the streams uploads create, if any.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Keep the progress of the next upload that the server starts in
+    /// `checkpointer`'s file.
+    pub fn set_upload_checkpoint(&mut self, checkpointer: UploadCheckpointer) {
+        self.upload_checkpoint = Some(checkpointer);
+    }
+
+    /// Take the checkpoints requested for an upload the server started.
+    pub fn take_upload_checkpoint(&mut self) -> Option<UploadCheckpointer> {
+        self.upload_checkpoint.take()
+    }
+
+    /// Log type, size, and leading bytes of every frame sent and received.
+    pub fn enable_frame_dump(&mut self) {
+        self.frame_dump = Some(FrameDump::new());
+    }
+
+    fn dump_frame(&mut self, direction: FrameDirection, message: &Message) {
+        if let Some(dump) = self.frame_dump.as_mut() {
+            let (kind, payload) = frame_parts(message);
+            logger::log_info(&dump.line(direction, kind, payload));
+        }
+    }
+
+    /// Inject faults into the frames sent on every connection from now on.
+    pub fn set_fault_injection(&mut self, config: FaultConfig) {
+        self.faults = Some(config);
+    }
+
+    /// Shape every connection from now on to a simulated link.
+    pub fn set_network_profile(&mut self, profile: NetworkProfile) {
+        self.network = Some(profile);
+    }
+
+    /// Record connects and frames of this client to a trace file.
+    pub fn set_trace(&mut self, recorder: TraceRecorder) {
+        self.trace = Some(recorder);
+    }
+
+    /// Get the control message encoding in use.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Offer a binary control message encoding through HELLO.
+    ///
+    /// Falls back to JSON when the server rejects HELLO or declines the offer.
+    pub async fn negotiate(&mut self, preferred: Encoding) -> Result<Encoding> {
+        self.preferred = preferred;
+        self.encoding = Encoding::Json;
+        if !preferred.is_binary() {
+            return Ok(Encoding::Json);
+        }
+
+        let hello = ControlMessage::Hello {
+            encodings: vec![preferred.as_str().to_string(), Encoding::Json.as_str().to_string()],
+        };
+        self.send_control_message(hello).await?;
+        if let ControlMessage::HelloAck { encoding } =
+            self.receive_reply("HELLO", &["HELLO_ACK"]).await?
+        {
+            self.encoding = Encoding::parse(&encoding).unwrap_or_default();
         }
+        Ok(self.encoding)
     }
 
+    /// Get the subprotocol the server selected when connecting, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// Connect to `uri`, offering the `audio-stream.v1` subprotocol. Servers
+    /// that accept the handshake without selecting it are reconnected to
+    /// without the offer, as the WebSocket handshake rules would otherwise
+    /// fail the connection. `tcp://` URIs connect over raw TCP instead. With
+    /// an access token set, the connection is authenticated before returning.
     pub async fn connect(&mut self, uri: &str) -> Result<()> {
-        let (stream, _) = connect_async(uri)
-            .await
-            .context(format!("Failed to connect to WebSocket server: {}", uri))?;
+        let connect_error = |e: WsError| {
+            ClientError::connection(format!("Failed to connect to WebSocket server: {}", uri), e)
+        };
+        if uri.split_once("://").is_some_and(|(scheme, _)| scheme == TCP_SCHEME) {
+            let transport = TcpTransport::connect(uri).await.map_err(connect_error)?;
+            self.set_transport(uri, Box::new(transport), None);
+            return self.authenticate().await;
+        }
 
-        self.stream = Some(stream);
-        Ok(())
+        let mut request = uri.into_client_request().map_err(connect_error)?;
+        request
+            .headers_mut()
+            .insert(SUBPROTOCOL_HEADER, HeaderValue::from_static(SUBPROTOCOL));
+        let (stream, subprotocol) = match connect_async(request).await {
+            Ok((stream, response)) => {
+                logger::log_debug(&format!("Negotiated subprotocol {}", SUBPROTOCOL));
+                let selected = response
+                    .headers()
+                    .get(SUBPROTOCOL_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                (stream, selected)
+            }
+            Err(WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+                SubProtocolError::NoSubProtocol,
+            ))) => {
+                logger::log_warn(&format!(
+                    "Server did not select subprotocol {}, reconnecting without it",
+                    SUBPROTOCOL
+                ));
+                (connect_async(uri).await.map_err(connect_error)?.0, None)
+            }
+            Err(e) => return Err(connect_error(e)),
+        };
+
+        self.set_transport(uri, Box::new(stream), subprotocol);
+        self.authenticate().await
+    }
+
+    /// Send AUTH with the access token, if one was set.
+    async fn authenticate(&mut self) -> Result<()> {
+        let Some(token) = ACCESS_TOKEN.get() else {
+            return Ok(());
+        };
+        let auth = ControlMessage::Auth {
+            token: token.clone(),
+        };
+        self.send_control_message(auth).await?;
+        match self.receive_reply("AUTH", &["AUTHENTICATED"]).await? {
+            ControlMessage::Authenticated { usage } => {
+                logger::log_debug(&format!(
+                    "Authenticated as {}: {} bytes stored, {} bytes transferred",
+                    usage.token, usage.stored_bytes, usage.transferred_bytes
+                ));
+                Ok(())
+            }
+            other => Err(ClientError::unexpected("AUTH", other)),
+        }
+    }
+
+    /// Use `transport`, e.g. a [`MockTransport`](super::transport::MockTransport),
+    /// as the connection to `uri`.
+    pub fn connect_with(&mut self, uri: &str, transport: Box<dyn Transport>) {
+        self.set_transport(uri, transport, None);
+    }
+
+    fn set_transport(
+        &mut self,
+        uri: &str,
+        transport: Box<dyn Transport>,
+        subprotocol: Option<String>,
+    ) {
+        let transport: Box<dyn Transport> = match &self.faults {
+            Some(faults) => Box::new(FaultyTransport::new(
+                transport,
+                faults.injector(self.connections),
+            )),
+            None => transport,
+        };
+        self.stream = Some(match self.network {
+            Some(profile) => Box::new(ShapedTransport::new(transport, profile)),
+            None => transport,
+        });
+        self.connections += 1;
+        self.subprotocol = subprotocol;
+        // Every connection starts out with JSON control messages
+        self.encoding = Encoding::Json;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(TraceEvent::Connect {
+                uri: uri.to_string(),
+            });
+        }
     }
 
     pub async fn send_text(&mut self, message: &str) -> Result<()> {
-        let stream = self.stream.as_mut().context("Not connected")?;
-        stream
-            .send(Message::Text(Utf8Bytes::from(message)))
+        self.send_message(Message::Text(Utf8Bytes::from(message)), "Failed to send text message")
             .await
-            .context("Failed to send text message")?;
-        Ok(())
     }
 
-    pub async fn send_binary(&mut self, data: Vec<u8>) -> Result<()> {
-        let stream = self.stream.as_mut().context("Not connected")?;
-        stream
-            .send(Message::Binary(Bytes::from(data)))
+    pub async fn send_binary(&mut self, data: impl Into<Bytes>) -> Result<()> {
+        let data = data.into();
+        if self.capture_timestamps {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            return self.send_timestamped_binary(&data, now.as_micros() as u64).await;
+        }
+        let data = if self.encoding.is_binary() { Bytes::from(data_frame(&data)) } else { data };
+        self.send_message(Message::Binary(data), "Failed to send binary message")
             .await
-            .context("Failed to send binary message")?;
-        Ok(())
+    }
+
+    /// Send audio data captured at `timestamp` (microseconds since the Unix
+    /// epoch). Only framed sessions, i.e. binary control message encodings,
+    /// can carry timestamps.
+    pub async fn send_timestamped_binary(&mut self, data: &[u8], timestamp: u64) -> Result<()> {
+        if !self.encoding.is_binary() {
+            return Err(ClientError::Protocol(
+                "Capture timestamps need a binary control message encoding (cbor or msgpack)"
+                    .to_string(),
+            ));
+        }
+        let data = timestamped_data_frame(timestamp, data);
+        self.send_message(Message::Binary(Bytes::from(data)), "Failed to send binary message")
+            .await
+    }
+
+    /// Send a WebSocket message as-is, without data frame prefixing.
+    pub async fn send_raw(&mut self, message: Message) -> Result<()> {
+        self.send_message(message, "Failed to send message").await
+    }
+
+    async fn send_message(&mut self, message: Message, context: &str) -> Result<()> {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record_send(&message);
+        }
+        self.dump_frame(FrameDirection::Outbound, &message);
+        let stream = self.stream.as_mut().ok_or_else(ClientError::not_connected)?;
+        let sent = match message {
+            Message::Text(text) => stream.send_text(text.to_string()).await,
+            Message::Binary(data) => stream.send_binary(data).await,
+            other => {
+                return Err(ClientError::Protocol(format!(
+                    "Cannot send {} frames",
+                    frame_parts(&other).0
+                )))
+            }
+        };
+        sent.map_err(|e| ClientError::connection(context, e))
     }
 
     pub async fn receive(&mut self) -> Result<Option<Message>> {
-        let stream = self.stream.as_mut().context("Not connected")?;
-        let msg = stream.next().await;
+        let stream = self.stream.as_mut().ok_or_else(ClientError::not_connected)?;
+        let msg = stream.receive().await;
         match msg {
-            Some(result) => Ok(Some(result?)),
+            Some(result) => {
+                let msg = result
+                    .map_err(|e| ClientError::connection("Failed to receive message", e))?;
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.record_recv(&msg);
+                }
+                self.dump_frame(FrameDirection::Inbound, &msg);
+                Ok(Some(msg))
+            }
             None => Ok(None),
         }
     }
@@ -88,7 +432,7 @@ impl WebSocketClient {
         match msg {
             Some(Message::Text(text)) => Ok(text.to_string()),
             Some(Message::Close(_)) => Ok(String::new()),
-            _ => anyhow::bail!("Expected text message, got {:?}", msg),
+            _ => Err(ClientError::Protocol(format!("Expected text message, got {:?}", msg))),
         }
     }
 
@@ -98,30 +442,133 @@ impl WebSocketClient {
         match msg {
             Some(Message::Binary(data)) => Ok(data.to_vec()),
             Some(Message::Close(_)) => Ok(Vec::new()),
-            _ => anyhow::bail!("Expected binary message, got {:?}", msg),
+            _ => Err(ClientError::Protocol(format!("Expected binary message, got {:?}", msg))),
+        }
+    }
+
+    pub async fn receive_incoming(&mut self) -> Result<Incoming> {
+        // Pings are answered by the WebSocket layer itself
+        let mut message = self.receive().await?;
+        while let Some(Message::Ping(_) | Message::Pong(_)) = message {
+            message = self.receive().await?;
+        }
+        match message {
+            Some(Message::Binary(data)) if self.encoding.is_binary() => match data.split_first() {
+                Some((&FRAME_DATA, payload)) => Ok(Incoming::Binary(payload.to_vec())),
+                Some((&FRAME_CONTROL, payload)) => {
+                    Ok(Incoming::Control(self.encoding.decode(payload)?))
+                }
+                _ => Err(ClientError::Protocol("Unknown binary frame kind".to_string())),
+            },
+            Some(Message::Binary(data)) => Ok(Incoming::Binary(data.to_vec())),
+            Some(Message::Text(text)) => Ok(Incoming::Control(
+                ControlMessage::from_json(&text).map_err(|e| {
+                    ClientError::Protocol(format!("Failed to parse control message: {}", e))
+                })?,
+            )),
+            Some(Message::Close(_)) | None => Ok(Incoming::Closed),
+            Some(other) => Err(ClientError::Protocol(format!("Unexpected message: {:?}", other))),
+        }
+    }
+
+    /// Reconnect to the node named in a MOVED response.
+    pub async fn follow_redirect(&mut self, location: &str) -> Result<()> {
+        let _ = self.close().await;
+        self.stream = None;
+        self.connect(location).await?;
+        self.negotiate(self.preferred).await?;
+        Ok(())
+    }
+
+    /// Check that the server still answers by re-offering the encoding in
+    /// use through HELLO, which leaves the connection as it was.
+    pub async fn check_health(&mut self) -> Result<()> {
+        let hello = ControlMessage::Hello {
+            encodings: vec![self.encoding.as_str().to_string()],
+        };
+        self.send_control_message(hello).await?;
+        match self.receive_reply("HELLO", &["HELLO_ACK"]).await? {
+            ControlMessage::HelloAck { .. } => Ok(()),
+            other => Err(ClientError::unexpected("HELLO", other)),
         }
     }
 
     pub async fn close(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            if let Some(trace) = self.trace.as_mut() {
+                trace.record(TraceEvent::Send {
+                    frame: TraceFrame::Close,
+                });
+            }
+            self.dump_frame(FrameDirection::Outbound, &Message::Close(None));
+        }
         if let Some(stream) = self.stream.as_mut() {
-            stream.close(None)
+            stream.close()
                 .await
-                .context("Failed to close WebSocket connection")?;
+                .map_err(|e| ClientError::connection("Failed to close WebSocket connection", e))?;
         }
         Ok(())
     }
 
     pub async fn send_control_message(&mut self, msg: ControlMessage) -> Result<()> {
-        let json = serde_json::to_string(&msg)
-            .context("Failed to serialize control message")?;
+        if self.encoding.is_binary() {
+            let frame = self.encoding.encode_frame(&msg)?;
+            return self
+                .send_message(Message::Binary(Bytes::from(frame)), "Failed to send control message")
+                .await;
+        }
+        let json = msg.to_json().map_err(|e| {
+            ClientError::Protocol(format!("Failed to serialize control message: {}", e))
+        })?;
         self.send_text(&json).await
     }
 
     pub async fn receive_control_message(&mut self) -> Result<ControlMessage> {
-        let text = self.receive_text().await?;
-        if text.is_empty() {
-            anyhow::bail!("Connection closed");
+        match self.receive_incoming().await? {
+            Incoming::Control(msg) => Ok(msg),
+            Incoming::Closed => Err(ClientError::closed()),
+            Incoming::Binary(data) => Err(ClientError::Protocol(format!(
+                "Expected control message, got {} bytes of data",
+                data.len()
+            ))),
+        }
+    }
+
+    /// Wait for the reply to `request`: the next control message of one of
+    /// the `expected` types, or an ERROR or MOVED. Anything else arriving
+    /// first, e.g. a late ACK, a duplicate STARTED, or data left over from an
+    /// earlier GET, is skipped, for up to [`REPLY_TIMEOUT`].
+    pub async fn receive_reply(
+        &mut self,
+        request: &str,
+        expected: &[&str],
+    ) -> Result<ControlMessage> {
+        let deadline = tokio::time::Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let incoming = tokio::time::timeout_at(deadline, self.receive_incoming())
+                .await
+                .map_err(|_| {
+                    ClientError::Timeout(format!(
+                        "no reply to {} within {}s",
+                        request,
+                        REPLY_TIMEOUT.as_secs()
+                    ))
+                })??;
+            let skipped = match incoming {
+                Incoming::Control(
+                    message @ (ControlMessage::Error { .. } | ControlMessage::Moved { .. }),
+                ) => return Ok(message),
+                Incoming::Control(message) if expected.contains(&message.type_name()) => {
+                    return Ok(message)
+                }
+                Incoming::Control(message) => message.type_name().to_string(),
+                Incoming::Binary(data) => format!("{} bytes of data", data.len()),
+                Incoming::Closed => return Err(ClientError::closed()),
+            };
+            logger::log_debug(&format!(
+                "Skipped {} while waiting for the reply to {}",
+                skipped, request
+            ));
         }
-        serde_json::from_str(&text).context("Failed to parse control message")
     }
 }
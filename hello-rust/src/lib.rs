@@ -1,4 +1,8 @@
+#[cfg(any(feature = "client", feature = "server"))]
 pub mod cli;
+#[cfg(feature = "client")]
 pub mod client;
 pub mod logger;
+pub mod protocol;
+#[cfg(feature = "server")]
 pub mod server;
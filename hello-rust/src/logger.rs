@@ -1,6 +1,28 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
 use chrono::Local;
 
 static mut VERBOSE: bool = false;
+// Set while a full-screen UI owns the terminal
+static QUIET: AtomicBool = AtomicBool::new(false);
+// Errors logged while quiet, printed once output is back
+static HELD_ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static COLOR: AtomicBool = AtomicBool::new(false);
+// Set while stdout carries data instead of logs
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Width of banner rules.
+const RULE_WIDTH: usize = 40;
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
 
 pub fn init(verbose: bool) {
     unsafe {
@@ -12,29 +34,123 @@ fn is_verbose() -> bool {
     unsafe { VERBOSE }
 }
 
+/// Color log levels and banners when stdout is a terminal, unless disabled
+/// with `--no-color` or the NO_COLOR environment variable, so captured logs
+/// stay free of escape codes.
+pub fn init_color(no_color: bool) {
+    let enabled =
+        !no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal();
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether log output is colored.
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Wrap `text` in an ANSI style when colors are enabled.
+fn paint(style: &str, text: &str) -> String {
+    if color_enabled() {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Suppress all log output, e.g. while the terminal dashboard is shown.
+/// Errors are held back meanwhile and printed when output is restored.
+pub fn set_quiet(quiet: bool) {
+    let mut held = HELD_ERRORS.lock().unwrap();
+    QUIET.store(quiet, Ordering::Relaxed);
+    if !quiet {
+        for error in held.drain(..) {
+            eprintln!("{}", error);
+        }
+    }
+}
+
+fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Write all log output to stderr, e.g. while a download is teed to stdout.
+pub fn set_stderr(stderr: bool) {
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+/// Print a log line to stdout, or to stderr when stdout carries data.
+fn emit(line: &str) {
+    if STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
 fn format_timestamp() -> String {
     Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
 }
 
+/// Format a log line; level tags are padded so messages line up.
+fn line(level: &str, style: &str, message: &str) -> String {
+    let tag = format!("[{}]", level);
+    format!(
+        "{} {}{:pad$}{}",
+        paint(DIM, &format!("[{}]", format_timestamp())),
+        paint(style, &tag),
+        "",
+        message,
+        pad = 8 - tag.len()
+    )
+}
+
 pub fn log_debug(message: &str) {
-    if is_verbose() {
-        println!("[{}] [debug] {}", format_timestamp(), message);
+    if is_verbose() && !is_quiet() {
+        emit(&line("debug", DIM, message));
     }
 }
 
 pub fn log_info(message: &str) {
-    println!("[{}] [info] {}", format_timestamp(), message);
+    if is_quiet() {
+        return;
+    }
+    emit(&line("info", GREEN, message));
 }
 
 pub fn log_warn(message: &str) {
-    println!("[{}] [warn] {}", format_timestamp(), message);
+    if is_quiet() {
+        return;
+    }
+    emit(&line("warn", YELLOW, message));
 }
 
 pub fn log_error(message: &str) {
-    eprintln!("[{}] [error] {}", format_timestamp(), message);
+    let line = line("error", RED, message);
+    // Checked under the lock, so no error slips in while output is restored
+    let mut held = HELD_ERRORS.lock().unwrap();
+    if is_quiet() {
+        held.push(line);
+    } else {
+        eprintln!("{}", line);
+    }
+}
+
+/// Log a horizontal rule.
+pub fn log_rule() {
+    log_info(&paint(CYAN, &"=".repeat(RULE_WIDTH)));
+}
+
+/// Log a title between two rules.
+pub fn log_banner(title: &str) {
+    log_rule();
+    log_info(&paint(BOLD, title));
+    log_rule();
 }
 
 pub fn log_phase(phase: &str) {
-    println!();
-    println!("[{}] [info] === {} ===", format_timestamp(), phase);
+    if is_quiet() {
+        return;
+    }
+    emit("");
+    log_info(&paint(BOLD, &format!("=== {} ===", phase)));
 }
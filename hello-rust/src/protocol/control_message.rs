@@ -0,0 +1,1267 @@
+// Typed control messages exchanged over the WebSocket text channel.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::ExtentList;
+
+/// Length served by GET when the request omits it.
+pub const DEFAULT_GET_LENGTH: usize = 65536;
+
+/// A waveform min/max pair, normalized to [-1.0, 1.0].
+pub type Peak = [f32; 2];
+
+/// Capture time of the chunk uploaded at `offset`, in microseconds since
+/// the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkTimestamp {
+    pub offset: u64,
+    pub timestamp: u64,
+}
+
+/// Bytes stored and transferred under an access token, with its quotas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUsage {
+    /// Name of the token; its secret is never sent back.
+    pub token: String,
+    /// Bytes uploaded to streams that still exist.
+    pub stored_bytes: u64,
+    /// Bytes uploaded and downloaded since the server started.
+    pub transferred_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_quota: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_quota: Option<u64>,
+}
+
+/// One stream of a LIST_RESULT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamSummary {
+    pub stream_id: String,
+    pub size: u64,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+fn default_get_length() -> usize {
+    DEFAULT_GET_LENGTH
+}
+
+/// Control message, serialized as `{"type": "<TYPE>", ...camelCase fields}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "SCREAMING_SNAKE_CASE",
+    rename_all_fields = "camelCase"
+)]
+pub enum ControlMessage {
+    /// Client -> server: offer control message encodings, most preferred first.
+    Hello { encodings: Vec<String> },
+    /// Server -> client: encoding used for the rest of the connection.
+    HelloAck { encoding: String },
+    /// Client -> server: authenticate the connection with an access token.
+    Auth { token: String },
+    /// Server -> client: token accepted, with its usage so far.
+    Authenticated { usage: TokenUsage },
+    /// Client -> server: create a stream and start uploading.
+    Start {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file_name: Option<String>,
+        /// Expected stream size in bytes, used to preallocate the cache file.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+        /// Reopen the existing finalized stream and append to it instead of
+        /// creating a new one.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        append: bool,
+        /// Resume an interrupted upload of the stream, which is still being
+        /// uploaded; STARTED lists the ranges the server already has.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resume: bool,
+        /// Key/value tags to find the stream by with LIST; an append adds
+        /// them to the stream's tags.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        tags: BTreeMap<String, String>,
+        /// Ask for an ACK after every data frame written, to pace the upload
+        /// on; only honored when STARTED echoes it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        acks: bool,
+        /// Fail with STREAM_EXISTS when the ID is in use, instead of starting
+        /// a new version of a finalized stream; set for generated IDs.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        exclusive: bool,
+    },
+    /// Server -> client: stream created, or reopened with new data going to
+    /// `offset`. A resumed upload gets the byte ranges received so far.
+    Started {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        received: Option<ExtentList>,
+        /// Largest data frame payload the server accepts; larger frames are
+        /// refused with CHUNK_TOO_LARGE.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_chunk_size: Option<usize>,
+        /// Data frames of the upload are answered with ACK, as asked in
+        /// START.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        acks: bool,
+    },
+    /// Server -> client: the data frames of an upload started with `acks`
+    /// were written, `bytes` in total since STARTED.
+    Ack { stream_id: String, bytes: u64 },
+    /// Client -> server: write the following data frames of the upload at
+    /// `offset` instead of after the last byte written. Not acknowledged.
+    Seek { stream_id: String, offset: u64 },
+    /// Client -> server: halt the connection's upload; data frames are
+    /// refused until RESUME, and the stream is kept as it is.
+    Pause { stream_id: String },
+    /// Server -> client: upload halted with `offset` bytes written.
+    Paused { stream_id: String, offset: u64 },
+    /// Client -> server: continue the halted upload on this connection.
+    Resume { stream_id: String },
+    /// Server -> client: upload continues; data is appended at `offset`.
+    Resumed { stream_id: String, offset: u64 },
+    /// Client -> server: finalize the stream.
+    Stop { stream_id: String },
+    /// Server -> client: stream finalized, with its final size and SHA-256
+    /// when the server reports them.
+    Stopped {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<String>,
+    },
+    /// Client -> server: read a byte range; answered with a binary frame.
+    /// With a time range in seconds, `offset` counts from the first byte of
+    /// the range and reads stop at its end.
+    Get {
+        stream_id: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default = "default_get_length")]
+        length: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_time: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        end_time: Option<f64>,
+        /// Follow every N-th chunk of a download read in order from offset
+        /// 0 with a CHECKPOINT.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checkpoint_every: Option<u32>,
+        /// Read this earlier generation of a stream uploaded again instead
+        /// of the current one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// Server -> client: GET reached the end of a finalized stream.
+    DataEnd { stream_id: String, size: u64 },
+    /// Server -> client: SHA-256 of the bytes `[0, offset)` sent so far,
+    /// after the chunk a GET with `checkpointEvery` asked for.
+    Checkpoint {
+        stream_id: String,
+        offset: u64,
+        checksum: String,
+    },
+    /// Client -> server: query the byte count of a finalized stream.
+    Size { stream_id: String },
+    /// Server -> client: finalized byte count.
+    SizeResult { stream_id: String, size: u64 },
+    /// Client -> server: query stream information, of generation `version`
+    /// if given.
+    Stat {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// Server -> client: stream information.
+    StatResult {
+        stream_id: String,
+        size: u64,
+        status: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<String>,
+        #[serde(default)]
+        metadata: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        tags: BTreeMap<String, String>,
+        /// Whether the stream is protected from expiry.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pinned: bool,
+        /// Seconds until the stream expires, when it was given a TTL.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_in: Option<u64>,
+        /// Usage of the token the connection authenticated with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        usage: Option<TokenUsage>,
+        /// Generation described: 1 for the first upload of the stream ID,
+        /// counting up with every upload after it was finalized.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+        /// Earlier generations still readable with GET and STAT by version.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        versions: Vec<u64>,
+    },
+    /// Client -> server: list streams in stream ID order, only those carrying
+    /// every tag of `tags`, starting after the stream ID `after`.
+    List {
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        tags: BTreeMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Server -> client: one page of streams; `next` is the `after` of the
+    /// following page, if there is one.
+    ListResult {
+        streams: Vec<StreamSummary>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<String>,
+    },
+    /// Client -> server: request waveform peaks.
+    Peaks { stream_id: String },
+    /// Server -> client: waveform peaks.
+    PeaksResult { stream_id: String, peaks: Vec<Peak> },
+    /// Client -> server: request the SHA-256 of each block overlapping a byte
+    /// range of a finalized stream; `length` of `None` runs to the end. Long
+    /// ranges are answered in pages; ask again from the block after the last.
+    BlockHashes {
+        stream_id: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        length: Option<u64>,
+    },
+    /// Server -> client: hex block hashes starting at `first_block`, checked
+    /// against the stored data before being sent.
+    BlockHashesResult {
+        stream_id: String,
+        block_size: u64,
+        first_block: u64,
+        hashes: Vec<String>,
+    },
+    /// Client -> server: request the capture timestamps recorded for a stream.
+    Timestamps { stream_id: String },
+    /// Server -> client: capture timestamps in offset order; empty when the
+    /// stream was uploaded without them.
+    TimestampsResult {
+        stream_id: String,
+        timestamps: Vec<ChunkTimestamp>,
+    },
+    /// Client -> server: create `target_stream_id` as a snapshot of the
+    /// finalized stream `stream_id` without copying its data.
+    Clone {
+        stream_id: String,
+        target_stream_id: String,
+    },
+    /// Server -> client: snapshot created.
+    Cloned {
+        stream_id: String,
+        source_stream_id: String,
+        size: u64,
+    },
+    /// Client -> server: delete a finalized stream.
+    Delete { stream_id: String },
+    /// Server -> client: stream deleted.
+    Deleted { stream_id: String },
+    /// Client -> server: protect a stream from expiry.
+    Pin { stream_id: String },
+    /// Client -> server: let a pinned stream expire again.
+    Unpin { stream_id: String },
+    /// Server -> client: pin state of the stream after PIN or UNPIN.
+    Pinned { stream_id: String, pinned: bool },
+    /// Client -> server: expire a stream `ttl` seconds from now, whether or
+    /// not it is read; without `ttl`, it expires like other streams again.
+    SetTtl {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    /// Server -> client: TTL of the stream after SET_TTL.
+    TtlSet {
+        stream_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    /// Admin -> server: enter drain mode, authorized by the server's admin
+    /// token.
+    Drain { token: String },
+    /// Server -> client: drain mode entered with `uploads` still in flight;
+    /// the server exits once its connections close.
+    Draining { uploads: usize },
+    /// Server -> client: the stream is owned by another cluster node.
+    Moved {
+        stream_id: String,
+        location: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Server -> client: request failed.
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stream_id: Option<String>,
+        /// Machine-readable failure category, e.g. `STREAM_NOT_FOUND`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        message: String,
+    },
+    /// Any `type` this build does not know, so peers can answer it with an
+    /// ERROR instead of failing to parse; never sent.
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+impl ControlMessage {
+    /// Create an ERROR message.
+    pub fn error(message: impl Into<String>) -> Self {
+        ControlMessage::Error {
+            stream_id: None,
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    /// Create an ERROR message about a stream with a failure code.
+    pub fn stream_error(
+        stream_id: impl Into<String>,
+        code: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        ControlMessage::Error {
+            stream_id: Some(stream_id.into()),
+            code: Some(code.into()),
+            message: message.into(),
+        }
+    }
+
+    /// Get the wire name of the message type.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ControlMessage::Hello { .. } => "HELLO",
+            ControlMessage::HelloAck { .. } => "HELLO_ACK",
+            ControlMessage::Auth { .. } => "AUTH",
+            ControlMessage::Authenticated { .. } => "AUTHENTICATED",
+            ControlMessage::Start { .. } => "START",
+            ControlMessage::Started { .. } => "STARTED",
+            ControlMessage::Ack { .. } => "ACK",
+            ControlMessage::Seek { .. } => "SEEK",
+            ControlMessage::Pause { .. } => "PAUSE",
+            ControlMessage::Paused { .. } => "PAUSED",
+            ControlMessage::Resume { .. } => "RESUME",
+            ControlMessage::Resumed { .. } => "RESUMED",
+            ControlMessage::Stop { .. } => "STOP",
+            ControlMessage::Stopped { .. } => "STOPPED",
+            ControlMessage::Get { .. } => "GET",
+            ControlMessage::DataEnd { .. } => "DATA_END",
+            ControlMessage::Checkpoint { .. } => "CHECKPOINT",
+            ControlMessage::Size { .. } => "SIZE",
+            ControlMessage::SizeResult { .. } => "SIZE_RESULT",
+            ControlMessage::Stat { .. } => "STAT",
+            ControlMessage::StatResult { .. } => "STAT_RESULT",
+            ControlMessage::List { .. } => "LIST",
+            ControlMessage::ListResult { .. } => "LIST_RESULT",
+            ControlMessage::Peaks { .. } => "PEAKS",
+            ControlMessage::PeaksResult { .. } => "PEAKS_RESULT",
+            ControlMessage::BlockHashes { .. } => "BLOCK_HASHES",
+            ControlMessage::BlockHashesResult { .. } => "BLOCK_HASHES_RESULT",
+            ControlMessage::Timestamps { .. } => "TIMESTAMPS",
+            ControlMessage::TimestampsResult { .. } => "TIMESTAMPS_RESULT",
+            ControlMessage::Clone { .. } => "CLONE",
+            ControlMessage::Cloned { .. } => "CLONED",
+            ControlMessage::Delete { .. } => "DELETE",
+            ControlMessage::Deleted { .. } => "DELETED",
+            ControlMessage::Pin { .. } => "PIN",
+            ControlMessage::Unpin { .. } => "UNPIN",
+            ControlMessage::Pinned { .. } => "PINNED",
+            ControlMessage::SetTtl { .. } => "SET_TTL",
+            ControlMessage::TtlSet { .. } => "TTL_SET",
+            ControlMessage::Drain { .. } => "DRAIN",
+            ControlMessage::Draining { .. } => "DRAINING",
+            ControlMessage::Moved { .. } => "MOVED",
+            ControlMessage::Error { .. } => "ERROR",
+            ControlMessage::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// Get the stream the message refers to, if any.
+    pub fn stream_id(&self) -> Option<&str> {
+        match self {
+            ControlMessage::Start { stream_id, .. }
+            | ControlMessage::Started { stream_id, .. }
+            | ControlMessage::Ack { stream_id, .. }
+            | ControlMessage::Seek { stream_id, .. }
+            | ControlMessage::Pause { stream_id }
+            | ControlMessage::Paused { stream_id, .. }
+            | ControlMessage::Resume { stream_id }
+            | ControlMessage::Resumed { stream_id, .. }
+            | ControlMessage::Stop { stream_id }
+            | ControlMessage::Stopped { stream_id, .. }
+            | ControlMessage::Get { stream_id, .. }
+            | ControlMessage::DataEnd { stream_id, .. }
+            | ControlMessage::Checkpoint { stream_id, .. }
+            | ControlMessage::Size { stream_id }
+            | ControlMessage::SizeResult { stream_id, .. }
+            | ControlMessage::Stat { stream_id, .. }
+            | ControlMessage::StatResult { stream_id, .. }
+            | ControlMessage::Peaks { stream_id }
+            | ControlMessage::PeaksResult { stream_id, .. }
+            | ControlMessage::BlockHashes { stream_id, .. }
+            | ControlMessage::BlockHashesResult { stream_id, .. }
+            | ControlMessage::Timestamps { stream_id }
+            | ControlMessage::TimestampsResult { stream_id, .. }
+            | ControlMessage::Clone { stream_id, .. }
+            | ControlMessage::Cloned { stream_id, .. }
+            | ControlMessage::Delete { stream_id }
+            | ControlMessage::Deleted { stream_id }
+            | ControlMessage::Pin { stream_id }
+            | ControlMessage::Unpin { stream_id }
+            | ControlMessage::Pinned { stream_id, .. }
+            | ControlMessage::SetTtl { stream_id, .. }
+            | ControlMessage::TtlSet { stream_id, .. }
+            | ControlMessage::Moved { stream_id, .. } => Some(stream_id),
+            ControlMessage::Error { stream_id, .. } => stream_id.as_deref(),
+            ControlMessage::Hello { .. }
+            | ControlMessage::HelloAck { .. }
+            | ControlMessage::Auth { .. }
+            | ControlMessage::Authenticated { .. }
+            | ControlMessage::List { .. }
+            | ControlMessage::ListResult { .. }
+            | ControlMessage::Drain { .. }
+            | ControlMessage::Draining { .. }
+            | ControlMessage::Unknown => None,
+        }
+    }
+
+    /// Serialize to a JSON text frame.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Parse a JSON text frame.
+    pub fn from_json(text: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn round_trip(message: ControlMessage, expected: Value) {
+        let encoded: Value = serde_json::from_str(&message.to_json().unwrap()).unwrap();
+        assert_eq!(encoded, expected, "wire format of {}", message.type_name());
+        assert_eq!(encoded["type"], message.type_name());
+
+        let decoded = ControlMessage::from_json(&expected.to_string()).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        round_trip(
+            ControlMessage::Hello {
+                encodings: vec!["cbor".to_string(), "json".to_string()],
+            },
+            json!({"type": "HELLO", "encodings": ["cbor", "json"]}),
+        );
+        round_trip(
+            ControlMessage::HelloAck {
+                encoding: "cbor".to_string(),
+            },
+            json!({"type": "HELLO_ACK", "encoding": "cbor"}),
+        );
+    }
+
+    #[test]
+    fn start_round_trip() {
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "stream-1".to_string(),
+                content_type: Some("audio/mpeg".to_string()),
+                file_name: Some("hello.mp3".to_string()),
+                size: Some(92124),
+                append: false,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: false,
+                exclusive: false,
+            },
+            json!({
+                "type": "START",
+                "streamId": "stream-1",
+                "contentType": "audio/mpeg",
+                "fileName": "hello.mp3",
+                "size": 92124
+            }),
+        );
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "stream-1".to_string(),
+                content_type: None,
+                file_name: None,
+                size: None,
+                append: false,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: false,
+                exclusive: false,
+            },
+            json!({"type": "START", "streamId": "stream-1"}),
+        );
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "stream-1".to_string(),
+                content_type: None,
+                file_name: None,
+                size: None,
+                append: true,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: false,
+                exclusive: false,
+            },
+            json!({"type": "START", "streamId": "stream-1", "append": true}),
+        );
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "stream-1".to_string(),
+                content_type: None,
+                file_name: None,
+                size: None,
+                append: false,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: false,
+                exclusive: true,
+            },
+            json!({"type": "START", "streamId": "stream-1", "exclusive": true}),
+        );
+    }
+
+    #[test]
+    fn started_and_stopped_round_trip() {
+        round_trip(
+            ControlMessage::Started {
+                stream_id: "s".to_string(),
+                message: Some("Stream created".to_string()),
+                offset: None,
+                received: None,
+                max_chunk_size: None,
+                acks: false,
+            },
+            json!({"type": "STARTED", "streamId": "s", "message": "Stream created"}),
+        );
+        round_trip(
+            ControlMessage::Started {
+                stream_id: "s".to_string(),
+                message: None,
+                offset: Some(92124),
+                received: None,
+                max_chunk_size: Some(1048576),
+                acks: false,
+            },
+            json!({
+                "type": "STARTED",
+                "streamId": "s",
+                "offset": 92124,
+                "maxChunkSize": 1048576
+            }),
+        );
+        round_trip(
+            ControlMessage::Stop {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "STOP", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Stopped {
+                stream_id: "s".to_string(),
+                message: None,
+                size: None,
+                checksum: None,
+            },
+            json!({"type": "STOPPED", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Stopped {
+                stream_id: "s".to_string(),
+                message: Some("Stream finalized".to_string()),
+                size: Some(92124),
+                checksum: Some("ab12".to_string()),
+            },
+            json!({
+                "type": "STOPPED",
+                "streamId": "s",
+                "message": "Stream finalized",
+                "size": 92124,
+                "checksum": "ab12"
+            }),
+        );
+    }
+
+    #[test]
+    fn get_round_trip_and_defaults() {
+        round_trip(
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 131072,
+                length: 4096,
+                start_time: None,
+                end_time: None,
+                checkpoint_every: None,
+                version: None,
+            },
+            json!({"type": "GET", "streamId": "s", "offset": 131072, "length": 4096}),
+        );
+        round_trip(
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 0,
+                length: 4096,
+                start_time: Some(30.0),
+                end_time: Some(60.5),
+                checkpoint_every: None,
+                version: None,
+            },
+            json!({
+                "type": "GET",
+                "streamId": "s",
+                "offset": 0,
+                "length": 4096,
+                "startTime": 30.0,
+                "endTime": 60.5
+            }),
+        );
+
+        let decoded = ControlMessage::from_json(r#"{"type":"GET","streamId":"s"}"#).unwrap();
+        assert_eq!(
+            decoded,
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 0,
+                length: DEFAULT_GET_LENGTH,
+                start_time: None,
+                end_time: None,
+                checkpoint_every: None,
+                version: None,
+            }
+        );
+    }
+
+    #[test]
+    fn data_end_round_trip() {
+        round_trip(
+            ControlMessage::DataEnd {
+                stream_id: "s".to_string(),
+                size: 92124,
+            },
+            json!({"type": "DATA_END", "streamId": "s", "size": 92124}),
+        );
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        round_trip(
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 0,
+                length: 65536,
+                start_time: None,
+                end_time: None,
+                checkpoint_every: Some(16),
+                version: None,
+            },
+            json!({
+                "type": "GET",
+                "streamId": "s",
+                "offset": 0,
+                "length": 65536,
+                "checkpointEvery": 16
+            }),
+        );
+        round_trip(
+            ControlMessage::Checkpoint {
+                stream_id: "s".to_string(),
+                offset: 1048576,
+                checksum: "ab12".to_string(),
+            },
+            json!({
+                "type": "CHECKPOINT",
+                "streamId": "s",
+                "offset": 1048576,
+                "checksum": "ab12"
+            }),
+        );
+    }
+
+    #[test]
+    fn size_round_trip() {
+        round_trip(
+            ControlMessage::Size {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "SIZE", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::SizeResult {
+                stream_id: "s".to_string(),
+                size: 92124,
+            },
+            json!({"type": "SIZE_RESULT", "streamId": "s", "size": 92124}),
+        );
+    }
+
+    #[test]
+    fn stat_round_trip() {
+        round_trip(
+            ControlMessage::Stat {
+                stream_id: "s".to_string(),
+                version: None,
+            },
+            json!({"type": "STAT", "streamId": "s"}),
+        );
+
+        let mut metadata = HashMap::new();
+        metadata.insert("fileName".to_string(), "hello.mp3".to_string());
+        round_trip(
+            ControlMessage::StatResult {
+                stream_id: "s".to_string(),
+                size: 92124,
+                status: "READY".to_string(),
+                checksum: Some("abc".to_string()),
+                metadata,
+                tags: BTreeMap::new(),
+                pinned: false,
+                expires_in: None,
+                usage: None,
+                version: None,
+                versions: Vec::new(),
+            },
+            json!({
+                "type": "STAT_RESULT",
+                "streamId": "s",
+                "size": 92124,
+                "status": "READY",
+                "checksum": "abc",
+                "metadata": {"fileName": "hello.mp3"}
+            }),
+        );
+    }
+
+    #[test]
+    fn versions_round_trip() {
+        round_trip(
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 0,
+                length: 4096,
+                start_time: None,
+                end_time: None,
+                checkpoint_every: None,
+                version: Some(1),
+            },
+            json!({"type": "GET", "streamId": "s", "offset": 0, "length": 4096, "version": 1}),
+        );
+        round_trip(
+            ControlMessage::Stat {
+                stream_id: "s".to_string(),
+                version: Some(1),
+            },
+            json!({"type": "STAT", "streamId": "s", "version": 1}),
+        );
+        round_trip(
+            ControlMessage::StatResult {
+                stream_id: "s".to_string(),
+                size: 92124,
+                status: "READY".to_string(),
+                checksum: None,
+                metadata: HashMap::new(),
+                tags: BTreeMap::new(),
+                pinned: false,
+                expires_in: None,
+                usage: None,
+                version: Some(3),
+                versions: vec![1, 2],
+            },
+            json!({
+                "type": "STAT_RESULT",
+                "streamId": "s",
+                "size": 92124,
+                "status": "READY",
+                "metadata": {},
+                "version": 3,
+                "versions": [1, 2]
+            }),
+        );
+    }
+
+    #[test]
+    fn acks_round_trip() {
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "s".to_string(),
+                content_type: None,
+                file_name: None,
+                size: None,
+                append: false,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: true,
+                exclusive: false,
+            },
+            json!({"type": "START", "streamId": "s", "acks": true}),
+        );
+        round_trip(
+            ControlMessage::Started {
+                stream_id: "s".to_string(),
+                message: None,
+                offset: None,
+                received: None,
+                max_chunk_size: None,
+                acks: true,
+            },
+            json!({"type": "STARTED", "streamId": "s", "acks": true}),
+        );
+        round_trip(
+            ControlMessage::Ack {
+                stream_id: "s".to_string(),
+                bytes: 131072,
+            },
+            json!({"type": "ACK", "streamId": "s", "bytes": 131072}),
+        );
+    }
+
+    #[test]
+    fn resume_and_seek_round_trip() {
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "s".to_string(),
+                content_type: None,
+                file_name: None,
+                size: Some(262144),
+                append: false,
+                resume: true,
+                tags: BTreeMap::new(),
+                acks: false,
+                exclusive: false,
+            },
+            json!({"type": "START", "streamId": "s", "size": 262144, "resume": true}),
+        );
+        let mut received = ExtentList::prefix(65536);
+        received.insert(131072, 196608);
+        round_trip(
+            ControlMessage::Started {
+                stream_id: "s".to_string(),
+                message: None,
+                offset: None,
+                received: Some(received),
+                max_chunk_size: None,
+                acks: false,
+            },
+            json!({
+                "type": "STARTED",
+                "streamId": "s",
+                "received": [[0, 65536], [131072, 196608]]
+            }),
+        );
+        round_trip(
+            ControlMessage::Seek {
+                stream_id: "s".to_string(),
+                offset: 65536,
+            },
+            json!({"type": "SEEK", "streamId": "s", "offset": 65536}),
+        );
+    }
+
+    #[test]
+    fn pause_and_resume_round_trip() {
+        round_trip(
+            ControlMessage::Pause {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "PAUSE", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Paused {
+                stream_id: "s".to_string(),
+                offset: 65536,
+            },
+            json!({"type": "PAUSED", "streamId": "s", "offset": 65536}),
+        );
+        round_trip(
+            ControlMessage::Resume {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "RESUME", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Resumed {
+                stream_id: "s".to_string(),
+                offset: 65536,
+            },
+            json!({"type": "RESUMED", "streamId": "s", "offset": 65536}),
+        );
+    }
+
+    #[test]
+    fn tags_and_list_round_trip() {
+        let tags = BTreeMap::from([("project".to_string(), "podcast42".to_string())]);
+        round_trip(
+            ControlMessage::Start {
+                stream_id: "s".to_string(),
+                content_type: None,
+                file_name: None,
+                size: None,
+                append: false,
+                resume: false,
+                tags: tags.clone(),
+                acks: false,
+                exclusive: false,
+            },
+            json!({"type": "START", "streamId": "s", "tags": {"project": "podcast42"}}),
+        );
+        round_trip(
+            ControlMessage::List {
+                tags: tags.clone(),
+                after: Some("s".to_string()),
+                limit: Some(2),
+            },
+            json!({
+                "type": "LIST",
+                "tags": {"project": "podcast42"},
+                "after": "s",
+                "limit": 2
+            }),
+        );
+        round_trip(
+            ControlMessage::List {
+                tags: BTreeMap::new(),
+                after: None,
+                limit: None,
+            },
+            json!({"type": "LIST"}),
+        );
+        round_trip(
+            ControlMessage::ListResult {
+                streams: vec![StreamSummary {
+                    stream_id: "t".to_string(),
+                    size: 4,
+                    status: "READY".to_string(),
+                    tags,
+                }],
+                next: Some("t".to_string()),
+            },
+            json!({
+                "type": "LIST_RESULT",
+                "streams": [{
+                    "streamId": "t",
+                    "size": 4,
+                    "status": "READY",
+                    "tags": {"project": "podcast42"}
+                }],
+                "next": "t"
+            }),
+        );
+    }
+
+    #[test]
+    fn peaks_round_trip() {
+        round_trip(
+            ControlMessage::Peaks {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "PEAKS", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::PeaksResult {
+                stream_id: "s".to_string(),
+                peaks: vec![[-0.5, 0.5], [0.0, 0.25]],
+            },
+            json!({"type": "PEAKS_RESULT", "streamId": "s", "peaks": [[-0.5, 0.5], [0.0, 0.25]]}),
+        );
+    }
+
+    #[test]
+    fn block_hashes_round_trip() {
+        round_trip(
+            ControlMessage::BlockHashes {
+                stream_id: "s".to_string(),
+                offset: 65536,
+                length: None,
+            },
+            json!({"type": "BLOCK_HASHES", "streamId": "s", "offset": 65536}),
+        );
+        round_trip(
+            ControlMessage::BlockHashesResult {
+                stream_id: "s".to_string(),
+                block_size: 65536,
+                first_block: 1,
+                hashes: vec!["ab".to_string(), "cd".to_string()],
+            },
+            json!({
+                "type": "BLOCK_HASHES_RESULT",
+                "streamId": "s",
+                "blockSize": 65536,
+                "firstBlock": 1,
+                "hashes": ["ab", "cd"]
+            }),
+        );
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        round_trip(
+            ControlMessage::Timestamps {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "TIMESTAMPS", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::TimestampsResult {
+                stream_id: "s".to_string(),
+                timestamps: vec![ChunkTimestamp {
+                    offset: 65536,
+                    timestamp: 1_700_000_000_000_000,
+                }],
+            },
+            json!({
+                "type": "TIMESTAMPS_RESULT",
+                "streamId": "s",
+                "timestamps": [{"offset": 65536, "timestamp": 1_700_000_000_000_000u64}]
+            }),
+        );
+    }
+
+    #[test]
+    fn clone_round_trip() {
+        round_trip(
+            ControlMessage::Clone {
+                stream_id: "s".to_string(),
+                target_stream_id: "t".to_string(),
+            },
+            json!({"type": "CLONE", "streamId": "s", "targetStreamId": "t"}),
+        );
+        round_trip(
+            ControlMessage::Cloned {
+                stream_id: "t".to_string(),
+                source_stream_id: "s".to_string(),
+                size: 92124,
+            },
+            json!({"type": "CLONED", "streamId": "t", "sourceStreamId": "s", "size": 92124}),
+        );
+    }
+
+    #[test]
+    fn delete_round_trip() {
+        round_trip(
+            ControlMessage::Delete {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "DELETE", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Deleted {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "DELETED", "streamId": "s"}),
+        );
+    }
+
+    #[test]
+    fn pin_and_ttl_round_trip() {
+        round_trip(
+            ControlMessage::Pin {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "PIN", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Unpin {
+                stream_id: "s".to_string(),
+            },
+            json!({"type": "UNPIN", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::Pinned {
+                stream_id: "s".to_string(),
+                pinned: true,
+            },
+            json!({"type": "PINNED", "streamId": "s", "pinned": true}),
+        );
+        round_trip(
+            ControlMessage::SetTtl {
+                stream_id: "s".to_string(),
+                ttl: Some(3600),
+            },
+            json!({"type": "SET_TTL", "streamId": "s", "ttl": 3600}),
+        );
+        round_trip(
+            ControlMessage::TtlSet {
+                stream_id: "s".to_string(),
+                ttl: None,
+            },
+            json!({"type": "TTL_SET", "streamId": "s"}),
+        );
+        round_trip(
+            ControlMessage::StatResult {
+                stream_id: "s".to_string(),
+                size: 1,
+                status: "READY".to_string(),
+                checksum: None,
+                metadata: HashMap::new(),
+                tags: BTreeMap::new(),
+                pinned: true,
+                expires_in: Some(60),
+                usage: None,
+                version: None,
+                versions: Vec::new(),
+            },
+            json!({
+                "type": "STAT_RESULT",
+                "streamId": "s",
+                "size": 1,
+                "status": "READY",
+                "metadata": {},
+                "pinned": true,
+                "expiresIn": 60
+            }),
+        );
+    }
+
+    #[test]
+    fn auth_round_trip() {
+        round_trip(
+            ControlMessage::Auth {
+                token: "secret".to_string(),
+            },
+            json!({"type": "AUTH", "token": "secret"}),
+        );
+        let usage = TokenUsage {
+            token: "team-a".to_string(),
+            stored_bytes: 1024,
+            transferred_bytes: 4096,
+            storage_quota: Some(1 << 20),
+            transfer_quota: None,
+        };
+        round_trip(
+            ControlMessage::Authenticated {
+                usage: usage.clone(),
+            },
+            json!({
+                "type": "AUTHENTICATED",
+                "usage": {
+                    "token": "team-a",
+                    "storedBytes": 1024,
+                    "transferredBytes": 4096,
+                    "storageQuota": 1048576
+                }
+            }),
+        );
+        round_trip(
+            ControlMessage::StatResult {
+                stream_id: "s".to_string(),
+                size: 1,
+                status: "READY".to_string(),
+                checksum: None,
+                metadata: HashMap::new(),
+                tags: BTreeMap::new(),
+                pinned: false,
+                expires_in: None,
+                usage: Some(usage),
+                version: None,
+                versions: Vec::new(),
+            },
+            json!({
+                "type": "STAT_RESULT",
+                "streamId": "s",
+                "size": 1,
+                "status": "READY",
+                "metadata": {},
+                "usage": {
+                    "token": "team-a",
+                    "storedBytes": 1024,
+                    "transferredBytes": 4096,
+                    "storageQuota": 1048576
+                }
+            }),
+        );
+    }
+
+    #[test]
+    fn drain_round_trip() {
+        round_trip(
+            ControlMessage::Drain {
+                token: "secret".to_string(),
+            },
+            json!({"type": "DRAIN", "token": "secret"}),
+        );
+        round_trip(
+            ControlMessage::Draining { uploads: 2 },
+            json!({"type": "DRAINING", "uploads": 2}),
+        );
+    }
+
+    #[test]
+    fn moved_and_error_round_trip() {
+        round_trip(
+            ControlMessage::Moved {
+                stream_id: "s".to_string(),
+                location: "ws://node-b:8080/audio".to_string(),
+                message: None,
+            },
+            json!({"type": "MOVED", "streamId": "s", "location": "ws://node-b:8080/audio"}),
+        );
+        round_trip(
+            ControlMessage::error("Stream not found: s"),
+            json!({"type": "ERROR", "message": "Stream not found: s"}),
+        );
+        round_trip(
+            ControlMessage::stream_error("s", "STREAM_NOT_FOUND", "Stream not found: s"),
+            json!({
+                "type": "ERROR",
+                "streamId": "s",
+                "code": "STREAM_NOT_FOUND",
+                "message": "Stream not found: s"
+            }),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_type_and_missing_fields() {
+        let message = ControlMessage::from_json(r#"{"type":"FOO","streamId":"s"}"#).unwrap();
+        assert_eq!(message, ControlMessage::Unknown);
+        assert!(message.to_json().is_err());
+
+        let err = ControlMessage::from_json(r#"{"type":"START"}"#).unwrap_err();
+        assert!(err.to_string().contains("streamId"));
+
+        // Snake-case field names are not part of the protocol
+        assert!(ControlMessage::from_json(r#"{"type":"STOP","stream_id":"s"}"#).is_err());
+    }
+
+    #[test]
+    fn stream_id_accessor() {
+        assert_eq!(
+            ControlMessage::Stop {
+                stream_id: "s".to_string()
+            }
+            .stream_id(),
+            Some("s")
+        );
+        assert_eq!(ControlMessage::error("x").stream_id(), None);
+    }
+}
@@ -0,0 +1,270 @@
+// Control message encodings negotiated through the HELLO exchange.
+// JSON is the default and travels in text frames. CBOR and MessagePack travel
+// in binary frames, so once one is selected every binary frame starts with a
+// kind byte telling control messages apart from audio data. Only these
+// framed sessions can tag audio data with its capture time.
+
+use thiserror::Error;
+
+use super::ControlMessage;
+
+/// Control message could not be encoded or decoded.
+#[derive(Debug, Error)]
+#[error("{encoding} {operation} failed: {message}")]
+pub struct EncodingError {
+    pub encoding: &'static str,
+    pub operation: &'static str,
+    pub message: String,
+}
+
+/// Kind byte of a binary frame carrying audio data.
+pub const FRAME_DATA: u8 = 0x00;
+
+/// Kind byte of a binary frame carrying an encoded control message.
+pub const FRAME_CONTROL: u8 = 0x01;
+
+/// Kind byte of a binary frame carrying audio data prefixed with its capture
+/// time, as microseconds since the Unix epoch in 8 little-endian bytes.
+pub const FRAME_TIMESTAMPED_DATA: u8 = 0x02;
+
+/// Wire encoding of control messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Encoding {
+    /// Encodings in server preference order.
+    pub const ALL: [Encoding; 3] = [Encoding::Cbor, Encoding::MessagePack, Encoding::Json];
+
+    /// Get the name used in HELLO and on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    /// Parse an encoding name.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Pick the first encoding offered by the client that is supported,
+    /// falling back to JSON.
+    pub fn negotiate(offered: &[String]) -> Self {
+        offered
+            .iter()
+            .filter_map(|name| Encoding::parse(name))
+            .find(|encoding| Encoding::ALL.contains(encoding))
+            .unwrap_or_default()
+    }
+
+    /// Whether control messages travel in binary frames.
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    /// Encode a control message.
+    pub fn encode(&self, message: &ControlMessage) -> Result<Vec<u8>, EncodingError> {
+        let result = match self {
+            Encoding::Json => serde_json::to_vec(message).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(message, &mut buf)
+                    .map(|_| buf)
+                    .map_err(|e| e.to_string())
+            }
+            Encoding::MessagePack => rmp_serde::to_vec_named(message).map_err(|e| e.to_string()),
+        };
+        result.map_err(|message| self.error("encoding", message))
+    }
+
+    /// Decode a control message.
+    pub fn decode(&self, data: &[u8]) -> Result<ControlMessage, EncodingError> {
+        let result = match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+        };
+        result.map_err(|message| self.error("decoding", message))
+    }
+
+    /// Encode a control message as a binary frame payload (kind byte + body).
+    pub fn encode_frame(&self, message: &ControlMessage) -> Result<Vec<u8>, EncodingError> {
+        let body = self.encode(message)?;
+        let mut frame = Vec::with_capacity(body.len() + 1);
+        frame.push(FRAME_CONTROL);
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    fn error(&self, operation: &'static str, message: String) -> EncodingError {
+        EncodingError {
+            encoding: self.as_str(),
+            operation,
+            message,
+        }
+    }
+}
+
+/// Prefix audio data with the data kind byte.
+pub fn data_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(FRAME_DATA);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Prefix audio data with the timestamped data kind byte and its capture time.
+pub fn timestamped_data_frame(timestamp: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 9);
+    frame.push(FRAME_TIMESTAMPED_DATA);
+    frame.extend_from_slice(&timestamp.to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Split the payload of a timestamped data frame (after the kind byte) into
+/// capture time and audio data; `None` if the timestamp is truncated.
+pub fn split_timestamp(payload: &[u8]) -> Option<(u64, &[u8])> {
+    let (timestamp, data) = payload.split_first_chunk::<8>()?;
+    Some((u64::from_le_bytes(*timestamp), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn samples() -> Vec<ControlMessage> {
+        let mut metadata = HashMap::new();
+        metadata.insert("fileName".to_string(), "hello.mp3".to_string());
+        vec![
+            ControlMessage::Hello {
+                encodings: vec!["cbor".to_string(), "json".to_string()],
+            },
+            ControlMessage::HelloAck {
+                encoding: "cbor".to_string(),
+            },
+            ControlMessage::Start {
+                stream_id: "s".to_string(),
+                content_type: Some("audio/mpeg".to_string()),
+                file_name: None,
+                size: Some(92124),
+                append: false,
+                resume: false,
+                tags: BTreeMap::new(),
+                acks: true,
+                exclusive: false,
+            },
+            ControlMessage::Ack {
+                stream_id: "s".to_string(),
+                bytes: 1 << 33,
+            },
+            ControlMessage::Get {
+                stream_id: "s".to_string(),
+                offset: 1 << 40,
+                length: 65536,
+                start_time: Some(1.5),
+                end_time: None,
+                checkpoint_every: None,
+                version: None,
+            },
+            ControlMessage::StatResult {
+                stream_id: "s".to_string(),
+                size: 92124,
+                status: "READY".to_string(),
+                checksum: None,
+                metadata,
+                tags: BTreeMap::new(),
+                pinned: false,
+                expires_in: None,
+                usage: None,
+                version: None,
+                versions: Vec::new(),
+            },
+            ControlMessage::PeaksResult {
+                stream_id: "s".to_string(),
+                peaks: vec![[-0.5, 0.5], [0.0, 0.25]],
+            },
+            ControlMessage::error("boom"),
+        ]
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MessagePack] {
+            for message in samples() {
+                let encoded = encoding.encode(&message).unwrap();
+                let decoded = encoding.decode(&encoded).unwrap();
+                assert_eq!(
+                    decoded,
+                    message,
+                    "{} via {}",
+                    message.type_name(),
+                    encoding.as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn binary_encodings_are_smaller_than_json() {
+        let message = ControlMessage::Get {
+            stream_id: "stream-1".to_string(),
+            offset: 131072,
+            length: 65536,
+            start_time: None,
+            end_time: None,
+            checkpoint_every: None,
+            version: None,
+        };
+        let json = Encoding::Json.encode(&message).unwrap().len();
+        assert!(Encoding::Cbor.encode(&message).unwrap().len() < json);
+        assert!(Encoding::MessagePack.encode(&message).unwrap().len() < json);
+    }
+
+    #[test]
+    fn negotiate_takes_first_supported_offer() {
+        let offer = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            Encoding::negotiate(&offer(&["msgpack", "cbor"])),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            Encoding::negotiate(&offer(&["bson", "cbor"])),
+            Encoding::Cbor
+        );
+        assert_eq!(Encoding::negotiate(&offer(&["bson"])), Encoding::Json);
+        assert_eq!(Encoding::negotiate(&[]), Encoding::Json);
+    }
+
+    #[test]
+    fn frames_carry_kind_byte() {
+        let message = ControlMessage::error("x");
+        let frame = Encoding::Cbor.encode_frame(&message).unwrap();
+        assert_eq!(frame[0], FRAME_CONTROL);
+        assert_eq!(Encoding::Cbor.decode(&frame[1..]).unwrap(), message);
+        assert_eq!(data_frame(&[7, 8]), vec![FRAME_DATA, 7, 8]);
+    }
+    #[test]
+    fn timestamped_frames_split_back() {
+        let frame = timestamped_data_frame(1_700_000_000_000_000, &[7, 8]);
+        assert_eq!(frame[0], FRAME_TIMESTAMPED_DATA);
+        assert_eq!(
+            split_timestamp(&frame[1..]),
+            Some((1_700_000_000_000_000, &[7u8, 8][..]))
+        );
+        assert_eq!(split_timestamp(&frame[1..5]), None);
+    }
+}
@@ -0,0 +1,142 @@
+// Byte ranges received for a stream being uploaded.
+// A resumed START is answered with the ranges the server already has, as
+// `[start, end)` pairs on the wire, e.g. `"received": [[0, 65536], [131072,
+// 196608]]`. The client then uploads only the gaps, each preceded by a SEEK.
+
+use serde::{Deserialize, Serialize};
+
+/// Sorted byte ranges `[start, end)` that neither overlap nor touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ExtentList(Vec<(u64, u64)>);
+
+impl ExtentList {
+    /// Create a list holding the single range `[0, end)`, or nothing for 0.
+    pub fn prefix(end: u64) -> Self {
+        let mut list = Self::default();
+        list.insert(0, end);
+        list
+    }
+
+    /// Add `[start, end)`, merging it with the ranges it overlaps or touches.
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        // First range that ends at or after `start`, and first that starts
+        // after `end`: everything between merges with the new range
+        let first = self.0.partition_point(|&(_, e)| e < start);
+        let last = self.0.partition_point(|&(s, _)| s <= end);
+        let (start, end) = self.0[first..last]
+            .iter()
+            .fold((start, end), |(s, e), &(rs, re)| (s.min(rs), e.max(re)));
+        self.0.splice(first..last, [(start, end)]);
+    }
+
+    /// Get the ranges in offset order.
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.0
+    }
+
+    /// Check whether no byte was received.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the end of the last range, or 0 when empty.
+    pub fn end(&self) -> u64 {
+        self.0.last().map_or(0, |&(_, end)| end)
+    }
+
+    /// Get the end of the range starting at offset 0, or 0 when byte 0 is
+    /// missing.
+    pub fn contiguous_end(&self) -> u64 {
+        match self.0.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+
+    /// Count the bytes of `[start, end)` not in the list.
+    pub fn uncovered(&self, start: u64, end: u64) -> u64 {
+        let first = self.0.partition_point(|&(_, e)| e <= start);
+        let covered: u64 = self.0[first..]
+            .iter()
+            .take_while(|&&(s, _)| s < end)
+            .map(|&(s, e)| e.min(end) - s.max(start))
+            .sum();
+        end.saturating_sub(start) - covered
+    }
+
+    /// Get the ranges of `[0, size)` not in the list, in offset order.
+    pub fn missing(&self, size: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut offset = 0;
+        for &(start, end) in &self.0 {
+            if start >= size {
+                break;
+            }
+            if start > offset {
+                missing.push((offset, start));
+            }
+            offset = end;
+        }
+        if offset < size {
+            missing.push((offset, size));
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_merges_overlapping_and_touching_ranges() {
+        let mut list = ExtentList::default();
+        list.insert(100, 200);
+        list.insert(300, 400);
+        list.insert(0, 50);
+        assert_eq!(list.ranges(), &[(0, 50), (100, 200), (300, 400)]);
+
+        list.insert(200, 300);
+        assert_eq!(list.ranges(), &[(0, 50), (100, 400)]);
+        list.insert(40, 120);
+        assert_eq!(list.ranges(), &[(0, 400)]);
+        list.insert(10, 10);
+        assert_eq!(list.contiguous_end(), 400);
+    }
+
+    #[test]
+    fn missing_lists_the_gaps_up_to_size() {
+        let mut list = ExtentList::default();
+        list.insert(100, 200);
+        list.insert(300, 400);
+        assert_eq!(list.contiguous_end(), 0);
+        assert_eq!(list.missing(500), vec![(0, 100), (200, 300), (400, 500)]);
+        assert_eq!(list.missing(250), vec![(0, 100), (200, 250)]);
+        assert!(ExtentList::prefix(500).missing(500).is_empty());
+    }
+
+    #[test]
+    fn uncovered_counts_the_bytes_outside_the_ranges() {
+        let mut list = ExtentList::default();
+        list.insert(100, 200);
+        list.insert(300, 400);
+        assert_eq!(list.uncovered(0, 500), 300);
+        assert_eq!(list.uncovered(150, 350), 100);
+        assert_eq!(list.uncovered(100, 200), 0);
+        assert_eq!(list.uncovered(400, 450), 50);
+        assert_eq!(list.uncovered(50, 50), 0);
+    }
+
+    #[test]
+    fn serializes_as_pairs() {
+        let mut list = ExtentList::prefix(65536);
+        list.insert(131072, 196608);
+        let json = serde_json::to_value(&list).unwrap();
+        assert_eq!(json, serde_json::json!([[0, 65536], [131072, 196608]]));
+        assert_eq!(serde_json::from_value::<ExtentList>(json).unwrap(), list);
+    }
+}
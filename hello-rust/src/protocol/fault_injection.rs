@@ -0,0 +1,236 @@
+// Fault injection for resilience testing (--inject-faults).
+// Either side can drop, delay, truncate, or duplicate the frames it sends,
+// chosen per frame by a seeded generator, so a failing run of the retry,
+// resume, and acknowledgement paths is reproduced by passing the same spec.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+
+/// Longest delay when the spec sets none.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(200);
+
+/// Invalid fault injection spec.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FaultSpecError {
+    #[error("expected key=value, got '{0}'")]
+    Syntax(String),
+    #[error("unknown fault '{0}' (expected drop, delay, truncate, duplicate, delay-ms, or seed)")]
+    UnknownKey(String),
+    #[error("invalid value '{value}' for {key}")]
+    Value { key: String, value: String },
+    #[error("fault probabilities add up to more than 1")]
+    Overcommitted,
+}
+
+/// Probability of each fault per frame, and the seed of the generator
+/// choosing them. Parsed from a spec such as
+/// `drop=0.01,delay=0.05,delay-ms=500,truncate=0.01,duplicate=0.01,seed=42`;
+/// without a seed, one is drawn from the clock and shown by `Display`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub drop: f64,
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub truncate: f64,
+    pub duplicate: f64,
+    pub seed: u64,
+}
+
+impl FaultConfig {
+    pub fn parse(spec: &str) -> Result<Self, FaultSpecError> {
+        let mut config = FaultConfig {
+            drop: 0.0,
+            delay: 0.0,
+            max_delay: DEFAULT_MAX_DELAY,
+            truncate: 0.0,
+            duplicate: 0.0,
+            seed: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        };
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| FaultSpecError::Syntax(entry.to_string()))?;
+            let invalid = || FaultSpecError::Value {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            let probability = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(invalid)
+            };
+            match key {
+                "drop" => config.drop = probability()?,
+                "delay" => config.delay = probability()?,
+                "truncate" => config.truncate = probability()?,
+                "duplicate" => config.duplicate = probability()?,
+                "delay-ms" => {
+                    config.max_delay = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "seed" => config.seed = value.parse().map_err(|_| invalid())?,
+                _ => return Err(FaultSpecError::UnknownKey(key.to_string())),
+            }
+        }
+        if config.drop + config.delay + config.truncate + config.duplicate > 1.0 {
+            return Err(FaultSpecError::Overcommitted);
+        }
+        Ok(config)
+    }
+
+    /// Create the injector of the `connection`-th connection; each one gets
+    /// its own sequence of faults, so a reconnect doesn't replay the faults
+    /// that ended the previous connection.
+    pub fn injector(&self, connection: u64) -> FaultInjector {
+        FaultInjector {
+            config: self.clone(),
+            state: self.seed ^ connection.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+}
+
+impl fmt::Display for FaultConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={},delay={},delay-ms={},truncate={},duplicate={},seed={}",
+            self.drop,
+            self.delay,
+            self.max_delay.as_millis(),
+            self.truncate,
+            self.duplicate,
+            self.seed
+        )
+    }
+}
+
+/// Fault applied to one outbound frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Don't send the frame.
+    Drop,
+    /// Send the frame after waiting this long.
+    Delay(Duration),
+    /// Send only this many leading bytes of the frame.
+    Truncate(usize),
+    /// Send the frame twice.
+    Duplicate,
+}
+
+/// Per-connection fault generator.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    state: u64,
+}
+
+impl FaultInjector {
+    /// Choose the fault for the next frame of `len` bytes, if any.
+    pub fn next_fault(&mut self, len: usize) -> Option<Fault> {
+        let roll = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        let FaultConfig {
+            drop,
+            delay,
+            max_delay,
+            truncate,
+            duplicate,
+            ..
+        } = self.config;
+        if roll < drop {
+            return Some(Fault::Drop);
+        }
+        if roll < drop + delay {
+            let max = max_delay.as_millis() as u64;
+            return Some(Fault::Delay(Duration::from_millis(
+                self.next_u64() % (max + 1),
+            )));
+        }
+        if roll < drop + delay + truncate {
+            return Some(Fault::Truncate(
+                (self.next_u64() % len.max(1) as u64) as usize,
+            ));
+        }
+        if roll < drop + delay + truncate + duplicate {
+            return Some(Fault::Duplicate);
+        }
+        None
+    }
+
+    // SplitMix64: small, seedable, and the same on every platform
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Cut `text` to at most `len` bytes without splitting a character, so a
+/// truncated text frame is still valid UTF-8.
+pub fn truncate_text(text: &str, len: usize) -> &str {
+    let mut end = len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_spec() {
+        let config = FaultConfig::parse("drop=0.1, delay=0.2,delay-ms=50,seed=7").unwrap();
+        assert_eq!(config.drop, 0.1);
+        assert_eq!(config.delay, 0.2);
+        assert_eq!(config.max_delay, Duration::from_millis(50));
+        assert_eq!(config.truncate, 0.0);
+        assert_eq!(config.seed, 7);
+        assert_eq!(FaultConfig::parse(&config.to_string()).unwrap(), config);
+
+        assert_eq!(
+            FaultConfig::parse("drop=0.6,duplicate=0.6"),
+            Err(FaultSpecError::Overcommitted)
+        );
+        assert!(matches!(
+            FaultConfig::parse("drop=2"),
+            Err(FaultSpecError::Value { .. })
+        ));
+        assert!(matches!(
+            FaultConfig::parse("reorder=0.1"),
+            Err(FaultSpecError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let config =
+            FaultConfig::parse("drop=0.2,delay=0.2,truncate=0.2,duplicate=0.2,seed=42").unwrap();
+        let faults = |connection| {
+            let mut injector = config.injector(connection);
+            (0..64)
+                .map(|_| injector.next_fault(100))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(faults(0), faults(0));
+        assert_ne!(faults(0), faults(1));
+        assert!(faults(0).iter().any(Option::is_none));
+        assert!(faults(0).contains(&Some(Fault::Drop)));
+        assert!(faults(0).contains(&Some(Fault::Duplicate)));
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        assert_eq!(truncate_text("héllo", 2), "h");
+        assert_eq!(truncate_text("héllo", 3), "hé");
+        assert_eq!(truncate_text("abc", 10), "abc");
+    }
+}
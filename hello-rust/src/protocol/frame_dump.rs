@@ -0,0 +1,126 @@
+// Wire-level frame dump for diagnosing protocol mismatches.
+// Each side keeps one FrameDump per connection and formats one line per frame
+// with its direction, a per-direction sequence number, the frame type, size,
+// and the leading payload bytes in hex.
+
+/// Number of leading payload bytes shown in a dump line.
+pub const DUMP_PREFIX_LEN: usize = 16;
+
+/// Direction of a frame relative to the side doing the dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Inbound,
+    Outbound,
+}
+
+/// Per-connection frame counters.
+#[derive(Debug, Clone, Default)]
+pub struct FrameDump {
+    inbound: u64,
+    outbound: u64,
+}
+
+impl FrameDump {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a frame and format its dump line.
+    pub fn line(&mut self, direction: FrameDirection, kind: &str, payload: &[u8]) -> String {
+        let (arrow, sequence) = match direction {
+            FrameDirection::Inbound => {
+                self.inbound += 1;
+                ("<-", self.inbound)
+            }
+            FrameDirection::Outbound => {
+                self.outbound += 1;
+                ("->", self.outbound)
+            }
+        };
+        let mut line = format!(
+            "[frame] {} #{} {} {} bytes",
+            arrow,
+            sequence,
+            kind,
+            payload.len()
+        );
+        if !payload.is_empty() {
+            line.push_str(": ");
+            line.push_str(&hex_prefix(payload));
+        }
+        line
+    }
+}
+
+/// Frame type name and payload of a WebSocket message.
+#[cfg(any(feature = "client", feature = "server"))]
+pub fn frame_parts(message: &tungstenite::Message) -> (&'static str, &[u8]) {
+    use tungstenite::Message;
+
+    match message {
+        Message::Text(text) => ("text", text.as_bytes()),
+        Message::Binary(data) => ("binary", data),
+        Message::Ping(data) => ("ping", data),
+        Message::Pong(data) => ("pong", data),
+        Message::Close(_) => ("close", &[]),
+        Message::Frame(frame) => ("frame", frame.payload()),
+    }
+}
+
+/// Format the first [`DUMP_PREFIX_LEN`] bytes as space-separated hex.
+pub fn hex_prefix(payload: &[u8]) -> String {
+    let mut hex = payload
+        .iter()
+        .take(DUMP_PREFIX_LEN)
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if payload.len() > DUMP_PREFIX_LEN {
+        hex.push_str(" ...");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_direction_separately() {
+        let mut dump = FrameDump::new();
+        assert_eq!(
+            dump.line(FrameDirection::Outbound, "text", b"{}"),
+            "[frame] -> #1 text 2 bytes: 7b 7d"
+        );
+        assert_eq!(
+            dump.line(FrameDirection::Inbound, "binary", &[0x00, 0xff]),
+            "[frame] <- #1 binary 2 bytes: 00 ff"
+        );
+        assert_eq!(
+            dump.line(FrameDirection::Outbound, "close", &[]),
+            "[frame] -> #2 close 0 bytes"
+        );
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    #[test]
+    fn names_websocket_frames() {
+        use tungstenite::Message;
+
+        assert_eq!(frame_parts(&Message::text("{}")), ("text", &b"{}"[..]));
+        assert_eq!(
+            frame_parts(&Message::binary(vec![1, 2])),
+            ("binary", &[1, 2][..])
+        );
+        assert_eq!(frame_parts(&Message::Close(None)), ("close", &[][..]));
+    }
+
+    #[test]
+    fn truncates_long_payloads() {
+        let payload: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            hex_prefix(&payload),
+            "00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f ..."
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// Wire protocol shared by the client and the server.
+// Control messages are JSON text frames tagged by "type" with camelCase
+// fields; audio data travels as raw binary frames. A HELLO exchange can
+// switch control messages to CBOR or MessagePack for the connection, and the
+// handshake can name the protocol version as a WebSocket subprotocol. The
+// same messages can also travel over raw TCP with length-prefixed frames.
+pub mod control_message;
+pub mod encoding;
+pub mod extent_list;
+pub mod fault_injection;
+pub mod frame_dump;
+pub mod pcm_format;
+pub mod session_state;
+pub mod stream_id;
+pub mod subprotocol;
+pub mod tcp_framing;
+
+pub use control_message::{
+    ChunkTimestamp, ControlMessage, Peak, StreamSummary, TokenUsage, DEFAULT_GET_LENGTH,
+};
+pub use encoding::{
+    data_frame, split_timestamp, timestamped_data_frame, Encoding, FRAME_CONTROL, FRAME_DATA,
+    FRAME_TIMESTAMPED_DATA,
+};
+pub use extent_list::ExtentList;
+pub use fault_injection::{truncate_text, Fault, FaultConfig, FaultInjector, FaultSpecError};
+#[cfg(any(feature = "client", feature = "server"))]
+pub use frame_dump::frame_parts;
+pub use frame_dump::{FrameDirection, FrameDump};
+pub use pcm_format::{PcmFormat, WavHeader};
+pub use session_state::{SessionState, StateError};
+pub use stream_id::{
+    is_valid_namespace, split_namespace, validate_stream_id, InvalidStreamId, MAX_STREAM_ID_LEN,
+    NAMESPACE_SEPARATOR,
+};
+pub use subprotocol::{SubprotocolOffer, SUBPROTOCOL, SUBPROTOCOL_HEADER};
+pub use tcp_framing::{
+    decode_tcp_header, encode_tcp_frame, TcpFrameError, TcpFrameKind, TCP_HEADER_LEN, TCP_SCHEME,
+};
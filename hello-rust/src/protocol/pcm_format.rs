@@ -0,0 +1,321 @@
+// Raw PCM sample format declared by the uploader.
+// The format travels in the stream content type as
+// `audio/pcm;rate=<hz>;channels=<n>;bits=<8|16|24|32>` (little-endian, signed
+// except for 8-bit, as in WAV), which lets a server wrap the samples in a
+// RIFF/WAVE header on download, and can be read from or written to WAV
+// headers.
+
+use std::ops::Range;
+
+/// Content type of raw PCM streams, without parameters.
+pub const PCM_CONTENT_TYPE: &str = "audio/pcm";
+
+/// Length of the header produced by [`PcmFormat::wav_header`].
+pub const WAV_HEADER_LEN: usize = 44;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Fields of a RIFF/WAVE header, whatever its sample encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WavHeader {
+    /// Sample encoding (1 = integer PCM, 3 = IEEE float), read from the
+    /// sub-format of extensible headers
+    pub encoding: u16,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub byte_rate: u32,
+    pub bits_per_sample: u16,
+    /// Byte range of the sample data
+    pub data: Range<u64>,
+}
+
+impl WavHeader {
+    /// Parse the leading bytes of a WAV file by walking its chunk list;
+    /// `total_size` caps placeholder data sizes of streamed WAVs. `None`
+    /// for other files, or when no `fmt ` chunk precedes the data.
+    pub fn parse(header: &[u8], total_size: u64) -> Option<Self> {
+        if header.get(0..4)? != b"RIFF" || header.get(8..12)? != b"WAVE" {
+            return None;
+        }
+
+        let u16_at = |at: usize| {
+            header
+                .get(at..at + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        };
+        let u32_at = |at: usize| {
+            header
+                .get(at..at + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+
+        let mut offset = 12;
+        let mut format = None;
+        while offset + 8 <= header.len() {
+            let chunk_size = u32_at(offset + 4)? as usize;
+            let body = offset + 8;
+            match &header[offset..offset + 4] {
+                b"fmt " => {
+                    let mut encoding = u16_at(body)?;
+                    if encoding == WAVE_FORMAT_EXTENSIBLE {
+                        // Actual encoding is the first two bytes of the sub-format GUID
+                        encoding = u16_at(body + 24)?;
+                    }
+                    format = Some((
+                        encoding,
+                        u16_at(body + 2)?,
+                        u32_at(body + 4)?,
+                        u32_at(body + 8)?,
+                        u16_at(body + 14)?,
+                    ));
+                }
+                b"data" => {
+                    let (encoding, channels, sample_rate, byte_rate, bits_per_sample) = format?;
+                    let start = body as u64;
+                    let end = (start + chunk_size as u64).min(total_size);
+                    return Some(Self {
+                        encoding,
+                        sample_rate,
+                        channels,
+                        byte_rate,
+                        bits_per_sample,
+                        data: start..end.max(start),
+                    });
+                }
+                _ => {}
+            }
+            // Chunks are padded to an even number of bytes
+            offset = body + chunk_size + (chunk_size & 1);
+        }
+        None
+    }
+}
+
+/// Sample format of a raw PCM stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+
+impl PcmFormat {
+    /// Create a format, rejecting zero rates or channels, sample widths WAV
+    /// PCM does not support, and rates too large for the WAV header.
+    pub fn new(sample_rate: u32, channels: u16, bits_per_sample: u16) -> Option<Self> {
+        let block_align = channels as u64 * (bits_per_sample / 8) as u64;
+        let valid = sample_rate > 0
+            && channels > 0
+            && matches!(bits_per_sample, 8 | 16 | 24 | 32)
+            && block_align <= u16::MAX as u64
+            && sample_rate as u64 * block_align <= u32::MAX as u64;
+        valid.then_some(Self {
+            sample_rate,
+            channels,
+            bits_per_sample,
+        })
+    }
+
+    /// Parse a `RATE:CHANNELS:BITS` spec such as `44100:2:16`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.split(':');
+        let format = Self::new(
+            parts.next()?.trim().parse().ok()?,
+            parts.next()?.trim().parse().ok()?,
+            parts.next()?.trim().parse().ok()?,
+        )?;
+        parts.next().is_none().then_some(format)
+    }
+
+    /// Parse the format from a content type; `None` unless it is raw PCM
+    /// with all three parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut parts = content_type.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case(PCM_CONTENT_TYPE) {
+            return None;
+        }
+
+        let (mut rate, mut channels, mut bits) = (None, None, None);
+        for (key, value) in parts.filter_map(|param| param.split_once('=')) {
+            match key.trim().to_ascii_lowercase().as_str() {
+                "rate" => rate = value.trim().parse().ok(),
+                "channels" => channels = value.trim().parse().ok(),
+                "bits" => bits = value.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        Self::new(rate?, channels?, bits?)
+    }
+
+    /// Get the size of one frame, i.e. one sample for every channel.
+    pub fn frame_size(&self) -> usize {
+        self.channels as usize * (self.bits_per_sample / 8) as usize
+    }
+
+    /// Decode one little-endian sample, unsigned if 8-bit, to a fraction of
+    /// full scale in [-1.0, 1.0).
+    pub fn sample_value(sample: &[u8]) -> f64 {
+        match *sample {
+            [b] => (b as f64 - 128.0) / 128.0,
+            [b0, b1] => i16::from_le_bytes([b0, b1]) as f64 / 32768.0,
+            [b0, b1, b2] => (i32::from_le_bytes([0, b0, b1, b2]) >> 8) as f64 / 8_388_608.0,
+            [b0, b1, b2, b3] => i32::from_le_bytes([b0, b1, b2, b3]) as f64 / 2_147_483_648.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Get the byte offset, from the first sample, of the frame nearest to
+    /// `seconds` into the audio.
+    pub fn byte_offset(&self, seconds: f64) -> u64 {
+        let frames = (seconds * self.sample_rate as f64).round() as u64;
+        frames.saturating_mul(self.frame_size() as u64)
+    }
+
+    /// Get the content type declaring this format.
+    pub fn content_type(&self) -> String {
+        format!(
+            "{};rate={};channels={};bits={}",
+            PCM_CONTENT_TYPE, self.sample_rate, self.channels, self.bits_per_sample
+        )
+    }
+
+    /// Read the format and sample data range of an integer PCM WAV file from
+    /// its leading bytes; `total_size` caps placeholder data sizes of
+    /// streamed WAVs. `None` for other files or encodings.
+    pub fn from_wav_header(header: &[u8], total_size: u64) -> Option<(Self, Range<u64>)> {
+        let wav = WavHeader::parse(header, total_size)?;
+        if wav.encoding != WAVE_FORMAT_PCM {
+            return None;
+        }
+        let format = Self::new(wav.sample_rate, wav.channels, wav.bits_per_sample)?;
+        Some((format, wav.data))
+    }
+
+    /// Build the RIFF/WAVE header for `data_len` bytes of samples.
+    /// Lengths beyond the 4GB RIFF limit are clamped, as streaming writers do.
+    pub fn wav_header(&self, data_len: u64) -> [u8; WAV_HEADER_LEN] {
+        let data_len = data_len.min((u32::MAX - 36) as u64) as u32;
+        let block_align = self.frame_size() as u16;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        let mut header = [0u8; WAV_HEADER_LEN];
+        header[0..4].copy_from_slice(b"RIFF");
+        header[4..8].copy_from_slice(&(36 + data_len).to_le_bytes());
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        header[16..20].copy_from_slice(&16u32.to_le_bytes());
+        header[20..22].copy_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        header[22..24].copy_from_slice(&self.channels.to_le_bytes());
+        header[24..28].copy_from_slice(&self.sample_rate.to_le_bytes());
+        header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+        header[32..34].copy_from_slice(&block_align.to_le_bytes());
+        header[34..36].copy_from_slice(&self.bits_per_sample.to_le_bytes());
+        header[36..40].copy_from_slice(b"data");
+        header[40..44].copy_from_slice(&data_len.to_le_bytes());
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_round_trip() {
+        let format = PcmFormat::parse("44100:2:16").unwrap();
+        assert_eq!(
+            format.content_type(),
+            "audio/pcm;rate=44100;channels=2;bits=16"
+        );
+        assert_eq!(
+            PcmFormat::from_content_type("audio/PCM; bits=16; channels=2; rate=44100"),
+            Some(format)
+        );
+        assert_eq!(PcmFormat::from_content_type("audio/pcm;rate=44100"), None);
+        assert_eq!(PcmFormat::from_content_type("audio/mpeg"), None);
+        assert_eq!(PcmFormat::parse("44100:2:12"), None);
+        assert_eq!(PcmFormat::parse("44100:2:16:1"), None);
+    }
+
+    #[test]
+    fn byte_offsets_land_on_frames() {
+        let format = PcmFormat::new(44100, 2, 16).unwrap();
+        assert_eq!(format.byte_offset(0.0), 0);
+        assert_eq!(format.byte_offset(30.0), 30 * 44100 * 4);
+        assert_eq!(format.byte_offset(0.1), 4410 * 4);
+        assert_eq!(format.byte_offset(-1.0), 0);
+        assert_eq!(format.byte_offset(f64::INFINITY), u64::MAX);
+    }
+
+    #[test]
+    fn samples_decode_to_full_scale() {
+        assert_eq!(PcmFormat::sample_value(&[0x80]), 0.0);
+        assert_eq!(PcmFormat::sample_value(&[0x00]), -1.0);
+        assert_eq!(PcmFormat::sample_value(&0x4000i16.to_le_bytes()), 0.5);
+        assert_eq!(PcmFormat::sample_value(&[0x00, 0x00, 0x80]), -1.0);
+        assert_eq!(
+            PcmFormat::sample_value(&(-0x4000_0000i32).to_le_bytes()),
+            -0.5
+        );
+    }
+
+    #[test]
+    fn wav_header_fields() {
+        let header = PcmFormat::new(8000, 1, 16).unwrap().wav_header(1000);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(header[4..8].try_into().unwrap()), 1036);
+        assert_eq!(&header[8..16], b"WAVEfmt ");
+        assert_eq!(
+            u32::from_le_bytes(header[28..32].try_into().unwrap()),
+            16000
+        );
+        assert_eq!(u16::from_le_bytes(header[32..34].try_into().unwrap()), 2);
+        assert_eq!(&header[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(header[40..44].try_into().unwrap()), 1000);
+    }
+
+    #[test]
+    fn wav_header_parses_back() {
+        let format = PcmFormat::new(48000, 2, 24).unwrap();
+        let header = format.wav_header(600);
+        assert_eq!(
+            PcmFormat::from_wav_header(&header, 644),
+            Some((format, 44..644))
+        );
+        // A truncated file caps the declared data size
+        assert_eq!(
+            PcmFormat::from_wav_header(&header, 100),
+            Some((format, 44..100))
+        );
+        assert_eq!(PcmFormat::from_wav_header(b"ID3\x04", 4), None);
+    }
+
+    #[test]
+    fn wav_header_skips_padded_chunks() {
+        let format = PcmFormat::new(8000, 1, 8).unwrap();
+        let plain = format.wav_header(10);
+        // Insert an odd-sized LIST chunk, padded to an even size, before data
+        let mut header = plain[..36].to_vec();
+        header.extend_from_slice(b"LIST\x03\x00\x00\x00abc\x00");
+        header.extend_from_slice(&plain[36..]);
+
+        let wav = WavHeader::parse(&header, 1000).unwrap();
+        assert_eq!(wav.encoding, WAVE_FORMAT_PCM);
+        assert_eq!(wav.byte_rate, 8000);
+        assert_eq!(wav.data, 56..66);
+    }
+
+    #[test]
+    fn float_wav_is_not_pcm() {
+        let mut header = PcmFormat::new(48000, 2, 32).unwrap().wav_header(8);
+        header[20..22].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(WavHeader::parse(&header, 52).unwrap().encoding, 3);
+        assert_eq!(PcmFormat::from_wav_header(&header, 52), None);
+
+        // Data before any fmt chunk
+        let mut header = header.to_vec();
+        header[12..16].copy_from_slice(b"junk");
+        assert_eq!(WavHeader::parse(&header, 52), None);
+    }
+}
@@ -0,0 +1,211 @@
+// Upload session state machine shared by the client and the server.
+// A connection moves Idle -> Started -> Uploading -> Finalized and may start
+// another stream once the previous one is finalized.
+
+use thiserror::Error;
+
+/// Rejected state transition.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StateError {
+    #[error("{event} is not allowed while {state}")]
+    IllegalTransition {
+        state: &'static str,
+        event: &'static str,
+    },
+    #[error("{event} for stream {actual} but the active stream is {expected}")]
+    StreamMismatch {
+        event: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Upload state of a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SessionState {
+    #[default]
+    Idle,
+    /// START acknowledged, no data yet.
+    Started { stream_id: String },
+    /// At least one data frame received.
+    Uploading { stream_id: String, bytes: u64 },
+    /// STOP acknowledged.
+    Finalized { stream_id: String, bytes: u64 },
+}
+
+impl SessionState {
+    /// Get the state name used in errors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionState::Idle => "IDLE",
+            SessionState::Started { .. } => "STARTED",
+            SessionState::Uploading { .. } => "UPLOADING",
+            SessionState::Finalized { .. } => "FINALIZED",
+        }
+    }
+
+    /// Get the stream accepting data, if any.
+    pub fn active_stream(&self) -> Option<&str> {
+        match self {
+            SessionState::Started { stream_id } | SessionState::Uploading { stream_id, .. } => {
+                Some(stream_id)
+            }
+            SessionState::Idle | SessionState::Finalized { .. } => None,
+        }
+    }
+
+    /// Apply START.
+    pub fn start(&mut self, stream_id: &str) -> Result<(), StateError> {
+        match self {
+            SessionState::Idle | SessionState::Finalized { .. } => {
+                *self = SessionState::Started {
+                    stream_id: stream_id.to_string(),
+                };
+                Ok(())
+            }
+            _ => Err(self.illegal("START")),
+        }
+    }
+
+    /// Apply a data frame of `len` bytes and return the stream it belongs to.
+    pub fn data(&mut self, len: usize) -> Result<&str, StateError> {
+        let (stream_id, bytes) = match std::mem::take(self) {
+            SessionState::Started { stream_id } => (stream_id, len as u64),
+            SessionState::Uploading { stream_id, bytes } => (stream_id, bytes + len as u64),
+            other => {
+                *self = other;
+                return Err(self.illegal("DATA"));
+            }
+        };
+        *self = SessionState::Uploading { stream_id, bytes };
+        Ok(self.active_stream().unwrap_or_default())
+    }
+
+    /// Apply STOP for `stream_id` and return the number of bytes uploaded.
+    pub fn stop(&mut self, stream_id: &str) -> Result<u64, StateError> {
+        let bytes = match self {
+            SessionState::Started { .. } => 0,
+            SessionState::Uploading { bytes, .. } => *bytes,
+            _ => return Err(self.illegal("STOP")),
+        };
+        self.require_stream("STOP", stream_id)?;
+        *self = SessionState::Finalized {
+            stream_id: stream_id.to_string(),
+            bytes,
+        };
+        Ok(bytes)
+    }
+
+    /// Check that SEEK for `stream_id` applies to the active stream. The
+    /// state does not change.
+    pub fn seek(&self, stream_id: &str) -> Result<(), StateError> {
+        self.check_active("SEEK", stream_id)
+    }
+
+    /// Check that `event` (e.g. PAUSE) for `stream_id` applies to the active
+    /// stream. The state does not change.
+    pub fn check_active(&self, event: &'static str, stream_id: &str) -> Result<(), StateError> {
+        if self.active_stream().is_none() {
+            return Err(self.illegal(event));
+        }
+        self.require_stream(event, stream_id)
+    }
+
+    fn require_stream(&self, event: &'static str, stream_id: &str) -> Result<(), StateError> {
+        let expected = self.active_stream().unwrap_or_default();
+        if expected != stream_id {
+            return Err(StateError::StreamMismatch {
+                event,
+                expected: expected.to_string(),
+                actual: stream_id.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn illegal(&self, event: &'static str) -> StateError {
+        StateError::IllegalTransition {
+            state: self.name(),
+            event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_upload_cycle() {
+        let mut state = SessionState::default();
+        state.start("a").unwrap();
+        assert_eq!(state.active_stream(), Some("a"));
+        assert_eq!(state.data(10).unwrap(), "a");
+        assert_eq!(state.data(5).unwrap(), "a");
+        assert_eq!(state.stop("a").unwrap(), 15);
+        assert_eq!(state.name(), "FINALIZED");
+        assert_eq!(state.active_stream(), None);
+
+        // A finalized session can start the next stream
+        state.start("b").unwrap();
+        assert_eq!(state.stop("b").unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_data_and_stop_before_start() {
+        let mut state = SessionState::default();
+        assert_eq!(
+            state.data(1).unwrap_err(),
+            StateError::IllegalTransition {
+                state: "IDLE",
+                event: "DATA"
+            }
+        );
+        assert!(state.stop("a").is_err());
+        assert_eq!(state, SessionState::Idle);
+    }
+
+    #[test]
+    fn rejects_second_start_while_uploading() {
+        let mut state = SessionState::default();
+        state.start("a").unwrap();
+        state.data(1).unwrap();
+        let err = state.start("b").unwrap_err();
+        assert_eq!(err.to_string(), "START is not allowed while UPLOADING");
+        assert_eq!(state.active_stream(), Some("a"));
+    }
+
+    #[test]
+    fn rejects_stop_for_other_stream() {
+        let mut state = SessionState::default();
+        state.start("a").unwrap();
+        assert!(matches!(
+            state.stop("b"),
+            Err(StateError::StreamMismatch { .. })
+        ));
+        assert_eq!(state.active_stream(), Some("a"));
+    }
+
+    #[test]
+    fn seek_applies_to_the_active_stream_only() {
+        let mut state = SessionState::default();
+        assert!(state.seek("a").is_err());
+        state.start("a").unwrap();
+        state.seek("a").unwrap();
+        assert!(matches!(
+            state.seek("b"),
+            Err(StateError::StreamMismatch { event: "SEEK", .. })
+        ));
+        state.stop("a").unwrap();
+        assert!(state.seek("a").is_err());
+    }
+
+    #[test]
+    fn rejects_data_after_finalize() {
+        let mut state = SessionState::default();
+        state.start("a").unwrap();
+        state.stop("a").unwrap();
+        assert!(state.data(1).is_err());
+        assert_eq!(state.name(), "FINALIZED");
+    }
+}
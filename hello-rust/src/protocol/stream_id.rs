@@ -0,0 +1,153 @@
+// Stream ID rules.
+// Servers store every stream in files named after its ID, so an ID must be a
+// single file name that is valid on every platform: no path separators or
+// characters Windows rejects, no device names such as CON or LPT1, no
+// trailing dots or spaces (which Windows strips), and short enough to leave
+// room for the cache directory and the sidecar extensions.
+// The one exception is a namespace prefix: multi-tenant servers give every
+// tenant's streams IDs of the form `tenant/stream-id`, kept in a directory of
+// their own, where the namespace consists of letters, digits, '-' and '_'.
+
+use thiserror::Error;
+
+/// Longest accepted stream ID in bytes.
+pub const MAX_STREAM_ID_LEN: usize = 128;
+
+/// Separator between the namespace and the rest of a stream ID.
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// Characters Windows does not allow in file names, besides control
+/// characters.
+const RESERVED_CHARACTERS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// File names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a stream ID was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InvalidStreamId {
+    #[error("stream ID is empty")]
+    Empty,
+    #[error("stream ID is longer than {MAX_STREAM_ID_LEN} bytes")]
+    TooLong,
+    #[error("stream ID contains `{0}`, which is not allowed in file names")]
+    ReservedCharacter(char),
+    #[error("stream ID `{0}` is not a file name")]
+    NotAFileName(String),
+    #[error("stream ID `{0}` is a device name reserved on Windows")]
+    ReservedName(String),
+}
+
+/// Check whether `name` can be a namespace: letters, digits, '-' and '_'.
+pub fn is_valid_namespace(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Split `stream_id` into its namespace, if it starts with a valid one, and
+/// the rest.
+pub fn split_namespace(stream_id: &str) -> (Option<&str>, &str) {
+    match stream_id.split_once(NAMESPACE_SEPARATOR) {
+        Some((namespace, rest)) if is_valid_namespace(namespace) => (Some(namespace), rest),
+        _ => (None, stream_id),
+    }
+}
+
+/// Check that `stream_id`, after its namespace if any, can be used as a file
+/// name on every platform.
+pub fn validate_stream_id(stream_id: &str) -> Result<(), InvalidStreamId> {
+    if stream_id.len() > MAX_STREAM_ID_LEN {
+        return Err(InvalidStreamId::TooLong);
+    }
+    let (_, stream_id) = split_namespace(stream_id);
+    if stream_id.is_empty() {
+        return Err(InvalidStreamId::Empty);
+    }
+    if let Some(c) = stream_id
+        .chars()
+        .find(|c| c.is_control() || RESERVED_CHARACTERS.contains(c))
+    {
+        return Err(InvalidStreamId::ReservedCharacter(c));
+    }
+    if stream_id.starts_with('.') || stream_id.ends_with(['.', ' ']) {
+        return Err(InvalidStreamId::NotAFileName(stream_id.to_string()));
+    }
+
+    let stem = stream_id.split('.').next().unwrap_or(stream_id).trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| stem.eq_ignore_ascii_case(name))
+    {
+        return Err(InvalidStreamId::ReservedName(stream_id.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_and_descriptive_ids_are_accepted() {
+        for id in [
+            "stream-3f2a9c1d",
+            "podcast.ep-12",
+            "Über Mix 2024",
+            "console",
+            "team-a/stream-3f2a9c1d",
+        ] {
+            assert_eq!(validate_stream_id(id), Ok(()), "{}", id);
+        }
+    }
+
+    #[test]
+    fn ids_that_are_not_portable_file_names_are_rejected() {
+        assert_eq!(validate_stream_id(""), Err(InvalidStreamId::Empty));
+        assert_eq!(
+            validate_stream_id(&"a".repeat(MAX_STREAM_ID_LEN + 1)),
+            Err(InvalidStreamId::TooLong)
+        );
+        assert_eq!(
+            validate_stream_id("../etc/passwd"),
+            Err(InvalidStreamId::ReservedCharacter('/'))
+        );
+        for id in ["team-a/../b", "team a/b", "team-a/b/c"] {
+            assert_eq!(
+                validate_stream_id(id),
+                Err(InvalidStreamId::ReservedCharacter('/')),
+                "{}",
+                id
+            );
+        }
+        assert_eq!(validate_stream_id("team-a/"), Err(InvalidStreamId::Empty));
+        assert_eq!(
+            validate_stream_id("a\\b"),
+            Err(InvalidStreamId::ReservedCharacter('\\'))
+        );
+        assert_eq!(
+            validate_stream_id("c:stream"),
+            Err(InvalidStreamId::ReservedCharacter(':'))
+        );
+        assert_eq!(
+            validate_stream_id("tab\there"),
+            Err(InvalidStreamId::ReservedCharacter('\t'))
+        );
+        for id in ["..", ".hidden", "trailing.", "trailing "] {
+            assert_eq!(
+                validate_stream_id(id),
+                Err(InvalidStreamId::NotAFileName(id.to_string()))
+            );
+        }
+        for id in ["CON", "nul", "Com1.wav", "lpt9 .txt"] {
+            assert_eq!(
+                validate_stream_id(id),
+                Err(InvalidStreamId::ReservedName(id.to_string()))
+            );
+        }
+    }
+}
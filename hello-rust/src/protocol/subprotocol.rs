@@ -0,0 +1,66 @@
+// WebSocket subprotocol of the audio stream protocol.
+// Clients offer `audio-stream.v1` in the Sec-WebSocket-Protocol handshake
+// header and servers echo it back, as browser WebSocket APIs and strict
+// gateways expect once a subprotocol is requested. Handshakes without the
+// header are still accepted, so older peers keep working.
+
+/// Subprotocol name of this protocol version.
+pub const SUBPROTOCOL: &str = "audio-stream.v1";
+
+/// Handshake header carrying the offered or selected subprotocols.
+pub const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+/// Outcome of matching a handshake's subprotocol offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubprotocolOffer {
+    /// No subprotocol was offered.
+    None,
+    /// [`SUBPROTOCOL`] was among the offers and is selected.
+    Supported,
+    /// Only other subprotocols were offered.
+    Unsupported(Vec<String>),
+}
+
+impl SubprotocolOffer {
+    /// Match the values of the Sec-WebSocket-Protocol headers of a handshake,
+    /// each a comma-separated list of names.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let offers: Vec<String> = values
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        if offers.is_empty() {
+            SubprotocolOffer::None
+        } else if offers.iter().any(|name| name == SUBPROTOCOL) {
+            SubprotocolOffer::Supported
+        } else {
+            SubprotocolOffer::Unsupported(offers)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offers_match_across_headers_and_lists() {
+        assert_eq!(SubprotocolOffer::parse([]), SubprotocolOffer::None);
+        assert_eq!(SubprotocolOffer::parse([" , "]), SubprotocolOffer::None);
+        assert_eq!(
+            SubprotocolOffer::parse(["chat, audio-stream.v1"]),
+            SubprotocolOffer::Supported
+        );
+        assert_eq!(
+            SubprotocolOffer::parse(["chat", "audio-stream.v1"]),
+            SubprotocolOffer::Supported
+        );
+        assert_eq!(
+            SubprotocolOffer::parse(["audio-stream.v2,chat"]),
+            SubprotocolOffer::Unsupported(vec!["audio-stream.v2".to_string(), "chat".to_string()])
+        );
+    }
+}
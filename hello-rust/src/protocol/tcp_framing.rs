@@ -0,0 +1,111 @@
+// Length-prefixed framing of the raw TCP transport (tcp:// URIs).
+// Without WebSocket framing and the HTTP upgrade, every message travels as a
+// 4-byte big-endian length, a kind byte, and the payload, where the length
+// counts the kind byte and the payload. Kinds reuse the WebSocket opcodes, so
+// text control messages, binary data, and closes keep their meaning.
+
+use thiserror::Error;
+
+/// URI scheme selecting the raw TCP transport.
+pub const TCP_SCHEME: &str = "tcp";
+
+/// Bytes before the payload of a frame: length and kind.
+pub const TCP_HEADER_LEN: usize = 5;
+
+/// Largest accepted frame length, kind byte included.
+pub const MAX_TCP_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Kind of a raw TCP frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFrameKind {
+    /// JSON control message.
+    Text,
+    /// Audio data or a binary-encoded control message.
+    Binary,
+    /// The sender is closing the connection; the payload is a reason.
+    Close,
+}
+
+impl TcpFrameKind {
+    fn opcode(self) -> u8 {
+        match self {
+            TcpFrameKind::Text => 0x1,
+            TcpFrameKind::Binary => 0x2,
+            TcpFrameKind::Close => 0x8,
+        }
+    }
+
+    fn from_opcode(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x1 => Some(TcpFrameKind::Text),
+            0x2 => Some(TcpFrameKind::Binary),
+            0x8 => Some(TcpFrameKind::Close),
+            _ => None,
+        }
+    }
+}
+
+/// Malformed raw TCP frame header.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TcpFrameError {
+    #[error("TCP frame length {0} is out of range")]
+    Length(usize),
+    #[error("Unknown TCP frame kind {0:#04x}")]
+    UnknownKind(u8),
+}
+
+/// Build a frame carrying `payload`.
+pub fn encode_tcp_frame(kind: TcpFrameKind, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(TCP_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    frame.push(kind.opcode());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Parse a frame header into the frame kind and the payload length.
+pub fn decode_tcp_header(
+    header: &[u8; TCP_HEADER_LEN],
+) -> Result<(TcpFrameKind, usize), TcpFrameError> {
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if length == 0 || length > MAX_TCP_FRAME_LEN {
+        return Err(TcpFrameError::Length(length));
+    }
+    let kind = TcpFrameKind::from_opcode(header[4]).ok_or(TcpFrameError::UnknownKind(header[4]))?;
+    Ok((kind, length - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_and_reject_bad_headers() {
+        let frame = encode_tcp_frame(TcpFrameKind::Text, b"{\"type\":\"STOP\"}");
+        let header: [u8; TCP_HEADER_LEN] = frame[..TCP_HEADER_LEN].try_into().unwrap();
+        assert_eq!(
+            decode_tcp_header(&header),
+            Ok((TcpFrameKind::Text, frame.len() - TCP_HEADER_LEN))
+        );
+
+        let empty = encode_tcp_frame(TcpFrameKind::Close, &[]);
+        assert_eq!(empty, vec![0, 0, 0, 1, 0x8]);
+        assert_eq!(
+            decode_tcp_header(&empty[..].try_into().unwrap()),
+            Ok((TcpFrameKind::Close, 0))
+        );
+
+        assert_eq!(
+            decode_tcp_header(&[0, 0, 0, 0, 0x2]),
+            Err(TcpFrameError::Length(0))
+        );
+        assert_eq!(
+            decode_tcp_header(&[0x7f, 0, 0, 0, 0x2]),
+            Err(TcpFrameError::Length(0x7f00_0000))
+        );
+        assert_eq!(
+            decode_tcp_header(&[0, 0, 0, 1, 0x9]),
+            Err(TcpFrameError::UnknownKind(0x9))
+        );
+    }
+}
@@ -0,0 +1,50 @@
+// Cluster router deciding whether this node owns a stream.
+// Installed once at startup when cluster mode is enabled; requests for streams
+// owned by another node are answered with a MOVED redirect.
+
+use std::sync::{Arc, OnceLock};
+
+use super::HashRing;
+
+static ROUTER: OnceLock<Arc<ClusterRouter>> = OnceLock::new();
+
+/// Routes stream IDs to cluster nodes.
+pub struct ClusterRouter {
+    self_uri: String,
+    ring: HashRing,
+}
+
+impl ClusterRouter {
+    /// Create a router for the node reachable at `self_uri`.
+    pub fn new(self_uri: String, ring: HashRing) -> Self {
+        Self { self_uri, ring }
+    }
+
+    /// Install the process-wide router. Only the first call has an effect.
+    pub fn install(router: ClusterRouter) -> Arc<Self> {
+        ROUTER.get_or_init(|| Arc::new(router)).clone()
+    }
+
+    /// Get the installed router, if cluster mode is enabled.
+    pub fn current() -> Option<Arc<Self>> {
+        ROUTER.get().cloned()
+    }
+
+    /// Get the URI of the node owning a stream when it is not this node.
+    pub fn redirect_for(&self, stream_id: &str) -> Option<String> {
+        match self.ring.node_for(stream_id) {
+            Some(owner) if owner != self.self_uri => Some(owner.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Get this node's URI.
+    pub fn get_self_uri(&self) -> &str {
+        &self.self_uri
+    }
+
+    /// Get the hash ring.
+    pub fn get_ring(&self) -> &HashRing {
+        &self.ring
+    }
+}
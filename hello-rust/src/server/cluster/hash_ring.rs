@@ -0,0 +1,52 @@
+// Consistent hash ring mapping stream IDs to cluster nodes.
+// Uses SHA-256 so every node (and every build) agrees on placement.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Virtual nodes per physical node, smoothing the key distribution.
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// Consistent hash ring of node URIs.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+    nodes: Vec<String>,
+}
+
+impl HashRing {
+    /// Create a ring from node URIs with the given number of virtual nodes each.
+    pub fn new(nodes: &[String], virtual_nodes: usize) -> Self {
+        let mut ring = BTreeMap::new();
+        for node in nodes {
+            for replica in 0..virtual_nodes {
+                ring.insert(Self::hash(&format!("{}#{}", node, replica)), node.clone());
+            }
+        }
+
+        Self {
+            ring,
+            nodes: nodes.to_vec(),
+        }
+    }
+
+    /// Get the node owning a key.
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let hash = Self::hash(key);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Get all nodes in the ring.
+    pub fn get_nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    fn hash(key: &str) -> u64 {
+        let digest = Sha256::digest(key.as_bytes());
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+}
@@ -0,0 +1,6 @@
+// Server cluster module - consistent-hash routing of streams across nodes
+pub mod cluster_router;
+pub mod hash_ring;
+
+pub use cluster_router::ClusterRouter;
+pub use hash_ring::HashRing;
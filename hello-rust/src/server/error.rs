@@ -0,0 +1,86 @@
+// Error type returned by the server library.
+
+use thiserror::Error;
+
+use crate::protocol::encoding::EncodingError;
+use crate::protocol::StateError;
+use crate::server::memory::StreamError;
+
+/// Server failure, grouped by category so callers can match on it.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// The command line or configuration is inconsistent.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// The listener could not be bound.
+    #[error("Connection error on {addr}: {source}")]
+    Connection {
+        addr: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A client sent a message the protocol does not allow.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// A client sent a well-formed message with a value the server refuses.
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// A client sent an admin command without the admin token.
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    /// A client sent a stream operation without a valid access token.
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    /// A data frame was larger than the chunk size advertised in STARTED.
+    #[error("Chunk of {size} bytes exceeds the maximum chunk size of {limit} bytes")]
+    ChunkTooLarge { size: usize, limit: usize },
+    /// An access token used up its storage or transfer quota.
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    /// The server is in drain mode and takes no new uploads.
+    #[error("Server is draining; start the upload on another server")]
+    Draining,
+    /// A stream operation failed in the cache or registry.
+    #[error(transparent)]
+    Storage(#[from] StreamError),
+}
+
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+impl ServerError {
+    /// Get the code reported in protocol ERROR messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::Config(_) => "CONFIG_ERROR",
+            ServerError::Connection { .. } => "CONNECTION_ERROR",
+            ServerError::Protocol(_) => "PROTOCOL_ERROR",
+            ServerError::InvalidRequest(_) => "INVALID_REQUEST",
+            ServerError::Forbidden(_) => "FORBIDDEN",
+            ServerError::Unauthorized(_) => "UNAUTHORIZED",
+            ServerError::ChunkTooLarge { .. } => "CHUNK_TOO_LARGE",
+            ServerError::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            ServerError::Draining => "SERVER_DRAINING",
+            ServerError::Storage(e) => e.code(),
+        }
+    }
+
+    /// Get the stream the error refers to, if any.
+    pub fn stream_id(&self) -> Option<&str> {
+        match self {
+            ServerError::Storage(e) => Some(e.stream_id()),
+            _ => None,
+        }
+    }
+}
+
+impl From<StateError> for ServerError {
+    fn from(e: StateError) -> Self {
+        ServerError::Protocol(e.to_string())
+    }
+}
+
+impl From<EncodingError> for ServerError {
+    fn from(e: EncodingError) -> Self {
+        ServerError::Protocol(e.to_string())
+    }
+}
@@ -0,0 +1,66 @@
+// Audit logger consuming stream lifecycle events from the event bus.
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use super::{StreamEvent, StreamEventBus};
+use crate::logger;
+
+/// Spawn a task that writes every stream lifecycle event to the log.
+pub fn spawn(bus: &StreamEventBus) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => log_event(&event),
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!("Audit logger lagged, {} events skipped", skipped));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn log_event(event: &StreamEvent) {
+    match event {
+        StreamEvent::ChunkWritten {
+            stream_id,
+            offset,
+            length,
+            ..
+        } => logger::log_debug(&format!(
+            "[audit] {} stream={} offset={} length={}",
+            event.name(),
+            stream_id,
+            offset,
+            length
+        )),
+        StreamEvent::StreamFinalized {
+            stream_id,
+            total_size,
+            ..
+        } => logger::log_info(&format!(
+            "[audit] {} stream={} size={}",
+            event.name(),
+            stream_id,
+            total_size
+        )),
+        StreamEvent::VersionDeleted {
+            stream_id,
+            generation,
+            ..
+        } => logger::log_info(&format!(
+            "[audit] {} stream={} version={}",
+            event.name(),
+            stream_id,
+            generation
+        )),
+        _ => logger::log_info(&format!(
+            "[audit] {} stream={}",
+            event.name(),
+            event.stream_id()
+        )),
+    }
+}
@@ -0,0 +1,132 @@
+// Live feed of stream activity for monitoring dashboards.
+// `GET /events` on the WebSocket port answers with a Server-Sent Events stream
+// of the lifecycle events published on the event bus from then on:
+//   event: stream.finalized
+//   data: {"streamId":"1a2b3c4d","size":92124,"checksum":"...","timestamp":"..."}
+// Event types are stream.created, stream.finalized, stream.deleted, and
+// stream.evicted (deleted by expiry). The feed is an admin command: it needs
+// the server's --admin-token, as `Authorization: Bearer <token>` or, for
+// browser EventSource clients, as the `token` query parameter. It ends when
+// the client disconnects or the server starts draining.
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::TryRecvError;
+
+use super::{StreamEvent, StreamEventBus};
+use crate::server::network::drain;
+use crate::server::network::http_download::{self, RequestHead};
+
+/// URL path of the feed route.
+pub const EVENTS_PATH: &str = "/events";
+
+/// How often the feed checks for new events when idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Idle time after which a comment line is sent, so proxies keep the
+/// connection open and closed clients are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Data of one feed event.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FeedEvent<'a> {
+    stream_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    timestamp: String,
+}
+
+/// Check whether `path` is the feed route, with or without a query.
+pub fn is_events_path(path: &str) -> bool {
+    path.split('?').next() == Some(EVENTS_PATH)
+}
+
+/// Serve the feed on `stream` until the client disconnects.
+pub fn serve(stream: &mut TcpStream, head: &RequestHead, is_head: bool) {
+    let token = head
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| query_token(&head.path))
+        .unwrap_or_default();
+    if drain::authorize(token.trim()).is_err() {
+        http_download::write_status(stream, 403, "Forbidden");
+        return;
+    }
+
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if stream.write_all(header.as_bytes()).is_err() || is_head {
+        return;
+    }
+
+    let mut receiver = StreamEventBus::instance().subscribe();
+    let mut last_write = Instant::now();
+    while !drain::is_draining() {
+        let message = match receiver.try_recv() {
+            Ok(event) => match format_event(&event) {
+                Some(message) => message,
+                None => continue,
+            },
+            Err(TryRecvError::Lagged(skipped)) => format!(": {} events skipped\n\n", skipped),
+            Err(TryRecvError::Empty) if last_write.elapsed() >= KEEPALIVE_INTERVAL => {
+                ": keepalive\n\n".to_string()
+            }
+            Err(TryRecvError::Empty) => {
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(TryRecvError::Closed) => break,
+        };
+        if stream.write_all(message.as_bytes()).is_err() {
+            break;
+        }
+        last_write = Instant::now();
+    }
+}
+
+/// Format a lifecycle event as an SSE message; chunk writes are left out.
+fn format_event(event: &StreamEvent) -> Option<String> {
+    let version = match event {
+        StreamEvent::VersionDeleted { generation, .. } => Some(*generation),
+        _ => None,
+    };
+    let (name, size, checksum) = match event {
+        StreamEvent::StreamCreated { .. } => ("stream.created", None, None),
+        StreamEvent::StreamFinalized {
+            total_size,
+            checksum,
+            ..
+        } => ("stream.finalized", Some(*total_size), checksum.as_deref()),
+        StreamEvent::StreamDeleted { expired: true, .. } => ("stream.evicted", None, None),
+        StreamEvent::StreamDeleted { .. } => ("stream.deleted", None, None),
+        StreamEvent::VersionDeleted { .. } => ("stream.version_deleted", None, None),
+        StreamEvent::ChunkWritten { .. } => return None,
+    };
+    let data = FeedEvent {
+        stream_id: event.stream_id(),
+        size,
+        checksum,
+        version,
+        timestamp: format_time(event.timestamp()),
+    };
+    let data = serde_json::to_string(&data).ok()?;
+    Some(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+fn query_token(path: &str) -> Option<&str> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+}
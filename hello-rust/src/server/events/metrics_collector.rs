@@ -0,0 +1,254 @@
+// Stream metrics collected from the event bus.
+// Counters are rendered in the Prometheus text exposition format and served
+// at `GET /metrics` on the WebSocket port, followed by gauges of the buffer
+// pool's pressure and, with access tokens configured, the usage of each token.
+// Results of the integrity scrubber are reported here directly, as they are
+// not stream lifecycle events.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use super::{StreamEvent, StreamEventBus};
+use crate::logger;
+use crate::protocol::TokenUsage;
+use crate::server::memory::{MemoryPoolManager, ScrubReport};
+use crate::server::network::TokenQuotas;
+
+/// URL path of the metrics route.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Stream lifecycle counters.
+#[derive(Default)]
+pub struct StreamMetrics {
+    streams_created: AtomicU64,
+    streams_finalized: AtomicU64,
+    streams_deleted: AtomicU64,
+    versions_deleted: AtomicU64,
+    chunks_written: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_finalized: AtomicU64,
+    streams_scrubbed: AtomicU64,
+    streams_corrupted: AtomicU64,
+    memory_pool: OnceLock<Arc<MemoryPoolManager>>,
+}
+
+impl StreamMetrics {
+    /// Get the singleton instance of StreamMetrics.
+    pub fn instance() -> Arc<Self> {
+        static INSTANCE: OnceLock<Arc<StreamMetrics>> = OnceLock::new();
+
+        INSTANCE
+            .get_or_init(|| Arc::new(StreamMetrics::default()))
+            .clone()
+    }
+
+    /// Report the pressure of the connection buffer pool with the counters.
+    pub fn watch_memory_pool(&self, pool: Arc<MemoryPoolManager>) {
+        let _ = self.memory_pool.set(pool);
+    }
+
+    /// Update the counters for one event.
+    pub fn record(&self, event: &StreamEvent) {
+        match event {
+            StreamEvent::StreamCreated { .. } => {
+                self.streams_created.fetch_add(1, Ordering::Relaxed);
+            }
+            StreamEvent::ChunkWritten { length, .. } => {
+                self.chunks_written.fetch_add(1, Ordering::Relaxed);
+                self.bytes_written
+                    .fetch_add(*length as u64, Ordering::Relaxed);
+            }
+            StreamEvent::StreamFinalized { total_size, .. } => {
+                self.streams_finalized.fetch_add(1, Ordering::Relaxed);
+                self.bytes_finalized
+                    .fetch_add(*total_size, Ordering::Relaxed);
+            }
+            StreamEvent::StreamDeleted { .. } => {
+                self.streams_deleted.fetch_add(1, Ordering::Relaxed);
+            }
+            StreamEvent::VersionDeleted { .. } => {
+                self.versions_deleted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Count the streams checked by one integrity scrub.
+    pub fn record_scrub(&self, report: &ScrubReport) {
+        let corrupted = report.corrupted.len() as u64;
+        self.streams_scrubbed
+            .fetch_add(report.verified as u64 + corrupted, Ordering::Relaxed);
+        self.streams_corrupted
+            .fetch_add(corrupted, Ordering::Relaxed);
+    }
+
+    /// Render all counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "audio_stream_streams_created_total",
+                "Streams created",
+                &self.streams_created,
+            ),
+            (
+                "audio_stream_streams_finalized_total",
+                "Streams finalized",
+                &self.streams_finalized,
+            ),
+            (
+                "audio_stream_streams_deleted_total",
+                "Streams deleted",
+                &self.streams_deleted,
+            ),
+            (
+                "audio_stream_versions_deleted_total",
+                "Previous stream generations deleted beyond --keep-versions",
+                &self.versions_deleted,
+            ),
+            (
+                "audio_stream_chunks_written_total",
+                "Chunks written to stream caches",
+                &self.chunks_written,
+            ),
+            (
+                "audio_stream_bytes_written_total",
+                "Bytes written to stream caches",
+                &self.bytes_written,
+            ),
+            (
+                "audio_stream_bytes_finalized_total",
+                "Total size of finalized streams in bytes",
+                &self.bytes_finalized,
+            ),
+            (
+                "audio_stream_streams_scrubbed_total",
+                "Finalized streams re-hashed by the integrity scrubber",
+                &self.streams_scrubbed,
+            ),
+            (
+                "audio_stream_streams_corrupted_total",
+                "Streams found corrupted by the integrity scrubber",
+                &self.streams_corrupted,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        if let Some(pool) = self.memory_pool.get() {
+            Self::render_pool(&mut output, pool);
+        }
+        if let Some(quotas) = TokenQuotas::current() {
+            Self::render_tokens(&mut output, quotas);
+        }
+        output
+    }
+
+    fn render_tokens(output: &mut String, quotas: &TokenQuotas) {
+        type Value = fn(&TokenUsage) -> Option<u64>;
+
+        let usages = quotas.usages();
+        let series: [(&str, &str, &str, Value); 4] = [
+            (
+                "audio_stream_token_stored_bytes",
+                "Bytes stored in streams uploaded with each access token",
+                "gauge",
+                |usage| Some(usage.stored_bytes),
+            ),
+            (
+                "audio_stream_token_transferred_bytes_total",
+                "Bytes uploaded and downloaded with each access token",
+                "counter",
+                |usage| Some(usage.transferred_bytes),
+            ),
+            (
+                "audio_stream_token_storage_quota_bytes",
+                "Storage quota of each access token",
+                "gauge",
+                |usage| usage.storage_quota,
+            ),
+            (
+                "audio_stream_token_transfer_quota_bytes",
+                "Transfer quota of each access token",
+                "gauge",
+                |usage| usage.transfer_quota,
+            ),
+        ];
+        for (name, help, kind, value) in series {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            for usage in &usages {
+                if let Some(value) = value(usage) {
+                    let _ = writeln!(output, "{}{{token=\"{}\"}} {}", name, usage.token, value);
+                }
+            }
+        }
+    }
+
+    fn render_pool(output: &mut String, pool: &MemoryPoolManager) {
+        let pressure = pool.pressure();
+        let gauges = [
+            (
+                "audio_stream_pool_buffers_available",
+                "Pooled buffers ready to lease",
+                pressure.available as u64,
+            ),
+            (
+                "audio_stream_pool_buffers_in_use",
+                "Pooled buffers leased by connections",
+                pressure.in_use as u64,
+            ),
+            (
+                "audio_stream_pool_buffers_in_use_peak",
+                "Most pooled buffers leased at the same time",
+                pressure.peak_in_use as u64,
+            ),
+            (
+                "audio_stream_pool_buffer_bytes",
+                "Size of a pooled buffer in bytes",
+                pool.get_buffer_size() as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+
+        let name = "audio_stream_pool_exhausted_total";
+        let _ = writeln!(
+            output,
+            "# HELP {} Buffers allocated while the pool was empty",
+            name
+        );
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        let _ = writeln!(output, "{} {}", name, pressure.exhausted);
+    }
+}
+
+/// Spawn a task that feeds every stream lifecycle event into StreamMetrics.
+pub fn spawn(bus: &StreamEventBus) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let metrics = StreamMetrics::instance();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => metrics.record(&event),
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!(
+                        "Metrics collector lagged, {} events skipped",
+                        skipped
+                    ));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
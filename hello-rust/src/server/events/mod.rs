@@ -0,0 +1,9 @@
+// Server events module - stream lifecycle notifications
+pub mod audit_logger;
+pub mod event_feed;
+#[cfg(feature = "metrics")]
+pub mod metrics_collector;
+pub mod stream_event_bus;
+pub mod webhook_notifier;
+
+pub use stream_event_bus::{StreamEvent, StreamEventBus};
@@ -0,0 +1,161 @@
+// Stream event bus for publishing stream lifecycle events.
+// Cross-cutting consumers (metrics, audit logging, webhooks, the admin event
+// feed) subscribe here instead of hooking into StreamManager directly.
+
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use tokio::sync::broadcast;
+
+// Number of events buffered per subscriber before slow receivers start lagging
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Stream lifecycle event.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    StreamCreated {
+        stream_id: String,
+        timestamp: SystemTime,
+    },
+    ChunkWritten {
+        stream_id: String,
+        offset: u64,
+        length: usize,
+        timestamp: SystemTime,
+    },
+    StreamFinalized {
+        stream_id: String,
+        total_size: u64,
+        checksum: Option<String>,
+        timestamp: SystemTime,
+    },
+    StreamDeleted {
+        stream_id: String,
+        /// Deleted by expiry rather than on request.
+        expired: bool,
+        timestamp: SystemTime,
+    },
+    /// A previous generation of a stream was deleted to keep at most
+    /// --keep-versions of them; the stream itself remains.
+    VersionDeleted {
+        stream_id: String,
+        generation: u64,
+        timestamp: SystemTime,
+    },
+}
+
+impl StreamEvent {
+    /// Get the event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::StreamCreated { .. } => "StreamCreated",
+            StreamEvent::ChunkWritten { .. } => "ChunkWritten",
+            StreamEvent::StreamFinalized { .. } => "StreamFinalized",
+            StreamEvent::StreamDeleted { .. } => "StreamDeleted",
+            StreamEvent::VersionDeleted { .. } => "VersionDeleted",
+        }
+    }
+
+    /// Get the ID of the stream this event refers to.
+    pub fn stream_id(&self) -> &str {
+        match self {
+            StreamEvent::StreamCreated { stream_id, .. }
+            | StreamEvent::ChunkWritten { stream_id, .. }
+            | StreamEvent::StreamFinalized { stream_id, .. }
+            | StreamEvent::StreamDeleted { stream_id, .. }
+            | StreamEvent::VersionDeleted { stream_id, .. } => stream_id,
+        }
+    }
+
+    /// Get the time at which the event was published.
+    pub fn timestamp(&self) -> SystemTime {
+        match self {
+            StreamEvent::StreamCreated { timestamp, .. }
+            | StreamEvent::ChunkWritten { timestamp, .. }
+            | StreamEvent::StreamFinalized { timestamp, .. }
+            | StreamEvent::StreamDeleted { timestamp, .. }
+            | StreamEvent::VersionDeleted { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+/// Broadcast bus for stream lifecycle events.
+pub struct StreamEventBus {
+    sender: broadcast::Sender<StreamEvent>,
+}
+
+impl StreamEventBus {
+    /// Get the singleton instance of StreamEventBus.
+    pub fn instance() -> Arc<Self> {
+        static INSTANCE: OnceLock<Arc<StreamEventBus>> = OnceLock::new();
+
+        INSTANCE
+            .get_or_init(|| {
+                let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+                Arc::new(Self { sender })
+            })
+            .clone()
+    }
+
+    /// Publish an event to all current subscribers.
+    /// Events published while nobody is subscribed are dropped.
+    pub fn publish(&self, event: StreamEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribe to events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Get the number of active subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Publish a StreamCreated event.
+    pub fn stream_created(&self, stream_id: &str) {
+        self.publish(StreamEvent::StreamCreated {
+            stream_id: stream_id.to_string(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Publish a ChunkWritten event.
+    pub fn chunk_written(&self, stream_id: &str, offset: u64, length: usize) {
+        self.publish(StreamEvent::ChunkWritten {
+            stream_id: stream_id.to_string(),
+            offset,
+            length,
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Publish a StreamFinalized event.
+    pub fn stream_finalized(&self, stream_id: &str, total_size: u64, checksum: Option<String>) {
+        self.publish(StreamEvent::StreamFinalized {
+            stream_id: stream_id.to_string(),
+            total_size,
+            checksum,
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Publish a StreamDeleted event.
+    pub fn stream_deleted(&self, stream_id: &str, expired: bool) {
+        self.publish(StreamEvent::StreamDeleted {
+            stream_id: stream_id.to_string(),
+            expired,
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Publish a VersionDeleted event.
+    pub fn version_deleted(&self, stream_id: &str, generation: u64) {
+        self.publish(StreamEvent::VersionDeleted {
+            stream_id: stream_id.to_string(),
+            generation,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
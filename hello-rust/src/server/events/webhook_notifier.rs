@@ -0,0 +1,121 @@
+// Webhook notifier posting stream finalization events to configured URLs.
+// Lets downstream processing pipelines react to new audio without polling.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use super::{StreamEvent, StreamEventBus};
+use crate::logger;
+use crate::server::memory::StreamManager;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// JSON payload delivered to webhook endpoints.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    pub event: String,
+    pub stream_id: String,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub metadata: HashMap<String, String>,
+    pub finalized_at: String,
+}
+
+/// Spawn a task that notifies every webhook URL when a stream becomes Ready.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    urls: Vec<String>,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let urls = Arc::new(urls);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized {
+                    stream_id,
+                    total_size,
+                    checksum,
+                    timestamp,
+                }) => {
+                    let metadata = stream_manager
+                        .get_stream(&stream_id)
+                        .map(|ctx| ctx.lock().unwrap().get_metadata().clone())
+                        .unwrap_or_default();
+
+                    let payload = WebhookPayload {
+                        event: "stream.finalized".to_string(),
+                        stream_id,
+                        size: total_size,
+                        checksum,
+                        metadata,
+                        finalized_at: format_time(timestamp),
+                    };
+
+                    for url in urls.iter() {
+                        let url = url.clone();
+                        let payload = payload.clone();
+                        tokio::task::spawn_blocking(move || deliver(&url, &payload));
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!(
+                        "Webhook notifier lagged, {} events skipped",
+                        skipped
+                    ));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// POST the payload to a single URL, retrying a bounded number of times.
+fn deliver(url: &str, payload: &WebhookPayload) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(WEBHOOK_TIMEOUT))
+        .build()
+        .into();
+
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        match agent.post(url).send_json(payload) {
+            Ok(response) => {
+                logger::log_info(&format!(
+                    "Webhook delivered: stream={} url={} status={}",
+                    payload.stream_id,
+                    url,
+                    response.status()
+                ));
+                return;
+            }
+            Err(e) => {
+                logger::log_warn(&format!(
+                    "Webhook attempt {}/{} failed: stream={} url={} error={}",
+                    attempt, WEBHOOK_MAX_ATTEMPTS, payload.stream_id, url, e
+                ));
+                if attempt < WEBHOOK_MAX_ATTEMPTS {
+                    std::thread::sleep(WEBHOOK_RETRY_DELAY * attempt);
+                }
+            }
+        }
+    }
+
+    logger::log_error(&format!(
+        "Webhook delivery failed: stream={} url={}",
+        payload.stream_id, url
+    ));
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+}
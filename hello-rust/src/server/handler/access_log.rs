@@ -0,0 +1,90 @@
+// Access log of protocol operations.
+// Installed once at startup with --access-log; every control message a client
+// sends is written to its own file as one line in Common Log Format, followed by
+// the duration in milliseconds, apart from the diagnostic logs:
+//   127.0.0.1 - team-a [16/Oct/2026:17:20:38 +0000] "GET stream-1a2b" OK 65536 3
+// The user is the name of the access token the connection authenticated with.
+// The status is OK, MOVED, or the code of the ERROR sent. Bytes count the
+// frames sent to the client, or for a STOP the bytes uploaded to the stream.
+
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::Local;
+use tracing::warn;
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+/// Status of an operation that sent neither ERROR nor MOVED.
+pub const STATUS_OK: &str = "OK";
+
+/// Responses of the operation in progress on a connection, tallied as they
+/// are sent.
+#[derive(Debug, Clone, Default)]
+pub struct OperationTally {
+    pub bytes: u64,
+    pub status: Option<String>,
+}
+
+/// One protocol operation.
+pub struct AccessEntry<'a> {
+    pub peer: Option<SocketAddr>,
+    pub token: Option<&'a str>,
+    pub op: &'a str,
+    pub stream_id: Option<&'a str>,
+    pub status: &'a str,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// Append-only access log file.
+pub struct AccessLog {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl AccessLog {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Install the process-wide access log. Only the first call has an effect.
+    pub fn install(log: AccessLog) -> &'static Self {
+        ACCESS_LOG.get_or_init(|| log)
+    }
+
+    /// Get the installed access log, if access logging is enabled.
+    pub fn current() -> Option<&'static Self> {
+        ACCESS_LOG.get()
+    }
+
+    /// Write one line for an operation.
+    pub fn record(&self, entry: &AccessEntry) {
+        let line = Self::format(entry);
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+
+    fn format(entry: &AccessEntry) -> String {
+        format!(
+            "{} - {} [{}] \"{} {}\" {} {} {}",
+            entry
+                .peer
+                .map_or_else(|| "-".to_string(), |peer| peer.ip().to_string()),
+            entry.token.unwrap_or("-"),
+            Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            entry.op,
+            entry.stream_id.unwrap_or("-"),
+            entry.status,
+            entry.bytes,
+            entry.duration.as_millis()
+        )
+    }
+}
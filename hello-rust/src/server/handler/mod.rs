@@ -1,4 +1,6 @@
 // Server handler module - message processing
+pub mod access_log;
 pub mod websocket_message_handler;
 
-pub use websocket_message_handler::WebSocketMessageHandler;
+pub use access_log::{AccessEntry, AccessLog, OperationTally};
+pub use websocket_message_handler::{ClientSession, WebSocketMessageHandler};
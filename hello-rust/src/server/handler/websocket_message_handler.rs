@@ -1,28 +1,90 @@
 // WebSocket message handler for processing client messages.
-// Handles START, STOP, and GET message types.
+// Handles HELLO, AUTH, START, SEEK, PAUSE, RESUME, STOP, GET, SIZE, STAT,
+// LIST, and PEAKS message types, and the DRAIN admin command.
 
-use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::server::memory::{MemoryPoolManager, StreamManager};
+use crate::protocol::{
+    frame_parts, split_namespace, split_timestamp, ControlMessage, Encoding, FrameDirection,
+    FrameDump, SessionState, FRAME_CONTROL, FRAME_DATA, FRAME_TIMESTAMPED_DATA,
+};
+use crate::server::cluster::ClusterRouter;
+use crate::server::error::ServerError;
+use crate::server::handler::access_log::{AccessEntry, AccessLog, OperationTally, STATUS_OK};
+use crate::server::memory::block_index::BLOCK_SIZE;
+use crate::server::memory::{PooledBuffer, StreamError, StreamManager, StreamStatus};
+use crate::server::network::{drain, http_download, Connection, TokenQuotas};
+use crate::server::processing::waveform_peaks;
+use tracing::{error, info, warn};
 use tungstenite::protocol::Message as WsMessage;
-use tungstenite::{Bytes, Utf8Bytes, WebSocket};
-
-/// WebSocket message types
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ControlMessage {
-    #[serde(rename = "type")]
-    pub msg_type: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub stream_id: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub offset: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub length: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub message: Option<String>,
+use tungstenite::{Bytes, Utf8Bytes};
+
+/// Streams one LIST answers with when the request sets no limit, and the
+/// most it answers with at all.
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Most tags a stream may carry, and the longest key or value.
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LEN: usize = 256;
+
+/// Per-connection state tracked by the server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientSession {
+    /// Upload state of the connection.
+    pub state: SessionState,
+    /// Control message encoding negotiated through HELLO.
+    pub encoding: Encoding,
+    /// Frame counters when frame dumping is enabled.
+    pub frame_dump: Option<FrameDump>,
+    /// Address of the client, as written to the access log.
+    pub peer: Option<SocketAddr>,
+    /// Responses of the control message being handled.
+    pub operation: OperationTally,
+    /// Name of the access token the connection authenticated with.
+    pub token: Option<String>,
+    /// Offset of the next data frame after a SEEK; `None` appends.
+    pub write_offset: Option<u64>,
+    /// Largest data frame accepted, advertised in STARTED.
+    pub max_chunk_size: Option<usize>,
+    /// Running digest of the download read with `checkpointEvery`, if any.
+    pub download_digest: Option<DownloadDigest>,
+    /// Bytes written since STARTED when the upload asked for ACKs.
+    pub acked_bytes: Option<u64>,
+}
+
+/// Running SHA-256 of a download read in order from offset 0, reported in
+/// CHECKPOINT messages.
+#[derive(Debug, Clone)]
+pub struct DownloadDigest {
+    stream_id: String,
+    /// Offset the next GET must read from to continue the digest.
+    offset: u64,
+    hasher: Sha256,
+    chunks: u64,
+}
+
+/// A binary frame checked by `admit_binary`.
+// Handled right after it is admitted, so boxing would not pay off
+#[allow(clippy::large_enum_variant)]
+enum BinaryFrame<'a> {
+    /// Audio data to write to the client's active stream.
+    Data {
+        stream_id: String,
+        data: &'a [u8],
+        timestamp: Option<u64>,
+    },
+    /// A control message in the negotiated binary encoding.
+    Control(ControlMessage),
+    /// A refused frame, and whether the connection may go on.
+    Failed(ServerError, bool),
+    /// A frame that violates the protocol.
+    Invalid(&'static str),
 }
 
 pub struct WebSocketMessageHandler;
@@ -30,233 +92,1619 @@ pub struct WebSocketMessageHandler;
 impl WebSocketMessageHandler {
     /// Handle a text (JSON) control message.
     pub fn handle_text_message(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         stream_mgr: &Arc<StreamManager>,
-        _mem_pool: &Arc<MemoryPoolManager>,
+        buffer: &mut PooledBuffer,
         client_id: usize,
         message: &str,
     ) {
         let data: Value = match serde_json::from_str(message) {
             Ok(v) => v,
             Err(e) => {
-                eprintln!("Invalid JSON message: {:?}", e);
+                warn!("Invalid JSON message: {:?}", e);
                 Self::send_error(websocket, clients, client_id, "Invalid JSON format");
                 return;
             }
         };
 
-        let msg_type = data["type"].as_str().unwrap_or("");
-
-        match msg_type {
-            "START" => Self::handle_start(websocket, clients, stream_mgr, client_id, &data),
-            "STOP" => Self::handle_stop(websocket, clients, stream_mgr, client_id, &data),
-            "GET" => Self::handle_get(websocket, clients, stream_mgr, client_id, &data),
-            _ => {
-                eprintln!("Unknown message type: {}", msg_type);
+        let msg_type = data["type"].as_str().unwrap_or("").to_string();
+        let request = match serde_json::from_value::<ControlMessage>(data) {
+            Ok(ControlMessage::Unknown) => {
+                warn!("Unknown message type: {}", msg_type);
                 Self::send_error(
                     websocket,
                     clients,
                     client_id,
                     &format!("Unknown message type: {}", msg_type),
                 );
+                return;
             }
-        }
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Invalid {} message: {}", msg_type, e);
+                Self::send_error(
+                    websocket,
+                    clients,
+                    client_id,
+                    &format!("Invalid {} message: {}", msg_type, e),
+                );
+                return;
+            }
+        };
+
+        Self::handle_control_message(websocket, clients, stream_mgr, buffer, client_id, request);
     }
 
-    /// Handle binary audio data.
-    pub fn handle_binary_message(
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+    /// Handle a decoded control message, writing it to the access log when
+    /// one is installed.
+    fn handle_control_message(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         stream_mgr: &Arc<StreamManager>,
+        buffer: &mut PooledBuffer,
         client_id: usize,
-        data: &[u8],
+        request: ControlMessage,
+    ) {
+        let span = tracing::info_span!(
+            "request",
+            r#type = request.type_name(),
+            stream_id = request.stream_id()
+        );
+        let _entered = span.enter();
+
+        let Some(access_log) = AccessLog::current() else {
+            Self::dispatch(websocket, clients, stream_mgr, buffer, client_id, request);
+            return;
+        };
+        let op = request.type_name();
+        let stream_id = request.stream_id().map(str::to_string);
+        let started = Instant::now();
+        Self::session_mut(clients, client_id, |session| {
+            session.operation = OperationTally::default()
+        });
+        Self::dispatch(websocket, clients, stream_mgr, buffer, client_id, request);
+        Self::log_access(
+            access_log,
+            clients,
+            client_id,
+            op,
+            stream_id.as_deref(),
+            started.elapsed(),
+        );
+    }
+
+    /// Dispatch a decoded control message.
+    fn dispatch(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        buffer: &mut PooledBuffer,
+        client_id: usize,
+        request: ControlMessage,
     ) {
-        // Get active stream ID for this client
-        let stream_id = {
-            let clients = clients.lock().unwrap();
-            clients.get(&client_id).cloned()
+        // With access tokens configured, only HELLO, AUTH, and admin commands
+        // are served before AUTH
+        let open = matches!(
+            request,
+            ControlMessage::Hello { .. }
+                | ControlMessage::Auth { .. }
+                | ControlMessage::Drain { .. }
+        );
+        if TokenQuotas::current().is_some() && !open && Self::token_of(clients, client_id).is_none()
+        {
+            let e = ServerError::Unauthorized(format!(
+                "authenticate with AUTH before {}",
+                request.type_name()
+            ));
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+
+        // A token confined to a namespace only reaches the streams in it
+        if let Some(namespace) = Self::namespace_of(clients, client_id) {
+            let target = match &request {
+                ControlMessage::Clone {
+                    target_stream_id, ..
+                } => Some(target_stream_id.as_str()),
+                _ => None,
+            };
+            let outside = request
+                .stream_id()
+                .into_iter()
+                .chain(target)
+                .find(|stream_id| split_namespace(stream_id).0 != Some(namespace.as_str()));
+            if let Some(stream_id) = outside {
+                let e = ServerError::Forbidden(format!(
+                    "stream {} is outside namespace {}",
+                    stream_id, namespace
+                ));
+                Self::send_server_error(websocket, clients, client_id, &e);
+                return;
+            }
+        }
+
+        // In cluster mode, redirect requests for streams owned by another node
+        if let (Some(router), Some(stream_id)) = (ClusterRouter::current(), request.stream_id()) {
+            if let Some(owner) = router.redirect_for(stream_id) {
+                let response = ControlMessage::Moved {
+                    stream_id: stream_id.to_string(),
+                    message: Some(format!("Stream is owned by {}", owner)),
+                    location: owner,
+                };
+                Self::send_json(websocket, clients, client_id, &response);
+                return;
+            }
+        }
+
+        // Only the token that created a stream may change it
+        if let Err(e) = Self::check_owner(clients, stream_mgr, client_id, &request) {
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+
+        match request {
+            ControlMessage::Hello { encodings } => {
+                Self::handle_hello(websocket, clients, client_id, &encodings)
+            }
+            ControlMessage::Auth { token } => {
+                Self::handle_auth(websocket, clients, client_id, &token)
+            }
+            ControlMessage::Start {
+                stream_id,
+                content_type,
+                file_name,
+                size,
+                append,
+                resume,
+                tags,
+                acks,
+                exclusive,
+            } => Self::handle_start(
+                websocket,
+                clients,
+                stream_mgr,
+                client_id,
+                stream_id,
+                content_type,
+                file_name,
+                size,
+                append,
+                resume,
+                tags,
+                acks,
+                exclusive,
+            ),
+            ControlMessage::Seek { stream_id, offset } => Self::handle_seek(
+                websocket, clients, stream_mgr, client_id, &stream_id, offset,
+            ),
+            ControlMessage::Pause { stream_id } => {
+                Self::handle_pause(websocket, clients, stream_mgr, client_id, stream_id, true)
+            }
+            ControlMessage::Resume { stream_id } => {
+                Self::handle_pause(websocket, clients, stream_mgr, client_id, stream_id, false)
+            }
+            ControlMessage::Stop { stream_id } => {
+                Self::handle_stop(websocket, clients, stream_mgr, client_id, stream_id)
+            }
+            ControlMessage::Get {
+                stream_id,
+                offset,
+                length,
+                start_time,
+                end_time,
+                checkpoint_every,
+                version,
+            } => Self::handle_get(
+                websocket,
+                clients,
+                stream_mgr,
+                buffer,
+                client_id,
+                stream_id,
+                offset,
+                length,
+                (start_time, end_time),
+                checkpoint_every,
+                version,
+            ),
+            ControlMessage::Size { stream_id } => {
+                Self::handle_size(websocket, clients, stream_mgr, client_id, stream_id)
+            }
+            ControlMessage::Stat { stream_id, version } => Self::handle_stat(
+                websocket, clients, stream_mgr, client_id, stream_id, version,
+            ),
+            ControlMessage::List { tags, after, limit } => Self::handle_list(
+                websocket,
+                clients,
+                stream_mgr,
+                client_id,
+                &tags,
+                after.as_deref(),
+                limit,
+            ),
+            ControlMessage::Peaks { stream_id } => {
+                Self::handle_peaks(websocket, clients, stream_mgr, client_id, stream_id)
+            }
+            ControlMessage::BlockHashes {
+                stream_id,
+                offset,
+                length,
+            } => Self::handle_block_hashes(
+                websocket, clients, stream_mgr, client_id, stream_id, offset, length,
+            ),
+            ControlMessage::Timestamps { stream_id } => {
+                Self::handle_timestamps(websocket, clients, stream_mgr, client_id, stream_id)
+            }
+            ControlMessage::Clone {
+                stream_id,
+                target_stream_id,
+            } => Self::handle_clone(
+                websocket,
+                clients,
+                stream_mgr,
+                client_id,
+                stream_id,
+                target_stream_id,
+            ),
+            ControlMessage::Delete { stream_id } => {
+                Self::handle_delete(websocket, clients, stream_mgr, client_id, stream_id)
+            }
+            ControlMessage::Pin { stream_id } => {
+                Self::handle_pin(websocket, clients, stream_mgr, client_id, stream_id, true)
+            }
+            ControlMessage::Unpin { stream_id } => {
+                Self::handle_pin(websocket, clients, stream_mgr, client_id, stream_id, false)
+            }
+            ControlMessage::SetTtl { stream_id, ttl } => {
+                Self::handle_set_ttl(websocket, clients, stream_mgr, client_id, stream_id, ttl)
+            }
+            ControlMessage::Drain { token } => {
+                Self::handle_drain(websocket, clients, client_id, &token)
+            }
+            ControlMessage::Unknown => {
+                warn!("Unknown message type from client");
+                Self::send_error(websocket, clients, client_id, "Unknown message type");
+            }
+            other => {
+                warn!("Unexpected message type from client: {}", other.type_name());
+                Self::send_error(
+                    websocket,
+                    clients,
+                    client_id,
+                    &format!("Unexpected message type: {}", other.type_name()),
+                );
+            }
+        }
+    }
+
+    /// Handle binary frames that arrived together.
+    ///
+    /// Frames are raw audio data unless a binary control encoding was
+    /// negotiated, in which case a leading kind byte marks them as data or as
+    /// an encoded control message. Consecutive data frames are written to the
+    /// stream in one batch, before any control message that follows them.
+    ///
+    /// Returns `false` when a frame violates the protocol (no active stream),
+    /// in which case an ERROR response has already been sent to the client
+    /// and the frames after it are ignored.
+    pub fn handle_binary_messages(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        buffer: &mut PooledBuffer,
+        client_id: usize,
+        frames: &[Bytes],
+    ) -> bool {
+        let mut stream_id = String::new();
+        let mut batch = Vec::new();
+        for frame in frames {
+            let admitted = Self::admit_binary(clients, client_id, frame);
+            if !matches!(admitted, BinaryFrame::Data { .. }) {
+                Self::write_data(
+                    websocket, clients, stream_mgr, client_id, &stream_id, &mut batch,
+                );
+            }
+            match admitted {
+                BinaryFrame::Data {
+                    stream_id: data_stream_id,
+                    data,
+                    timestamp,
+                } => {
+                    stream_id = data_stream_id;
+                    batch.push((data, timestamp));
+                }
+                BinaryFrame::Control(request) => Self::handle_control_message(
+                    websocket, clients, stream_mgr, buffer, client_id, request,
+                ),
+                BinaryFrame::Failed(e, accepted) => {
+                    Self::send_server_error(websocket, clients, client_id, &e);
+                    if !accepted {
+                        return false;
+                    }
+                }
+                BinaryFrame::Invalid(message) => {
+                    Self::send_error(websocket, clients, client_id, message);
+                    return false;
+                }
+            }
+        }
+        Self::write_data(
+            websocket, clients, stream_mgr, client_id, &stream_id, &mut batch,
+        );
+        true
+    }
+
+    /// Check a binary frame against the client's upload state. Data frames
+    /// only change the state once they are written.
+    fn admit_binary<'a>(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        data: &'a [u8],
+    ) -> BinaryFrame<'a> {
+        let encoding = Self::encoding_of(clients, client_id);
+
+        let (data, timestamp) = if encoding.is_binary() {
+            match data.split_first() {
+                Some((&FRAME_DATA, payload)) => (payload, None),
+                Some((&FRAME_TIMESTAMPED_DATA, payload)) => match split_timestamp(payload) {
+                    Some((timestamp, payload)) => (payload, Some(timestamp)),
+                    None => return BinaryFrame::Invalid("Truncated timestamped data frame"),
+                },
+                Some((&FRAME_CONTROL, payload)) => {
+                    return match encoding.decode(payload) {
+                        Ok(request) => BinaryFrame::Control(request),
+                        Err(e) => BinaryFrame::Failed(e.into(), true),
+                    };
+                }
+                _ => return BinaryFrame::Invalid("Unknown binary frame kind"),
+            }
+        } else {
+            (data, None)
+        };
+
+        // Data is only accepted between START and STOP
+        let mut next = Self::state_of(clients, client_id);
+        let stream_id = match next.data(data.len()) {
+            Ok(stream_id) => stream_id.to_string(),
+            Err(e) => {
+                warn!(
+                    "Received {} bytes of binary data but no active stream for client {}: {}",
+                    data.len(),
+                    client_id,
+                    e
+                );
+                return BinaryFrame::Invalid("No active stream. Send START message first.");
+            }
         };
 
-        if stream_id.is_none() || stream_id.as_ref().unwrap().is_empty() {
-            println!("[ERROR] Received binary data but no active stream for client");
+        // Refuse frames larger than advertised in STARTED
+        if let Some(limit) = Self::max_chunk_size_of(clients, client_id) {
+            if data.len() > limit {
+                let e = ServerError::ChunkTooLarge {
+                    size: data.len(),
+                    limit,
+                };
+                warn!("{}", e);
+                return BinaryFrame::Failed(e, false);
+            }
+        }
+        BinaryFrame::Data {
+            stream_id,
+            data,
+            timestamp,
+        }
+    }
+
+    /// Write the data frames admitted for `stream_id`, after the previous
+    /// frame when SEEK moved it, and empty `batch`.
+    ///
+    /// The batch stops at the first frame past the quota of the connection's
+    /// token or rejected by a stream processor. The frames before it are
+    /// written and acknowledged, and only they count towards the upload
+    /// state and the quota.
+    fn write_data(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: &str,
+        batch: &mut Vec<(&[u8], Option<u64>)>,
+    ) {
+        if batch.is_empty() {
             return;
         }
+        let span = tracing::info_span!("data", stream_id);
+        let _entered = span.enter();
+
+        // Charge the quota before writing so bytes past it never reach the
+        // cache; frames charged but not written are refunded below. Only the
+        // bytes the stream does not have yet count as stored, so rewriting
+        // after a SEEK is not stored twice
+        let offset = Self::write_offset_of(clients, client_id);
+        let quota = TokenQuotas::current().zip(Self::token_of(clients, client_id));
+        let mut charges = Vec::with_capacity(batch.len());
+        let mut generation = 0;
+        let mut over_quota = None;
+        if let Some((quotas, token)) = &quota {
+            let position = stream_mgr
+                .write_position(stream_id, offset)
+                .unwrap_or_default();
+            generation = position.generation;
+            let mut start = position.offset;
+            for (index, (data, _)) in batch.iter().enumerate() {
+                let end = start.saturating_add(data.len() as u64);
+                let charge = (data.len() as u64, position.received.uncovered(start, end));
+                if let Err(e) =
+                    quotas.charge_upload(token, stream_id, generation, charge.0, charge.1)
+                {
+                    over_quota = Some((index, e));
+                    break;
+                }
+                charges.push(charge);
+                start = end;
+            }
+        }
+        if let Some((index, _)) = &over_quota {
+            batch.truncate(*index);
+        }
+
+        let (chunks, error) = if batch.is_empty() {
+            (0, None)
+        } else {
+            match stream_mgr.write_chunks(stream_id, offset, batch) {
+                Ok(written) => (written.chunks, written.rejected.map(ServerError::from)),
+                Err(e) => (0, Some(e.into())),
+            }
+        };
+        let written = batch[..chunks]
+            .iter()
+            .map(|(data, _)| data.len() as u64)
+            .sum();
+        if let Some((quotas, token)) = &quota {
+            let (bytes, stored) = charges[chunks..]
+                .iter()
+                .fold((0, 0), |(bytes, stored), charge| {
+                    (bytes + charge.0, stored + charge.1)
+                });
+            quotas.refund_upload(token, stream_id, generation, bytes, stored);
+        }
+
+        if chunks > 0 {
+            // Admission already checked that the frames belong to the stream
+            let mut next = Self::state_of(clients, client_id);
+            if next.data(written as usize).is_ok() {
+                Self::set_state(clients, client_id, next);
+            }
+            if let Some(offset) = offset {
+                Self::set_write_offset(clients, client_id, Some(offset + written));
+            }
+            if let Some(bytes) = Self::add_acked_bytes(clients, client_id, written) {
+                let ack = ControlMessage::Ack {
+                    stream_id: stream_id.to_string(),
+                    bytes,
+                };
+                Self::send_json(websocket, clients, client_id, &ack);
+            }
+        }
+        if let Some(e) = error.or(over_quota.map(|(_, e)| e)) {
+            Self::send_server_error(websocket, clients, client_id, &e);
+        }
+        batch.clear();
+    }
+
+    /// Count a ping as activity on the connection's upload, so a client that
+    /// is slow but alive does not get its stream paused.
+    pub fn handle_ping(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+    ) {
+        let state = Self::state_of(clients, client_id);
+        if let Some(stream_id) = state.active_stream() {
+            stream_mgr.touch_stream(stream_id);
+        }
+    }
 
-        let stream_id = stream_id.unwrap();
+    /// Handle HELLO message (negotiate the control message encoding).
+    fn handle_hello(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        encodings: &[String],
+    ) {
+        let encoding = Encoding::negotiate(encodings);
+        let response = ControlMessage::HelloAck {
+            encoding: encoding.as_str().to_string(),
+        };
 
-        // Write to stream
-        stream_mgr.write_chunk(&stream_id, data);
+        // The acknowledgement still uses the previous encoding
+        Self::send_json(websocket, clients, client_id, &response);
+        clients
+            .lock()
+            .unwrap()
+            .entry(client_id)
+            .or_default()
+            .encoding = encoding;
+        info!(
+            "Client {} negotiated {} control messages",
+            client_id,
+            encoding.as_str()
+        );
     }
 
-    /// Handle START message (create new stream).
+    /// Handle START message (create a new stream, reopen a finalized one for
+    /// appending, or resume an interrupted upload).
+    #[allow(clippy::too_many_arguments)]
     fn handle_start(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         stream_mgr: &Arc<StreamManager>,
         client_id: usize,
-        data: &Value,
+        stream_id: String,
+        content_type: Option<String>,
+        file_name: Option<String>,
+        size_hint: Option<u64>,
+        append: bool,
+        resume: bool,
+        tags: BTreeMap<String, String>,
+        acks: bool,
+        exclusive: bool,
     ) {
-        let stream_id = match data["streamId"].as_str() {
-            Some(id) => id.to_string(),
-            None => {
-                Self::send_error(websocket, clients, client_id, "Missing streamId");
+        if drain::is_draining() {
+            Self::send_server_error(websocket, clients, client_id, &ServerError::Draining);
+            return;
+        }
+        if let Err(e) = Self::validate_tags(&tags) {
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+        // The content type is served as a header of HTTP downloads
+        if let Some(content_type) = content_type
+            .as_deref()
+            .filter(|content_type| !http_download::is_valid_content_type(content_type))
+        {
+            let e = ServerError::InvalidRequest(format!(
+                "content type {:?} is not a MIME type",
+                content_type
+            ));
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+        if append && resume {
+            let e = ServerError::Protocol("START cannot both append and resume".to_string());
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+
+        let mut next = Self::state_of(clients, client_id);
+        if let Err(e) = next.start(&stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        // Refuse uploads declared larger than the token's storage quota
+        // allows; a resumed upload is already partly charged
+        let token = Self::token_of(clients, client_id);
+        if let (Some(quotas), Some(token), Some(size), false) =
+            (TokenQuotas::current(), token.as_deref(), size_hint, resume)
+        {
+            if let Err(e) = quotas.check_storage(token, size) {
+                Self::send_server_error(websocket, clients, client_id, &e);
+                return;
+            }
+        }
+
+        // Create stream, reopen it at its current end, or pick up its upload
+        let started = if append {
+            stream_mgr
+                .reopen_stream(&stream_id)
+                .map(|offset| (Some(offset), None))
+        } else if resume {
+            stream_mgr
+                .resume_stream(&stream_id)
+                .map(|received| (None, Some(received)))
+        } else {
+            stream_mgr
+                .create_stream(stream_id.clone(), size_hint, exclusive)
+                .map(|()| (None, None))
+        };
+        let (offset, received) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                Self::send_server_error(websocket, clients, client_id, &e.into());
                 return;
             }
         };
 
-        // Create stream
-        if stream_mgr.create_stream(stream_id.clone()) {
-            // Register this client with the stream
-            clients.lock().unwrap().insert(client_id, stream_id.clone());
-
-            let response = ControlMessage {
-                msg_type: "STARTED".to_string(), // Use uppercase to match client expectation
-                stream_id: Some(stream_id.clone()),
-                offset: None,
-                length: None,
-                message: Some("Stream created".to_string()),
-            };
+        // Persist client-declared content type and original file name, and
+        // the token that created the stream
+        if let Some(stream) = stream_mgr.get_stream(&stream_id) {
+            let mut ctx = stream.lock().unwrap();
+            if let Some(token) = token.filter(|_| !append && !resume) {
+                ctx.set_metadata("owner", token);
+            }
+            ctx.add_tags(tags);
+            if let Some(content_type) = content_type {
+                ctx.set_metadata("contentType", content_type);
+            }
+            if let Some(file_name) = file_name.as_deref() {
+                // Keep only the final path component
+                let file_name = file_name.rsplit(['/', '\\']).next().unwrap_or("");
+                if !file_name.is_empty() {
+                    ctx.set_metadata("fileName", file_name.to_string());
+                }
+            }
+        }
 
-            Self::send_json(websocket, clients, client_id, &response);
-            println!("Stream started: {}", stream_id);
+        // Register this client with the stream
+        Self::set_state(clients, client_id, next);
+        Self::set_write_offset(clients, client_id, None);
+        Self::session_mut(clients, client_id, |session| {
+            session.acked_bytes = acks.then_some(0)
+        });
+
+        let message = if append {
+            "Stream reopened"
+        } else if resume {
+            "Stream resumed"
         } else {
-            Self::send_error(
-                websocket,
-                clients,
-                client_id,
-                &format!("Failed to create stream: {}", stream_id),
-            );
+            "Stream created"
+        };
+        let response = ControlMessage::Started {
+            stream_id: stream_id.clone(),
+            message: Some(message.to_string()),
+            offset,
+            received,
+            max_chunk_size: Self::max_chunk_size_of(clients, client_id),
+            acks,
+        };
+
+        Self::send_json(websocket, clients, client_id, &response);
+        info!("Stream started: {}", stream_id);
+    }
+
+    /// Handle SEEK message (move where the connection's next data frame is
+    /// written). Only failures are answered.
+    fn handle_seek(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: &str,
+        offset: u64,
+    ) {
+        if let Err(e) = Self::state_of(clients, client_id).seek(stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+        // Only the declared size or the bytes written may be filled in
+        match stream_mgr.seek_limit(stream_id) {
+            Ok(limit) if offset > limit => {
+                let e = ServerError::Protocol(format!(
+                    "SEEK to offset {} is past the end of stream {} at {}",
+                    offset, stream_id, limit
+                ));
+                Self::send_server_error(websocket, clients, client_id, &e);
+            }
+            Ok(_) => Self::set_write_offset(clients, client_id, Some(offset)),
+            Err(e) => Self::send_server_error(websocket, clients, client_id, &e.into()),
+        }
+    }
+
+    /// Handle PAUSE and RESUME messages (halt the connection's upload, or
+    /// continue it).
+    fn handle_pause(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        pause: bool,
+    ) {
+        let event = if pause { "PAUSE" } else { "RESUME" };
+        if let Err(e) = Self::state_of(clients, client_id).check_active(event, &stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        let response = if pause {
+            stream_mgr
+                .pause_stream(&stream_id)
+                .map(|offset| ControlMessage::Paused { stream_id, offset })
+        } else {
+            stream_mgr
+                .unpause_stream(&stream_id)
+                .map(|offset| ControlMessage::Resumed { stream_id, offset })
+        };
+        match response {
+            Ok(response) => Self::send_json(websocket, clients, client_id, &response),
+            Err(e) => Self::send_server_error(websocket, clients, client_id, &e.into()),
         }
     }
 
     /// Handle STOP message (finalize stream).
     fn handle_stop(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         stream_mgr: &Arc<StreamManager>,
         client_id: usize,
-        data: &Value,
+        stream_id: String,
     ) {
-        let stream_id = match data["streamId"].as_str() {
-            Some(id) => id.to_string(),
-            None => {
-                Self::send_error(websocket, clients, client_id, "Missing streamId");
-                return;
-            }
-        };
+        let mut next = Self::state_of(clients, client_id);
+        if let Err(e) = next.stop(&stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
 
         // Finalize stream
-        if stream_mgr.finalize_stream(&stream_id) {
-            let response = ControlMessage {
-                msg_type: "STOPPED".to_string(), // Use uppercase to match client expectation
-                stream_id: Some(stream_id.clone()),
-                offset: None,
-                length: None,
+        if let Err(e) = stream_mgr.finalize_stream(&stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+        } else {
+            let (size, checksum) = match stream_mgr.get_stream(&stream_id) {
+                Some(stream) => {
+                    let ctx = stream.lock().unwrap();
+                    (
+                        Some(ctx.get_total_size()),
+                        ctx.get_checksum().map(str::to_string),
+                    )
+                }
+                None => (None, None),
+            };
+            let response = ControlMessage::Stopped {
+                stream_id: stream_id.clone(),
                 message: Some("Stream finalized".to_string()),
+                size,
+                checksum,
             };
 
             Self::send_json(websocket, clients, client_id, &response);
-            println!("Stream finalized: {}", stream_id);
+            info!("Stream finalized: {}", stream_id);
 
             // Unregister stream from client
-            clients.lock().unwrap().insert(client_id, String::new());
-        } else {
-            Self::send_error(
-                websocket,
-                clients,
-                client_id,
-                &format!("Failed to finalize stream: {}", stream_id),
-            );
+            Self::set_state(clients, client_id, next);
         }
     }
 
     /// Handle GET message (read stream data).
+    ///
+    /// With a time range, the range is mapped to bytes using the sample
+    /// format of the stream, and `offset` counts from its first byte. The
+    /// data is read into the connection's pooled buffer, so a GET answers
+    /// with at most the pool's buffer size, and copied from there into the
+    /// frame sent. With `checkpoint_every`, the chunks of a
+    /// download read in order from offset 0 feed a running digest. A
+    /// `version` reads an earlier generation of the stream.
+    #[allow(clippy::too_many_arguments)]
     fn handle_get(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         stream_mgr: &Arc<StreamManager>,
+        buffer: &mut PooledBuffer,
         client_id: usize,
-        data: &Value,
+        stream_id: String,
+        offset: u64,
+        length: usize,
+        (start_time, end_time): (Option<f64>, Option<f64>),
+        checkpoint_every: Option<u32>,
+        version: Option<u64>,
     ) {
-        let stream_id = match data["streamId"].as_str() {
-            Some(id) => id.to_string(),
-            None => {
-                Self::send_error(websocket, clients, client_id, "Missing streamId");
-                return;
+        let range = if start_time.is_some() || end_time.is_some() {
+            match Self::resolve_time_range(stream_mgr, &stream_id, version, start_time, end_time) {
+                Ok(range) => Some(range),
+                Err(e) => {
+                    Self::send_server_error(websocket, clients, client_id, &e);
+                    return;
+                }
             }
+        } else {
+            None
+        };
+        let (offset, length) = match &range {
+            Some(range) => {
+                let offset = range.start.saturating_add(offset);
+                let available = range.end.saturating_sub(offset);
+                (offset, std::cmp::min(length as u64, available) as usize)
+            }
+            None => (offset, length),
         };
 
-        let offset = data["offset"].as_u64().unwrap_or(0);
-        let length = data["length"].as_u64().unwrap_or(65536) as usize;
+        let token = Self::token_of(clients, client_id);
+        if let (Some(quotas), Some(token)) = (TokenQuotas::current(), token.as_deref()) {
+            if let Err(e) = quotas.check_transfer(token) {
+                Self::send_server_error(websocket, clients, client_id, &e);
+                return;
+            }
+        }
+
+        // Read data from stream behind the frame kind byte, if any, at most
+        // what the pooled buffer holds; larger requests get a short read
+        let prefix = if Self::encoding_of(clients, client_id).is_binary() {
+            1
+        } else {
+            0
+        };
+        let length = std::cmp::min(length, buffer.len() - prefix);
+        let read = match stream_mgr.read_version_into(
+            &stream_id,
+            version,
+            offset,
+            &mut buffer[prefix..prefix + length],
+        ) {
+            Ok(read) => read,
+            Err(e) => {
+                Self::send_server_error(websocket, clients, client_id, &e.into());
+                return;
+            }
+        };
 
-        // Read data from stream
-        let chunk_data = stream_mgr.read_chunk(&stream_id, offset, length);
+        if read > 0 {
+            if prefix > 0 {
+                buffer[0] = FRAME_DATA;
+            }
 
-        if !chunk_data.is_empty() {
             // Send binary data via WebSocket
-            match websocket.send(WsMessage::Binary(Bytes::from(chunk_data))) {
+            let frame = WsMessage::Binary(Bytes::copy_from_slice(&buffer[..prefix + read]));
+            match Self::send_frame(websocket, clients, client_id, frame) {
                 Ok(_) => {
-                    println!(
+                    if let (Some(quotas), Some(token)) = (TokenQuotas::current(), token.as_deref())
+                    {
+                        quotas.charge_download(token, read as u64);
+                    }
+                    info!(
                         "Sent {} bytes for stream {} at offset {}",
-                        length, stream_id, offset
+                        read, stream_id, offset
                     );
+                    if let Some(every) =
+                        checkpoint_every.filter(|&every| every > 0 && range.is_none())
+                    {
+                        let data = &buffer[prefix..prefix + read];
+                        Self::update_download_digest(
+                            websocket, clients, client_id, &stream_id, offset, data, every,
+                        );
+                    }
                 }
                 Err(e) => {
-                    eprintln!("Failed to send binary data: {:?}", e);
+                    error!("Failed to send binary data: {:?}", e);
                 }
             }
+        } else if let Some(range) = range.filter(|range| offset >= range.end) {
+            // Reads past the end of a time range mark the end of its data
+            let size = range.end - range.start;
+            let response = ControlMessage::DataEnd { stream_id, size };
+            Self::send_json(websocket, clients, client_id, &response);
+        } else if let Some(size) =
+            Self::finalized_size(stream_mgr, &stream_id, version).filter(|&size| offset >= size)
+        {
+            // Reads past the end of a finalized stream mark the end of data
+            let response = ControlMessage::DataEnd { stream_id, size };
+            Self::send_json(websocket, clients, client_id, &response);
         } else {
-            Self::send_error(
+            // Live readers poll again once the uploader has caught up
+            let e = StreamError::NoData { stream_id, offset };
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+        }
+    }
+
+    /// Feed the bytes a GET sent into the connection's download digest and
+    /// send a CHECKPOINT after every `every`-th chunk. A GET from offset 0
+    /// starts a new digest; one that does not continue the digest ends it.
+    fn update_download_digest(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        stream_id: &str,
+        offset: u64,
+        data: &[u8],
+        every: u32,
+    ) {
+        let checkpoint = {
+            let mut clients = clients.lock().unwrap();
+            let Some(session) = clients.get_mut(&client_id) else {
+                return;
+            };
+            if offset == 0 {
+                session.download_digest = Some(DownloadDigest {
+                    stream_id: stream_id.to_string(),
+                    offset: 0,
+                    hasher: Sha256::new(),
+                    chunks: 0,
+                });
+            }
+            let digest = match session.download_digest.as_mut() {
+                Some(digest) if digest.stream_id == stream_id && digest.offset == offset => digest,
+                _ => {
+                    session.download_digest = None;
+                    return;
+                }
+            };
+            digest.hasher.update(data);
+            digest.offset += data.len() as u64;
+            digest.chunks += 1;
+            (digest.chunks % u64::from(every) == 0).then(|| ControlMessage::Checkpoint {
+                stream_id: stream_id.to_string(),
+                offset: digest.offset,
+                checksum: format!("{:x}", digest.hasher.clone().finalize()),
+            })
+        };
+        if let Some(checkpoint) = checkpoint {
+            Self::send_json(websocket, clients, client_id, &checkpoint);
+        }
+    }
+
+    /// Map the time range of a GET, in seconds, to bytes of a stream.
+    fn resolve_time_range(
+        stream_mgr: &Arc<StreamManager>,
+        stream_id: &str,
+        version: Option<u64>,
+        start_time: Option<f64>,
+        end_time: Option<f64>,
+    ) -> Result<std::ops::Range<u64>, ServerError> {
+        let start = start_time.unwrap_or(0.0);
+        let valid = start.is_finite()
+            && start >= 0.0
+            && end_time.is_none_or(|end| end.is_finite() && end >= start);
+        if !valid {
+            return Err(ServerError::Protocol(format!(
+                "Invalid time range {}..{} for stream {}",
+                start,
+                end_time.map_or_else(String::new, |end| end.to_string()),
+                stream_id
+            )));
+        }
+        Ok(stream_mgr.time_range(stream_id, version, start, end_time)?)
+    }
+
+    /// Handle SIZE message (report the byte count of a finalized stream).
+    fn handle_size(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+    ) {
+        match Self::finalized_size(stream_mgr, &stream_id, None) {
+            Some(size) => {
+                let response = ControlMessage::SizeResult { stream_id, size };
+                Self::send_json(websocket, clients, client_id, &response);
+            }
+            None => Self::send_error(
+                websocket,
+                clients,
+                client_id,
+                &format!("Stream not found or not finalized: {}", stream_id),
+            ),
+        }
+    }
+
+    /// Get the size of a stream, or of generation `version` of it, if it has
+    /// been finalized.
+    fn finalized_size(
+        stream_mgr: &Arc<StreamManager>,
+        stream_id: &str,
+        version: Option<u64>,
+    ) -> Option<u64> {
+        let stream = stream_mgr.get_version(stream_id, version)?;
+        let ctx = stream.lock().unwrap();
+        (ctx.get_status() == StreamStatus::Ready).then(|| ctx.get_total_size())
+    }
+
+    /// Handle STAT message (report stream size, status, checksum, and metadata),
+    /// of the current generation of a stream or of an earlier `version`.
+    fn handle_stat(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        version: Option<u64>,
+    ) {
+        let stream = match stream_mgr.get_version(&stream_id, version) {
+            Some(stream) => stream,
+            None if version.is_some() && stream_mgr.get_stream(&stream_id).is_some() => {
+                let e = StreamError::VersionNotFound {
+                    stream_id,
+                    version: version.unwrap_or_default(),
+                };
+                Self::send_server_error(websocket, clients, client_id, &e.into());
+                return;
+            }
+            None => {
+                Self::send_error(
+                    websocket,
+                    clients,
+                    client_id,
+                    &format!("Stream not found: {}", stream_id),
+                );
+                return;
+            }
+        };
+
+        let usage = TokenQuotas::current()
+            .zip(Self::token_of(clients, client_id))
+            .and_then(|(quotas, token)| quotas.usage(&token));
+        let versions = stream_mgr.list_versions(&stream_id);
+        let response = {
+            let ctx = stream.lock().unwrap();
+            ControlMessage::StatResult {
+                version: Some(ctx.get_generation()),
+                versions,
+                stream_id,
+                size: ctx.get_total_size(),
+                status: ctx.get_status().as_str().to_string(),
+                checksum: ctx.get_checksum().map(str::to_string),
+                metadata: ctx.get_metadata().clone(),
+                tags: ctx.get_tags().clone(),
+                pinned: ctx.is_pinned(),
+                expires_in: ctx.get_expires_at().map(|expires_at| {
+                    expires_at
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .as_secs()
+                }),
+                usage,
+            }
+        };
+
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Check the tags of a START against the limits on their number and size.
+    fn validate_tags(tags: &BTreeMap<String, String>) -> Result<(), ServerError> {
+        if tags.len() > MAX_TAGS {
+            return Err(ServerError::Protocol(format!(
+                "{} tags given, at most {} are allowed",
+                tags.len(),
+                MAX_TAGS
+            )));
+        }
+        for (key, value) in tags {
+            if key.is_empty() || key.len() > MAX_TAG_LEN || value.len() > MAX_TAG_LEN {
+                return Err(ServerError::Protocol(format!(
+                    "Invalid tag {}: keys must be 1 to {} bytes and values at most {}",
+                    key, MAX_TAG_LEN, MAX_TAG_LEN
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle LIST message (page through the streams carrying given tags).
+    fn handle_list(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        tags: &BTreeMap<String, String>,
+        after: Option<&str>,
+        limit: Option<usize>,
+    ) {
+        let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+        let namespace = Self::namespace_of(clients, client_id);
+        let (streams, more) = stream_mgr.list_streams(tags, namespace.as_deref(), after, limit);
+        let next = streams
+            .last()
+            .filter(|_| more)
+            .map(|stream| stream.stream_id.clone());
+        let response = ControlMessage::ListResult { streams, next };
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Handle PEAKS message (return cached waveform min/max peaks).
+    fn handle_peaks(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+    ) {
+        let cache_path = match stream_mgr.get_stream(&stream_id) {
+            Some(stream) => stream.lock().unwrap().get_cache_path().to_string(),
+            None => {
+                Self::send_error(
+                    websocket,
+                    clients,
+                    client_id,
+                    &format!("Stream not found: {}", stream_id),
+                );
+                return;
+            }
+        };
+
+        match waveform_peaks::load_peaks(&cache_path) {
+            Some(peaks) => {
+                let response = ControlMessage::PeaksResult { stream_id, peaks };
+                Self::send_json(websocket, clients, client_id, &response);
+            }
+            None => Self::send_error(
                 websocket,
                 clients,
                 client_id,
-                &format!("Failed to read from stream: {}", stream_id),
+                &format!("Waveform peaks not available for stream: {}", stream_id),
+            ),
+        }
+    }
+
+    /// Handle BLOCK_HASHES message (return verified per-block hashes).
+    fn handle_block_hashes(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        offset: u64,
+        length: Option<u64>,
+    ) {
+        match stream_mgr.verify_range(&stream_id, offset, length) {
+            Ok((index, blocks)) => {
+                let response = ControlMessage::BlockHashesResult {
+                    stream_id,
+                    block_size: BLOCK_SIZE,
+                    first_block: blocks.start,
+                    hashes: blocks.filter_map(|block| index.hex_hash(block)).collect(),
+                };
+                Self::send_json(websocket, clients, client_id, &response);
+            }
+            Err(e) => Self::send_server_error(websocket, clients, client_id, &e.into()),
+        }
+    }
+
+    /// Handle TIMESTAMPS message (report the capture time of each chunk).
+    fn handle_timestamps(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+    ) {
+        match stream_mgr.get_timestamps(&stream_id) {
+            Ok(timestamps) => {
+                let response = ControlMessage::TimestampsResult {
+                    stream_id,
+                    timestamps,
+                };
+                Self::send_json(websocket, clients, client_id, &response);
+            }
+            Err(e) => Self::send_server_error(websocket, clients, client_id, &e.into()),
+        }
+    }
+
+    /// Handle CLONE message (snapshot a finalized stream).
+    fn handle_clone(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        target_stream_id: String,
+    ) {
+        if let Err(e) = stream_mgr.clone_stream(&stream_id, &target_stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        let size = Self::finalized_size(stream_mgr, &target_stream_id, None).unwrap_or(0);
+        let response = ControlMessage::Cloned {
+            stream_id: target_stream_id,
+            source_stream_id: stream_id,
+            size,
+        };
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Handle DELETE message (remove a finalized stream and its cache file).
+    fn handle_delete(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+    ) {
+        if let Err(e) = stream_mgr.delete_finalized_stream(&stream_id) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        let response = ControlMessage::Deleted { stream_id };
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Handle PIN and UNPIN messages (protect a stream from expiry or not).
+    fn handle_pin(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        pinned: bool,
+    ) {
+        if let Err(e) = stream_mgr.set_pinned(&stream_id, pinned) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        let response = ControlMessage::Pinned { stream_id, pinned };
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Handle SET_TTL message (expire a stream at a set time).
+    fn handle_set_ttl(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        stream_id: String,
+        ttl: Option<u64>,
+    ) {
+        if let Err(e) = stream_mgr.set_ttl(&stream_id, ttl.map(Duration::from_secs)) {
+            Self::send_server_error(websocket, clients, client_id, &e.into());
+            return;
+        }
+
+        let response = ControlMessage::TtlSet { stream_id, ttl };
+        Self::send_json(websocket, clients, client_id, &response);
+    }
+
+    /// Handle AUTH message (attribute the connection's usage to a token).
+    fn handle_auth(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        secret: &str,
+    ) {
+        let Some(quotas) = TokenQuotas::current() else {
+            let e = ServerError::Protocol(
+                "access tokens are disabled; start the server with --token".to_string(),
             );
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        };
+        let token = match quotas.authenticate(secret) {
+            Ok(token) => token,
+            Err(e) => {
+                Self::send_server_error(websocket, clients, client_id, &e);
+                return;
+            }
+        };
+
+        Self::session_mut(clients, client_id, |session| {
+            session.token = Some(token.to_string())
+        });
+        if let Some(usage) = quotas.usage(token) {
+            let response = ControlMessage::Authenticated { usage };
+            Self::send_json(websocket, clients, client_id, &response);
+        }
+        info!("Client {} authenticated as {}", client_id, token);
+    }
+
+    /// Get the name of the token a client authenticated with.
+    fn token_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> Option<String> {
+        clients
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .and_then(|session| session.token.clone())
+    }
+
+    /// Refuse requests that change a stream created through another token
+    /// than the connection's: uploads to it, and its deletion, pinning,
+    /// TTL, and clones.
+    fn check_owner(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        client_id: usize,
+        request: &ControlMessage,
+    ) -> Result<(), ServerError> {
+        let changes_stream = matches!(
+            request,
+            ControlMessage::Start { .. }
+                | ControlMessage::Seek { .. }
+                | ControlMessage::Pause { .. }
+                | ControlMessage::Resume { .. }
+                | ControlMessage::Stop { .. }
+                | ControlMessage::Clone { .. }
+                | ControlMessage::Delete { .. }
+                | ControlMessage::Pin { .. }
+                | ControlMessage::Unpin { .. }
+                | ControlMessage::SetTtl { .. }
+        );
+        let (Some(token), Some(stream_id), true) = (
+            Self::token_of(clients, client_id),
+            request.stream_id(),
+            changes_stream,
+        ) else {
+            return Ok(());
+        };
+        let owner = stream_mgr
+            .get_stream(stream_id)
+            .and_then(|stream| stream.lock().unwrap().get_metadata().get("owner").cloned());
+        match owner {
+            Some(owner) if owner != token => Err(ServerError::Forbidden(format!(
+                "stream {} belongs to another token",
+                stream_id
+            ))),
+            _ => Ok(()),
         }
     }
 
-    /// Send a JSON message to the client.
+    /// Get the namespace the connection's token is confined to, if any.
+    fn namespace_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> Option<String> {
+        let token = Self::token_of(clients, client_id)?;
+        TokenQuotas::current()?
+            .namespace(&token)
+            .map(str::to_string)
+    }
+
+    /// Write the access log line of a control message once it is handled.
+    fn log_access(
+        access_log: &AccessLog,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        op: &str,
+        stream_id: Option<&str>,
+        duration: Duration,
+    ) {
+        let clients = clients.lock().unwrap();
+        let Some(session) = clients.get(&client_id) else {
+            return;
+        };
+        let status = session.operation.status.as_deref().unwrap_or(STATUS_OK);
+        let bytes = match &session.state {
+            SessionState::Finalized { bytes, .. } if op == "STOP" && status == STATUS_OK => *bytes,
+            _ => session.operation.bytes,
+        };
+        access_log.record(&AccessEntry {
+            peer: session.peer,
+            token: session.token.as_deref(),
+            op,
+            stream_id,
+            status,
+            bytes,
+            duration,
+        });
+    }
+
+    /// Update a client's session in place.
+    fn session_mut(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        update: impl FnOnce(&mut ClientSession),
+    ) {
+        update(clients.lock().unwrap().entry(client_id).or_default());
+    }
+
+    /// Handle DRAIN message (admin: stop taking uploads and exit once the
+    /// open connections close).
+    fn handle_drain(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        token: &str,
+    ) {
+        if let Err(e) = drain::authorize(token) {
+            Self::send_server_error(websocket, clients, client_id, &e);
+            return;
+        }
+
+        if drain::begin() {
+            warn!("Drain mode entered: refusing new uploads and connections");
+        }
+        let uploads = clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.state.active_stream().is_some())
+            .count();
+        let response = ControlMessage::Draining { uploads };
+        Self::send_json(websocket, clients, client_id, &response);
+        info!("Draining with {} uploads in flight", uploads);
+    }
+
+    /// Get the control message encoding negotiated by a client.
+    fn encoding_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> Encoding {
+        clients
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .map(|session| session.encoding)
+            .unwrap_or_default()
+    }
+
+    /// Get a copy of a client's upload state to apply a transition to.
+    fn state_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> SessionState {
+        clients
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .map(|session| session.state.clone())
+            .unwrap_or_default()
+    }
+
+    /// Commit a client's upload state after a successful transition.
+    fn set_state(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        state: SessionState,
+    ) {
+        clients.lock().unwrap().entry(client_id).or_default().state = state;
+    }
+
+    /// Get the largest data frame a client may send, if limited.
+    fn max_chunk_size_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> Option<usize> {
+        clients
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .and_then(|session| session.max_chunk_size)
+    }
+
+    /// Get where a client's next data frame is written, if SEEK moved it.
+    fn write_offset_of(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+    ) -> Option<u64> {
+        clients
+            .lock()
+            .unwrap()
+            .get(&client_id)
+            .and_then(|session| session.write_offset)
+    }
+
+    /// Set where a client's next data frame is written; `None` appends.
+    fn set_write_offset(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        offset: Option<u64>,
+    ) {
+        clients
+            .lock()
+            .unwrap()
+            .entry(client_id)
+            .or_default()
+            .write_offset = offset;
+    }
+
+    /// Count `written` bytes of an upload that asked for ACKs, and return
+    /// the total to acknowledge.
+    fn add_acked_bytes(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        written: u64,
+    ) -> Option<u64> {
+        let mut clients = clients.lock().unwrap();
+        let acked = clients.get_mut(&client_id)?.acked_bytes.as_mut()?;
+        *acked += written;
+        Some(*acked)
+    }
+
+    /// Send a control message to the client in its negotiated encoding.
     fn send_json(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        _clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         client_id: usize,
         data: &ControlMessage,
     ) {
-        let json = match serde_json::to_string(data) {
-            Ok(j) => j,
+        let encoding = Self::encoding_of(clients, client_id);
+        let frame = if encoding.is_binary() {
+            encoding
+                .encode_frame(data)
+                .map(|frame| WsMessage::Binary(Bytes::from(frame)))
+                .map_err(|e| e.to_string())
+        } else {
+            data.to_json()
+                .map(|json| WsMessage::Text(Utf8Bytes::from(json)))
+                .map_err(|e| e.to_string())
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
             Err(e) => {
-                eprintln!("Error marshaling JSON: {:?}", e);
+                error!("Error encoding {} message: {:?}", encoding.as_str(), e);
                 return;
             }
         };
 
+        // ERROR and MOVED become the status of the operation in the access log
+        let status = match data {
+            ControlMessage::Error { code, .. } => Some(code.as_deref().unwrap_or("ERROR")),
+            ControlMessage::Moved { .. } => Some("MOVED"),
+            _ => None,
+        };
+        if let Some(status) = status {
+            Self::session_mut(clients, client_id, |session| {
+                session.operation.status = Some(status.to_string())
+            });
+        }
+
         // Send via WebSocket
-        match websocket.send(WsMessage::Text(Utf8Bytes::from(json.as_str()))) {
+        match Self::send_frame(websocket, clients, client_id, frame) {
             Ok(_) => {
-                println!("Sending to client {}: {}", client_id, json);
+                info!("Sending to client {}: {:?}", client_id, data);
             }
             Err(e) => {
-                eprintln!("Failed to send message to client: {:?}", e);
+                error!("Failed to send message to client: {:?}", e);
             }
         }
     }
 
+    /// Send a frame, logging it first when frame dumping is enabled.
+    fn send_frame(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        frame: WsMessage,
+    ) -> tungstenite::Result<()> {
+        Self::dump_frame(clients, client_id, FrameDirection::Outbound, &frame);
+        let len = frame.len() as u64;
+        websocket.send(frame)?;
+        Self::session_mut(clients, client_id, |session| session.operation.bytes += len);
+        Ok(())
+    }
+
+    /// Log a frame of this client's connection when frame dumping is enabled.
+    pub fn dump_frame(
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        direction: FrameDirection,
+        frame: &WsMessage,
+    ) {
+        let mut clients = clients.lock().unwrap();
+        let Some(dump) = clients
+            .get_mut(&client_id)
+            .and_then(|session| session.frame_dump.as_mut())
+        else {
+            return;
+        };
+
+        let (kind, payload) = frame_parts(frame);
+        info!(
+            "client {} {}",
+            client_id,
+            dump.line(direction, kind, payload)
+        );
+    }
+
+    /// Send an ERROR carrying the code and cause of a failure.
+    fn send_server_error(
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        client_id: usize,
+        error: &ServerError,
+    ) {
+        let response = ControlMessage::Error {
+            stream_id: error.stream_id().map(str::to_string),
+            code: Some(error.code().to_string()),
+            message: error.to_string(),
+        };
+
+        Self::send_json(websocket, clients, client_id, &response);
+        warn!("Sent error to client: {}", error);
+    }
+
     /// Send an error message to the client.
     fn send_error(
-        websocket: &mut WebSocket<std::net::TcpStream>,
-        clients: &Arc<Mutex<HashMap<usize, String>>>,
+        websocket: &mut Connection,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
         client_id: usize,
         message: &str,
     ) {
-        let response = ControlMessage {
-            msg_type: "ERROR".to_string(),
-            stream_id: None,
-            offset: None,
-            length: None,
-            message: Some(message.to_string()),
-        };
+        let response = ControlMessage::error(message);
 
         Self::send_json(websocket, clients, client_id, &response);
-        eprintln!("Sent error to client: {}", message);
+        warn!("Sent error to client: {}", message);
     }
 }
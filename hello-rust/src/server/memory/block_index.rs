@@ -0,0 +1,248 @@
+// Per-block SHA-256 index of a finalized stream.
+// Each 64KB block is hashed on finalize and the digests are stored in a
+// sidecar file next to the stream cache, so any byte range can be verified,
+// or proven to a client, by hashing only the blocks it covers.
+//
+// Sidecar format: the raw 32-byte digests of blocks 0..n, back to back.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Bytes covered by one index entry; the last block may be shorter.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Most blocks verified by one range request, 64MB of data. Longer ranges
+/// are cut short; the client asks again from the next block.
+pub const MAX_BLOCKS_PER_REQUEST: u64 = 1024;
+
+/// SHA-256 digest of one block.
+pub type BlockHash = [u8; 32];
+
+/// Block hashes of a stream of `size` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockIndex {
+    size: u64,
+    hashes: Vec<BlockHash>,
+}
+
+impl BlockIndex {
+    /// Hash `data` block by block.
+    pub fn build(data: &[u8]) -> Self {
+        let hashes = data
+            .chunks(BLOCK_SIZE as usize)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+        Self {
+            size: data.len() as u64,
+            hashes,
+        }
+    }
+
+    /// Hash `data` block by block, along with the SHA-256 checksum (hex) of
+    /// the whole of it, in a single pass over the data.
+    pub fn build_with_checksum(data: &[u8]) -> (Self, String) {
+        let mut digest = DigestBuilder::default();
+        digest.update(data);
+        digest.finish()
+    }
+
+    /// Get the sidecar path holding the index of a cache file.
+    pub fn index_path(cache_path: &str) -> PathBuf {
+        Path::new(cache_path).with_extension("blocks")
+    }
+
+    /// Write the index next to a cache file.
+    pub fn save(&self, cache_path: &str) -> std::io::Result<()> {
+        std::fs::write(Self::index_path(cache_path), self.hashes.concat())
+    }
+
+    /// Load the index of a cache file holding `size` bytes.
+    /// Returns `None` when the sidecar is missing or does not match the size.
+    pub fn load(cache_path: &str, size: u64) -> Option<Self> {
+        let data = std::fs::read(Self::index_path(cache_path)).ok()?;
+        if data.len() as u64 != Self::block_count_for(size) * 32 {
+            return None;
+        }
+        let hashes = data
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        Some(Self { size, hashes })
+    }
+
+    /// Get the size of the indexed stream.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the number of blocks.
+    pub fn block_count(&self) -> u64 {
+        self.hashes.len() as u64
+    }
+
+    /// Get the hash of a block.
+    pub fn hash(&self, block: u64) -> Option<&BlockHash> {
+        self.hashes.get(block as usize)
+    }
+
+    /// Get the blocks overlapping `length` bytes at `offset`, clamped to the
+    /// end of the stream.
+    pub fn blocks_covering(&self, offset: u64, length: u64) -> Range<u64> {
+        let end = offset.saturating_add(length).min(self.size);
+        if offset >= end {
+            return 0..0;
+        }
+        offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE)
+    }
+
+    /// Get the byte range of a block.
+    pub fn block_range(&self, block: u64) -> Range<u64> {
+        let start = block * BLOCK_SIZE;
+        start..(start + BLOCK_SIZE).min(self.size)
+    }
+
+    /// Get the hex digest of a block.
+    pub fn hex_hash(&self, block: u64) -> Option<String> {
+        self.hash(block)
+            .map(|hash| hash.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Check the contents of a block against its hash.
+    pub fn verify_block(&self, block: u64, data: &[u8]) -> bool {
+        self.hash(block)
+            .is_some_and(|hash| Sha256::digest(data).as_slice() == hash)
+    }
+
+    fn block_count_for(size: u64) -> u64 {
+        size.div_ceil(BLOCK_SIZE)
+    }
+}
+
+/// Checksum and block index of a stream, built as its bytes are fed in
+/// order, in pieces of any size.
+#[derive(Debug, Clone, Default)]
+pub struct DigestBuilder {
+    hashed: u64,
+    hasher: Sha256,
+    block: Sha256,
+    hashes: Vec<BlockHash>,
+}
+
+impl DigestBuilder {
+    /// Get the number of bytes fed so far.
+    pub fn hashed(&self) -> u64 {
+        self.hashed
+    }
+
+    /// Feed the bytes following those fed so far.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.hasher.update(data);
+        while !data.is_empty() {
+            let room = (BLOCK_SIZE - self.hashed % BLOCK_SIZE) as usize;
+            let (head, rest) = data.split_at(room.min(data.len()));
+            self.block.update(head);
+            self.hashed += head.len() as u64;
+            if self.hashed.is_multiple_of(BLOCK_SIZE) {
+                self.hashes.push(self.block.finalize_reset().into());
+            }
+            data = rest;
+        }
+    }
+
+    /// Get the block index and the SHA-256 checksum (hex) of the bytes fed.
+    pub fn finish(mut self) -> (BlockIndex, String) {
+        if !self.hashed.is_multiple_of(BLOCK_SIZE) {
+            self.hashes.push(self.block.finalize().into());
+        }
+        let index = BlockIndex {
+            size: self.hashed,
+            hashes: self.hashes,
+        };
+        (index, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two full blocks and a 100-byte tail.
+    fn sample() -> Vec<u8> {
+        (0..2 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn blocks_covering_clamps_to_the_stream() {
+        let index = BlockIndex::build(&sample());
+        assert_eq!(index.block_count(), 3);
+        assert_eq!(index.blocks_covering(0, 1), 0..1);
+        assert_eq!(index.blocks_covering(BLOCK_SIZE - 1, 2), 0..2);
+        assert_eq!(index.blocks_covering(BLOCK_SIZE, BLOCK_SIZE), 1..2);
+        assert_eq!(index.blocks_covering(BLOCK_SIZE + 1, u64::MAX), 1..3);
+        assert_eq!(index.blocks_covering(u64::MAX, u64::MAX), 0..0);
+        assert_eq!(index.blocks_covering(2 * BLOCK_SIZE + 100, 1), 0..0);
+        assert_eq!(index.blocks_covering(0, 0), 0..0);
+    }
+
+    #[test]
+    fn block_range_shortens_the_last_block() {
+        let index = BlockIndex::build(&sample());
+        assert_eq!(index.block_range(0), 0..BLOCK_SIZE);
+        assert_eq!(index.block_range(1), BLOCK_SIZE..2 * BLOCK_SIZE);
+        assert_eq!(index.block_range(2), 2 * BLOCK_SIZE..2 * BLOCK_SIZE + 100);
+    }
+
+    #[test]
+    fn verify_block_detects_changes() {
+        let data = sample();
+        let index = BlockIndex::build(&data);
+        let tail = &data[2 * BLOCK_SIZE as usize..];
+        assert!(index.verify_block(2, tail));
+        assert!(!index.verify_block(1, tail));
+        assert!(!index.verify_block(3, tail));
+
+        let mut changed = tail.to_vec();
+        changed[0] ^= 1;
+        assert!(!index.verify_block(2, &changed));
+    }
+
+    #[test]
+    fn checksum_is_computed_with_the_index() {
+        let data = sample();
+        let (index, checksum) = BlockIndex::build_with_checksum(&data);
+        assert_eq!(index, BlockIndex::build(&data));
+        assert_eq!(checksum, format!("{:x}", Sha256::digest(&data)));
+    }
+
+    #[test]
+    fn digest_is_independent_of_the_pieces_fed() {
+        let data = sample();
+        let mut digest = DigestBuilder::default();
+        for piece in data.chunks(10_007) {
+            digest.update(piece);
+        }
+        assert_eq!(digest.hashed(), data.len() as u64);
+        assert_eq!(digest.finish(), BlockIndex::build_with_checksum(&data));
+
+        // A stream ending on a block boundary has no empty last block
+        let mut digest = DigestBuilder::default();
+        digest.update(&data[..BLOCK_SIZE as usize]);
+        assert_eq!(digest.finish().0.block_count(), 1);
+    }
+
+    #[test]
+    fn load_refuses_an_index_of_another_size() {
+        let cache_path = std::env::temp_dir()
+            .join(format!("block-index-{}.cache", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let index = BlockIndex::build(&sample());
+        index.save(&cache_path).unwrap();
+
+        assert_eq!(BlockIndex::load(&cache_path, index.size()), Some(index));
+        assert_eq!(BlockIndex::load(&cache_path, 10), None);
+        let _ = std::fs::remove_file(BlockIndex::index_path(&cache_path));
+    }
+}
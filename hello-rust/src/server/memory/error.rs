@@ -0,0 +1,157 @@
+// Error types for the memory-mapped cache and the stream registry.
+
+use thiserror::Error;
+
+use crate::protocol::InvalidStreamId;
+
+/// Failure of a memory-mapped cache file operation.
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("I/O error on {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Cache file does not exist: {0}")]
+    Missing(String),
+    #[error("Cache file is not open: {0}")]
+    NotOpen(String),
+    #[error("Cache file is not mapped: {0}")]
+    NotMapped(String),
+    #[error("Range {offset}+{length} out of bounds for {path} ({size} bytes)")]
+    OutOfBounds {
+        path: String,
+        offset: u64,
+        length: usize,
+        size: u64,
+    },
+    #[error("Cache file {path} of {size} bytes exceeds the address space")]
+    TooLarge { path: String, size: u64 },
+    #[error("Batch of {count} writes to {path} exceeds the limit of {limit}")]
+    BatchTooLarge {
+        path: String,
+        count: usize,
+        limit: usize,
+    },
+}
+
+impl CacheError {
+    pub(crate) fn io(path: &str, source: std::io::Error) -> Self {
+        CacheError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+/// Failure of a stream registry operation.
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("Stream not found: {0}")]
+    NotFound(String),
+    #[error("Stream already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Stream {stream_id} has no version {version}")]
+    VersionNotFound { stream_id: String, version: u64 },
+    #[error("Invalid stream ID: {source}")]
+    InvalidId {
+        stream_id: String,
+        #[source]
+        source: InvalidStreamId,
+    },
+    #[error("Stream {stream_id} is {status}, expected {expected}")]
+    InvalidState {
+        stream_id: String,
+        status: &'static str,
+        expected: &'static str,
+    },
+    #[error("Processor {processor} rejected stream {stream_id}: {reason}")]
+    Rejected {
+        stream_id: String,
+        processor: String,
+        reason: String,
+    },
+    #[error("Journal error for stream {stream_id}: {source}")]
+    Journal {
+        stream_id: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("No data at offset {offset} of stream: {stream_id}")]
+    NoData { stream_id: String, offset: u64 },
+    #[error("Stream {stream_id} is missing the bytes at offset {offset}")]
+    Incomplete { stream_id: String, offset: u64 },
+    #[error("Stream {0} has no PCM sample format to map times to bytes")]
+    NoSampleFormat(String),
+    #[error("Stream {stream_id} failed verification at block {block}")]
+    Corrupted { stream_id: String, block: u64 },
+    #[error("Stream {0} changed while it was being cloned")]
+    Changed(String),
+    #[error("Storage error for stream {stream_id}: {source}")]
+    Cache {
+        stream_id: String,
+        #[source]
+        source: CacheError,
+    },
+}
+
+impl StreamError {
+    /// Get the code reported in protocol ERROR messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            StreamError::NotFound(_) => "STREAM_NOT_FOUND",
+            StreamError::AlreadyExists(_) => "STREAM_EXISTS",
+            StreamError::VersionNotFound { .. } => "VERSION_NOT_FOUND",
+            StreamError::InvalidId { .. } => "INVALID_STREAM_ID",
+            StreamError::InvalidState { .. } | StreamError::Changed(_) => "INVALID_STATE",
+            StreamError::Rejected { .. } => "REJECTED",
+            StreamError::NoData { .. } => "NO_DATA",
+            StreamError::NoSampleFormat(_) => "NO_SAMPLE_FORMAT",
+            StreamError::Incomplete { .. } => "INCOMPLETE_UPLOAD",
+            StreamError::Corrupted { .. } => "CHECKSUM_MISMATCH",
+            StreamError::Journal { .. } | StreamError::Cache { .. } => "STORAGE_ERROR",
+        }
+    }
+
+    /// Get the stream the error refers to.
+    pub fn stream_id(&self) -> &str {
+        match self {
+            StreamError::NotFound(stream_id)
+            | StreamError::AlreadyExists(stream_id)
+            | StreamError::NoSampleFormat(stream_id)
+            | StreamError::Changed(stream_id) => stream_id,
+            StreamError::InvalidId { stream_id, .. }
+            | StreamError::VersionNotFound { stream_id, .. }
+            | StreamError::InvalidState { stream_id, .. }
+            | StreamError::Rejected { stream_id, .. }
+            | StreamError::NoData { stream_id, .. }
+            | StreamError::Incomplete { stream_id, .. }
+            | StreamError::Corrupted { stream_id, .. }
+            | StreamError::Journal { stream_id, .. }
+            | StreamError::Cache { stream_id, .. } => stream_id,
+        }
+    }
+
+    pub(crate) fn cache(stream_id: &str, source: CacheError) -> Self {
+        StreamError::Cache {
+            stream_id: stream_id.to_string(),
+            source,
+        }
+    }
+
+    /// Check that `stream_id` can name cache files.
+    pub(crate) fn validate_id(stream_id: &str) -> Result<(), Self> {
+        crate::protocol::validate_stream_id(stream_id).map_err(|source| StreamError::InvalidId {
+            stream_id: stream_id.to_string(),
+            source,
+        })
+    }
+
+    pub(crate) fn journal(stream_id: &str, source: std::io::Error) -> Self {
+        StreamError::Journal {
+            stream_id: stream_id.to_string(),
+            source,
+        }
+    }
+}
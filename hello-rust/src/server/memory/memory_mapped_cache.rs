@@ -3,19 +3,26 @@
 // Matches Python MmapCache functionality.
 
 use memmap2::MmapMut;
+#[cfg(unix)]
+use memmap2::{Advice, UncheckedAdvice};
+use sha2::{Digest, Sha256};
 use std::fs::{File, OpenOptions};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
+use tracing::{info, warn};
+
+use super::read_ahead::ReadAhead;
+use super::{BlockIndex, CacheError, DigestBuilder};
 
 // Configuration constants - follows unified mmap specification v2.0.0
-#[allow(dead_code)]
 const DEFAULT_PAGE_SIZE: u64 = 64 * 1024 * 1024; // 64MB
-#[allow(dead_code)]
 const MAX_CACHE_SIZE: u64 = 8 * 1024 * 1024 * 1024; // 8GB
 #[allow(dead_code)]
-const SEGMENT_SIZE: u64 = 1 * 1024 * 1024 * 1024; // 1GB per segment
-#[allow(dead_code)]
+const SEGMENT_SIZE: u64 = 1024 * 1024 * 1024; // 1GB per segment
 const BATCH_OPERATION_LIMIT: usize = 1000; // Max batch operations
+const HUGE_PAGE_THRESHOLD: u64 = DEFAULT_PAGE_SIZE; // Smallest mapping backed by huge pages
 
 /// Memory-mapped cache implementation using memmap2.
 #[allow(dead_code)]
@@ -25,6 +32,11 @@ pub struct MemoryMappedCache {
     mmap: Mutex<Option<MmapMut>>,
     size: Mutex<u64>,
     is_open: Mutex<bool>,
+    huge_pages: bool,
+    large_files: bool,
+    read_ahead: Mutex<ReadAhead>,
+    /// Times the file was resized, each one remapping it.
+    resizes: AtomicUsize,
 }
 
 #[allow(dead_code)]
@@ -37,11 +49,37 @@ impl MemoryMappedCache {
             mmap: Mutex::new(None),
             size: Mutex::new(0),
             is_open: Mutex::new(false),
+            huge_pages: false,
+            large_files: false,
+            read_ahead: Mutex::new(ReadAhead::new(0)),
+            resizes: AtomicUsize::new(0),
         }
     }
 
+    /// Request transparent huge pages for mappings of at least 64MB.
+    /// Only supported on Linux; elsewhere the setting is ignored.
+    pub fn with_huge_pages(mut self, enabled: bool) -> Self {
+        self.huge_pages = enabled;
+        self
+    }
+
+    /// Preallocate files of any declared size, and grow files of unknown
+    /// size in 64MB steps rather than remapping them on every write, so
+    /// files of tens of GB are written at full speed.
+    pub fn with_large_files(mut self, enabled: bool) -> Self {
+        self.large_files = enabled;
+        self
+    }
+
+    /// Prefetch up to `limit` bytes ahead of readers reading the file
+    /// sequentially; 0 disables read-ahead.
+    pub fn with_read_ahead(mut self, limit: u64) -> Self {
+        self.read_ahead = Mutex::new(ReadAhead::new(limit));
+        self
+    }
+
     /// Create a new memory-mapped file.
-    pub fn create(&self, initial_size: u64) -> bool {
+    pub fn create(&self, initial_size: u64) -> Result<(), CacheError> {
         let mut file_lock = self.file.lock().unwrap();
 
         // Remove existing file
@@ -50,81 +88,79 @@ impl MemoryMappedCache {
         }
 
         // Create and open file
-        let file = match OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&self.path)
-        {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Error creating file {}: {:?}", self.path, e);
-                return false;
-            }
-        };
+            .map_err(|e| CacheError::io(&self.path, e))?;
 
         // Set file size
         if initial_size > 0 {
-            if let Err(e) = file.set_len(initial_size) {
-                eprintln!("Error truncating file {}: {:?}", self.path, e);
-                return false;
-            }
+            file.set_len(initial_size)
+                .map_err(|e| CacheError::io(&self.path, e))?;
         }
 
         *self.size.lock().unwrap() = initial_size;
         *file_lock = Some(file);
+        drop(file_lock);
 
         // Map file into memory if size > 0
         if initial_size > 0 {
-            if !self.map_file() {
-                return false;
-            }
+            self.map_file()?;
         }
 
         *self.is_open.lock().unwrap() = true;
-        println!(
+        info!(
             "Created mmap file: {} with size: {}",
             self.path, initial_size
         );
-        true
+        Ok(())
+    }
+
+    /// Create a memory-mapped file preallocated to `size_hint` bytes.
+    /// On Unix the file is extended with a hole, so disk space is only
+    /// consumed as data is written; elsewhere, and for hints above the
+    /// maximum cache size outside large-file mode, the file starts empty and
+    /// grows with each write.
+    pub fn create_sparse(&self, size_hint: u64) -> Result<(), CacheError> {
+        if cfg!(unix) && (self.large_files || size_hint <= MAX_CACHE_SIZE) {
+            self.create(size_hint)
+        } else {
+            self.create(0)
+        }
     }
 
     /// Open an existing memory-mapped file.
-    pub fn open(&self) -> bool {
+    pub fn open(&self) -> Result<(), CacheError> {
         if !Path::new(&self.path).exists() {
-            eprintln!("File does not exist: {}", self.path);
-            return false;
+            return Err(CacheError::Missing(self.path.clone()));
         }
 
-        let file = match OpenOptions::new().read(true).write(true).open(&self.path) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Error opening file {}: {:?}", self.path, e);
-                return false;
-            }
-        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(|e| CacheError::io(&self.path, e))?;
 
-        let size = match file.metadata() {
-            Ok(metadata) => metadata.len(),
-            Err(e) => {
-                eprintln!("Error getting file stats for {}: {:?}", self.path, e);
-                return false;
-            }
-        };
+        let size = file
+            .metadata()
+            .map_err(|e| CacheError::io(&self.path, e))?
+            .len();
 
         *self.size.lock().unwrap() = size;
         *self.file.lock().unwrap() = Some(file);
 
-        // Map file into memory if size > 0
+        // Map file into memory if size > 0; opened files are read front to back
         if size > 0 {
-            if !self.map_file() {
-                return false;
-            }
+            self.map_file()?;
+            self.advise_sequential();
         }
 
         *self.is_open.lock().unwrap() = true;
-        println!("Opened mmap file: {} with size: {}", self.path, size);
-        true
+        info!("Opened mmap file: {} with size: {}", self.path, size);
+        Ok(())
     }
 
     /// Close memory-mapped file.
@@ -134,87 +170,159 @@ impl MemoryMappedCache {
         }
     }
 
-    /// Write data to memory-mapped file.
-    pub fn write(&self, offset: u64, data: &[u8]) -> usize {
+    /// Write data to memory-mapped file, returning the number of bytes written.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<usize, CacheError> {
+        let required_size = self.extent_end(offset, data.len())?;
+
         // Check if file is open
         if !*self.is_open.lock().unwrap() {
-            let initial_size = offset + data.len() as u64;
-            if !self.create(initial_size) {
-                return 0;
-            }
+            self.create(required_size)?;
         }
 
         // Check required size
-        let required_size = offset + data.len() as u64;
         let current_size = *self.size.lock().unwrap();
         let has_mmap = self.mmap.lock().unwrap().is_some();
 
         // If file needs to grow or has no mmap yet, resize it
         if required_size > current_size || !has_mmap {
-            let new_size = std::cmp::max(required_size, current_size);
-            if !self.resize(new_size) {
-                eprintln!("Failed to resize file for write operation");
-                return 0;
-            }
+            self.resize(self.grown_size(required_size, current_size))?;
+        }
+
+        let mut mmap_lock = self.mmap.lock().unwrap();
+        let mmap = mmap_lock
+            .as_mut()
+            .ok_or_else(|| CacheError::NotMapped(self.path.clone()))?;
+        let start = offset as usize;
+        if start + data.len() > mmap.len() {
+            return Err(self.out_of_bounds(offset, data.len(), mmap.len()));
+        }
+
+        mmap[start..start + data.len()].copy_from_slice(data);
+        info!(
+            "Wrote {} bytes to {} at offset {}",
+            data.len(),
+            self.path,
+            offset
+        );
+        Ok(data.len())
+    }
+
+    /// Write several (offset, data) extents under a single mmap lock, growing
+    /// the file at most once. Returns the total number of bytes written.
+    pub fn write_batch(&self, writes: &[(u64, &[u8])]) -> Result<usize, CacheError> {
+        if writes.len() > BATCH_OPERATION_LIMIT {
+            return Err(CacheError::BatchTooLarge {
+                path: self.path.clone(),
+                count: writes.len(),
+                limit: BATCH_OPERATION_LIMIT,
+            });
+        }
+        let mut required_size = None;
+        for (offset, data) in writes {
+            required_size = required_size.max(Some(self.extent_end(*offset, data.len())?));
+        }
+        let Some(required_size) = required_size else {
+            return Ok(0);
+        };
+
+        if !*self.is_open.lock().unwrap() {
+            self.create(required_size)?;
+        }
+
+        let current_size = *self.size.lock().unwrap();
+        let has_mmap = self.mmap.lock().unwrap().is_some();
+        if required_size > current_size || !has_mmap {
+            self.resize(self.grown_size(required_size, current_size))?;
         }
 
         let mut mmap_lock = self.mmap.lock().unwrap();
-        if let Some(ref mut mmap) = *mmap_lock {
-            let offset = offset as usize;
-            if offset + data.len() <= mmap.len() {
-                mmap[offset..offset + data.len()].copy_from_slice(data);
-                println!(
-                    "Wrote {} bytes to {} at offset {}",
-                    data.len(),
-                    self.path,
-                    offset
-                );
-                data.len()
-            } else {
-                eprintln!("Write offset out of bounds");
-                0
+        let mmap = mmap_lock
+            .as_mut()
+            .ok_or_else(|| CacheError::NotMapped(self.path.clone()))?;
+
+        let mut total = 0;
+        for (offset, data) in writes {
+            let start = *offset as usize;
+            if start + data.len() > mmap.len() {
+                return Err(self.out_of_bounds(*offset, data.len(), mmap.len()));
             }
-        } else {
-            eprintln!("No mmap available after resize");
-            0
+            mmap[start..start + data.len()].copy_from_slice(data);
+            total += data.len();
         }
+
+        info!(
+            "Wrote {} bytes to {} in a batch of {} writes",
+            total,
+            self.path,
+            writes.len()
+        );
+        Ok(total)
     }
 
     /// Read data from memory-mapped file.
-    pub fn read(&self, offset: u64, length: usize) -> Vec<u8> {
+    /// Reads at or past the end of the file return an empty buffer.
+    pub fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, CacheError> {
         if !*self.is_open.lock().unwrap() || self.mmap.lock().unwrap().is_none() {
-            if !self.open() {
-                eprintln!("Failed to open file for reading: {}", self.path);
-                return Vec::new();
-            }
+            self.open()?;
         }
 
         let size = *self.size.lock().unwrap();
         if offset >= size {
-            return Vec::new();
+            return Ok(Vec::new());
         }
 
         let actual_length = std::cmp::min(length, (size - offset) as usize);
 
         let mmap_lock = self.mmap.lock().unwrap();
-        if let Some(ref mmap) = *mmap_lock {
-            let offset = offset as usize;
-            if offset + actual_length <= mmap.len() {
-                let data = mmap[offset..offset + actual_length].to_vec();
-                println!(
-                    "Read {} bytes from {} at offset {}",
-                    data.len(),
-                    self.path,
-                    offset
-                );
-                data
-            } else {
-                eprintln!("Read offset out of bounds");
-                Vec::new()
-            }
-        } else {
-            Vec::new()
+        let mmap = mmap_lock
+            .as_ref()
+            .ok_or_else(|| CacheError::NotMapped(self.path.clone()))?;
+        let start = offset as usize;
+        if start + actual_length > mmap.len() {
+            return Err(self.out_of_bounds(offset, actual_length, mmap.len()));
+        }
+
+        let data = mmap[start..start + actual_length].to_vec();
+        self.prefetch(mmap, offset, actual_length);
+        info!(
+            "Read {} bytes from {} at offset {}",
+            data.len(),
+            self.path,
+            offset
+        );
+        Ok(data)
+    }
+
+    /// Read data from memory-mapped file into `buffer`, returning the number
+    /// of bytes read; reads stop at the end of the file.
+    pub fn read_into(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, CacheError> {
+        if !*self.is_open.lock().unwrap() || self.mmap.lock().unwrap().is_none() {
+            self.open()?;
+        }
+
+        let size = *self.size.lock().unwrap();
+        if offset >= size {
+            return Ok(0);
         }
+
+        let actual_length = std::cmp::min(buffer.len(), (size - offset) as usize);
+
+        let mmap_lock = self.mmap.lock().unwrap();
+        let mmap = mmap_lock
+            .as_ref()
+            .ok_or_else(|| CacheError::NotMapped(self.path.clone()))?;
+        let start = offset as usize;
+        if start + actual_length > mmap.len() {
+            return Err(self.out_of_bounds(offset, actual_length, mmap.len()));
+        }
+
+        buffer[..actual_length].copy_from_slice(&mmap[start..start + actual_length]);
+        self.prefetch(mmap, offset, actual_length);
+        info!(
+            "Read {} bytes from {} at offset {}",
+            actual_length, self.path, offset
+        );
+        Ok(actual_length)
     }
 
     /// Get the size of the file.
@@ -233,106 +341,459 @@ impl MemoryMappedCache {
     }
 
     /// Resize the file to a new size.
-    pub fn resize(&self, new_size: u64) -> bool {
-        if !*self.is_open.lock().unwrap() {
-            eprintln!("File not open for resize: {}", self.path);
-            return false;
-        }
+    pub fn resize(&self, new_size: u64) -> Result<(), CacheError> {
+        self.ensure_open()?;
 
         // Unmap current mmap
         self.unmap_file();
 
         // Resize file
-        {
-            let mut file_lock = self.file.lock().unwrap();
-            if let Some(ref mut file) = *file_lock {
-                if let Err(e) = file.set_len(new_size) {
-                    eprintln!("Error resizing file {}: {:?}", self.path, e);
-                    return false;
-                }
-            }
-        } // Release file_lock here
+        if let Some(ref mut file) = *self.file.lock().unwrap() {
+            file.set_len(new_size)
+                .map_err(|e| CacheError::io(&self.path, e))?;
+        }
 
         *self.size.lock().unwrap() = new_size;
+        self.resizes.fetch_add(1, Ordering::Relaxed);
 
         // Remap file if size > 0
         if new_size > 0 {
-            if !self.map_file() {
-                return false;
-            }
+            self.map_file()?;
         }
 
-        println!("Resized file {} to {} bytes", self.path, new_size);
-        true
+        info!("Resized file {} to {} bytes", self.path, new_size);
+        Ok(())
+    }
+
+    /// Largest size the file may grow to: 8GB, unlimited in large-file mode.
+    pub fn max_size(&self) -> u64 {
+        if self.large_files {
+            u64::MAX
+        } else {
+            MAX_CACHE_SIZE
+        }
+    }
+
+    /// Number of times the file was resized, and so remapped, since it was
+    /// created or opened.
+    pub fn resize_count(&self) -> usize {
+        self.resizes.load(Ordering::Relaxed)
     }
 
     /// Flush all mapped data to disk.
-    pub fn flush(&self) -> bool {
-        if !*self.is_open.lock().unwrap() {
-            eprintln!("File not open for flush: {}", self.path);
-            return false;
+    pub fn flush(&self) -> Result<(), CacheError> {
+        self.ensure_open()?;
+
+        if let Some(ref mmap) = *self.mmap.lock().unwrap() {
+            mmap.flush().map_err(|e| CacheError::io(&self.path, e))?;
         }
 
+        info!("Flushed file: {}", self.path);
+        Ok(())
+    }
+
+    /// Flush a byte range of mapped data to disk.
+    pub fn flush_range(&self, offset: u64, length: usize) -> Result<(), CacheError> {
         if let Some(ref mmap) = *self.mmap.lock().unwrap() {
-            if let Err(e) = mmap.flush() {
-                eprintln!("Error flushing file {}: {:?}", self.path, e);
-                return false;
+            let start = offset as usize;
+            if start + length > mmap.len() {
+                return Err(self.out_of_bounds(offset, length, mmap.len()));
+            }
+            mmap.flush_range(start, length)
+                .map_err(|e| CacheError::io(&self.path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Release the disk blocks backing a byte range; the range reads back as
+    /// zeros. Uses FALLOC_FL_PUNCH_HOLE on Linux and zero-fills elsewhere.
+    pub fn punch_hole(&self, offset: u64, length: usize) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        let size = *self.size.lock().unwrap();
+        if offset + length as u64 > size {
+            return Err(self.out_of_bounds(offset, length, size as usize));
+        }
+        if length == 0 {
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            if let Some(ref file) = *self.file.lock().unwrap() {
+                // SAFETY: the descriptor is owned by `file` and stays open for
+                // the duration of the call.
+                let result = unsafe {
+                    libc::fallocate(
+                        file.as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        offset as libc::off_t,
+                        length as libc::off_t,
+                    )
+                };
+                if result == 0 {
+                    info!(
+                        "Punched hole of {} bytes in {} at offset {}",
+                        length, self.path, offset
+                    );
+                    return Ok(());
+                }
+                let e = std::io::Error::last_os_error();
+                // Filesystems without hole support fall back to zero-filling
+                if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(CacheError::io(&self.path, e));
+                }
             }
         }
 
-        println!("Flushed file: {}", self.path);
-        true
+        if let Some(ref mut mmap) = *self.mmap.lock().unwrap() {
+            let start = offset as usize;
+            mmap[start..start + length].fill(0);
+        }
+        Ok(())
     }
 
     /// Finalize the file to its final size.
-    pub fn finalize(&self, final_size: u64) -> bool {
-        if !*self.is_open.lock().unwrap() {
-            eprintln!("File not open for finalization: {}", self.path);
-            return false;
+    pub fn finalize(&self, final_size: u64) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        self.resize(final_size)?;
+
+        if let Some(ref mmap) = *self.mmap.lock().unwrap() {
+            mmap.flush().map_err(|e| CacheError::io(&self.path, e))?;
+            // The data is on disk now; let the kernel reclaim the pages
+            Self::advise_dont_need(mmap);
         }
 
-        if !self.resize(final_size) {
-            eprintln!("Failed to resize file during finalization: {}", self.path);
-            return false;
+        info!("Finalized file: {} with size: {}", self.path, final_size);
+        Ok(())
+    }
+
+    /// Create `target` with the contents of the finalized cache file `source`
+    /// without duplicating its blocks where possible: a reflink (FICLONE) on
+    /// Linux filesystems that support copy-on-write, otherwise a hard link on
+    /// Unix. Finalized files are only written again after [`Self::unshare`].
+    /// Falls back to a full copy when neither is available.
+    pub fn clone_file(source: &str, target: &str) -> Result<(), CacheError> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let source_file = File::open(source).map_err(|e| CacheError::io(source, e))?;
+            let target_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(target)
+                .map_err(|e| CacheError::io(target, e))?;
+            // SAFETY: both descriptors are owned by open files that outlive the call.
+            let result = unsafe {
+                libc::ioctl(
+                    target_file.as_raw_fd(),
+                    libc::FICLONE,
+                    source_file.as_raw_fd(),
+                )
+            };
+            if result == 0 {
+                info!("Cloned {} to {} (reflink)", source, target);
+                return Ok(());
+            }
+            drop(target_file);
+            let _ = std::fs::remove_file(target);
         }
 
-        // MmapMut flushes automatically when dropped, but we can force flush
-        if let Some(ref mmap) = *self.mmap.lock().unwrap() {
-            mmap.flush().ok();
+        #[cfg(unix)]
+        if std::fs::hard_link(source, target).is_ok() {
+            info!("Cloned {} to {} (hard link)", source, target);
+            return Ok(());
         }
 
-        println!("Finalized file: {} with size: {}", self.path, final_size);
-        true
+        std::fs::copy(source, target).map_err(|e| CacheError::io(target, e))?;
+        info!("Cloned {} to {} (copy)", source, target);
+        Ok(())
     }
 
-    /// Map the file into memory using memmap2.
-    fn map_file(&self) -> bool {
-        let file_lock = self.file.lock().unwrap();
-        if let Some(ref file) = *file_lock {
-            let size = *self.size.lock().unwrap() as usize;
-            if size > 0 {
-                // Map entire file into memory (read-write mode)
-                match unsafe { MmapMut::map_mut(file) } {
-                    Ok(mmap) => {
-                        *self.mmap.lock().unwrap() = Some(mmap);
-                        println!("Successfully mapped file: {} ({} bytes)", self.path, size);
-                        true
-                    }
-                    Err(e) => {
-                        eprintln!("Error mapping file {}: {:?}", self.path, e);
-                        false
-                    }
-                }
-            } else {
-                false
+    /// Give a hard-linked file (see [`Self::clone_file`]) an inode of its own
+    /// before it is written again, so the writes don't show up in its clones.
+    pub fn unshare(&self) -> Result<(), CacheError> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata =
+                std::fs::metadata(&self.path).map_err(|e| CacheError::io(&self.path, e))?;
+            if metadata.nlink() > 1 {
+                let copy_path = format!("{}.unshare", self.path);
+                std::fs::copy(&self.path, &copy_path)
+                    .and_then(|_| std::fs::rename(&copy_path, &self.path))
+                    .map_err(|e| {
+                        let _ = std::fs::remove_file(&copy_path);
+                        CacheError::io(&self.path, e)
+                    })?;
+                // Drop the mapping of the shared inode and map the copy
+                self.unmap_file();
+                self.open()?;
+                info!("Unshared file: {}", self.path);
             }
+        }
+        Ok(())
+    }
+
+    /// Compute the SHA-256 checksum (hex) of the mapped contents.
+    pub fn compute_sha256(&self) -> Result<String, CacheError> {
+        let size = *self.size.lock().unwrap() as usize;
+        let mmap_lock = self.mmap.lock().unwrap();
+        let mut hasher = Sha256::new();
+
+        match *mmap_lock {
+            Some(ref mmap) => hasher.update(&mmap[..std::cmp::min(size, mmap.len())]),
+            None if size == 0 => {}
+            None => return Err(CacheError::NotMapped(self.path.clone())),
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Compute the SHA-256 checksum (hex) and the per-block hash index of the
+    /// mapped contents in one pass.
+    pub fn compute_digests(&self) -> Result<(String, BlockIndex), CacheError> {
+        let size = *self.size.lock().unwrap() as usize;
+        let mmap_lock = self.mmap.lock().unwrap();
+
+        let (index, checksum) = match *mmap_lock {
+            Some(ref mmap) => {
+                BlockIndex::build_with_checksum(&mmap[..std::cmp::min(size, mmap.len())])
+            }
+            None if size == 0 => BlockIndex::build_with_checksum(&[]),
+            None => return Err(CacheError::NotMapped(self.path.clone())),
+        };
+        Ok((checksum, index))
+    }
+
+    /// Feed the mapped bytes in `range` to `digest`, without copying them.
+    pub fn hash_range(
+        &self,
+        range: Range<u64>,
+        digest: &mut DigestBuilder,
+    ) -> Result<(), CacheError> {
+        if range.start >= range.end {
+            return Ok(());
+        }
+        let mmap_lock = self.mmap.lock().unwrap();
+        let mmap = mmap_lock
+            .as_ref()
+            .ok_or_else(|| CacheError::NotMapped(self.path.clone()))?;
+        let length = (range.end - range.start) as usize;
+        if range.end > mmap.len() as u64 {
+            return Err(self.out_of_bounds(range.start, length, mmap.len()));
+        }
+        digest.update(&mmap[range.start as usize..range.end as usize]);
+        Ok(())
+    }
+
+    /// Compute the per-block hash index of the mapped contents.
+    pub fn compute_block_index(&self) -> Result<BlockIndex, CacheError> {
+        let size = *self.size.lock().unwrap() as usize;
+        let mmap_lock = self.mmap.lock().unwrap();
+
+        match *mmap_lock {
+            Some(ref mmap) => Ok(BlockIndex::build(&mmap[..std::cmp::min(size, mmap.len())])),
+            None if size == 0 => Ok(BlockIndex::build(&[])),
+            None => Err(CacheError::NotMapped(self.path.clone())),
+        }
+    }
+
+    /// Size to resize to for a write ending at `required_size`. Past the end
+    /// of the file, large-file mode grows by at least a page of 64MB; reads
+    /// are bounded by the bytes written, and finalize trims the rest.
+    fn grown_size(&self, required_size: u64, current_size: u64) -> u64 {
+        if self.large_files && required_size > current_size {
+            std::cmp::max(required_size, current_size + DEFAULT_PAGE_SIZE)
+        } else {
+            std::cmp::max(required_size, current_size)
+        }
+    }
+
+    fn ensure_open(&self) -> Result<(), CacheError> {
+        if *self.is_open.lock().unwrap() {
+            Ok(())
         } else {
-            false
+            Err(CacheError::NotOpen(self.path.clone()))
+        }
+    }
+
+    /// End of `length` bytes at `offset`, refused when it overflows.
+    fn extent_end(&self, offset: u64, length: usize) -> Result<u64, CacheError> {
+        offset
+            .checked_add(length as u64)
+            .ok_or_else(|| CacheError::OutOfBounds {
+                path: self.path.clone(),
+                offset,
+                length,
+                size: *self.size.lock().unwrap(),
+            })
+    }
+
+    fn out_of_bounds(&self, offset: u64, length: usize, size: usize) -> CacheError {
+        CacheError::OutOfBounds {
+            path: self.path.clone(),
+            offset,
+            length,
+            size: size as u64,
+        }
+    }
+
+    /// Map the file into memory using memmap2.
+    fn map_file(&self) -> Result<(), CacheError> {
+        let file_lock = self.file.lock().unwrap();
+        let file = file_lock
+            .as_ref()
+            .ok_or_else(|| CacheError::NotOpen(self.path.clone()))?;
+        let size = *self.size.lock().unwrap();
+        if size == 0 {
+            return Err(CacheError::NotMapped(self.path.clone()));
+        }
+        // Offsets are u64 throughout; only the mapping is bounded by usize
+        if usize::try_from(size).is_err() {
+            return Err(CacheError::TooLarge {
+                path: self.path.clone(),
+                size,
+            });
+        }
+
+        // Map entire file into memory (read-write mode)
+        let mmap = unsafe { MmapMut::map_mut(file) }.map_err(|e| CacheError::io(&self.path, e))?;
+        if self.huge_pages && size >= HUGE_PAGE_THRESHOLD {
+            self.advise_huge_pages(&mmap);
+        }
+        *self.mmap.lock().unwrap() = Some(mmap);
+        info!("Successfully mapped file: {} ({} bytes)", self.path, size);
+        Ok(())
+    }
+
+    /// Hint that the mapping will be read sequentially (MADV_SEQUENTIAL).
+    /// Advice is best effort, so failures are ignored.
+    #[cfg(unix)]
+    fn advise_sequential(&self) {
+        if let Some(ref mmap) = *self.mmap.lock().unwrap() {
+            let _ = mmap.advise(Advice::Sequential);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn advise_sequential(&self) {}
+
+    /// Prefetch what the read-ahead asks for after a read of `length` bytes
+    /// at `offset`.
+    fn prefetch(&self, mmap: &MmapMut, offset: u64, length: usize) {
+        let range = self
+            .read_ahead
+            .lock()
+            .unwrap()
+            .on_read(offset, length as u64);
+        if let Some(range) = range {
+            let start = range.start.min(mmap.len() as u64) as usize;
+            Self::advise_will_need(mmap, start, (range.end - range.start) as usize);
         }
     }
 
+    /// Prefetch up to `length` bytes from `offset` (MADV_WILLNEED), so the
+    /// next chunks of a download are already in the page cache.
+    #[cfg(unix)]
+    fn advise_will_need(mmap: &MmapMut, offset: usize, length: usize) {
+        let length = std::cmp::min(length, mmap.len().saturating_sub(offset));
+        if length > 0 {
+            let _ = mmap.advise_range(Advice::WillNeed, offset, length);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn advise_will_need(_mmap: &MmapMut, _offset: usize, _length: usize) {}
+
+    /// Drop the mapped pages from memory (MADV_DONTNEED) after a flush.
+    #[cfg(unix)]
+    fn advise_dont_need(mmap: &MmapMut) {
+        // SAFETY: the mapping is shared and file backed, and the caller holds
+        // the mmap lock after flushing, so the pages are re-read from the file
+        // on next access and no slice into the mapping is alive.
+        let _ = unsafe { mmap.unchecked_advise(UncheckedAdvice::DontNeed) };
+    }
+
+    #[cfg(not(unix))]
+    fn advise_dont_need(_mmap: &MmapMut) {}
+
+    /// Back the mapping with transparent huge pages (MADV_HUGEPAGE).
+    #[cfg(target_os = "linux")]
+    fn advise_huge_pages(&self, mmap: &MmapMut) {
+        if let Err(e) = mmap.advise(Advice::HugePage) {
+            warn!("Huge pages unavailable for {}: {}", self.path, e);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn advise_huge_pages(&self, _mmap: &MmapMut) {}
+
     /// Unmap the file from memory.
     fn unmap_file(&self) {
         *self.mmap.lock().unwrap() = None;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache(name: &str) -> MemoryMappedCache {
+        let path = std::env::temp_dir().join(format!("{}-{}.cache", name, std::process::id()));
+        MemoryMappedCache::new(path.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn batch_grows_the_file_once() {
+        let cache = temp_cache("write-batch");
+        cache.create(0).unwrap();
+
+        let batch: [(u64, &[u8]); 3] = [(0, b"abcd"), (4, b"efgh"), (8, b"ij")];
+        assert_eq!(cache.write_batch(&batch).unwrap(), 10);
+        assert_eq!((cache.resize_count(), cache.get_size()), (1, 10));
+
+        let batch: [(u64, &[u8]); 2] = [(10, b"kl"), (12, b"mnop")];
+        assert_eq!(cache.write_batch(&batch).unwrap(), 6);
+        assert_eq!((cache.resize_count(), cache.get_size()), (2, 16));
+        assert_eq!(cache.read(0, 16).unwrap(), b"abcdefghijklmnop");
+
+        let _ = std::fs::remove_file(cache.get_path());
+    }
+
+    #[test]
+    fn write_past_the_offset_range_is_refused() {
+        let cache = temp_cache("write-overflow");
+        cache.create(16).unwrap();
+
+        assert!(matches!(
+            cache.write(u64::MAX - 2, b"abcd"),
+            Err(CacheError::OutOfBounds { .. })
+        ));
+        let batch: [(u64, &[u8]); 2] = [(0, b"ab"), (u64::MAX, b"c")];
+        assert!(matches!(
+            cache.write_batch(&batch),
+            Err(CacheError::OutOfBounds { .. })
+        ));
+        assert_eq!((cache.resize_count(), cache.get_size()), (0, 16));
+
+        let _ = std::fs::remove_file(cache.get_path());
+    }
+
+    #[test]
+    fn batch_over_the_limit_is_refused() {
+        let cache = temp_cache("write-batch-limit");
+        let writes: Vec<(u64, &[u8])> = (0..=BATCH_OPERATION_LIMIT as u64)
+            .map(|offset| (offset, &b"x"[..]))
+            .collect();
+        assert!(matches!(
+            cache.write_batch(&writes),
+            Err(CacheError::BatchTooLarge { count, limit, .. })
+                if count == BATCH_OPERATION_LIMIT + 1 && limit == BATCH_OPERATION_LIMIT
+        ));
+        assert!(!cache.is_open());
+    }
+}
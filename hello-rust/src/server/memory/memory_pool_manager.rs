@@ -2,9 +2,15 @@
 // Pre-allocates buffers to minimize allocation overhead.
 // Implemented as a singleton to ensure a single shared pool across all streams.
 // Matches C++ MemoryPoolManager and Java MemoryPoolManager functionality.
+// Each connection leases one buffer for its lifetime; the pressure counters
+// show how many are in use and how often the pool ran dry.
 
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+use tracing::{debug, warn};
+
 /// Memory pool manager singleton.
 #[allow(dead_code)]
 pub struct MemoryPoolManager {
@@ -12,6 +18,20 @@ pub struct MemoryPoolManager {
     pool_size: usize,
     available_buffers: Mutex<Vec<Vec<u8>>>,
     total_buffers: Mutex<usize>,
+    in_use: AtomicUsize,
+    peak_in_use: AtomicUsize,
+    exhausted: AtomicU64,
+}
+
+/// Snapshot of the pool's load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolPressure {
+    pub available: usize,
+    pub in_use: usize,
+    pub peak_in_use: usize,
+    pub total: usize,
+    /// Buffers allocated because the pool was empty.
+    pub exhausted: u64,
 }
 
 #[allow(dead_code)]
@@ -22,42 +42,51 @@ impl MemoryPoolManager {
         static INSTANCE: OnceLock<Arc<MemoryPoolManager>> = OnceLock::new();
 
         INSTANCE
-            .get_or_init(|| {
-                let available_buffers = (0..pool_size).map(|_| vec![0u8; buffer_size]).collect();
-
-                Arc::new(Self {
-                    buffer_size,
-                    pool_size,
-                    available_buffers: Mutex::new(available_buffers),
-                    total_buffers: Mutex::new(pool_size),
-                })
-            })
+            .get_or_init(|| Arc::new(Self::new(buffer_size, pool_size)))
             .clone()
     }
 
+    fn new(buffer_size: usize, pool_size: usize) -> Self {
+        let available_buffers = (0..pool_size).map(|_| vec![0u8; buffer_size]).collect();
+
+        Self {
+            buffer_size,
+            pool_size,
+            available_buffers: Mutex::new(available_buffers),
+            total_buffers: Mutex::new(pool_size),
+            in_use: AtomicUsize::new(0),
+            peak_in_use: AtomicUsize::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
     /// Acquire a buffer from the pool.
     /// If pool is exhausted, allocates a new buffer dynamically.
     pub fn acquire_buffer(&self) -> Vec<u8> {
         let mut buffers = self.available_buffers.lock().unwrap();
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_in_use.fetch_max(in_use, Ordering::Relaxed);
 
         if let Some(buffer) = buffers.pop() {
-            println!("Acquired buffer from pool ({} remaining)", buffers.len());
+            debug!("Acquired buffer from pool ({} remaining)", buffers.len());
             buffer
         } else {
             drop(buffers);
             // Pool exhausted, allocate new buffer
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
             let mut total = self.total_buffers.lock().unwrap();
             *total += 1;
-            println!("Pool exhausted, allocated new buffer (total: {})", *total);
+            debug!("Pool exhausted, allocated new buffer (total: {})", *total);
             vec![0u8; self.buffer_size]
         }
     }
 
     /// Release a buffer back to the pool.
     pub fn release_buffer(&self, mut buffer: Vec<u8>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
         if buffer.len() != self.buffer_size {
-            println!(
-                "Warning: Buffer size mismatch: expected {}, got {}",
+            warn!(
+                "Buffer size mismatch: expected {}, got {}",
                 self.buffer_size,
                 buffer.len()
             );
@@ -73,7 +102,26 @@ impl MemoryPoolManager {
             buffers.push(buffer);
         }
 
-        println!("Released buffer to pool ({} available)", buffers.len());
+        debug!("Released buffer to pool ({} available)", buffers.len());
+    }
+
+    /// Lease a buffer that goes back to the pool when dropped.
+    pub fn lease(self: &Arc<Self>) -> PooledBuffer {
+        PooledBuffer {
+            pool: self.clone(),
+            buffer: self.acquire_buffer(),
+        }
+    }
+
+    /// Get the current load of the pool.
+    pub fn pressure(&self) -> PoolPressure {
+        PoolPressure {
+            available: self.get_available_buffers(),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak_in_use: self.peak_in_use.load(Ordering::Relaxed),
+            total: self.get_total_buffers(),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
     }
 
     /// Get the number of available buffers in the pool.
@@ -91,3 +139,55 @@ impl MemoryPoolManager {
         self.buffer_size
     }
 }
+
+/// Buffer leased from a [`MemoryPoolManager`]. It may be resized while
+/// leased; it is restored to the pool's buffer size when returned.
+pub struct PooledBuffer {
+    pool: Arc<MemoryPoolManager>,
+    buffer: Vec<u8>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(self.pool.buffer_size, 0);
+        buffer.shrink_to(self.pool.buffer_size);
+        self.pool.release_buffer(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leased_buffer_returns_at_its_original_size() {
+        let pool = Arc::new(MemoryPoolManager::new(1024, 1));
+        {
+            let mut buffer = pool.lease();
+            buffer.resize(1 << 20, 7);
+            assert_eq!(pool.pressure().in_use, 1);
+        }
+
+        let pressure = pool.pressure();
+        assert_eq!((pressure.available, pressure.in_use), (1, 0));
+        let buffer = pool.acquire_buffer();
+        assert_eq!(buffer.len(), 1024);
+        assert!(buffer.capacity() < 1 << 20);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+    }
+}
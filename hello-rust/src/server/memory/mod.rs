@@ -1,10 +1,19 @@
 // Server memory module - cache and stream management
+pub mod block_index;
+pub mod error;
 pub mod memory_mapped_cache;
 pub mod memory_pool_manager;
+pub mod read_ahead;
 pub mod stream_context;
+pub mod stream_journal;
 pub mod stream_manager;
+pub mod timestamp_index;
 
+pub use block_index::{BlockIndex, DigestBuilder};
+pub use error::{CacheError, StreamError};
 pub use memory_mapped_cache::MemoryMappedCache;
-pub use memory_pool_manager::MemoryPoolManager;
+pub use memory_pool_manager::{MemoryPoolManager, PoolPressure, PooledBuffer};
 pub use stream_context::{StreamContext, StreamStatus};
-pub use stream_manager::StreamManager;
+pub use stream_journal::StreamJournal;
+pub use stream_manager::{BatchWrite, CacheSweepReport, ScrubReport, StreamManager, WritePosition};
+pub use timestamp_index::TimestampIndex;
@@ -0,0 +1,123 @@
+// Read-ahead for downloads from memory-mapped cache files.
+// Each cache file tracks the readers whose reads continue where their last
+// one ended, so concurrent downloads of one stream are told apart. A reader's
+// window starts at the size of its reads and doubles with every sequential
+// read up to the configured limit; whenever less than half a window is left
+// prefetched ahead of it, the pages up to a window ahead are requested
+// (MADV_WILLNEED), so cold files are read from disk in large requests before
+// the GETs reach them instead of one page fault at a time. Reads elsewhere in
+// the file, e.g. after a SEEK, prefetch nothing until they turn sequential.
+
+use std::ops::Range;
+
+/// Sequential readers tracked per file; the least recent one is dropped.
+const MAX_READERS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Reader {
+    /// End of the last read.
+    end: u64,
+    /// Bytes prefetched ahead of `end` once the window is refilled.
+    window: u64,
+    /// End of the bytes prefetched so far.
+    prefetched: u64,
+}
+
+/// Sequential readers of one cache file.
+#[derive(Debug)]
+pub struct ReadAhead {
+    limit: u64,
+    /// Most recent reader first.
+    readers: Vec<Reader>,
+}
+
+impl ReadAhead {
+    /// Create the state of a file prefetching at most `limit` bytes ahead of
+    /// a reader; 0 disables read-ahead.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            readers: Vec::new(),
+        }
+    }
+
+    /// Record a read of `length` bytes at `offset` and return the byte range
+    /// to prefetch, if any.
+    pub fn on_read(&mut self, offset: u64, length: u64) -> Option<Range<u64>> {
+        if self.limit == 0 || length == 0 {
+            return None;
+        }
+        let end = offset + length;
+        let reader = match self.readers.iter().position(|reader| reader.end == offset) {
+            Some(index) => Some(self.readers.remove(index)),
+            // A read from the start of the file begins a download
+            None if offset == 0 => Some(Reader {
+                end: 0,
+                window: 0,
+                prefetched: 0,
+            }),
+            None => None,
+        };
+        let Some(mut reader) = reader else {
+            self.track(Reader {
+                end,
+                window: 0,
+                prefetched: end,
+            });
+            return None;
+        };
+
+        reader.window = (reader.window * 2).max(length).min(self.limit);
+        reader.end = end;
+        let start = reader.prefetched.max(end);
+        let range = (start - end <= reader.window / 2).then(|| start..end + reader.window);
+        if let Some(range) = &range {
+            reader.prefetched = range.end;
+        }
+        self.track(reader);
+        range
+    }
+
+    fn track(&mut self, reader: Reader) {
+        self.readers.insert(0, reader);
+        self.readers.truncate(MAX_READERS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_grows_with_sequential_reads() {
+        let mut read_ahead = ReadAhead::new(8000);
+        assert_eq!(read_ahead.on_read(0, 1000), Some(1000..2000));
+        assert_eq!(read_ahead.on_read(1000, 1000), Some(2000..4000));
+        assert_eq!(read_ahead.on_read(2000, 1000), Some(4000..7000));
+        // Refilled only once less than half a window is left ahead
+        assert_eq!(read_ahead.on_read(3000, 1000), Some(7000..12000));
+        assert_eq!(read_ahead.on_read(4000, 1000), None);
+        assert_eq!(read_ahead.on_read(5000, 1000), None);
+        assert_eq!(read_ahead.on_read(6000, 1000), None);
+        assert_eq!(read_ahead.on_read(7000, 1000), Some(12000..16000));
+    }
+
+    #[test]
+    fn random_reads_prefetch_nothing() {
+        let mut read_ahead = ReadAhead::new(8000);
+        assert_eq!(read_ahead.on_read(50_000, 1000), None);
+        assert_eq!(read_ahead.on_read(20_000, 1000), None);
+        // Until one of them continues
+        assert_eq!(read_ahead.on_read(51_000, 1000), Some(52_000..53_000));
+        assert_eq!(ReadAhead::new(0).on_read(0, 1000), None);
+    }
+
+    #[test]
+    fn interleaved_readers_are_told_apart() {
+        let mut read_ahead = ReadAhead::new(8000);
+        assert_eq!(read_ahead.on_read(0, 1000), Some(1000..2000));
+        assert_eq!(read_ahead.on_read(10_000, 1000), None);
+        assert_eq!(read_ahead.on_read(1000, 1000), Some(2000..4000));
+        assert_eq!(read_ahead.on_read(11_000, 1000), Some(12_000..13_000));
+    }
+}
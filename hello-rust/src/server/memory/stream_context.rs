@@ -2,13 +2,22 @@
 // Contains stream metadata and cache file handle.
 // Matches Python StreamContext and Java StreamContext functionality.
 
-use std::time::SystemTime;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::{ExtentList, PcmFormat};
+
+// Leading bytes of a WAV stream searched for its data chunk
+const WAV_HEADER_SEARCH_BYTES: u64 = 64 * 1024;
 
 /// Stream status enumeration
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)]
 pub enum StreamStatus {
     Uploading,
+    /// Upload abandoned by a silent client; kept until resumed or expired.
+    Paused,
     Ready,
     Error,
 }
@@ -18,6 +27,7 @@ impl StreamStatus {
     pub fn as_str(&self) -> &'static str {
         match self {
             StreamStatus::Uploading => "UPLOADING",
+            StreamStatus::Paused => "PAUSED",
             StreamStatus::Ready => "READY",
             StreamStatus::Error => "ERROR",
         }
@@ -35,6 +45,27 @@ pub struct StreamContext {
     pub created_at: SystemTime,
     pub last_accessed_at: SystemTime,
     pub status: StreamStatus,
+    pub checksum: Option<String>,
+    pub metadata: HashMap<String, String>,
+    /// Key/value tags given with START, matched by LIST.
+    pub tags: BTreeMap<String, String>,
+    pub journal: Option<std::sync::Arc<super::StreamJournal>>,
+    pub block_index: Option<std::sync::Arc<super::BlockIndex>>,
+    /// Checksum of the bytes received contiguously from the start, kept up
+    /// to date while uploading; `None` once hashed bytes were rewritten.
+    pub digest: Option<super::DigestBuilder>,
+    pub timestamps: super::TimestampIndex,
+    /// Byte ranges written since the upload started, filled out of order by
+    /// resumed uploads.
+    pub received: ExtentList,
+    /// Protected from expiry (PIN).
+    pub pinned: bool,
+    /// Explicit end of life set with SET_TTL, replacing idle expiry.
+    pub expires_at: Option<SystemTime>,
+    /// Number of the upload under this stream ID, counted from 1; a
+    /// finalized stream uploaded again keeps its data as the previous
+    /// generation.
+    pub generation: u64,
 }
 
 #[allow(dead_code)]
@@ -51,6 +82,17 @@ impl StreamContext {
             created_at: now,
             last_accessed_at: now,
             status: StreamStatus::Uploading,
+            checksum: None,
+            metadata: HashMap::new(),
+            tags: BTreeMap::new(),
+            journal: None,
+            block_index: None,
+            digest: Some(super::DigestBuilder::default()),
+            timestamps: super::TimestampIndex::default(),
+            received: ExtentList::default(),
+            pinned: false,
+            expires_at: None,
+            generation: 1,
         }
     }
 
@@ -118,4 +160,163 @@ impl StreamContext {
     pub fn set_mmap_file(&mut self, file: Option<std::sync::Arc<super::MemoryMappedCache>>) {
         self.mmap_file = file;
     }
+
+    /// Get SHA-256 checksum of the finalized data (hex).
+    pub fn get_checksum(&self) -> Option<&str> {
+        self.checksum.as_deref()
+    }
+
+    /// Set SHA-256 checksum of the finalized data (hex).
+    pub fn set_checksum(&mut self, checksum: Option<String>) {
+        self.checksum = checksum;
+    }
+
+    /// Get stream metadata.
+    pub fn get_metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Set a stream metadata entry.
+    pub fn set_metadata(&mut self, key: &str, value: String) {
+        self.metadata.insert(key.to_string(), value);
+    }
+
+    /// Get stream tags.
+    pub fn get_tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Add tags, replacing the values of tags already set.
+    pub fn add_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.tags.extend(tags);
+    }
+
+    /// Check whether the stream carries every tag of `filter`.
+    pub fn has_tags(&self, filter: &BTreeMap<String, String>) -> bool {
+        filter
+            .iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+
+    /// Get the byte ranges written during the upload.
+    pub fn get_received(&self) -> &ExtentList {
+        &self.received
+    }
+
+    /// Set the byte ranges written during the upload.
+    pub fn set_received(&mut self, received: ExtentList) {
+        self.received = received;
+    }
+
+    /// Record the bytes `[start, end)` as written.
+    pub fn add_received(&mut self, start: u64, end: u64) {
+        self.received.insert(start, end);
+    }
+
+    /// Get write-ahead journal.
+    pub fn get_journal(&self) -> Option<&std::sync::Arc<super::StreamJournal>> {
+        self.journal.as_ref()
+    }
+
+    /// Set write-ahead journal.
+    pub fn set_journal(&mut self, journal: Option<std::sync::Arc<super::StreamJournal>>) {
+        self.journal = journal;
+    }
+
+    /// Get per-block hash index of the finalized data.
+    pub fn get_block_index(&self) -> Option<&std::sync::Arc<super::BlockIndex>> {
+        self.block_index.as_ref()
+    }
+
+    /// Set per-block hash index of the finalized data.
+    pub fn set_block_index(&mut self, index: Option<std::sync::Arc<super::BlockIndex>>) {
+        self.block_index = index;
+    }
+
+    /// Take the running checksum of the received data.
+    pub fn take_digest(&mut self) -> Option<super::DigestBuilder> {
+        self.digest.take()
+    }
+
+    /// Set running checksum of the received data.
+    pub fn set_digest(&mut self, digest: Option<super::DigestBuilder>) {
+        self.digest = digest;
+    }
+
+    /// Get capture timestamps of the uploaded chunks.
+    pub fn get_timestamps(&self) -> &super::TimestampIndex {
+        &self.timestamps
+    }
+
+    /// Get capture timestamps of the uploaded chunks for recording.
+    pub fn get_timestamps_mut(&mut self) -> &mut super::TimestampIndex {
+        &mut self.timestamps
+    }
+
+    /// Set capture timestamps of the uploaded chunks.
+    pub fn set_timestamps(&mut self, timestamps: super::TimestampIndex) {
+        self.timestamps = timestamps;
+    }
+
+    /// Check whether the stream is protected from expiry.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Protect the stream from expiry, or stop protecting it.
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+
+    /// Get the explicit end of life of the stream, if any.
+    pub fn get_expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// Set the explicit end of life of the stream; `None` restores idle expiry.
+    pub fn set_expires_at(&mut self, expires_at: Option<SystemTime>) {
+        self.expires_at = expires_at;
+    }
+
+    /// Get the generation of the stream.
+    pub fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Set the generation of the stream.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Check whether the stream has outlived its explicit end of life, or
+    /// else went unread for longer than `idle_ttl`. Pinned streams and
+    /// streams still being uploaded never expire.
+    pub fn is_expired(&self, now: SystemTime, idle_ttl: Option<Duration>) -> bool {
+        if self.pinned || self.status == StreamStatus::Uploading {
+            return false;
+        }
+        match self.expires_at {
+            Some(expires_at) => now >= expires_at,
+            None => idle_ttl.is_some_and(|ttl| {
+                now.duration_since(self.last_accessed_at)
+                    .is_ok_and(|idle| idle > ttl)
+            }),
+        }
+    }
+
+    /// Get the sample format and sample bytes of the stream, from its raw PCM
+    /// content type or the header of a PCM WAV stream.
+    pub fn sample_layout(&self) -> Option<(PcmFormat, Range<u64>)> {
+        if let Some(format) = self
+            .metadata
+            .get("contentType")
+            .and_then(|content_type| PcmFormat::from_content_type(content_type))
+        {
+            return Some((format, 0..self.total_size));
+        }
+
+        let header_len = std::cmp::min(self.total_size, WAV_HEADER_SEARCH_BYTES) as usize;
+        let header = self.mmap_file.as_ref()?.read(0, header_len).ok()?;
+        PcmFormat::from_wav_header(&header, self.total_size)
+    }
 }
@@ -0,0 +1,243 @@
+// Write-ahead journal for crash-consistent stream uploads.
+// Every chunk extent is appended (and synced) only after its bytes have been
+// flushed to the cache file, so on restart the journal tells exactly how much
+// of the cache file is valid. Finalized streams are recorded as well, which
+// lets the server reload its registry after a restart.
+//
+// Journal format (one record per line):
+//   START <stream_id>
+//   CHUNK <offset> <length>
+//   FINAL <size> [checksum]
+//   REOPEN
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::protocol::ExtentList;
+
+/// Per-stream append-only journal.
+pub struct StreamJournal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// State reconstructed from a journal.
+#[derive(Debug, Clone, Default)]
+pub struct JournalState {
+    pub stream_id: String,
+    /// End of the contiguous range of committed bytes starting at offset 0
+    pub committed_offset: u64,
+    /// Every committed byte range, including those past a gap
+    pub received: ExtentList,
+    pub finalized_size: Option<u64>,
+    pub checksum: Option<String>,
+    /// Length of the journal up to the last complete record
+    pub valid_length: u64,
+}
+
+impl StreamJournal {
+    /// Get the journal path for a cache file.
+    pub fn journal_path(cache_path: &str) -> PathBuf {
+        Path::new(cache_path).with_extension("journal")
+    }
+
+    /// Create a new journal, replacing any existing one.
+    pub fn create(cache_path: &str, stream_id: &str) -> std::io::Result<Self> {
+        let path = Self::journal_path(cache_path);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+
+        let journal = Self {
+            path,
+            file: Mutex::new(file),
+        };
+        journal.append(&format!("START {}", stream_id))?;
+        Ok(journal)
+    }
+
+    /// Reopen an existing journal for appending, dropping any torn tail record.
+    pub fn reopen(path: &Path, valid_length: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(valid_length)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record a committed chunk extent.
+    pub fn append_chunk(&self, offset: u64, length: usize) -> std::io::Result<()> {
+        self.append(&format!("CHUNK {} {}", offset, length))
+    }
+
+    /// Record stream finalization.
+    pub fn append_final(&self, size: u64, checksum: Option<&str>) -> std::io::Result<()> {
+        match checksum {
+            Some(checksum) => self.append(&format!("FINAL {} {}", size, checksum)),
+            None => self.append(&format!("FINAL {}", size)),
+        }
+    }
+
+    /// Record that a finalized stream was reopened for appending.
+    pub fn append_reopen(&self) -> std::io::Result<()> {
+        self.append("REOPEN")
+    }
+
+    /// Delete the journal file.
+    pub fn remove(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+
+    /// Get the journal path.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&self, record: &str) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(record.as_bytes())?;
+        file.write_all(b"\n")?;
+        file.sync_data()
+    }
+
+    /// Replay a journal file. Parsing stops at the first torn or malformed record.
+    pub fn replay(path: &Path) -> std::io::Result<JournalState> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut state = JournalState::default();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 || !line.ends_with('\n') {
+                break;
+            }
+
+            let mut parts = line.split_whitespace();
+            let valid = match (parts.next(), parts.next(), parts.next()) {
+                (Some("START"), Some(id), None) => {
+                    state.stream_id = id.to_string();
+                    true
+                }
+                (Some("CHUNK"), Some(offset), Some(length)) => {
+                    match (offset.parse::<u64>(), length.parse::<u64>()) {
+                        (Ok(offset), Ok(length)) => match offset.checked_add(length) {
+                            Some(end) => {
+                                state.received.insert(offset, end);
+                                true
+                            }
+                            None => false,
+                        },
+                        _ => false,
+                    }
+                }
+                (Some("FINAL"), Some(size), checksum) => match size.parse::<u64>() {
+                    Ok(size) => {
+                        state.finalized_size = Some(size);
+                        state.checksum = checksum.map(str::to_string);
+                        true
+                    }
+                    Err(_) => false,
+                },
+                (Some("REOPEN"), None, None) => {
+                    state.finalized_size = None;
+                    state.checksum = None;
+                    true
+                }
+                _ => false,
+            };
+
+            if !valid {
+                break;
+            }
+            state.valid_length += n as u64;
+        }
+
+        // Committed offset is the end of the contiguous prefix of extents
+        state.committed_offset = state.received.contiguous_end();
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{}-{}.cache", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn replay_restores_extents_and_finalization() {
+        let cache_path = temp_cache_path("journal-replay");
+        let journal = StreamJournal::create(&cache_path, "song").unwrap();
+        journal.append_chunk(0, 4).unwrap();
+        journal.append_chunk(8, 4).unwrap();
+        journal.append_final(12, Some("abc")).unwrap();
+
+        let state = StreamJournal::replay(journal.get_path()).unwrap();
+        assert_eq!(state.stream_id, "song");
+        assert_eq!(state.committed_offset, 4);
+        assert_eq!(state.received.ranges(), &[(0, 4), (8, 12)]);
+        assert_eq!(state.finalized_size, Some(12));
+        assert_eq!(state.checksum.as_deref(), Some("abc"));
+
+        journal.append_reopen().unwrap();
+        let state = StreamJournal::replay(journal.get_path()).unwrap();
+        assert_eq!(state.finalized_size, None);
+        assert_eq!(state.checksum, None);
+        assert_eq!(
+            state.valid_length,
+            std::fs::metadata(journal.get_path()).unwrap().len()
+        );
+        journal.remove();
+    }
+
+    #[test]
+    fn replay_stops_at_malformed_records() {
+        let path = StreamJournal::journal_path(&temp_cache_path("journal-malformed"));
+        let valid = "START song\nCHUNK 0 4\n";
+        for invalid in [
+            "CHUNK x 4\n",
+            "CHUNK 4\n",
+            "CHUNK 18446744073709551615 2\n",
+            "FINAL\n",
+            "REOPEN now\n",
+            "SEEK 4\n",
+        ] {
+            std::fs::write(&path, format!("{}{}CHUNK 4 4\n", valid, invalid)).unwrap();
+            let state = StreamJournal::replay(&path).unwrap();
+            assert_eq!(state.committed_offset, 4, "{:?}", invalid);
+            assert_eq!(state.valid_length, valid.len() as u64, "{:?}", invalid);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reopen_drops_a_torn_tail_record() {
+        let path = StreamJournal::journal_path(&temp_cache_path("journal-torn"));
+        std::fs::write(&path, "START song\nCHUNK 0 4\nCHUNK 4").unwrap();
+        let state = StreamJournal::replay(&path).unwrap();
+        assert_eq!(state.committed_offset, 4);
+
+        let journal = StreamJournal::reopen(&path, state.valid_length).unwrap();
+        journal.append_chunk(4, 4).unwrap();
+        let state = StreamJournal::replay(&path).unwrap();
+        assert_eq!(state.committed_offset, 8);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "START song\nCHUNK 0 4\nCHUNK 4 4\n"
+        );
+        journal.remove();
+    }
+}
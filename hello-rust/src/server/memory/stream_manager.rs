@@ -2,70 +2,299 @@
 // Thread-safe registry of stream contexts.
 // Matches Python StreamManager and Java StreamManager functionality.
 
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, SystemTime};
 
-use super::{MemoryMappedCache, StreamContext, StreamStatus};
+use super::block_index::MAX_BLOCKS_PER_REQUEST;
+use super::{
+    BlockIndex, CacheError, DigestBuilder, MemoryMappedCache, StreamContext, StreamError,
+    StreamJournal, StreamStatus, TimestampIndex,
+};
+use crate::protocol::{
+    is_valid_namespace, split_namespace, ChunkTimestamp, ExtentList, StreamSummary,
+};
+use crate::server::events::StreamEventBus;
+use crate::server::network::TokenQuotas;
+use crate::server::processing::StreamProcessor;
+use tracing::{error, info, warn};
+
+// Files kept in the cache directory for each stream, by extension
+const CACHE_FILE_EXTENSIONS: [&str; 5] = ["cache", "journal", "peaks", "blocks", "times"];
+
+// Prefix of the cache subdirectory holding the streams of a namespace
+const NAMESPACE_DIR_PREFIX: char = '@';
+
+// Prefix of the cache subdirectories holding later generations of streams,
+// followed by the generation; stream IDs never start with a dot
+const VERSION_DIR_PREFIX: &str = ".v";
+
+/// Previous generations of a finalized stream kept when it is uploaded again,
+/// unless set otherwise.
+const DEFAULT_KEEP_VERSIONS: usize = 3;
+
+/// Bytes prefetched ahead of sequential readers of a cache file, unless set
+/// otherwise.
+const DEFAULT_READ_AHEAD: u64 = 8 * 1024 * 1024;
+
+/// Largest cache file preallocated for the size declared in START, unless
+/// set otherwise.
+const DEFAULT_MAX_PREALLOCATION: u64 = 1024 * 1024 * 1024;
+
+// File of the cache directory, with the namespace and generation it belongs to
+type CacheEntry = (Option<String>, u64, std::fs::DirEntry);
+
+// Previous generations of one stream, by generation
+type StreamVersions = BTreeMap<u64, Arc<Mutex<StreamContext>>>;
+
+/// Outcome of [`StreamManager::sweep_orphans`].
+#[derive(Debug, Default, Clone)]
+pub struct CacheSweepReport {
+    /// Files removed because no registered stream owns them.
+    pub orphaned_files: Vec<String>,
+    /// Disk space released by removing them.
+    pub reclaimed_bytes: u64,
+    /// Streams dropped from the registry because their cache file is gone.
+    pub missing_files: Vec<String>,
+}
+
+impl CacheSweepReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_files.is_empty() && self.missing_files.is_empty()
+    }
+}
+
+/// Outcome of [`StreamManager::scrub_streams`].
+#[derive(Debug, Default, Clone)]
+pub struct ScrubReport {
+    /// Finalized streams whose data still matches their checksum.
+    pub verified: usize,
+    /// Streams marked as Error because their data no longer matches.
+    pub corrupted: Vec<String>,
+}
+
+/// Outcome of [`StreamManager::write_chunks`].
+#[derive(Debug, Default)]
+pub struct BatchWrite {
+    /// Chunks written from the front of the batch.
+    pub chunks: usize,
+    /// Bytes written.
+    pub bytes: usize,
+    /// Why the chunk after them was rejected, if one was.
+    pub rejected: Option<StreamError>,
+}
+
+/// Where the next write to an upload lands, from
+/// [`StreamManager::write_position`].
+#[derive(Debug, Default)]
+pub struct WritePosition {
+    /// Generation of the stream written.
+    pub generation: u64,
+    /// Offset the write starts at.
+    pub offset: u64,
+    /// Byte ranges written so far.
+    pub received: ExtentList,
+}
 
 /// Stream manager for managing multiple concurrent streams.
 #[allow(dead_code)]
 pub struct StreamManager {
-    cache_directory: String,
+    cache_directory: PathBuf,
     streams: Arc<Mutex<HashMap<String, Arc<Mutex<StreamContext>>>>>,
+    /// IDs of streams whose files are being set up, not registered yet.
+    /// Locked after `streams`.
+    creating: Mutex<HashSet<String>>,
+    /// Previous generations of streams uploaded again, by generation. Locked
+    /// after `streams` when both are needed.
+    versions: Mutex<HashMap<String, StreamVersions>>,
+    keep_versions: AtomicUsize,
+    event_bus: Arc<StreamEventBus>,
+    processors: RwLock<Vec<Arc<dyn StreamProcessor>>>,
+    journaling: AtomicBool,
+    huge_pages: AtomicBool,
+    large_files: AtomicBool,
+    read_ahead: AtomicU64,
+    max_preallocation: AtomicU64,
 }
 
 #[allow(dead_code)]
 impl StreamManager {
-    /// Get the singleton instance of StreamManager.
-    pub fn instance(cache_directory: String) -> Arc<Self> {
-        static INSTANCE: OnceLock<Arc<StreamManager>> = OnceLock::new();
-
-        INSTANCE
-            .get_or_init(|| {
-                // Create cache directory if it doesn't exist
-                if let Err(e) = std::fs::create_dir_all(&cache_directory) {
-                    eprintln!("Failed to create cache directory: {:?}", e);
-                }
+    /// Open a StreamManager over `cache_directory`, one per server, so
+    /// servers in one process never share a cache.
+    pub fn open(cache_directory: impl Into<PathBuf>) -> Arc<Self> {
+        let cache_directory = cache_directory.into();
+        // Create cache directory if it doesn't exist
+        if let Err(e) = std::fs::create_dir_all(&cache_directory) {
+            error!("Failed to create cache directory: {:?}", e);
+        }
 
-                Arc::new(Self {
-                    cache_directory,
-                    streams: Arc::new(Mutex::new(HashMap::new())),
-                })
-            })
-            .clone()
+        Arc::new(Self::new(cache_directory))
+    }
+
+    fn new(cache_directory: PathBuf) -> Self {
+        Self {
+            cache_directory,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            creating: Mutex::new(HashSet::new()),
+            versions: Mutex::new(HashMap::new()),
+            keep_versions: AtomicUsize::new(DEFAULT_KEEP_VERSIONS),
+            event_bus: StreamEventBus::instance(),
+            processors: RwLock::new(Vec::new()),
+            journaling: AtomicBool::new(false),
+            huge_pages: AtomicBool::new(false),
+            large_files: AtomicBool::new(false),
+            read_ahead: AtomicU64::new(DEFAULT_READ_AHEAD),
+            max_preallocation: AtomicU64::new(DEFAULT_MAX_PREALLOCATION),
+        }
+    }
+
+    /// Register a processor invoked on every chunk write and finalize.
+    pub fn register_processor(&self, processor: Arc<dyn StreamProcessor>) {
+        info!("Registered stream processor: {}", processor.name());
+        self.processors.write().unwrap().push(processor);
+    }
+
+    /// Enable or disable the per-stream write-ahead journal.
+    pub fn set_journaling(&self, enabled: bool) {
+        self.journaling.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Request huge pages for large cache file mappings.
+    pub fn set_huge_pages(&self, enabled: bool) {
+        self.huge_pages.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Enable large-file mode for new cache files.
+    pub fn set_large_files(&self, enabled: bool) {
+        self.large_files.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Prefetch up to `limit` bytes ahead of sequential downloads of cache
+    /// files opened from now on; 0 disables read-ahead.
+    pub fn set_read_ahead(&self, limit: u64) {
+        self.read_ahead.store(limit, Ordering::Relaxed);
+    }
+
+    /// Preallocate at most `limit` bytes of the cache files of new streams,
+    /// whatever size their START declares; they grow past it as written.
+    pub fn set_max_preallocation(&self, limit: u64) {
+        self.max_preallocation.store(limit, Ordering::Relaxed);
+    }
+
+    /// Keep up to `count` previous generations of a finalized stream that is
+    /// uploaded again; with 0, uploading to a stream ID in use fails.
+    pub fn set_keep_versions(&self, count: usize) {
+        self.keep_versions.store(count, Ordering::Relaxed);
     }
 
     /// Create a new stream.
-    pub fn create_stream(&self, stream_id: String) -> bool {
+    /// A `size_hint` preallocates the cache file sparsely where supported,
+    /// up to the preallocation limit.
+    /// A finalized stream of the same ID becomes its previous generation,
+    /// still readable by version, unless the creation is `exclusive`.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn create_stream(
+        &self,
+        stream_id: String,
+        size_hint: Option<u64>,
+        exclusive: bool,
+    ) -> Result<(), StreamError> {
+        StreamError::validate_id(&stream_id)?;
+
+        // Reserve the ID, so that the files are set up without holding the
+        // registry lock
+        let generation = {
+            let streams = self.streams.lock().unwrap();
+            let generation = self.next_generation(&streams, &stream_id, exclusive)?;
+            if !self.creating.lock().unwrap().insert(stream_id.clone()) {
+                return Err(StreamError::AlreadyExists(stream_id));
+            }
+            generation
+        };
+        let created = self.create_context(&stream_id, generation, size_hint);
+
         let mut streams = self.streams.lock().unwrap();
+        self.creating.lock().unwrap().remove(&stream_id);
+        let context = created?;
+        // The stream it replaces may have been deleted or reopened meanwhile
+        if self.next_generation(&streams, &stream_id, exclusive).ok() != Some(generation) {
+            Self::remove_files(&context);
+            return Err(StreamError::AlreadyExists(stream_id));
+        }
+        let cache_path = context.get_cache_path().to_string();
+
+        // Add to registry
+        if let Some(previous) = streams.insert(stream_id.clone(), Arc::new(Mutex::new(context))) {
+            self.archive_version(&stream_id, previous);
+        }
+
+        info!("Created stream: {} at path: {}", stream_id, cache_path);
+        self.event_bus.stream_created(&stream_id);
+        Ok(())
+    }
 
-        // Check if stream already exists
-        if streams.contains_key(&stream_id) {
-            println!("Stream already exists: {}", stream_id);
-            return false;
+    /// Get the generation a new stream `stream_id` gets. Only finalized
+    /// streams get a new generation, unless the creation is `exclusive`.
+    fn next_generation(
+        &self,
+        streams: &HashMap<String, Arc<Mutex<StreamContext>>>,
+        stream_id: &str,
+        exclusive: bool,
+    ) -> Result<u64, StreamError> {
+        let Some(current) = streams.get(stream_id) else {
+            return Ok(1);
+        };
+        let current = current.lock().unwrap();
+        if exclusive
+            || self.keep_versions.load(Ordering::Relaxed) == 0
+            || current.get_status() != StreamStatus::Ready
+        {
+            return Err(StreamError::AlreadyExists(stream_id.to_string()));
         }
+        Ok(current.get_generation() + 1)
+    }
 
-        // Create new stream context
-        let cache_path = self.get_cache_path(&stream_id);
-        let mut context = StreamContext::new(stream_id.clone(), cache_path.clone());
+    /// Set up the cache file, and the journal when enabled, of a new stream.
+    fn create_context(
+        &self,
+        stream_id: &str,
+        generation: u64,
+        size_hint: Option<u64>,
+    ) -> Result<StreamContext, StreamError> {
+        let cache_path = self.prepare_cache_path(stream_id, generation)?;
+        let mut context = StreamContext::new(stream_id.to_string(), cache_path.clone());
+        context.set_generation(generation);
         context.set_status(StreamStatus::Uploading);
         context.update_access_time();
 
         // Create memory-mapped cache file
-        let mmap_file = Arc::new(MemoryMappedCache::new(cache_path.clone()));
-        if !mmap_file.create(0) {
-            return false;
-        }
+        let mmap_file = Arc::new(
+            MemoryMappedCache::new(cache_path.clone())
+                .with_huge_pages(self.huge_pages.load(Ordering::Relaxed))
+                .with_large_files(self.large_files.load(Ordering::Relaxed))
+                .with_read_ahead(self.read_ahead.load(Ordering::Relaxed)),
+        );
+        let preallocation = size_hint
+            .unwrap_or(0)
+            .min(self.max_preallocation.load(Ordering::Relaxed));
+        mmap_file
+            .create_sparse(preallocation)
+            .map_err(|e| StreamError::cache(stream_id, e))?;
 
         context.set_mmap_file(Some(mmap_file));
+        // Timestamps are appended as chunks arrive, so none may be left over
+        let _ = std::fs::remove_file(TimestampIndex::index_path(&cache_path));
 
-        // Add to registry
-        streams.insert(stream_id.clone(), Arc::new(Mutex::new(context)));
-
-        println!("Created stream: {} at path: {}", stream_id, cache_path);
-        true
+        if self.journaling.load(Ordering::Relaxed) {
+            let journal = StreamJournal::create(&cache_path, stream_id).map_err(|e| {
+                Self::remove_files(&context);
+                StreamError::journal(stream_id, e)
+            })?;
+            context.set_journal(Some(Arc::new(journal)));
+        }
+        Ok(context)
     }
 
     /// Get a stream context.
@@ -80,30 +309,117 @@ impl StreamManager {
         context
     }
 
-    /// Delete a stream.
-    pub fn delete_stream(&self, stream_id: &str) -> bool {
-        let mut streams = self.streams.lock().unwrap();
+    /// Get generation `version` of a stream, the current one if `None`.
+    pub fn get_version(
+        &self,
+        stream_id: &str,
+        version: Option<u64>,
+    ) -> Option<Arc<Mutex<StreamContext>>> {
+        let current = self.get_stream(stream_id)?;
+        match version {
+            Some(version) if current.lock().unwrap().get_generation() != version => {
+                let versions = self.versions.lock().unwrap();
+                versions.get(stream_id)?.get(&version).cloned()
+            }
+            _ => Some(current),
+        }
+    }
 
-        if let Some(context) = streams.remove(stream_id) {
-            let ctx = context.lock().unwrap();
+    /// List the previous generations kept of a stream, oldest first.
+    pub fn list_versions(&self, stream_id: &str) -> Vec<u64> {
+        let versions = self.versions.lock().unwrap();
+        versions
+            .get(stream_id)
+            .map(|versions| versions.keys().copied().collect())
+            .unwrap_or_default()
+    }
 
-            // Close memory-mapped file
-            if let Some(mmap) = ctx.get_mmap_file() {
-                mmap.close();
+    /// Keep `previous`, replaced by a new generation, as a version of
+    /// `stream_id`, and delete the oldest versions beyond the limit.
+    fn archive_version(&self, stream_id: &str, previous: Arc<Mutex<StreamContext>>) {
+        let generation = previous.lock().unwrap().get_generation();
+        let mut versions = self.versions.lock().unwrap();
+        let kept = versions.entry(stream_id.to_string()).or_default();
+        kept.insert(generation, previous);
+        let keep = self.keep_versions.load(Ordering::Relaxed);
+        while kept.len() > keep {
+            let Some((generation, version)) = kept.pop_first() else {
+                break;
+            };
+            Self::remove_files(&version.lock().unwrap());
+            info!("Deleted version {} of stream {}", generation, stream_id);
+            if let Some(quotas) = TokenQuotas::current() {
+                quotas.release_version(stream_id, generation);
             }
+            self.event_bus.version_deleted(stream_id, generation);
+        }
+        if kept.is_empty() {
+            versions.remove(stream_id);
+        }
+    }
 
-            // Remove cache file
-            let cache_path = ctx.get_cache_path();
-            if PathBuf::from(cache_path).exists() {
-                let _ = std::fs::remove_file(cache_path);
-            }
+    /// Delete a stream.
+    pub fn delete_stream(&self, stream_id: &str) -> Result<(), StreamError> {
+        self.remove_stream(stream_id, false)
+    }
 
-            println!("Deleted stream: {}", stream_id);
-            true
-        } else {
-            println!("Stream not found for deletion: {}", stream_id);
-            false
+    /// Delete a stream, on request or because it `expired`.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    fn remove_stream(&self, stream_id: &str, expired: bool) -> Result<(), StreamError> {
+        let context = self
+            .streams
+            .lock()
+            .unwrap()
+            .remove(stream_id)
+            .ok_or_else(|| StreamError::NotFound(stream_id.to_string()))?;
+        Self::remove_files(&context.lock().unwrap());
+        let versions = self.versions.lock().unwrap().remove(stream_id);
+        for version in versions.into_iter().flat_map(BTreeMap::into_values) {
+            Self::remove_files(&version.lock().unwrap());
         }
+
+        info!("Deleted stream: {}", stream_id);
+        if let Some(quotas) = TokenQuotas::current() {
+            quotas.release(stream_id);
+        }
+        self.event_bus.stream_deleted(stream_id, expired);
+        Ok(())
+    }
+
+    /// Close the cache file of a stream dropped from the registry and delete
+    /// it with its sidecar files.
+    fn remove_files(ctx: &StreamContext) {
+        // Close memory-mapped file
+        if let Some(mmap) = ctx.get_mmap_file() {
+            mmap.close();
+        }
+
+        if let Some(journal) = ctx.get_journal() {
+            journal.remove();
+        }
+
+        // Remove cache file
+        let cache_path = ctx.get_cache_path();
+        if PathBuf::from(cache_path).exists() {
+            let _ = std::fs::remove_file(cache_path);
+        }
+        let _ = std::fs::remove_file(BlockIndex::index_path(cache_path));
+        let _ = std::fs::remove_file(TimestampIndex::index_path(cache_path));
+        let _ = std::fs::remove_file(Path::new(cache_path).with_extension("peaks"));
+        // The directory of a later generation goes once it is empty
+        if ctx.get_generation() > 1 {
+            if let Some(directory) = Path::new(cache_path).parent() {
+                let _ = std::fs::remove_dir(directory);
+            }
+        }
+    }
+
+    /// Delete a finalized stream on request of a client; a stream that is
+    /// still being uploaded is refused.
+    pub fn delete_finalized_stream(&self, stream_id: &str) -> Result<(), StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        Self::require_status(&stream.lock().unwrap(), StreamStatus::Ready)?;
+        self.delete_stream(stream_id)
     }
 
     /// List all active streams.
@@ -112,150 +428,1586 @@ impl StreamManager {
         streams.keys().cloned().collect()
     }
 
-    /// Write a chunk of data to a stream.
-    pub fn write_chunk(&self, stream_id: &str, data: &[u8]) -> bool {
-        let stream = self.get_stream(stream_id);
-        if stream.is_none() {
-            eprintln!("Stream not found for write: {}", stream_id);
-            return false;
+    /// List the streams carrying every tag of `tags` in stream ID order, at
+    /// most `limit` of them after the stream ID `after`, only those of
+    /// `namespace` if given. Returns the page and whether more streams match.
+    pub fn list_streams(
+        &self,
+        tags: &BTreeMap<String, String>,
+        namespace: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> (Vec<StreamSummary>, bool) {
+        let streams = self.streams.lock().unwrap();
+        let mut ids: Vec<&String> = streams
+            .keys()
+            .filter(|id| namespace.is_none_or(|namespace| split_namespace(id).0 == Some(namespace)))
+            .filter(|id| after.is_none_or(|after| id.as_str() > after))
+            .collect();
+        ids.sort();
+
+        let mut page = Vec::new();
+        for id in ids {
+            let ctx = streams[id].lock().unwrap();
+            if !ctx.has_tags(tags) {
+                continue;
+            }
+            if page.len() == limit {
+                return (page, true);
+            }
+            page.push(StreamSummary {
+                stream_id: id.clone(),
+                size: ctx.get_total_size(),
+                status: ctx.get_status().as_str().to_string(),
+                tags: ctx.get_tags().clone(),
+            });
         }
+        (page, false)
+    }
 
-        let stream = stream.unwrap();
+    /// Write a chunk of data to a stream, returning the number of bytes written.
+    pub fn write_chunk(&self, stream_id: &str, data: &[u8]) -> Result<usize, StreamError> {
+        self.write_chunk_at(stream_id, None, data, None)
+    }
+
+    /// Write a chunk captured at `timestamp` (microseconds since the Unix
+    /// epoch) and record the capture time in the stream's timestamp index.
+    pub fn write_timestamped_chunk(
+        &self,
+        stream_id: &str,
+        data: &[u8],
+        timestamp: u64,
+    ) -> Result<usize, StreamError> {
+        self.write_chunk_at(stream_id, None, data, Some(timestamp))
+    }
+
+    /// Get the capture timestamps recorded for a stream.
+    pub fn get_timestamps(&self, stream_id: &str) -> Result<Vec<ChunkTimestamp>, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let ctx = stream.lock().unwrap();
+        Ok(ctx.get_timestamps().entries().to_vec())
+    }
+
+    /// Write a chunk at `offset`, or after the last byte written when `None`,
+    /// with the capture `timestamp` when one was sent.
+    pub fn write_chunk_at(
+        &self,
+        stream_id: &str,
+        offset: Option<u64>,
+        data: &[u8],
+        timestamp: Option<u64>,
+    ) -> Result<usize, StreamError> {
+        let batch = self.write_chunks(stream_id, offset, &[(data, timestamp)])?;
+        match batch.rejected {
+            Some(e) => Err(e),
+            None => Ok(batch.bytes),
+        }
+    }
+
+    /// Write consecutive chunks at `offset`, or after the last byte written
+    /// when `None`, each with its capture timestamp when one was sent. The
+    /// chunks are copied into the cache file in one batch, growing it at most
+    /// once. The processors see every chunk before it is copied; the batch
+    /// stops at the first chunk one of them rejects, and only the chunks
+    /// before it are written.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn write_chunks(
+        &self,
+        stream_id: &str,
+        offset: Option<u64>,
+        chunks: &[(&[u8], Option<u64>)],
+    ) -> Result<BatchWrite, StreamError> {
+        let stream = self.require_stream(stream_id)?;
         let mut ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Uploading)?;
 
-        if ctx.get_status() != StreamStatus::Uploading {
-            eprintln!("Stream {} is not in uploading state", stream_id);
-            return false;
+        let mmap = Self::require_mmap(&ctx)?;
+        let current_offset = offset.unwrap_or(ctx.get_current_offset());
+        let mut extents = Vec::with_capacity(chunks.len());
+        let mut end = current_offset;
+        for (data, _) in chunks {
+            extents.push((end, *data));
+            end = end.checked_add(data.len() as u64).ok_or_else(|| {
+                StreamError::cache(
+                    stream_id,
+                    CacheError::OutOfBounds {
+                        path: ctx.get_cache_path().to_string(),
+                        offset: end,
+                        length: data.len(),
+                        size: mmap.get_size(),
+                    },
+                )
+            })?;
+        }
+
+        // A rejected chunk never reaches the cache file and leaves the offset
+        // after the chunks before it, so the next write takes its place
+        let mut rejected = None;
+        let processors = self.processors.read().unwrap();
+        'chunks: for (index, (chunk_offset, data)) in extents.iter().enumerate() {
+            for processor in processors.iter() {
+                if let Err(e) = processor.on_chunk(stream_id, *chunk_offset, data) {
+                    rejected = Some((
+                        index,
+                        StreamError::Rejected {
+                            stream_id: stream_id.to_string(),
+                            processor: processor.name().to_string(),
+                            reason: e.to_string(),
+                        },
+                    ));
+                    break 'chunks;
+                }
+            }
+        }
+        drop(processors);
+        let rejected = rejected.map(|(index, e)| {
+            extents.truncate(index);
+            e
+        });
+        if extents.is_empty() {
+            return Ok(BatchWrite {
+                rejected,
+                ..BatchWrite::default()
+            });
         }
 
         // Write data to memory-mapped file
-        let mmap = ctx.get_mmap_file();
-        if mmap.is_none() {
-            eprintln!("No mmap file for stream {}", stream_id);
-            return false;
+        let written = mmap
+            .write_batch(&extents)
+            .map_err(|e| StreamError::cache(stream_id, e))?;
+
+        // Journal the extents only once their bytes are on disk
+        if let Some(journal) = ctx.get_journal() {
+            mmap.flush_range(current_offset, written)
+                .map_err(|e| StreamError::cache(stream_id, e))?;
+            for (chunk_offset, data) in &extents {
+                journal
+                    .append_chunk(*chunk_offset, data.len())
+                    .map_err(|e| StreamError::journal(stream_id, e))?;
+            }
         }
 
-        let current_offset = ctx.get_current_offset();
-        let written = mmap.unwrap().write(current_offset, data);
+        let cache_path = ctx.get_cache_path().to_string();
+        for ((chunk_offset, _), (_, timestamp)) in extents.iter().zip(chunks) {
+            if let Some(timestamp) = timestamp {
+                ctx.get_timestamps_mut()
+                    .append(&cache_path, *chunk_offset, *timestamp)
+                    .map_err(|e| StreamError::cache(stream_id, CacheError::io(&cache_path, e)))?;
+            }
+        }
 
-        if written > 0 {
-            let new_offset = current_offset + written as u64;
-            let new_total = ctx.get_total_size() + written as u64;
-            ctx.set_current_offset(new_offset);
-            ctx.set_total_size(new_total);
-            ctx.update_access_time();
+        // Writes into gaps left by a resumed upload don't move the end
+        let end = current_offset + written as u64;
+        let new_offset = ctx.get_current_offset().max(end);
+        let new_total = ctx.get_total_size().max(end);
+        ctx.add_received(current_offset, end);
+        ctx.set_current_offset(new_offset);
+        ctx.set_total_size(new_total);
+        ctx.update_access_time();
 
-            println!(
-                "Wrote {} bytes to stream {} at offset {}",
-                written, stream_id, current_offset
-            );
-            true
-        } else {
-            eprintln!("Failed to write data to stream {}", stream_id);
-            false
+        // Hash the bytes contiguous from the start as they arrive, so
+        // finalizing does not hash the whole stream under the lock; a
+        // rewrite of hashed bytes leaves the work to finalize
+        if let Some(mut digest) = ctx.take_digest() {
+            let contiguous = ctx.get_received().contiguous_end();
+            if current_offset >= digest.hashed()
+                && mmap
+                    .hash_range(digest.hashed()..contiguous, &mut digest)
+                    .is_ok()
+            {
+                ctx.set_digest(Some(digest));
+            }
         }
+
+        info!(
+            "Wrote {} bytes in {} chunks to stream {} at offset {}",
+            written,
+            extents.len(),
+            stream_id,
+            current_offset
+        );
+        self.event_bus
+            .chunk_written(stream_id, current_offset, written);
+        Ok(BatchWrite {
+            chunks: extents.len(),
+            bytes: written,
+            rejected,
+        })
     }
 
-    /// Read a chunk of data from a stream.
-    pub fn read_chunk(&self, stream_id: &str, offset: u64, length: usize) -> Vec<u8> {
-        let stream = self.get_stream(stream_id);
-        if stream.is_none() {
-            eprintln!("Stream not found for read: {}", stream_id);
-            return Vec::new();
-        }
+    /// Furthest offset a SEEK may move an upload to: the end of the bytes
+    /// written or of the file preallocated for the declared size, and never
+    /// past the largest cache file.
+    pub fn seek_limit(&self, stream_id: &str) -> Result<u64, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Uploading)?;
+        let mmap = Self::require_mmap(&ctx)?;
+        Ok(ctx
+            .get_total_size()
+            .max(mmap.get_size())
+            .min(mmap.max_size()))
+    }
 
-        let stream = stream.unwrap();
-        let mut ctx = stream.lock().unwrap();
+    /// Get where a write to an upload lands, at `offset` or else at its
+    /// current offset.
+    pub fn write_position(
+        &self,
+        stream_id: &str,
+        offset: Option<u64>,
+    ) -> Result<WritePosition, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let ctx = stream.lock().unwrap();
+        Ok(WritePosition {
+            generation: ctx.get_generation(),
+            offset: offset.unwrap_or(ctx.get_current_offset()),
+            received: ctx.get_received().clone(),
+        })
+    }
 
-        let mmap = ctx.get_mmap_file();
-        if mmap.is_none() {
-            eprintln!("No mmap file for stream {}", stream_id);
-            return Vec::new();
-        }
+    /// Read a chunk of data from a stream.
+    /// Reads at or past the end of the stream return an empty buffer.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn read_chunk(
+        &self,
+        stream_id: &str,
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let mut ctx = stream.lock().unwrap();
 
-        let data = mmap.unwrap().read(offset, length);
+        // The cache file may be preallocated past the bytes written so far
+        let available = ctx.get_total_size().saturating_sub(offset);
+        let length = std::cmp::min(length as u64, available) as usize;
+        let data = if length == 0 {
+            Vec::new()
+        } else {
+            Self::require_mmap(&ctx)?
+                .read(offset, length)
+                .map_err(|e| StreamError::cache(stream_id, e))?
+        };
         ctx.update_access_time();
 
-        println!(
+        info!(
             "Read {} bytes from stream {} at offset {}",
             data.len(),
             stream_id,
             offset
         );
-        data
+        Ok(data)
+    }
+
+    /// Read a chunk of data from a stream into `buffer`, returning the
+    /// number of bytes read; reads at or past the end of the stream read none.
+    pub fn read_chunk_into(
+        &self,
+        stream_id: &str,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, StreamError> {
+        self.read_version_into(stream_id, None, offset, buffer)
+    }
+
+    /// Read a chunk of generation `version` of a stream, the current one if
+    /// `None`, into `buffer`, returning the number of bytes read.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn read_version_into(
+        &self,
+        stream_id: &str,
+        version: Option<u64>,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, StreamError> {
+        let stream = self.require_version(stream_id, version)?;
+        let mut ctx = stream.lock().unwrap();
+
+        // The cache file may be preallocated past the bytes written so far
+        let available = ctx.get_total_size().saturating_sub(offset);
+        let length = std::cmp::min(buffer.len() as u64, available) as usize;
+        let read = if length == 0 {
+            0
+        } else {
+            Self::require_mmap(&ctx)?
+                .read_into(offset, &mut buffer[..length])
+                .map_err(|e| StreamError::cache(stream_id, e))?
+        };
+        ctx.update_access_time();
+
+        info!(
+            "Read {} bytes from stream {} at offset {}",
+            read, stream_id, offset
+        );
+        Ok(read)
+    }
+
+    /// Map a time range in seconds to the bytes of a raw PCM or PCM WAV
+    /// stream, of generation `version` if given.
+    /// Ranges stop at the end of the samples once the stream is finalized; an
+    /// open-ended range of a stream still uploading runs to `u64::MAX`.
+    pub fn time_range(
+        &self,
+        stream_id: &str,
+        version: Option<u64>,
+        start: f64,
+        end: Option<f64>,
+    ) -> Result<std::ops::Range<u64>, StreamError> {
+        let stream = self.require_version(stream_id, version)?;
+        let ctx = stream.lock().unwrap();
+        let (format, data) = ctx
+            .sample_layout()
+            .ok_or_else(|| StreamError::NoSampleFormat(stream_id.to_string()))?;
+
+        let data_end = if ctx.get_status() == StreamStatus::Ready {
+            data.end
+        } else {
+            u64::MAX
+        };
+        let end = end.map_or(data_end, |end| {
+            data_end.min(data.start.saturating_add(format.byte_offset(end)))
+        });
+        let start = data
+            .start
+            .saturating_add(format.byte_offset(start))
+            .min(end);
+        Ok(start..end)
+    }
+
+    /// Re-hash the stored blocks overlapping a byte range of a finalized
+    /// stream and compare them with its block index.
+    /// Returns the verified blocks; `length` of `None` runs to the end. At
+    /// most [`MAX_BLOCKS_PER_REQUEST`] blocks are verified per call.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn verify_range(
+        &self,
+        stream_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(Arc<BlockIndex>, std::ops::Range<u64>), StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let (index, mmap) = {
+            let ctx = stream.lock().unwrap();
+            Self::require_status(&ctx, StreamStatus::Ready)?;
+
+            let index = ctx.get_block_index().cloned().ok_or_else(|| {
+                StreamError::cache(
+                    stream_id,
+                    CacheError::Missing(
+                        BlockIndex::index_path(ctx.get_cache_path())
+                            .to_string_lossy()
+                            .into_owned(),
+                    ),
+                )
+            })?;
+            (index, Self::require_mmap(&ctx)?)
+        };
+
+        // Hash without the stream lock; indexed bytes are never rewritten,
+        // a reopened stream only grows past them
+        let blocks = index.blocks_covering(offset, length.unwrap_or(u64::MAX));
+        let blocks = blocks.start..blocks.end.min(blocks.start + MAX_BLOCKS_PER_REQUEST);
+        for block in blocks.clone() {
+            let range = index.block_range(block);
+            let data = mmap
+                .read(range.start, (range.end - range.start) as usize)
+                .map_err(|e| StreamError::cache(stream_id, e))?;
+            if !index.verify_block(block, &data) {
+                return Err(StreamError::Corrupted {
+                    stream_id: stream_id.to_string(),
+                    block,
+                });
+            }
+        }
+
+        info!(
+            "Verified blocks {}..{} of stream {}",
+            blocks.start, blocks.end, stream_id
+        );
+        Ok((index, blocks))
     }
 
     /// Finalize a stream.
-    pub fn finalize_stream(&self, stream_id: &str) -> bool {
-        let stream = self.get_stream(stream_id);
-        if stream.is_none() {
-            eprintln!("Stream not found for finalization: {}", stream_id);
-            return false;
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn finalize_stream(&self, stream_id: &str) -> Result<(), StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let mut ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Uploading)?;
+
+        if let Some(&(offset, _)) = ctx.get_received().missing(ctx.get_total_size()).first() {
+            return Err(StreamError::Incomplete {
+                stream_id: stream_id.to_string(),
+                offset,
+            });
+        }
+
+        let mmap = Self::require_mmap(&ctx)?;
+        mmap.finalize(ctx.get_total_size())
+            .map_err(|e| StreamError::cache(stream_id, e))?;
+        // Only bytes not hashed while uploading are hashed here
+        let mut digest = ctx.take_digest().unwrap_or_default();
+        mmap.hash_range(digest.hashed()..ctx.get_total_size(), &mut digest)
+            .map_err(|e| StreamError::cache(stream_id, e))?;
+        let (block_index, checksum) = digest.finish();
+        ctx.set_checksum(Some(checksum));
+        if let Err(e) = block_index.save(ctx.get_cache_path()) {
+            error!("Failed to save block index of {}: {:?}", stream_id, e);
+        }
+        ctx.set_block_index(Some(Arc::new(block_index)));
+
+        for processor in self.processors.read().unwrap().iter() {
+            if let Err(e) = processor.on_finalize(&mut ctx) {
+                ctx.set_status(StreamStatus::Error);
+                return Err(StreamError::Rejected {
+                    stream_id: stream_id.to_string(),
+                    processor: processor.name().to_string(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+        if let Some(journal) = ctx.get_journal() {
+            journal
+                .append_final(ctx.get_total_size(), ctx.get_checksum())
+                .map_err(|e| StreamError::journal(stream_id, e))?;
         }
 
-        let stream = stream.unwrap();
+        ctx.set_status(StreamStatus::Ready);
+        ctx.update_access_time();
+
+        info!(
+            "Finalized stream: {} with {} bytes",
+            stream_id,
+            ctx.get_total_size()
+        );
+        self.event_bus.stream_finalized(
+            stream_id,
+            ctx.get_total_size(),
+            ctx.get_checksum().map(str::to_string),
+        );
+        Ok(())
+    }
+
+    /// Create `target_id` as a finalized stream sharing the data of the
+    /// finalized stream `source_id`. The cache file is cloned at the storage
+    /// layer (see [`MemoryMappedCache::clone_file`]) and the clone is
+    /// announced like a freshly finalized stream.
+    #[tracing::instrument(skip_all, fields(stream_id = source_id, target_stream_id = target_id))]
+    pub fn clone_stream(&self, source_id: &str, target_id: &str) -> Result<(), StreamError> {
+        StreamError::validate_id(target_id)?;
+        let source = self.require_stream(source_id)?;
+
+        // Reserve the ID, so that the files are cloned without holding the
+        // registry lock; a copy of a large stream can take a while
+        {
+            let streams = self.streams.lock().unwrap();
+            if streams.contains_key(target_id)
+                || !self.creating.lock().unwrap().insert(target_id.to_string())
+            {
+                return Err(StreamError::AlreadyExists(target_id.to_string()));
+            }
+        }
+        let cloned = self.clone_context(source_id, &source, target_id);
+
+        let mut streams = self.streams.lock().unwrap();
+        self.creating.lock().unwrap().remove(target_id);
+        let context = cloned?;
+        // The source was not locked while its file was cloned; a clone of a
+        // stream reopened or replaced meanwhile may hold other contents
+        let unchanged = {
+            let source = source.lock().unwrap();
+            source.get_status() == StreamStatus::Ready
+                && source.get_total_size() == context.get_total_size()
+                && source.get_checksum() == context.get_checksum()
+        };
+        if !unchanged {
+            Self::remove_files(&context);
+            return Err(StreamError::Changed(source_id.to_string()));
+        }
+        let size = context.get_total_size();
+        let checksum = context.get_checksum().map(str::to_string);
+        streams.insert(target_id.to_string(), Arc::new(Mutex::new(context)));
+        drop(streams);
+
+        info!(
+            "Cloned stream {} to {} ({} bytes)",
+            source_id, target_id, size
+        );
+        self.event_bus.stream_created(target_id);
+        self.event_bus.stream_finalized(target_id, size, checksum);
+        Ok(())
+    }
+
+    /// Set up the cache file, sidecars, and journal of `target_id` as a copy
+    /// of the finalized stream `source`, which is only locked to read its
+    /// state.
+    fn clone_context(
+        &self,
+        source_id: &str,
+        source: &Mutex<StreamContext>,
+        target_id: &str,
+    ) -> Result<StreamContext, StreamError> {
+        let cache_path = self.prepare_cache_path(target_id, 1)?;
+        let mut context = StreamContext::new(target_id.to_string(), cache_path.clone());
+        let source_path = {
+            let source = source.lock().unwrap();
+            Self::require_status(&source, StreamStatus::Ready)?;
+            context.set_current_offset(source.get_total_size());
+            context.set_total_size(source.get_total_size());
+            context.set_checksum(source.get_checksum().map(str::to_string));
+            for (key, value) in source.get_metadata() {
+                context.set_metadata(key, value.clone());
+            }
+            context.add_tags(source.get_tags().clone());
+            context.set_block_index(source.get_block_index().cloned());
+            context.set_timestamps(source.get_timestamps().clone());
+            source.get_cache_path().to_string()
+        };
+        context.set_metadata("clonedFrom", source_id.to_string());
+        let size = context.get_total_size();
+
+        MemoryMappedCache::clone_file(&source_path, &cache_path)
+            .map_err(|e| StreamError::cache(target_id, e))?;
+        let mmap_file = Arc::new(
+            MemoryMappedCache::new(cache_path.clone())
+                .with_huge_pages(self.huge_pages.load(Ordering::Relaxed))
+                .with_large_files(self.large_files.load(Ordering::Relaxed))
+                .with_read_ahead(self.read_ahead.load(Ordering::Relaxed)),
+        );
+        if let Err(e) = mmap_file.open() {
+            Self::remove_files(&context);
+            return Err(StreamError::cache(target_id, e));
+        }
+        context.set_mmap_file(Some(mmap_file));
+
+        if let Some(index) = context.get_block_index() {
+            if let Err(e) = index.save(&cache_path) {
+                error!("Failed to save block index of {}: {:?}", target_id, e);
+            }
+        }
+        if !context.get_timestamps().is_empty() {
+            if let Err(e) = context.get_timestamps().save(&cache_path) {
+                error!("Failed to save timestamps of {}: {:?}", target_id, e);
+            }
+        }
+
+        if self.journaling.load(Ordering::Relaxed) {
+            let journal = StreamJournal::create(&cache_path, target_id)
+                .and_then(|journal| {
+                    journal.append_chunk(0, size as usize)?;
+                    journal.append_final(size, context.get_checksum())?;
+                    Ok(journal)
+                })
+                .map_err(|e| {
+                    Self::remove_files(&context);
+                    StreamError::journal(target_id, e)
+                })?;
+            context.set_journal(Some(Arc::new(journal)));
+        }
+
+        context.set_status(StreamStatus::Ready);
+        Ok(context)
+    }
+
+    /// Reopen a finalized stream for appending and return the offset at
+    /// which new data will be written.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn reopen_stream(&self, stream_id: &str) -> Result<u64, StreamError> {
+        let stream = self.require_stream(stream_id)?;
         let mut ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Ready)?;
 
-        if ctx.get_status() != StreamStatus::Uploading {
-            println!(
-                "Stream {} is not in uploading state for finalization",
-                stream_id
-            );
-            return false;
+        Self::require_mmap(&ctx)?
+            .unshare()
+            .map_err(|e| StreamError::cache(stream_id, e))?;
+        if let Some(journal) = ctx.get_journal() {
+            journal
+                .append_reopen()
+                .map_err(|e| StreamError::journal(stream_id, e))?;
         }
 
-        let mmap = ctx.get_mmap_file();
-        if mmap.is_none() {
-            eprintln!("No mmap file for stream {}", stream_id);
-            return false;
+        // Checksum and block index describe the old contents; the appended
+        // stream is hashed again from the start
+        ctx.set_checksum(None);
+        ctx.set_block_index(None);
+        ctx.set_digest(Some(DigestBuilder::default()));
+        let _ = std::fs::remove_file(BlockIndex::index_path(ctx.get_cache_path()));
+
+        let offset = ctx.get_total_size();
+        ctx.set_current_offset(offset);
+        ctx.set_received(ExtentList::prefix(offset));
+        ctx.set_status(StreamStatus::Uploading);
+        ctx.update_access_time();
+
+        info!(
+            "Reopened stream {} for appending at offset {}",
+            stream_id, offset
+        );
+        Ok(offset)
+    }
+
+    /// Resume an interrupted or paused upload and return the byte ranges
+    /// written so far, so the client can send only the missing ones.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn resume_stream(&self, stream_id: &str) -> Result<ExtentList, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let mut ctx = stream.lock().unwrap();
+        if ctx.get_status() == StreamStatus::Paused {
+            ctx.set_status(StreamStatus::Uploading);
         }
+        Self::require_status(&ctx, StreamStatus::Uploading)?;
+        ctx.update_access_time();
+
+        let received = ctx.get_received().clone();
+        info!(
+            "Resumed upload of stream {} with {} ranges received",
+            stream_id,
+            received.ranges().len()
+        );
+        Ok(received)
+    }
 
-        if mmap.unwrap().finalize(ctx.get_total_size()) {
-            ctx.set_status(StreamStatus::Ready);
-            ctx.update_access_time();
+    fn require_stream(&self, stream_id: &str) -> Result<Arc<Mutex<StreamContext>>, StreamError> {
+        self.get_stream(stream_id)
+            .ok_or_else(|| StreamError::NotFound(stream_id.to_string()))
+    }
 
-            println!(
-                "Finalized stream: {} with {} bytes",
-                stream_id,
-                ctx.get_total_size()
-            );
-            true
+    fn require_version(
+        &self,
+        stream_id: &str,
+        version: Option<u64>,
+    ) -> Result<Arc<Mutex<StreamContext>>, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let Some(version) = version else {
+            return Ok(stream);
+        };
+        self.get_version(stream_id, Some(version))
+            .ok_or_else(|| StreamError::VersionNotFound {
+                stream_id: stream_id.to_string(),
+                version,
+            })
+    }
+
+    fn require_status(ctx: &StreamContext, expected: StreamStatus) -> Result<(), StreamError> {
+        if ctx.get_status() == expected {
+            Ok(())
         } else {
-            eprintln!(
-                "Failed to finalize memory-mapped file for stream {}",
-                stream_id
+            Err(StreamError::InvalidState {
+                stream_id: ctx.get_stream_id().to_string(),
+                status: ctx.get_status().as_str(),
+                expected: expected.as_str(),
+            })
+        }
+    }
+
+    fn require_mmap(ctx: &StreamContext) -> Result<Arc<MemoryMappedCache>, StreamError> {
+        ctx.get_mmap_file().cloned().ok_or_else(|| {
+            StreamError::cache(
+                ctx.get_stream_id(),
+                CacheError::NotOpen(ctx.get_cache_path().to_string()),
+            )
+        })
+    }
+
+    /// Rebuild the registry from write-ahead journals in the cache directory.
+    /// Cache files are truncated to their last committed offset, and the
+    /// uncommitted gaps of interrupted uploads are punched out; finalized
+    /// streams come back as Ready, interrupted uploads as Uploading, as do
+    /// finalized streams whose committed bytes fall short of their size.
+    pub fn recover_from_journals(&self) -> usize {
+        let entries = match self.cache_entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to scan cache directory for journals: {:?}", e);
+                return 0;
+            }
+        };
+
+        let mut recovered = 0;
+        for (_, generation, entry) in entries {
+            let journal_path = entry.path();
+            if journal_path.extension().and_then(|e| e.to_str()) != Some("journal") {
+                continue;
+            }
+            match self.recover_stream(&journal_path, generation) {
+                Ok(true) => recovered += 1,
+                Ok(false) => warn!("Ignoring journal without START: {:?}", journal_path),
+                Err(e) => error!("Failed to recover {:?}: {}", journal_path, e),
+            }
+        }
+
+        info!("Recovered {} streams from journals", recovered);
+        recovered
+    }
+
+    fn recover_stream(
+        &self,
+        journal_path: &std::path::Path,
+        generation: u64,
+    ) -> Result<bool, StreamError> {
+        let state = StreamJournal::replay(journal_path)
+            .map_err(|e| StreamError::journal(&journal_path.to_string_lossy(), e))?;
+        if state.stream_id.is_empty() {
+            return Ok(false);
+        }
+
+        let stream_id = state.stream_id.clone();
+        StreamError::validate_id(&stream_id)?;
+        let cache_path = self.get_version_cache_path(&stream_id, generation);
+        // A stream finalized beyond its committed bytes lost data its
+        // checksum covers, so it comes back as an upload to resume
+        let finalized_size = state
+            .finalized_size
+            .filter(|&size| size <= state.committed_offset);
+        let size = match finalized_size {
+            Some(size) => size,
+            // Keep the ranges past the first gap so a resumed upload only
+            // sends the gaps
+            None => state.received.end(),
+        };
+
+        // Drop the undefined tail beyond the last committed byte
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&cache_path)
+            .and_then(|f| f.set_len(size))
+            .map_err(|e| StreamError::cache(&stream_id, CacheError::io(&cache_path, e)))?;
+
+        let mmap_file = Arc::new(
+            MemoryMappedCache::new(cache_path.clone())
+                .with_huge_pages(self.huge_pages.load(Ordering::Relaxed))
+                .with_large_files(self.large_files.load(Ordering::Relaxed))
+                .with_read_ahead(self.read_ahead.load(Ordering::Relaxed)),
+        );
+        mmap_file
+            .open()
+            .map_err(|e| StreamError::cache(&stream_id, e))?;
+        // Chunks never committed may have reached the file before the crash;
+        // release their blocks so the gaps take no disk until resent
+        if finalized_size.is_none() {
+            for (start, end) in state.received.missing(size) {
+                if let Err(e) = mmap_file.punch_hole(start, (end - start) as usize) {
+                    error!("Failed to release uncommitted range of {}: {}", stream_id, e);
+                }
+            }
+        }
+
+        let journal = StreamJournal::reopen(journal_path, state.valid_length)
+            .map_err(|e| StreamError::journal(&stream_id, e))?;
+        if let (Some(lost_size), None) = (state.finalized_size, finalized_size) {
+            warn!(
+                "Stream {} was finalized at {} bytes but only {} are committed, resuming it",
+                stream_id, lost_size, state.committed_offset
             );
-            false
+            journal
+                .append_reopen()
+                .map_err(|e| StreamError::journal(&stream_id, e))?;
+            // The block index describes the lost contents
+            let _ = std::fs::remove_file(BlockIndex::index_path(&cache_path));
+        }
+
+        let mut context = StreamContext::new(stream_id.clone(), cache_path);
+        context.set_generation(generation);
+        context.set_mmap_file(Some(mmap_file.clone()));
+        context.set_journal(Some(Arc::new(journal)));
+        context.set_current_offset(size);
+        context.set_total_size(size);
+        // Rewrite the sidecar without records of chunks lost in the crash
+        let timestamps = TimestampIndex::load(context.get_cache_path(), size);
+        if timestamps.is_empty() {
+            let _ = std::fs::remove_file(TimestampIndex::index_path(context.get_cache_path()));
+        } else if let Err(e) = timestamps.save(context.get_cache_path()) {
+            error!("Failed to save timestamps of {}: {:?}", stream_id, e);
         }
+        context.set_timestamps(timestamps);
+        if finalized_size.is_some() {
+            let block_index = match BlockIndex::load(context.get_cache_path(), size) {
+                Some(index) => index,
+                None => {
+                    let index = mmap_file
+                        .compute_block_index()
+                        .map_err(|e| StreamError::cache(&stream_id, e))?;
+                    if let Err(e) = index.save(context.get_cache_path()) {
+                        error!("Failed to save block index of {}: {:?}", stream_id, e);
+                    }
+                    index
+                }
+            };
+            context.set_block_index(Some(Arc::new(block_index)));
+            context.set_checksum(state.checksum.clone());
+            context.set_status(StreamStatus::Ready);
+        } else {
+            context.set_received(state.received.clone());
+            context.set_status(StreamStatus::Uploading);
+        }
+
+        info!(
+            "Recovered stream {} generation {} ({} bytes, {})",
+            stream_id,
+            generation,
+            size,
+            context.get_status().as_str()
+        );
+        // Earlier generations come back as versions of the latest one
+        let context = Arc::new(Mutex::new(context));
+        let mut streams = self.streams.lock().unwrap();
+        let newer = streams
+            .get(&stream_id)
+            .is_some_and(|current| current.lock().unwrap().get_generation() > generation);
+        if newer {
+            self.archive_version(&stream_id, context);
+        } else if let Some(previous) = streams.insert(stream_id.clone(), context) {
+            self.archive_version(&stream_id, previous);
+        }
+        Ok(true)
     }
 
     /// Clean up old streams (older than max_age_hours).
     pub fn cleanup_old_streams(&self, max_age_hours: u64) {
-        let streams = self.streams.lock().unwrap();
+        self.expire_streams(Some(Duration::from_secs(max_age_hours * 3600)));
+    }
+
+    /// Delete the streams that expired: those past the end of life set with
+    /// SET_TTL, and those unread for longer than `idle_ttl`. Pinned streams
+    /// are kept. Returns the number of streams deleted.
+    pub fn expire_streams(&self, idle_ttl: Option<Duration>) -> usize {
         let now = SystemTime::now();
-        let cutoff = Duration::from_secs(max_age_hours * 3600);
+        // Collect first: deleting takes the registry lock again
+        let expired: Vec<String> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, ctx)| ctx.lock().unwrap().is_expired(now, idle_ttl))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut deleted = 0;
+        for stream_id in expired {
+            info!("Expiring stream: {}", stream_id);
+            match self.remove_stream(&stream_id, true) {
+                Ok(()) => deleted += 1,
+                Err(e) => error!("Failed to expire stream {}: {}", stream_id, e),
+            }
+        }
+        deleted
+    }
+
+    /// Halt an upload on request of its client and return the bytes written
+    /// so far. Writes fail until [`StreamManager::unpause_stream`].
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn pause_stream(&self, stream_id: &str) -> Result<u64, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let mut ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Uploading)?;
+        ctx.set_status(StreamStatus::Paused);
+        ctx.update_access_time();
+
+        info!(
+            "Paused stream {} at offset {}",
+            stream_id,
+            ctx.get_current_offset()
+        );
+        Ok(ctx.get_current_offset())
+    }
 
-        let to_remove: Vec<String> = streams
+    /// Continue a paused upload and return the offset new data goes to.
+    #[tracing::instrument(skip_all, fields(stream_id = stream_id))]
+    pub fn unpause_stream(&self, stream_id: &str) -> Result<u64, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let mut ctx = stream.lock().unwrap();
+        Self::require_status(&ctx, StreamStatus::Paused)?;
+        ctx.set_status(StreamStatus::Uploading);
+        ctx.update_access_time();
+
+        info!(
+            "Resumed stream {} at offset {}",
+            stream_id,
+            ctx.get_current_offset()
+        );
+        Ok(ctx.get_current_offset())
+    }
+
+    /// Record activity on a stream being uploaded without writing to it, such
+    /// as a heartbeat of the uploading client.
+    pub fn touch_stream(&self, stream_id: &str) {
+        if let Some(stream) = self.get_stream(stream_id) {
+            stream.lock().unwrap().update_access_time();
+        }
+    }
+
+    /// Pause the uploads that got neither data nor heartbeats for longer
+    /// than `idle`. Their data and offset are kept: a resumed START picks
+    /// them up again, and once paused they can expire like finalized
+    /// streams. Returns the number of streams paused.
+    pub fn pause_idle_uploads(&self, idle: Duration) -> usize {
+        let now = SystemTime::now();
+        let streams: Vec<(String, Arc<Mutex<StreamContext>>)> = self
+            .streams
+            .lock()
+            .unwrap()
             .iter()
-            .filter(|(_, ctx)| {
-                let ctx = ctx.lock().unwrap();
-                if let Ok(age) = now.duration_since(ctx.get_last_accessed_at()) {
-                    age > cutoff
-                } else {
-                    false
+            .map(|(id, ctx)| (id.clone(), ctx.clone()))
+            .collect();
+
+        let mut paused = 0;
+        for (stream_id, stream) in streams {
+            let mut ctx = stream.lock().unwrap();
+            let silent = now
+                .duration_since(ctx.get_last_accessed_at())
+                .is_ok_and(|elapsed| elapsed > idle);
+            if ctx.get_status() == StreamStatus::Uploading && silent {
+                ctx.set_status(StreamStatus::Paused);
+                info!(
+                    "Paused stream {} at offset {} after {}s without activity",
+                    stream_id,
+                    ctx.get_current_offset(),
+                    idle.as_secs()
+                );
+                paused += 1;
+            }
+        }
+        paused
+    }
+
+    /// Protect a stream from expiry, or stop protecting it.
+    pub fn set_pinned(&self, stream_id: &str, pinned: bool) -> Result<(), StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        stream.lock().unwrap().set_pinned(pinned);
+        info!(
+            "{} stream {}",
+            if pinned { "Pinned" } else { "Unpinned" },
+            stream_id
+        );
+        Ok(())
+    }
+
+    /// Expire a stream `ttl` from now, whether or not it is read; `None`
+    /// restores idle expiry.
+    pub fn set_ttl(&self, stream_id: &str, ttl: Option<Duration>) -> Result<(), StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+        stream.lock().unwrap().set_expires_at(expires_at);
+        match ttl {
+            Some(ttl) => info!("Stream {} expires in {}s", stream_id, ttl.as_secs()),
+            None => info!("Stream {} expires when idle", stream_id),
+        }
+        Ok(())
+    }
+
+    /// Re-hash the cache file of every finalized stream and compare it with
+    /// the checksum stored when the stream was finalized. Streams that no
+    /// longer match are marked as Error, so they are no longer served.
+    /// `pause` is slept between streams to keep the disk available to
+    /// transfers.
+    pub fn scrub_streams(&self, pause: Duration) -> ScrubReport {
+        let mut stream_ids = self.list_active_streams();
+        stream_ids.sort();
+
+        let mut report = ScrubReport::default();
+        for stream_id in stream_ids {
+            match self.scrub_stream(&stream_id) {
+                Ok(Some(true)) => report.verified += 1,
+                Ok(Some(false)) => report.corrupted.push(stream_id),
+                Ok(None) => continue,
+                Err(e) => warn!("Failed to scrub stream {}: {}", stream_id, e),
+            }
+            std::thread::sleep(pause);
+        }
+        report
+    }
+
+    /// Check one finalized stream against its checksum. Returns `None` when
+    /// the stream is not finalized or has no checksum.
+    fn scrub_stream(&self, stream_id: &str) -> Result<Option<bool>, StreamError> {
+        let stream = self.require_stream(stream_id)?;
+        let (mmap, expected) = {
+            let ctx = stream.lock().unwrap();
+            match ctx.get_checksum() {
+                Some(checksum) if ctx.get_status() == StreamStatus::Ready => {
+                    (Self::require_mmap(&ctx)?, checksum.to_string())
                 }
-            })
+                _ => return Ok(None),
+            }
+        };
+        // Hash without the context lock so reads of the stream go on
+        let actual = mmap
+            .compute_sha256()
+            .map_err(|e| StreamError::cache(stream_id, e))?;
+        if actual == expected {
+            return Ok(Some(true));
+        }
+
+        let mut ctx = stream.lock().unwrap();
+        // The stream may have been reopened while it was hashed
+        if ctx.get_status() != StreamStatus::Ready || ctx.get_checksum() != Some(&expected) {
+            return Ok(None);
+        }
+        ctx.set_status(StreamStatus::Error);
+        error!(
+            "Stream {} is corrupted: checksum {} does not match {}",
+            stream_id, actual, expected
+        );
+        Ok(Some(false))
+    }
+
+    /// Remove cache, journal, and peaks files that belong to no registered
+    /// stream, and drop registry entries whose cache file has disappeared.
+    pub fn sweep_orphans(&self) -> CacheSweepReport {
+        // Holding the registry lock keeps new streams from appearing mid-sweep
+        let mut streams = self.streams.lock().unwrap();
+        let versions = self.versions.lock().unwrap();
+        // Streams being created have files but no registry entry yet
+        let creating = self.creating.lock().unwrap();
+        let mut report = CacheSweepReport::default();
+
+        let missing: Vec<String> = streams
+            .iter()
+            .filter(|(_, ctx)| !Path::new(ctx.lock().unwrap().get_cache_path()).exists())
             .map(|(id, _)| id.clone())
             .collect();
+        for stream_id in missing {
+            if let Some(context) = streams.remove(&stream_id) {
+                let ctx = context.lock().unwrap();
+                if let Some(mmap) = ctx.get_mmap_file() {
+                    mmap.close();
+                }
+                if let Some(journal) = ctx.get_journal() {
+                    journal.remove();
+                }
+            }
+            info!("Dropped stream with missing cache file: {}", stream_id);
+            if let Some(quotas) = TokenQuotas::current() {
+                quotas.release(&stream_id);
+            }
+            self.event_bus.stream_deleted(&stream_id, false);
+            report.missing_files.push(stream_id);
+        }
 
-        for stream_id in to_remove {
-            println!("Cleaning up old stream: {}", stream_id);
-            self.delete_stream(&stream_id);
+        let entries = match self.cache_entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to scan cache directory: {:?}", e);
+                return report;
+            }
+        };
+        for (namespace, generation, entry) in entries {
+            let path = entry.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if !extension.is_some_and(|e| CACHE_FILE_EXTENSIONS.contains(&e)) {
+                continue;
+            }
+            let owned = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(|stem| match &namespace {
+                    Some(namespace) => format!("{}/{}", namespace, stem),
+                    None => stem.to_string(),
+                })
+                .is_some_and(|stream_id| {
+                    streams.get(&stream_id).is_some_and(|current| {
+                        current.lock().unwrap().get_generation() == generation
+                    }) || versions
+                        .get(&stream_id)
+                        .is_some_and(|kept| kept.contains_key(&generation))
+                        || creating.contains(&stream_id)
+                });
+            if owned {
+                continue;
+            }
+
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    info!("Removed orphaned cache file: {:?} ({} bytes)", path, size);
+                    report.reclaimed_bytes += size;
+                    report
+                        .orphaned_files
+                        .push(path.to_string_lossy().into_owned());
+                }
+                Err(e) => error!("Failed to remove orphaned file {:?}: {:?}", path, e),
+            }
+        }
+
+        if !report.is_clean() {
+            info!(
+                "Cache sweep: removed {} orphaned files ({} bytes), dropped {} streams with missing files",
+                report.orphaned_files.len(),
+                report.reclaimed_bytes,
+                report.missing_files.len()
+            );
+        }
+        report
+    }
+
+    /// Get cache file path for a stream. The streams of a namespace are kept
+    /// in a subdirectory named after it, e.g. `@team-a/stream-1.cache`.
+    pub fn get_cache_path(&self, stream_id: &str) -> String {
+        let path = match split_namespace(stream_id) {
+            (Some(namespace), name) => self
+                .cache_directory
+                .join(format!("{}{}", NAMESPACE_DIR_PREFIX, namespace))
+                .join(format!("{}.cache", name)),
+            (None, _) => self.cache_directory.join(format!("{}.cache", stream_id)),
+        };
+        long_path(path).to_string_lossy().into_owned()
+    }
+
+    /// Get the cache file path of generation `generation` of a stream. The
+    /// first one is at [`Self::get_cache_path`], later ones in a subdirectory
+    /// next to it, e.g. `.v2/stream-1.cache`.
+    pub fn get_version_cache_path(&self, stream_id: &str, generation: u64) -> String {
+        let cache_path = self.get_cache_path(stream_id);
+        let path = Path::new(&cache_path);
+        match (path.parent(), path.file_name()) {
+            (Some(directory), Some(name)) if generation > 1 => directory
+                .join(format!("{}{}", VERSION_DIR_PREFIX, generation))
+                .join(name)
+                .to_string_lossy()
+                .into_owned(),
+            _ => cache_path,
+        }
+    }
+
+    /// Get the cache file path of a new stream, creating the directories of
+    /// its namespace and generation if needed.
+    fn prepare_cache_path(&self, stream_id: &str, generation: u64) -> Result<String, StreamError> {
+        let cache_path = self.get_version_cache_path(stream_id, generation);
+        if let Some(directory) = Path::new(&cache_path).parent() {
+            std::fs::create_dir_all(directory)
+                .map_err(|e| StreamError::cache(stream_id, CacheError::io(&cache_path, e)))?;
+        }
+        Ok(cache_path)
+    }
+
+    /// List the files of the cache directory and of its namespace and
+    /// generation subdirectories, with the namespace and generation each
+    /// belongs to.
+    fn cache_entries(&self) -> std::io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.cache_directory)?.flatten() {
+            let namespace = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(NAMESPACE_DIR_PREFIX))
+                .filter(|namespace| is_valid_namespace(namespace))
+                .map(str::to_string);
+            match namespace {
+                Some(namespace) if entry.path().is_dir() => {
+                    let Ok(files) = std::fs::read_dir(entry.path()) else {
+                        continue;
+                    };
+                    for file in files.flatten() {
+                        Self::add_cache_entry(&mut entries, Some(&namespace), file);
+                    }
+                }
+                _ => Self::add_cache_entry(&mut entries, None, entry),
+            }
         }
+        Ok(entries)
     }
 
-    /// Get cache file path for a stream.
-    fn get_cache_path(&self, stream_id: &str) -> String {
-        format!("{}/{}.cache", self.cache_directory, stream_id)
+    /// Add a file of a stream directory to `entries`, or the files of a
+    /// generation subdirectory.
+    fn add_cache_entry(
+        entries: &mut Vec<CacheEntry>,
+        namespace: Option<&str>,
+        entry: std::fs::DirEntry,
+    ) {
+        let generation = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(VERSION_DIR_PREFIX))
+            .and_then(|generation| generation.parse::<u64>().ok())
+            .filter(|&generation| generation > 1);
+        match generation {
+            Some(generation) if entry.path().is_dir() => {
+                let Ok(files) = std::fs::read_dir(entry.path()) else {
+                    return;
+                };
+                let namespace = namespace.map(str::to_string);
+                entries.extend(
+                    files
+                        .flatten()
+                        .map(|file| (namespace.clone(), generation, file)),
+                );
+            }
+            _ => entries.push((namespace.map(str::to_string), 1, entry)),
+        }
+    }
+}
+
+/// Extend paths beyond MAX_PATH to the verbatim `\\?\` form, so cache files
+/// in deep directories can be created and opened on Windows.
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    const MAX_PATH: usize = 260;
+    // Leave room for sidecar files, whose extensions are longer than "cache"
+    if path.as_os_str().len() + 8 < MAX_PATH {
+        return path;
+    }
+    let Ok(absolute) = std::path::absolute(&path) else {
+        return path;
+    };
+    let absolute = absolute.to_string_lossy().into_owned();
+    if absolute.starts_with(r"\\?\") {
+        PathBuf::from(absolute)
+    } else if let Some(share) = absolute.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", share))
+    } else {
+        PathBuf::from(format!(r"\\?\{}", absolute))
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::events::StreamEvent;
+    use sha2::{Digest, Sha256};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn temp_manager(name: &str) -> StreamManager {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        StreamManager::new(directory)
+    }
+
+    // Rejects chunks starting with "bad"
+    struct RejectMarked;
+
+    impl StreamProcessor for RejectMarked {
+        fn name(&self) -> &str {
+            "reject-marked"
+        }
+
+        fn on_chunk(&self, _stream_id: &str, _offset: u64, data: &[u8]) -> anyhow::Result<()> {
+            anyhow::ensure!(!data.starts_with(b"bad"), "marked chunk");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn seek_is_limited_to_the_declared_size_or_end() {
+        let manager = temp_manager("seek-limit");
+        manager
+            .create_stream("declared".to_string(), Some(1000), false)
+            .unwrap();
+        assert_eq!(manager.seek_limit("declared").unwrap(), 1000);
+
+        manager
+            .create_stream("unknown".to_string(), None, false)
+            .unwrap();
+        assert_eq!(manager.seek_limit("unknown").unwrap(), 0);
+        manager.write_chunk("unknown", b"abcd").unwrap();
+        assert_eq!(manager.seek_limit("unknown").unwrap(), 4);
+
+        // Declared sizes preallocate no more than the limit
+        manager.set_max_preallocation(4096);
+        manager
+            .create_stream("huge".to_string(), Some(u64::MAX), false)
+            .unwrap();
+        assert_eq!(manager.seek_limit("huge").unwrap(), 4096);
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn streams_being_created_are_reserved() {
+        let manager = temp_manager("create-reserved");
+        manager.creating.lock().unwrap().insert("busy".to_string());
+        assert!(matches!(
+            manager.create_stream("busy".to_string(), None, false),
+            Err(StreamError::AlreadyExists(_))
+        ));
+
+        // The sweep spares the files of a stream not registered yet
+        let cache_path = manager.get_cache_path("busy");
+        std::fs::write(&cache_path, b"data").unwrap();
+        assert!(manager.sweep_orphans().is_clean());
+        assert!(Path::new(&cache_path).exists());
+
+        manager.creating.lock().unwrap().clear();
+        assert_eq!(manager.sweep_orphans().orphaned_files.len(), 1);
+        manager
+            .create_stream("busy".to_string(), None, false)
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn clones_reserve_their_id_while_the_files_are_set_up() {
+        let manager = temp_manager("clone-reserved");
+        manager
+            .create_stream("source".to_string(), None, false)
+            .unwrap();
+        manager.write_chunk("source", b"abcd").unwrap();
+        manager.finalize_stream("source").unwrap();
+
+        manager.creating.lock().unwrap().insert("copy".to_string());
+        assert!(matches!(
+            manager.clone_stream("source", "copy"),
+            Err(StreamError::AlreadyExists(_))
+        ));
+        manager.creating.lock().unwrap().clear();
+
+        manager.clone_stream("source", "copy").unwrap();
+        assert_eq!(manager.read_chunk("copy", 0, 4).unwrap(), b"abcd");
+        assert!(manager.creating.lock().unwrap().is_empty());
+
+        // A source no longer finalized is refused, and its ID freed again
+        manager.reopen_stream("source").unwrap();
+        assert!(matches!(
+            manager.clone_stream("source", "other"),
+            Err(StreamError::InvalidState { .. })
+        ));
+        assert!(manager.creating.lock().unwrap().is_empty());
+        assert!(!Path::new(&manager.get_cache_path("other")).exists());
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn versions_beyond_the_limit_are_deleted_and_announced() {
+        let manager = temp_manager("versions-pruned");
+        manager.set_keep_versions(1);
+        let mut events = manager.event_bus.subscribe();
+        for data in [b"one", b"two", b"six"] {
+            manager
+                .create_stream("pruned".to_string(), None, false)
+                .unwrap();
+            manager.write_chunk("pruned", data).unwrap();
+            manager.finalize_stream("pruned").unwrap();
+        }
+        assert_eq!(manager.list_versions("pruned"), vec![2]);
+        assert!(!Path::new(&manager.get_version_cache_path("pruned", 1)).exists());
+
+        // Other tests publish on the same bus
+        let mut deleted = Vec::new();
+        loop {
+            match events.try_recv() {
+                Ok(StreamEvent::VersionDeleted {
+                    stream_id,
+                    generation,
+                    ..
+                }) if stream_id == "pruned" => deleted.push(generation),
+                Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        assert_eq!(deleted, vec![1]);
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn write_past_the_offset_range_leaves_the_stream_usable() {
+        let manager = temp_manager("write-overflow");
+        manager
+            .create_stream("overflow".to_string(), None, false)
+            .unwrap();
+
+        let result = manager.write_chunk_at("overflow", Some(u64::MAX - 2), b"abcd", None);
+        assert!(matches!(
+            result,
+            Err(StreamError::Cache {
+                source: CacheError::OutOfBounds { .. },
+                ..
+            })
+        ));
+        assert_eq!(manager.write_chunk("overflow", b"abcd").unwrap(), 4);
+        assert_eq!(manager.read_chunk("overflow", 0, 4).unwrap(), b"abcd");
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn rejected_chunks_are_not_journaled() {
+        let manager = temp_manager("journal-rejected");
+        manager.set_journaling(true);
+        manager.register_processor(Arc::new(RejectMarked));
+        manager
+            .create_stream("journaled".to_string(), None, false)
+            .unwrap();
+
+        assert_eq!(manager.write_chunk("journaled", b"good").unwrap(), 4);
+        assert!(matches!(
+            manager.write_chunk("journaled", b"bad!"),
+            Err(StreamError::Rejected { .. })
+        ));
+
+        let journal = StreamJournal::journal_path(&manager.get_cache_path("journaled"));
+        let state = StreamJournal::replay(&journal).unwrap();
+        assert_eq!(state.received.ranges(), &[(0, 4)]);
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn rejected_chunks_never_reach_the_cache_file() {
+        let manager = temp_manager("cache-rejected");
+        manager.register_processor(Arc::new(RejectMarked));
+        manager
+            .create_stream("checked".to_string(), None, false)
+            .unwrap();
+        manager.write_chunk("checked", b"good").unwrap();
+
+        assert!(manager.write_chunk("checked", b"bad!").is_err());
+        let stream = manager.get_stream("checked").unwrap();
+        let size = stream.lock().unwrap().get_mmap_file().unwrap().get_size();
+        assert_eq!(size, 4);
+
+        manager.write_chunk("checked", b"next").unwrap();
+        assert_eq!(manager.read_chunk("checked", 0, 8).unwrap(), b"goodnext");
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn batch_stops_at_the_first_rejected_chunk() {
+        let manager = temp_manager("batch-rejected");
+        manager.register_processor(Arc::new(RejectMarked));
+        manager
+            .create_stream("batched".to_string(), None, false)
+            .unwrap();
+
+        let chunks: [(&[u8], Option<u64>); 3] =
+            [(b"one", Some(1)), (b"bad", Some(2)), (b"two", Some(3))];
+        let batch = manager.write_chunks("batched", None, &chunks).unwrap();
+        assert_eq!((batch.chunks, batch.bytes), (1, 3));
+        assert!(matches!(batch.rejected, Some(StreamError::Rejected { .. })));
+        assert_eq!(manager.get_timestamps("batched").unwrap().len(), 1);
+
+        manager.write_chunk("batched", b"two").unwrap();
+        assert_eq!(manager.read_chunk("batched", 0, 6).unwrap(), b"onetwo");
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recovery_releases_uncommitted_ranges() {
+        use std::os::unix::fs::MetadataExt;
+
+        const MB: usize = 1024 * 1024;
+        let manager = temp_manager("recover-holes");
+        let cache_path = manager.get_cache_path("holes");
+        std::fs::write(&cache_path, vec![7u8; 3 * MB]).unwrap();
+        // The middle megabyte reached the file but was never journaled
+        let journal = StreamJournal::create(&cache_path, "holes").unwrap();
+        journal.append_chunk(0, MB).unwrap();
+        journal.append_chunk(2 * MB as u64, MB).unwrap();
+        drop(journal);
+        let blocks = || std::fs::metadata(&cache_path).unwrap().blocks();
+        let allocated = blocks();
+
+        assert_eq!(manager.recover_from_journals(), 1);
+        assert!(
+            blocks() < allocated,
+            "{} blocks allocated after recovery, {} before",
+            blocks(),
+            allocated
+        );
+        let received = manager.resume_stream("holes").unwrap();
+        assert_eq!(
+            received.missing(3 * MB as u64),
+            vec![(MB as u64, 2 * MB as u64)]
+        );
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn finalize_beyond_the_committed_bytes_is_resumed() {
+        let manager = temp_manager("recover-lost-final");
+        let cache_path = manager.get_cache_path("lost");
+        std::fs::write(&cache_path, b"abcdefghijkl").unwrap();
+        // The record of the middle chunk was lost, the FINAL after it was not
+        let journal = StreamJournal::create(&cache_path, "lost").unwrap();
+        journal.append_chunk(0, 4).unwrap();
+        journal.append_chunk(8, 4).unwrap();
+        journal.append_final(12, Some("stale")).unwrap();
+        drop(journal);
+
+        assert_eq!(manager.recover_from_journals(), 1);
+        let stream = manager.get_stream("lost").unwrap();
+        let ctx = stream.lock().unwrap();
+        assert_eq!(ctx.get_status(), StreamStatus::Uploading);
+        assert_eq!(ctx.get_checksum(), None);
+        drop(ctx);
+        let state = StreamJournal::replay(&StreamJournal::journal_path(&cache_path)).unwrap();
+        assert_eq!(state.finalized_size, None);
+
+        let received = manager.resume_stream("lost").unwrap();
+        assert_eq!(received.missing(12), vec![(4, 8)]);
+        manager
+            .write_chunk_at("lost", Some(4), b"efgh", None)
+            .unwrap();
+        manager.finalize_stream("lost").unwrap();
+        let expected = format!("{:x}", Sha256::digest(b"abcdefghijkl"));
+        assert_eq!(
+            stream.lock().unwrap().get_checksum(),
+            Some(expected.as_str())
+        );
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn uploads_are_hashed_as_their_bytes_fill_in() {
+        let manager = temp_manager("digest-gaps");
+        manager
+            .create_stream("gaps".to_string(), None, false)
+            .unwrap();
+        let hashed = || {
+            let stream = manager.get_stream("gaps").unwrap();
+            let ctx = stream.lock().unwrap();
+            ctx.digest.as_ref().map(DigestBuilder::hashed)
+        };
+
+        manager
+            .write_chunk_at("gaps", Some(4), b"efgh", None)
+            .unwrap();
+        assert_eq!(hashed(), Some(0));
+        manager
+            .write_chunk_at("gaps", Some(0), b"abcd", None)
+            .unwrap();
+        assert_eq!(hashed(), Some(8));
+
+        manager.finalize_stream("gaps").unwrap();
+        let stream = manager.get_stream("gaps").unwrap();
+        let ctx = stream.lock().unwrap();
+        let expected = format!("{:x}", Sha256::digest(b"abcdefgh"));
+        assert_eq!(ctx.get_checksum(), Some(expected.as_str()));
+        assert_eq!(ctx.get_block_index().unwrap().block_count(), 1);
+        drop(ctx);
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
+    }
+
+    #[test]
+    fn rewritten_bytes_are_hashed_on_finalize() {
+        let manager = temp_manager("digest-rewrite");
+        manager
+            .create_stream("rewrite".to_string(), None, false)
+            .unwrap();
+        manager.write_chunk("rewrite", b"abcd").unwrap();
+        manager
+            .write_chunk_at("rewrite", Some(0), b"wxyz", None)
+            .unwrap();
+
+        manager.finalize_stream("rewrite").unwrap();
+        let stream = manager.get_stream("rewrite").unwrap();
+        let expected = format!("{:x}", Sha256::digest(b"wxyz"));
+        assert_eq!(
+            stream.lock().unwrap().get_checksum(),
+            Some(expected.as_str())
+        );
+
+        let _ = std::fs::remove_dir_all(&manager.cache_directory);
     }
 }
@@ -0,0 +1,102 @@
+// Capture timestamps of the chunks of a stream.
+// Uploaders on a framed session can tag each chunk with the time it was
+// captured. The (offset, timestamp) pairs are appended to a sidecar file next
+// to the stream cache as chunks arrive, so downloads of several live streams,
+// e.g. the microphones of one recording, can be aligned on a common clock.
+//
+// Sidecar format: 16-byte records of little-endian offset and timestamp.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::ChunkTimestamp;
+
+const RECORD_LEN: usize = 16;
+
+/// Capture timestamps of a stream, in offset order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimestampIndex {
+    entries: Vec<ChunkTimestamp>,
+}
+
+impl TimestampIndex {
+    /// Get the sidecar path holding the timestamps of a cache file.
+    pub fn index_path(cache_path: &str) -> PathBuf {
+        Path::new(cache_path).with_extension("times")
+    }
+
+    /// Load the timestamps of a cache file holding `size` bytes.
+    /// Records past the end of the data, e.g. of chunks lost in a crash,
+    /// are dropped; a missing sidecar yields an empty index.
+    pub fn load(cache_path: &str, size: u64) -> Self {
+        let data = std::fs::read(Self::index_path(cache_path)).unwrap_or_default();
+        let entries = data
+            .chunks_exact(RECORD_LEN)
+            .map(|record| ChunkTimestamp {
+                offset: u64::from_le_bytes(record[..8].try_into().unwrap()),
+                timestamp: u64::from_le_bytes(record[8..].try_into().unwrap()),
+            })
+            .take_while(|entry| entry.offset < size)
+            .collect();
+        Self { entries }
+    }
+
+    /// Record the capture time of the chunk at `offset` and append it to the
+    /// sidecar of `cache_path`.
+    pub fn append(&mut self, cache_path: &str, offset: u64, timestamp: u64) -> std::io::Result<()> {
+        let entry = ChunkTimestamp { offset, timestamp };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::index_path(cache_path))?
+            .write_all(&Self::record(&entry))?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the whole index next to a cache file, replacing any sidecar.
+    pub fn save(&self, cache_path: &str) -> std::io::Result<()> {
+        let data: Vec<u8> = self.entries.iter().flat_map(Self::record).collect();
+        std::fs::write(Self::index_path(cache_path), data)
+    }
+
+    /// Follow the removal of the bytes in `range` from the stream: later
+    /// chunks move back, and the last chunk starting inside the range is
+    /// taken to start where the range did.
+    pub fn remove_range(&mut self, range: Range<u64>) {
+        let removed = range.end - range.start;
+        let mut entries: Vec<ChunkTimestamp> = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let offset = if entry.offset < range.start {
+                entry.offset
+            } else {
+                entry.offset.saturating_sub(removed).max(range.start)
+            };
+            if entries.last().is_some_and(|last| last.offset == offset) {
+                entries.pop();
+            }
+            entries.push(ChunkTimestamp {
+                offset,
+                timestamp: entry.timestamp,
+            });
+        }
+        self.entries = entries;
+    }
+
+    pub fn entries(&self) -> &[ChunkTimestamp] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn record(entry: &ChunkTimestamp) -> [u8; RECORD_LEN] {
+        let mut record = [0u8; RECORD_LEN];
+        record[..8].copy_from_slice(&entry.offset.to_le_bytes());
+        record[8..].copy_from_slice(&entry.timestamp.to_le_bytes());
+        record
+    }
+}
@@ -1,39 +1,387 @@
 // Audio stream server module
+pub mod cluster;
+pub mod error;
+pub mod events;
 pub mod handler;
 pub mod memory;
 pub mod network;
+pub mod processing;
+#[cfg(feature = "client")]
+pub mod replication;
 
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cli::ServerConfig;
+use crate::server::cluster::{hash_ring, ClusterRouter, HashRing};
+use crate::server::events::{audit_logger, event_feed, webhook_notifier, StreamEventBus};
+use crate::server::handler::AccessLog;
 use crate::server::memory::MemoryPoolManager;
+use crate::server::processing::{
+    waveform_peaks, AudioProbeProcessor, PeaksConfig, SilenceTrimProcessor,
+};
+#[cfg(feature = "transcode")]
+use crate::server::processing::{transcoder, TranscodeFormat, TranscoderConfig};
 use crate::server::memory::StreamManager;
-use crate::server::network::AudioWebSocketServer;
+use crate::server::network::{drain, AudioWebSocketServer, CidrBlock, TokenQuotas};
 use crate::logger;
+pub use error::{Result, ServerError};
+
+/// How often expired streams are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Pause between streams during an integrity scrub.
+const SCRUB_PAUSE: Duration = Duration::from_millis(100);
+
+pub async fn run(config: &ServerConfig) -> Result<()> {
+    let port = config.port;
+    let path = &config.path;
+    logger::init(config.verbose);
+    logger::init_color(config.no_color);
+    init_tracing();
 
-pub async fn run(port: u16, path: &str) -> anyhow::Result<()> {
     logger::log_info("Starting Audio Server Application...");
     logger::log_info(&format!("Port: {}, Endpoint: {}", port, path));
     logger::log_info("Press Ctrl+C to stop");
 
-    let stream_manager = StreamManager::instance("cache".to_string());
+    // A chunk must fit in a single buffered message
+    if config.max_chunk_size == 0 || config.max_chunk_size > config.max_buffered_bytes {
+        return Err(ServerError::Config(format!(
+            "--max-chunk-size must be between 1 and --max-buffered-bytes ({})",
+            config.max_buffered_bytes
+        )));
+    }
+
+    if config.reuse_port {
+        match config.shard_port() {
+            Some(shard_port) if shard_port != port && Some(shard_port) != config.tcp_port => {}
+            _ => {
+                return Err(ServerError::Config(
+                    "--reuse-port needs a --node-uri on a port of its own, other than --port and --tcp-port"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    if !config.cluster_nodes.is_empty() {
+        let self_uri = config
+            .node_uri
+            .clone()
+            .unwrap_or_else(|| format!("ws://localhost:{}{}", port, path));
+        if !config.cluster_nodes.contains(&self_uri) {
+            return Err(ServerError::Config(format!(
+                "--node-uri {} is not one of the --cluster-node entries",
+                self_uri
+            )));
+        }
+        let ring = HashRing::new(&config.cluster_nodes, hash_ring::DEFAULT_VIRTUAL_NODES);
+        ClusterRouter::install(ClusterRouter::new(self_uri.clone(), ring));
+        logger::log_info(&format!(
+            "Cluster mode: {} nodes, this node = {}",
+            config.cluster_nodes.len(),
+            self_uri
+        ));
+    }
+
+    // Subscribe consumers before any stream activity is published
+    let event_bus = StreamEventBus::instance();
+    audit_logger::spawn(&event_bus);
+    #[cfg(feature = "metrics")]
+    {
+        use crate::server::events::metrics_collector::{self, METRICS_PATH};
+
+        metrics_collector::spawn(&event_bus);
+        logger::log_info(&format!("Metrics: http://localhost:{}{}", port, METRICS_PATH));
+    }
+
+    let cache_directory = match &config.cache_shard {
+        Some(shard) => Path::new("cache").join(shard),
+        None => PathBuf::from("cache"),
+    };
+    let stream_manager = StreamManager::open(cache_directory.clone());
     let memory_pool = MemoryPoolManager::instance(64 * 1024, 16);
+    #[cfg(feature = "metrics")]
+    crate::server::events::metrics_collector::StreamMetrics::instance()
+        .watch_memory_pool(memory_pool.clone());
+
+    stream_manager.set_keep_versions(config.keep_versions);
+    if config.journal {
+        stream_manager.set_journaling(true);
+        stream_manager.recover_from_journals();
+    }
+
+    if config.huge_pages {
+        stream_manager.set_huge_pages(true);
+        logger::log_info("Huge pages: enabled for cache files of 64MB or more");
+    }
+
+    if config.large_files {
+        stream_manager.set_large_files(true);
+        logger::log_info("Large-file mode: enabled");
+    }
+    stream_manager.set_read_ahead(config.read_ahead);
+    stream_manager.set_max_preallocation(config.max_preallocation);
+
+    if let Some(path) = &config.access_log {
+        let access_log = AccessLog::open(path).map_err(|e| {
+            ServerError::Config(format!("Cannot open access log {}: {}", path, e))
+        })?;
+        AccessLog::install(access_log);
+        logger::log_info(&format!("Access log: {}", path));
+    }
+
+    let join = |blocks: &[CidrBlock]| {
+        blocks.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    };
+    if !config.allow.is_empty() {
+        logger::log_info(&format!("Allowed clients: {}", join(&config.allow)));
+    }
+    if !config.deny.is_empty() {
+        logger::log_info(&format!("Denied clients: {}", join(&config.deny)));
+    }
+    if let Some(token) = &config.admin_token {
+        drain::set_admin_token(token.clone());
+        logger::log_info("Admin commands: enabled");
+        logger::log_info(&format!(
+            "Event feed: http://localhost:{}{}",
+            port,
+            event_feed::EVENTS_PATH
+        ));
+    }
+    if !config.tokens.is_empty() {
+        TokenQuotas::install(TokenQuotas::new(
+            &config.tokens,
+            &config.storage_quotas,
+            &config.transfer_quotas,
+            &config.namespaces,
+        )?);
+        logger::log_info(&format!(
+            "Access tokens: {}",
+            config
+                .tokens
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    } else if !config.storage_quotas.is_empty()
+        || !config.transfer_quotas.is_empty()
+        || !config.namespaces.is_empty()
+    {
+        return Err(ServerError::Config(
+            "--storage-quota, --transfer-quota, and --namespace need --token".to_string(),
+        ));
+    }
+    if !config.allowed_origins.is_empty() {
+        logger::log_info(&format!(
+            "Allowed origins: {}",
+            config.allowed_origins.join(", ")
+        ));
+    }
+
+    if let Some(faults) = &config.inject_faults {
+        logger::log_warn(&format!("Fault injection: {}", faults));
+    }
+
+    if let Some(interval) = config.cache_sweep_interval {
+        // Sweep after journal recovery so recovered streams keep their files
+        stream_manager.sweep_orphans();
+        if interval > 0 {
+            let sweeper = stream_manager.clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(interval));
+                sweeper.sweep_orphans();
+            });
+            logger::log_info(&format!("Cache sweep: every {}s", interval));
+        }
+    }
+
+    if let Some(interval) = config.scrub_interval {
+        let scrubber = stream_manager.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(interval));
+            let report = scrubber.scrub_streams(SCRUB_PAUSE);
+            #[cfg(feature = "metrics")]
+            crate::server::events::metrics_collector::StreamMetrics::instance()
+                .record_scrub(&report);
+            if !report.corrupted.is_empty() {
+                logger::log_error(&format!(
+                    "Integrity scrub: {} corrupted streams: {}",
+                    report.corrupted.len(),
+                    report.corrupted.join(", ")
+                ));
+            }
+        });
+        logger::log_info(&format!("Integrity scrub: every {}s", interval));
+    }
+
+    // Streams given a TTL expire even without --stream-ttl
+    let idle_ttl = config.stream_ttl.map(Duration::from_secs);
+    let pause_after = config.pause_after.map(Duration::from_secs);
+    let expirer = stream_manager.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EXPIRY_INTERVAL);
+        if let Some(idle) = pause_after {
+            expirer.pause_idle_uploads(idle);
+        }
+        expirer.expire_streams(idle_ttl);
+    });
+    if let Some(ttl) = config.stream_ttl {
+        logger::log_info(&format!("Stream TTL: {}s without reads", ttl));
+    }
+    if let Some(secs) = config.pause_after {
+        logger::log_info(&format!("Uploads pause after {}s without activity", secs));
+    }
+
+    // Trim before probing so probed durations describe the stored audio
+    if config.trim_silence {
+        stream_manager.register_processor(Arc::new(SilenceTrimProcessor::new(
+            config.silence_threshold_db,
+        )));
+        logger::log_info(&format!(
+            "Silence trimming: below {} dBFS",
+            config.silence_threshold_db
+        ));
+    }
+    if config.probe_audio {
+        stream_manager.register_processor(Arc::new(AudioProbeProcessor));
+    }
+    logger::log_info(&format!(
+        "StreamManager: cache directory = {}",
+        cache_directory.display()
+    ));
+
+    #[cfg(feature = "transcode")]
+    if !config.transcode_formats.is_empty() {
+        let formats = config
+            .transcode_formats
+            .iter()
+            .map(|name| {
+                TranscodeFormat::parse(name).ok_or_else(|| {
+                    ServerError::Config(format!("Unsupported transcode format: {}", name))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        transcoder::spawn(
+            &event_bus,
+            stream_manager.clone(),
+            TranscoderConfig {
+                ffmpeg_path: config.ffmpeg_path.clone(),
+                formats,
+            },
+        );
+        logger::log_info(&format!("Transcoding: {}", config.transcode_formats.join(", ")));
+    }
+
+    if config.waveform_peaks {
+        waveform_peaks::spawn(
+            &event_bus,
+            stream_manager.clone(),
+            PeaksConfig {
+                buckets: config.peak_buckets,
+                ffmpeg_path: Some(config.ffmpeg_path.clone()),
+            },
+        );
+        logger::log_info(&format!("Waveform peaks: {} buckets", config.peak_buckets));
+    }
+
+    #[cfg(feature = "loudness")]
+    if let Some(target_lufs) = config.loudness_target {
+        use crate::server::processing::loudness::{self, LoudnessConfig};
+
+        loudness::spawn(
+            &event_bus,
+            stream_manager.clone(),
+            LoudnessConfig {
+                ffmpeg_path: config.ffmpeg_path.clone(),
+                target_lufs,
+                true_peak_db: -1.0,
+            },
+        );
+        logger::log_info(&format!("Loudness normalization: {} LUFS", target_lufs));
+    }
+
+    #[cfg(feature = "rtp")]
+    if let Some(destination) = &config.rtp_destination {
+        use crate::server::processing::rtp_egress::{self, RtpEgressConfig};
+        use std::net::ToSocketAddrs;
+
+        let destination = destination
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| {
+                ServerError::Config(format!("Invalid RTP destination: {}", destination))
+            })?;
+        rtp_egress::spawn(
+            &event_bus,
+            stream_manager.clone(),
+            RtpEgressConfig {
+                destination,
+                payload_type: config.rtp_payload_type,
+            },
+        );
+        logger::log_info(&format!("RTP egress: {}", destination));
+    }
+
+    #[cfg(feature = "client")]
+    if let Some(peer_uri) = &config.replicate_to {
+        use crate::server::replication::{replicator, ReplicatorConfig};
+
+        replicator::spawn(
+            &event_bus,
+            stream_manager.clone(),
+            ReplicatorConfig {
+                peer_uri: peer_uri.clone(),
+            },
+        );
+        logger::log_info(&format!("Replication: {}", peer_uri));
+    }
+
+    if !config.webhook_urls.is_empty() {
+        webhook_notifier::spawn(&event_bus, stream_manager.clone(), config.webhook_urls.clone());
+        logger::log_info(&format!("Webhooks: {}", config.webhook_urls.join(", ")));
+    }
 
-    logger::log_info(&format!("StreamManager: cache directory = cache"));
     logger::log_info(&format!("MemoryPool: {} buffers × {} bytes",
         memory_pool.get_total_buffers(), memory_pool.get_buffer_size()));
 
-    let ws_server = AudioWebSocketServer::new(
-        port,
-        path.to_string(),
-        stream_manager,
-        memory_pool,
-    );
+    let ws_server = AudioWebSocketServer::new(config.clone(), stream_manager, memory_pool);
 
     logger::log_info(&format!("AudioWebSocketServer initialized on 0.0.0.0:{}{}", port, path));
 
-    // Start server (blocking)
-    ws_server.start();
+    // Start server (blocking until a drain completes)
+    ws_server.start()?;
+    if drain::is_draining() {
+        logger::log_info("Server stopped after draining");
+        return Ok(());
+    }
 
-    tokio::signal::ctrl_c().await?;
+    tokio::signal::ctrl_c()
+        .await
+        .map_err(|source| ServerError::Connection {
+            addr: "signal handler".to_string(),
+            source,
+        })?;
 
     logger::log_info("Server stopped");
     Ok(())
 }
+
+/// Install the log subscriber of the server. Connections, requests, and stream
+/// operations run in spans carrying `client_id` and `stream_id`, so RUST_LOG
+/// can narrow a busy server's output to one stream, e.g.
+/// `RUST_LOG='warn,[{stream_id=stream-1a2b}]=info'`. The default is `info`.
+fn init_tracing() {
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    // Already installed when the server runs embedded in another process
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(logger::color_enabled())
+        .try_init();
+}
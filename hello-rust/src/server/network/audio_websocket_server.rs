@@ -1,111 +1,624 @@
 // WebSocket server for audio streaming.
-// Handles client connections and message routing.
+// Handles client connections and message routing, over WebSocket and, with
+// --tcp-port, over raw TCP.
+// Every connection blocks a thread, so slow clients are bounded: the request
+// head and handshake must complete within --handshake-timeout, connections
+// idle for --idle-timeout are closed, and no message may buffer more than
+// --max-buffered-bytes. Connections from addresses outside the --allow and
+// --deny rules are closed as soon as they are accepted. With --allowed-origin,
+// upgrades from browser pages of other origins are refused, so arbitrary
+// websites cannot drive a local server through the visitor's browser. In
+// drain mode, new connections are rejected like those beyond capacity. With
+// --reuse-port, both listeners bind with SO_REUSEPORT so that several server
+// processes can share their ports; each process also listens on the port of
+// its --node-uri alone, where requests redirected to it arrive.
 // Matches Python WebSocketServer and Java AudioWebSocketServer functionality.
 
 use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::server::handler::WebSocketMessageHandler;
+use crate::cli::ServerConfig;
+use crate::protocol::{
+    FrameDirection, FrameDump, SubprotocolOffer, SUBPROTOCOL, SUBPROTOCOL_HEADER,
+};
+use crate::server::error::ServerError;
+use crate::server::handler::{ClientSession, WebSocketMessageHandler};
 use crate::server::memory::{MemoryPoolManager, StreamManager};
+use crate::server::network::{
+    drain, http_download, Connection, ConnectionLimiter, ConnectionSlot, IpFilter,
+};
+#[cfg(unix)]
+use socket2::{Domain, Socket, Type};
+use tracing::{error, info, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::handshake::HandshakeError;
+use tungstenite::http::header::ORIGIN;
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::protocol::WebSocketConfig;
+
+/// Pending connections a SO_REUSEPORT listener queues, as std uses for its
+/// own listeners.
+#[cfg(unix)]
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Data frames that already arrived written to a stream in one batch.
+const MAX_BATCH_FRAMES: usize = 64;
+
+/// Connections served so far; numbers each connection's fault sequence.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
 
 /// WebSocket server for handling audio stream uploads and downloads.
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct AudioWebSocketServer {
-    port: u16,
-    path: String,
-    clients: Arc<Mutex<HashMap<usize, String>>>, // Maps client to stream ID
+    config: Arc<ServerConfig>,
+    clients: Arc<Mutex<HashMap<usize, ClientSession>>>, // Maps client to its session
     stream_manager: Arc<StreamManager>,
     memory_pool: Arc<MemoryPoolManager>,
+    limiter: Arc<ConnectionLimiter>,
+    ip_filter: Arc<IpFilter>,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// Stops a server started by [`AudioWebSocketServer::spawn_on_ephemeral_port`]
+/// when shut down or dropped. Connections already accepted are served until
+/// their clients disconnect.
+pub struct ShutdownHandle {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ShutdownHandle {
+    /// Address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections and wait until the port is released.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the accept loop, which checks the flag per connection
+        let _ = std::net::TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 impl AudioWebSocketServer {
     /// Create a new WebSocket server.
     pub fn new(
-        port: u16,
-        path: String,
+        config: ServerConfig,
         stream_manager: Arc<StreamManager>,
         memory_pool: Arc<MemoryPoolManager>,
     ) -> Self {
+        let limiter = ConnectionLimiter::new(
+            config.max_connections as usize,
+            config.connection_queue as usize,
+        );
+        let ip_filter = Arc::new(IpFilter::new(config.allow.clone(), config.deny.clone()));
         Self {
-            port,
-            path,
+            config: Arc::new(config),
             clients: Arc::new(Mutex::new(HashMap::new())),
             stream_manager,
             memory_pool,
+            limiter,
+            ip_filter,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start the WebSocket server, and the raw TCP listener when a TCP port
+    /// is configured.
+    pub fn start(&self) -> Result<(), ServerError> {
+        if let Some(port) = self.config.tcp_port {
+            let addr = format!("0.0.0.0:{}", port);
+            let listener = Self::bind(&addr, self.config.reuse_port).map_err(|source| {
+                ServerError::Connection {
+                    addr: addr.clone(),
+                    source,
+                }
+            })?;
+            info!("Raw TCP server started on tcp://{}", addr);
+
+            let clients = self.clients.clone();
+            let stream_mgr = self.stream_manager.clone();
+            let mem_pool = self.memory_pool.clone();
+            let limiter = self.limiter.clone();
+            let ip_filter = self.ip_filter.clone();
+            let config = self.config.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let addr = stream.peer_addr().ok();
+                            if !Self::admits(&ip_filter, addr) {
+                                continue;
+                            }
+                            if drain::is_draining() {
+                                warn!(
+                                    "Rejecting TCP connection from {:?}: server is draining",
+                                    addr
+                                );
+                                continue;
+                            }
+                            let Some(slot) = limiter.admit() else {
+                                warn!(
+                                    "Rejecting TCP connection from {:?}: server is at capacity",
+                                    addr
+                                );
+                                continue;
+                            };
+                            let clients = clients.clone();
+                            let stream_mgr = stream_mgr.clone();
+                            let mem_pool = mem_pool.clone();
+                            let config = config.clone();
+                            Self::spawn_worker(slot, addr, move || {
+                                Self::set_timeouts(&stream, Self::idle_timeout(&config));
+                                Self::serve(
+                                    Connection::Tcp {
+                                        stream,
+                                        max_frame_len: config.max_buffered_bytes as usize,
+                                    },
+                                    addr,
+                                    &clients,
+                                    &stream_mgr,
+                                    &mem_pool,
+                                    &config,
+                                );
+                            });
+                        }
+                        Err(e) => {
+                            error!("Error accepting TCP connection: {:?}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        // Requests for streams of the other processes sharing the port are
+        // redirected to their --node-uri, whose port only they listen on
+        if let Some(port) = self.config.shard_port() {
+            let addr = format!("0.0.0.0:{}", port);
+            let listener = Self::bind(&addr, false).map_err(|source| {
+                ServerError::Connection {
+                    addr: addr.clone(),
+                    source,
+                }
+            })?;
+            info!("Shard listener started on ws://{}", addr);
+
+            let server = self.clone();
+            std::thread::spawn(move || server.accept(listener));
         }
+
+        let addr = format!("0.0.0.0:{}", self.config.port);
+        let listener = Self::bind(&addr, self.config.reuse_port).map_err(|source| {
+            ServerError::Connection {
+                addr: addr.clone(),
+                source,
+            }
+        })?;
+        info!("WebSocket server started on ws://{}", addr);
+        info!(
+            "Serving up to {} connections with {} more queued",
+            self.config.max_connections, self.config.connection_queue
+        );
+
+        self.accept(listener);
+        Ok(())
     }
 
-    /// Start the WebSocket server.
-    pub fn start(&self) {
-        use tungstenite::protocol::Message;
+    /// Bind a listener to `addr`, with SO_REUSEPORT when `reuse_port` is set.
+    #[cfg(unix)]
+    fn bind(addr: &str, reuse_port: bool) -> std::io::Result<TcpListener> {
+        if !reuse_port {
+            return TcpListener::bind(addr);
+        }
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(socket.into())
+    }
 
-        let addr = format!("0.0.0.0:{}", self.port);
-        let listener = std::net::TcpListener::bind(&addr).expect("Failed to bind to address");
-        println!("WebSocket server started on ws://{}", addr);
+    #[cfg(not(unix))]
+    fn bind(addr: &str, reuse_port: bool) -> std::io::Result<TcpListener> {
+        if reuse_port {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "--reuse-port is only supported on Unix",
+            ));
+        }
+        TcpListener::bind(addr)
+    }
+
+    /// Start the WebSocket server on a free port of the loopback interface,
+    /// serving it on a background thread. Returns the bound address and the
+    /// handle that stops the server, so a client and a server can run in
+    /// one process, e.g. in integration tests.
+    pub fn spawn_on_ephemeral_port(self) -> Result<(SocketAddr, ShutdownHandle), ServerError> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| Ok((listener.local_addr()?, listener)));
+        let (addr, listener) = listener.map_err(|source| ServerError::Connection {
+            addr: "127.0.0.1:0".to_string(),
+            source,
+        })?;
+        info!("WebSocket server started on ws://{}", addr);
+
+        let shutdown = self.shutdown.clone();
+        let thread = std::thread::spawn(move || self.accept(listener));
+        Ok((
+            addr,
+            ShutdownHandle {
+                addr,
+                shutdown,
+                thread: Some(thread),
+            },
+        ))
+    }
+
+    /// Serve every connection accepted by `listener` on its own thread,
+    /// within the limits of the connection limiter.
+    fn accept(&self, listener: TcpListener) {
+        match listener.local_addr() {
+            Ok(addr) => drain::spawn_watcher(
+                self.limiter.clone(),
+                self.shutdown.clone(),
+                addr,
+                Duration::from_secs(self.config.drain_timeout),
+            ),
+            Err(e) => warn!("Drain mode unavailable: {}", e),
+        }
 
         for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
                     let addr = stream.peer_addr().ok();
+                    if !Self::admits(&self.ip_filter, addr) {
+                        continue;
+                    }
+                    if drain::is_draining() {
+                        Self::reject(stream, addr, "server is draining");
+                        continue;
+                    }
+                    let Some(slot) = self.limiter.admit() else {
+                        Self::reject(stream, addr, "server is at capacity");
+                        continue;
+                    };
                     let clients = self.clients.clone();
                     let stream_mgr = self.stream_manager.clone();
                     let mem_pool = self.memory_pool.clone();
-                    let _path = self.path.clone();
-
-                    std::thread::spawn(move || {
-                        let mut websocket = tungstenite::accept(stream).unwrap();
-
-                        // Generate client ID
-                        let client_id = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_nanos() as usize;
-                        clients.lock().unwrap().insert(client_id, String::new());
-
-                        println!("Client connected: {:?}", addr);
-
-                        // Handle messages
-                        loop {
-                            match websocket.read() {
-                                Ok(msg) => match msg {
-                                    Message::Text(text) => {
-                                        WebSocketMessageHandler::handle_text_message(
-                                            &mut websocket,
-                                            &clients,
-                                            &stream_mgr,
-                                            &mem_pool,
-                                            client_id,
-                                            &text,
-                                        );
-                                    }
-                                    Message::Binary(data) => {
-                                        WebSocketMessageHandler::handle_binary_message(
-                                            &clients,
-                                            &stream_mgr,
+                    let config = self.config.clone();
+
+                    Self::spawn_worker(slot, addr, move || {
+                        let handshake_timeout = Duration::from_secs(config.handshake_timeout);
+                        Self::set_timeouts(&stream, Some(handshake_timeout));
+                        let Some(head) =
+                            http_download::peek_request_head(&stream, handshake_timeout)
+                        else {
+                            warn!(
+                                "Closing connection from {:?}: no complete request head within {}s",
+                                addr, config.handshake_timeout
+                            );
+                            return;
+                        };
+
+                        // Plain HTTP requests (no upgrade) go to the download path
+                        if !head.is_websocket_upgrade() {
+                            Self::set_timeouts(&stream, Self::idle_timeout(&config));
+                            http_download::serve(stream, &head, &stream_mgr, &config);
+                            return;
+                        }
+
+                        let max_buffered = config.max_buffered_bytes as usize;
+                        let defaults = WebSocketConfig::default();
+                        // tungstenite requires room beyond its write buffer
+                        let max_write_buffered = max_buffered.max(defaults.write_buffer_size + 1);
+                        let websocket_config = defaults
+                            .max_message_size(Some(max_buffered))
+                            .max_frame_size(Some(max_buffered))
+                            .max_write_buffer_size(max_write_buffered);
+                        #[allow(clippy::result_large_err)]
+                        let callback = |request: &Request, response: Response| {
+                            Self::check_origin(&config.allowed_origins, request)?;
+                            Self::negotiate_subprotocol(request, response)
+                        };
+                        let websocket = match tungstenite::accept_hdr_with_config(
+                            stream,
+                            callback,
+                            Some(websocket_config),
+                        ) {
+                            Ok(websocket) => websocket,
+                            Err(HandshakeError::Interrupted(_)) => {
+                                warn!(
+                                    "WebSocket handshake from {:?} timed out after {}s",
+                                    addr, config.handshake_timeout
+                                );
+                                return;
+                            }
+                            Err(e) => {
+                                warn!("WebSocket handshake failed for {:?}: {}", addr, e);
+                                return;
+                            }
+                        };
+                        Self::set_timeouts(websocket.get_ref(), Self::idle_timeout(&config));
+                        Self::serve(
+                            Connection::WebSocket(Box::new(websocket)),
+                            addr,
+                            &clients,
+                            &stream_mgr,
+                            &mem_pool,
+                            &config,
+                        );
+                    });
+                }
+                Err(e) => {
+                    error!("Error accepting connection: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Run `serve` on a new thread once the connection holds a slot; the slot
+    /// is released when the thread ends.
+    fn spawn_worker(
+        mut slot: ConnectionSlot,
+        addr: Option<SocketAddr>,
+        serve: impl FnOnce() + Send + 'static,
+    ) {
+        let spawned = std::thread::Builder::new().spawn(move || {
+            if slot.is_queued() {
+                info!("Connection from {:?} queued until a slot is free", addr);
+                slot.wait();
+            }
+            serve();
+            drop(slot);
+        });
+        if let Err(e) = spawned {
+            error!(
+                "Failed to start thread for connection from {:?}: {}",
+                addr, e
+            );
+        }
+    }
+
+    /// Check a new connection against the address rules; connections of
+    /// unknown address are only admitted without rules.
+    fn admits(ip_filter: &IpFilter, addr: Option<SocketAddr>) -> bool {
+        let admitted = match addr {
+            Some(addr) => ip_filter.admits(addr.ip()),
+            None => ip_filter.is_empty(),
+        };
+        if !admitted {
+            warn!("Closing connection from {:?}: address not allowed", addr);
+        }
+        admitted
+    }
+
+    /// Get the idle timeout of established connections, if any.
+    fn idle_timeout(config: &ServerConfig) -> Option<Duration> {
+        (config.idle_timeout > 0).then(|| Duration::from_secs(config.idle_timeout))
+    }
+
+    /// Bound every blocking read and write on `stream` by `timeout`.
+    fn set_timeouts(stream: &TcpStream, timeout: Option<Duration>) {
+        let _ = stream.set_read_timeout(timeout);
+        let _ = stream.set_write_timeout(timeout);
+    }
+
+    /// Turn away a connection while all slots and queue places are taken,
+    /// or while draining.
+    fn reject(mut stream: TcpStream, addr: Option<SocketAddr>, reason: &str) {
+        warn!("Rejecting connection from {:?}: {}", addr, reason);
+        let _ = stream.write_all(
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\n\
+              Content-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    /// Handle the messages of a connected client until it disconnects.
+    fn serve(
+        mut connection: Connection,
+        addr: Option<SocketAddr>,
+        clients: &Arc<Mutex<HashMap<usize, ClientSession>>>,
+        stream_mgr: &Arc<StreamManager>,
+        mem_pool: &Arc<MemoryPoolManager>,
+        config: &ServerConfig,
+    ) {
+        use tungstenite::protocol::frame::coding::CloseCode;
+        use tungstenite::protocol::{CloseFrame, Message};
+
+        // Generate client ID
+        let client_id = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as usize;
+        let session = ClientSession {
+            frame_dump: config.frame_dump.then(FrameDump::new),
+            peer: addr,
+            max_chunk_size: Some(config.max_chunk_size as usize),
+            ..ClientSession::default()
+        };
+        clients.lock().unwrap().insert(client_id, session);
+        let span = tracing::info_span!("connection", client_id, peer = ?addr);
+        let _entered = span.enter();
+
+        info!("Client connected: {:?}", addr);
+        // Returned to the pool when the client disconnects
+        let mut buffer = mem_pool.lease();
+        if let Some(faults) = &config.inject_faults {
+            let sequence = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+            connection = connection.with_faults(faults.injector(sequence));
+        }
+
+        // Handle messages; one read ahead when batching data frames
+        let mut queued = None;
+        loop {
+            match queued.take().unwrap_or_else(|| connection.read()) {
+                Ok(msg) => {
+                    WebSocketMessageHandler::dump_frame(
+                        clients,
+                        client_id,
+                        FrameDirection::Inbound,
+                        &msg,
+                    );
+                    match msg {
+                        Message::Text(text) => {
+                            WebSocketMessageHandler::handle_text_message(
+                                &mut connection,
+                                clients,
+                                stream_mgr,
+                                &mut buffer,
+                                client_id,
+                                &text,
+                            );
+                        }
+                        Message::Binary(data) => {
+                            // Take the frames that arrived meanwhile along
+                            let mut frames = vec![data];
+                            while frames.len() < MAX_BATCH_FRAMES {
+                                match connection.read_queued() {
+                                    Some(Ok(Message::Binary(data))) => {
+                                        WebSocketMessageHandler::dump_frame(
+                                            clients,
                                             client_id,
-                                            &data,
+                                            FrameDirection::Inbound,
+                                            &Message::Binary(data.clone()),
                                         );
+                                        frames.push(data);
                                     }
-                                    Message::Close(_) => {
-                                        println!("Client disconnected: {:?}", addr);
-                                        clients.lock().unwrap().remove(&client_id);
+                                    Some(read) => {
+                                        queued = Some(read);
                                         break;
                                     }
-                                    _ => {}
-                                },
-                                Err(e) => {
-                                    println!("Error reading message: {:?}", e);
-                                    clients.lock().unwrap().remove(&client_id);
-                                    break;
+                                    None => break,
                                 }
                             }
+                            let accepted = WebSocketMessageHandler::handle_binary_messages(
+                                &mut connection,
+                                clients,
+                                stream_mgr,
+                                &mut buffer,
+                                client_id,
+                                &frames,
+                            );
+
+                            if !accepted && config.close_on_protocol_error {
+                                info!("Closing connection after protocol error: {:?}", addr);
+                                let _ = connection.close(Some(CloseFrame {
+                                    code: CloseCode::Policy,
+                                    reason: "Protocol violation".into(),
+                                }));
+                                let _ = connection.flush();
+                                clients.lock().unwrap().remove(&client_id);
+                                break;
+                            }
                         }
-                    });
+                        Message::Ping(_) => {
+                            WebSocketMessageHandler::handle_ping(clients, stream_mgr, client_id)
+                        }
+                        Message::Close(_) => {
+                            info!("Client disconnected: {:?}", addr);
+                            clients.lock().unwrap().remove(&client_id);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    warn!(
+                        "Closing connection idle for {}s: {:?}",
+                        config.idle_timeout, addr
+                    );
+                    clients.lock().unwrap().remove(&client_id);
+                    break;
                 }
                 Err(e) => {
-                    eprintln!("Error accepting connection: {:?}", e);
+                    info!("Error reading message: {:?}", e);
+                    clients.lock().unwrap().remove(&client_id);
+                    break;
                 }
             }
         }
     }
+
+    /// Refuse upgrades whose `Origin` header is not one of `allowed_origins`.
+    /// Only browsers send the header, so other clients pass, as does every
+    /// upgrade when no origins are configured.
+    // The error type is fixed by tungstenite's handshake callback
+    #[allow(clippy::result_large_err)]
+    fn check_origin(allowed_origins: &[String], request: &Request) -> Result<(), ErrorResponse> {
+        let Some(origin) = request.headers().get(ORIGIN) else {
+            return Ok(());
+        };
+        let origin = origin.to_str().unwrap_or_default().trim_end_matches('/');
+        let allowed = allowed_origins.is_empty()
+            || allowed_origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin));
+        if allowed {
+            return Ok(());
+        }
+
+        warn!("Refusing WebSocket upgrade from origin {}", origin);
+        let mut error = ErrorResponse::new(Some(format!("Origin {} is not allowed", origin)));
+        *error.status_mut() = StatusCode::FORBIDDEN;
+        Err(error)
+    }
+
+    /// Select the `audio-stream.v1` subprotocol when the client offers it,
+    /// and refuse handshakes offering only subprotocols this server does not
+    /// speak; handshakes without an offer are accepted as before.
+    // The signature is fixed by tungstenite's handshake callback
+    #[allow(clippy::result_large_err)]
+    fn negotiate_subprotocol(
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        let offers = request
+            .headers()
+            .get_all(SUBPROTOCOL_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        match SubprotocolOffer::parse(offers) {
+            SubprotocolOffer::None => Ok(response),
+            SubprotocolOffer::Supported => {
+                response
+                    .headers_mut()
+                    .insert(SUBPROTOCOL_HEADER, HeaderValue::from_static(SUBPROTOCOL));
+                Ok(response)
+            }
+            SubprotocolOffer::Unsupported(offers) => {
+                let mut error = ErrorResponse::new(Some(format!(
+                    "Unsupported subprotocol {}; this server speaks {}",
+                    offers.join(", "),
+                    SUBPROTOCOL
+                )));
+                *error.status_mut() = StatusCode::BAD_REQUEST;
+                Err(error)
+            }
+        }
+    }
 }
@@ -0,0 +1,170 @@
+// Client connection of the server: a WebSocket, or a raw TCP stream carrying
+// length-prefixed frames (--tcp-port). Both deliver the same text, binary, and
+// close messages, so the message handlers serve either transport. With
+// --inject-faults, either one is wrapped to fault the messages sent. Raw TCP
+// frames longer than --max-buffered-bytes are refused before their payload is
+// buffered, as tungstenite does for WebSocket messages.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::protocol::{
+    decode_tcp_header, encode_tcp_frame, truncate_text, Fault, FaultInjector, TcpFrameKind,
+    TCP_HEADER_LEN,
+};
+use tracing::debug;
+use tungstenite::protocol::{CloseFrame, Message};
+use tungstenite::{Bytes, Utf8Bytes, WebSocket};
+
+/// Transport of a client connection.
+pub enum Connection {
+    WebSocket(Box<WebSocket<TcpStream>>),
+    Tcp {
+        stream: TcpStream,
+        max_frame_len: usize,
+    },
+    Faulty(Box<FaultyConnection>),
+}
+
+impl Connection {
+    /// Wrap the connection to inject the faults of `injector` into the text
+    /// and binary messages sent.
+    pub fn with_faults(self, injector: FaultInjector) -> Self {
+        Connection::Faulty(Box::new(FaultyConnection {
+            inner: self,
+            injector,
+        }))
+    }
+
+    /// Read the next message; a raw TCP peer closing the stream between
+    /// frames reads as a close.
+    pub fn read(&mut self) -> tungstenite::Result<Message> {
+        let (stream, max_frame_len) = match self {
+            Connection::WebSocket(websocket) => return websocket.read(),
+            Connection::Tcp {
+                stream,
+                max_frame_len,
+            } => (stream, *max_frame_len),
+            Connection::Faulty(faulty) => return faulty.inner.read(),
+        };
+
+        let mut header = [0u8; TCP_HEADER_LEN];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Message::Close(None)),
+            Err(e) => return Err(e.into()),
+        }
+        let (kind, length) = decode_tcp_header(&header)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        if length > max_frame_len {
+            return Err(tungstenite::Error::Capacity(
+                tungstenite::error::CapacityError::MessageTooLong {
+                    size: length,
+                    max_size: max_frame_len,
+                },
+            ));
+        }
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload)?;
+        Ok(match kind {
+            TcpFrameKind::Text => Message::Text(Utf8Bytes::try_from(payload)?),
+            TcpFrameKind::Binary => Message::Binary(Bytes::from(payload)),
+            TcpFrameKind::Close => Message::Close(None),
+        })
+    }
+
+    /// Read a message that has already arrived, without waiting for one;
+    /// `None` when there is none. Raw TCP frames are only read by `read`.
+    pub fn read_queued(&mut self) -> Option<tungstenite::Result<Message>> {
+        let websocket = match self {
+            Connection::WebSocket(websocket) => websocket,
+            Connection::Tcp { .. } => return None,
+            Connection::Faulty(faulty) => return faulty.inner.read_queued(),
+        };
+        websocket.get_ref().set_nonblocking(true).ok()?;
+        let read = websocket.read();
+        let _ = websocket.get_ref().set_nonblocking(false);
+        match read {
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => None,
+            read => Some(read),
+        }
+    }
+
+    /// Send a message. Raw TCP has no ping or pong frames, so those are
+    /// dropped.
+    pub fn send(&mut self, message: Message) -> tungstenite::Result<()> {
+        let stream = match self {
+            Connection::WebSocket(websocket) => return websocket.send(message),
+            Connection::Tcp { stream, .. } => stream,
+            Connection::Faulty(faulty) => return faulty.send(message),
+        };
+
+        let frame = match &message {
+            Message::Text(text) => encode_tcp_frame(TcpFrameKind::Text, text.as_bytes()),
+            Message::Binary(data) => encode_tcp_frame(TcpFrameKind::Binary, data),
+            Message::Close(frame) => encode_tcp_frame(
+                TcpFrameKind::Close,
+                frame
+                    .as_ref()
+                    .map_or(&[][..], |frame| frame.reason.as_bytes()),
+            ),
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => return Ok(()),
+        };
+        stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Start closing the connection with an optional reason.
+    pub fn close(&mut self, frame: Option<CloseFrame>) -> tungstenite::Result<()> {
+        match self {
+            Connection::WebSocket(websocket) => websocket.close(frame),
+            Connection::Tcp { .. } => self.send(Message::Close(frame)),
+            Connection::Faulty(faulty) => faulty.inner.close(frame),
+        }
+    }
+
+    pub fn flush(&mut self) -> tungstenite::Result<()> {
+        match self {
+            Connection::WebSocket(websocket) => websocket.flush(),
+            Connection::Tcp { stream, .. } => Ok(stream.flush()?),
+            Connection::Faulty(faulty) => faulty.inner.flush(),
+        }
+    }
+}
+
+/// Connection dropping, delaying, truncating, or duplicating the text and
+/// binary messages sent, as chosen by its injector.
+pub struct FaultyConnection {
+    inner: Connection,
+    injector: FaultInjector,
+}
+
+impl FaultyConnection {
+    fn send(&mut self, message: Message) -> tungstenite::Result<()> {
+        let (kind, len) = match &message {
+            Message::Text(text) => ("text", text.len()),
+            Message::Binary(data) => ("binary", data.len()),
+            _ => return self.inner.send(message),
+        };
+        let Some(fault) = self.injector.next_fault(len) else {
+            return self.inner.send(message);
+        };
+        debug!("[fault] {:?} on {} frame of {} bytes", fault, kind, len);
+        match fault {
+            Fault::Drop => Ok(()),
+            Fault::Delay(delay) => {
+                std::thread::sleep(delay);
+                self.inner.send(message)
+            }
+            Fault::Truncate(len) => self.inner.send(match message {
+                Message::Text(text) => Message::Text(truncate_text(&text, len).into()),
+                Message::Binary(data) => Message::Binary(data.slice(..len)),
+                other => other,
+            }),
+            Fault::Duplicate => {
+                self.inner.send(message.clone())?;
+                self.inner.send(message)
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Connection admission for the blocking accept loops.
+// Every connection is served on its own thread, so the number of threads is
+// bounded by admitting at most `max_active` connections at a time. Excess
+// connections wait in a bounded queue for a free slot; once the queue is full
+// too, new connections are rejected right away.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Limits the connections served at the same time.
+pub struct ConnectionLimiter {
+    max_active: usize,
+    max_queued: usize,
+    state: Mutex<LimiterState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    active: usize,
+    queued: usize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_active: usize, max_queued: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_active: max_active.max(1),
+            max_queued,
+            state: Mutex::new(LimiterState::default()),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Admit a new connection, either to a free slot or to the queue.
+    /// Returns `None` when both are full and the connection must be rejected.
+    pub fn admit(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let mut state = self.state.lock().unwrap();
+        let queued = if state.active < self.max_active && state.queued == 0 {
+            state.active += 1;
+            false
+        } else if state.queued < self.max_queued {
+            state.queued += 1;
+            true
+        } else {
+            return None;
+        };
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            queued,
+        })
+    }
+
+    /// Get the number of connections served and waiting.
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.active, state.queued)
+    }
+}
+
+/// Admission of one connection; frees its slot or queue place when dropped.
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    queued: bool,
+}
+
+impl ConnectionSlot {
+    /// Whether the connection waits for a free slot.
+    pub fn is_queued(&self) -> bool {
+        self.queued
+    }
+
+    /// Block until the connection holds a slot.
+    pub fn wait(&mut self) {
+        if !self.queued {
+            return;
+        }
+        let limiter = &self.limiter;
+        let mut state = limiter
+            .released
+            .wait_while(limiter.state.lock().unwrap(), |state| {
+                state.active >= limiter.max_active
+            })
+            .unwrap();
+        state.queued -= 1;
+        state.active += 1;
+        self.queued = false;
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if self.queued {
+            state.queued -= 1;
+        } else {
+            state.active -= 1;
+            self.limiter.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_to_slots_then_queue_then_rejects() {
+        let limiter = ConnectionLimiter::new(2, 1);
+        let first = limiter.admit().unwrap();
+        let second = limiter.admit().unwrap();
+        assert!(!first.is_queued() && !second.is_queued());
+        let third = limiter.admit().unwrap();
+        assert!(third.is_queued());
+        assert!(limiter.admit().is_none());
+        assert_eq!(limiter.load(), (2, 1));
+    }
+
+    #[test]
+    fn queued_connection_gets_the_slot_dropped() {
+        let limiter = ConnectionLimiter::new(1, 1);
+        let active = limiter.admit().unwrap();
+        let mut queued = limiter.admit().unwrap();
+        let waiter = std::thread::spawn(move || {
+            queued.wait();
+            queued
+        });
+
+        drop(active);
+        let promoted = waiter.join().unwrap();
+        assert!(!promoted.is_queued());
+        assert_eq!(limiter.load(), (1, 0));
+        drop(promoted);
+        assert_eq!(limiter.load(), (0, 0));
+    }
+
+    #[test]
+    fn dropped_queued_connection_frees_its_place() {
+        let limiter = ConnectionLimiter::new(1, 1);
+        let _active = limiter.admit().unwrap();
+        let queued = limiter.admit().unwrap();
+        assert!(limiter.admit().is_none());
+
+        drop(queued);
+        assert_eq!(limiter.load(), (1, 0));
+        assert!(limiter.admit().unwrap().is_queued());
+    }
+}
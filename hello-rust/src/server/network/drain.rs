@@ -0,0 +1,98 @@
+// Drain mode for zero-downtime restarts.
+// An admin sends DRAIN with the server's --admin-token. From then on START is
+// refused and new connections are turned away with 503, while open connections
+// are still served, so in-flight uploads can finalize and downloads complete.
+// Once the last connection closes, or --drain-timeout passes, the accept loop
+// stops and the server exits.
+
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::server::error::ServerError;
+use crate::server::network::ConnectionLimiter;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+static ADMIN_TOKEN: OnceLock<String> = OnceLock::new();
+static DRAIN_STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// How often the drain watcher checks for open connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Install the token authorizing admin commands. Only the first call has an
+/// effect; without a token, admin commands are refused.
+pub fn set_admin_token(token: String) {
+    let _ = ADMIN_TOKEN.set(token);
+}
+
+/// Check the token sent with an admin command. The comparison takes the
+/// same time wherever the token differs, so it cannot be guessed byte by
+/// byte from response times.
+pub fn authorize(token: &str) -> Result<(), ServerError> {
+    match ADMIN_TOKEN.get() {
+        None => Err(ServerError::Forbidden(
+            "admin commands are disabled; start the server with --admin-token".to_string(),
+        )),
+        Some(expected) if !bool::from(expected.as_bytes().ct_eq(token.as_bytes())) => {
+            Err(ServerError::Forbidden("invalid admin token".to_string()))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Enter drain mode. Returns `false` when the server was already draining.
+pub fn begin() -> bool {
+    let mut started = false;
+    DRAIN_STARTED.get_or_init(|| {
+        started = true;
+        Instant::now()
+    });
+    started
+}
+
+/// Check whether the server is in drain mode.
+pub fn is_draining() -> bool {
+    DRAIN_STARTED.get().is_some()
+}
+
+/// Watch for the end of a drain on a background thread: once no connection
+/// holds or waits for a slot of `limiter`, or `timeout` has passed since the
+/// drain began, set `shutdown` and wake the accept loop listening on
+/// `listen_addr`. The thread ends when `shutdown` is set.
+pub fn spawn_watcher(
+    limiter: Arc<ConnectionLimiter>,
+    shutdown: Arc<AtomicBool>,
+    listen_addr: SocketAddr,
+    timeout: Duration,
+) {
+    let mut wake_addr = listen_addr;
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+
+    std::thread::spawn(move || {
+        while !shutdown.load(Ordering::SeqCst) {
+            std::thread::sleep(POLL_INTERVAL);
+            let Some(started) = DRAIN_STARTED.get() else {
+                continue;
+            };
+            let (active, queued) = limiter.load();
+            if active + queued == 0 {
+                info!("Drain complete: all connections closed");
+            } else if started.elapsed() >= timeout {
+                warn!(
+                    "Drain timed out after {}s with {} connections open",
+                    timeout.as_secs(),
+                    active + queued
+                );
+            } else {
+                continue;
+            }
+
+            shutdown.store(true, Ordering::SeqCst);
+            let _ = TcpStream::connect(wake_addr);
+        }
+    });
+}
@@ -0,0 +1,395 @@
+// Plain HTTP download path for finalized streams.
+// Serves `GET /streams/<streamId>` on the WebSocket port with Content-Type and
+// Content-Disposition taken from the stream metadata, so browsers and tools
+// like curl keep sensible file names and types. With --synthesize-wav, raw PCM
+// streams are served as WAV by prepending a generated RIFF header. With access
+// tokens configured, downloads need an `Authorization: Bearer <secret>` header
+// and count against the token's transfer quota; tokens confined to a
+// namespace only get its streams, at `/streams/<namespace>/<streamId>`. In
+// cluster mode, downloads of streams owned by another node are redirected to
+// its HTTP address.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::cli::ServerConfig;
+use crate::protocol::pcm_format::{PcmFormat, WAV_HEADER_LEN};
+use crate::protocol::split_namespace;
+use crate::server::cluster::ClusterRouter;
+use crate::server::events::event_feed;
+use crate::server::memory::{StreamManager, StreamStatus};
+use crate::server::network::TokenQuotas;
+
+/// Content type served when a stream declares none, or none that is safe to
+/// put in a header.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Longest content type accepted in START.
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+
+/// URL prefix of the download route.
+pub const DOWNLOAD_PREFIX: &str = "/streams/";
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Parsed HTTP request line and headers.
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Get a header value (case-insensitive name).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Check whether this request asks for a WebSocket upgrade.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("Upgrade")
+            .map(|value| value.eq_ignore_ascii_case("websocket"))
+            .unwrap_or(false)
+    }
+}
+
+/// Peek at the request head without consuming it, so the stream can still be
+/// handed to the WebSocket handshake afterwards. Gives up once the head is not
+/// complete within `timeout`, however slowly its bytes trickle in.
+pub fn peek_request_head(stream: &TcpStream, timeout: Duration) -> Option<RequestHead> {
+    let previous_timeout = stream.read_timeout().ok().flatten();
+    let _ = stream.set_read_timeout(Some(timeout));
+    let deadline = Instant::now() + timeout;
+
+    let mut buffer = vec![0u8; MAX_REQUEST_HEAD];
+    let mut head = None;
+    let mut last_len = 0;
+    loop {
+        let n = match stream.peek(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if let Some(end) = find_head_end(&buffer[..n]) {
+            head = parse_request_head(&buffer[..end]);
+            break;
+        }
+        if n == buffer.len() || Instant::now() >= deadline {
+            break;
+        }
+        if n == last_len {
+            // Wait for more of the head to arrive
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        last_len = n;
+    }
+
+    let _ = stream.set_read_timeout(previous_timeout);
+    head
+}
+
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|p| p + 4)
+}
+
+fn parse_request_head(data: &[u8]) -> Option<RequestHead> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Some(RequestHead {
+        method,
+        path,
+        headers,
+    })
+}
+
+/// Serve a plain HTTP request, consuming the request head from the stream.
+pub fn serve(
+    mut stream: TcpStream,
+    head: &RequestHead,
+    stream_mgr: &StreamManager,
+    config: &ServerConfig,
+) {
+    // Consume the head we only peeked at so far
+    let mut discard = vec![0u8; head_length(&stream)];
+    let _ = stream.read_exact(&mut discard);
+
+    let is_head = head.method == "HEAD";
+    if head.method != "GET" && !is_head {
+        write_status(&mut stream, 405, "Method Not Allowed");
+        return;
+    }
+
+    #[cfg(feature = "metrics")]
+    if head.path == crate::server::events::metrics_collector::METRICS_PATH {
+        serve_metrics(&mut stream, is_head);
+        return;
+    }
+
+    if event_feed::is_events_path(&head.path) {
+        event_feed::serve(&mut stream, head, is_head);
+        return;
+    }
+
+    let stream_id = match head.path.strip_prefix(DOWNLOAD_PREFIX) {
+        Some(id) if !id.is_empty() && !split_namespace(id).1.contains('/') => {
+            id.split('?').next().unwrap_or(id)
+        }
+        _ => {
+            write_status(&mut stream, 404, "Not Found");
+            return;
+        }
+    };
+
+    let token = match TokenQuotas::current() {
+        Some(quotas) => {
+            let secret = head
+                .header("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            let Ok(token) = quotas.authenticate(secret.trim()) else {
+                write_status(&mut stream, 401, "Unauthorized");
+                return;
+            };
+            if quotas.check_transfer(token).is_err() {
+                write_status(&mut stream, 429, "Too Many Requests");
+                return;
+            }
+            if let Some(namespace) = quotas.namespace(token) {
+                if split_namespace(stream_id).0 != Some(namespace) {
+                    write_status(&mut stream, 403, "Forbidden");
+                    return;
+                }
+            }
+            Some((quotas, token))
+        }
+        None => None,
+    };
+
+    if let Some(owner) = ClusterRouter::current().and_then(|router| router.redirect_for(stream_id))
+    {
+        match download_location(&owner, &head.path) {
+            Some(location) => write_redirect(&mut stream, &location),
+            None => write_status(&mut stream, 404, "Not Found"),
+        }
+        return;
+    }
+
+    let (cache_path, size, content_type, file_name) = match stream_mgr.get_stream(stream_id) {
+        Some(ctx) => {
+            let ctx = ctx.lock().unwrap();
+            if ctx.get_status() != StreamStatus::Ready {
+                drop(ctx);
+                write_status(&mut stream, 409, "Conflict");
+                return;
+            }
+            let metadata = ctx.get_metadata();
+            (
+                ctx.get_cache_path().to_string(),
+                ctx.get_total_size(),
+                metadata
+                    .get("contentType")
+                    .cloned()
+                    .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string()),
+                metadata
+                    .get("fileName")
+                    .cloned()
+                    .unwrap_or_else(|| split_namespace(stream_id).1.to_string()),
+            )
+        }
+        None => {
+            write_status(&mut stream, 404, "Not Found");
+            return;
+        }
+    };
+
+    // Raw PCM gets a RIFF header so the download plays as-is
+    let wav_header = PcmFormat::from_content_type(&content_type)
+        .filter(|_| config.synthesize_wav)
+        .map(|format| format.wav_header(size));
+    let (content_type, file_name, length) = match wav_header {
+        Some(_) => (
+            "audio/wav".to_string(),
+            Path::new(&file_name)
+                .with_extension("wav")
+                .to_string_lossy()
+                .into_owned(),
+            size + WAV_HEADER_LEN as u64,
+        ),
+        None => (content_type, file_name, size),
+    };
+
+    let header = response_header(&content_type, length, &file_name);
+    if stream.write_all(header.as_bytes()).is_err() || is_head {
+        return;
+    }
+    if let Some(wav_header) = wav_header {
+        if stream.write_all(&wav_header).is_err() {
+            return;
+        }
+    }
+
+    match std::fs::File::open(&cache_path) {
+        Ok(file) => {
+            let copied = std::io::copy(&mut file.take(size), &mut stream);
+            if let Some((quotas, token)) = token {
+                quotas.charge_download(token, copied.as_ref().map_or(0, |&n| n));
+            }
+            if let Err(e) = copied {
+                warn!("HTTP download of {} aborted: {:?}", stream_id, e);
+            } else {
+                info!("HTTP download of {} completed ({} bytes)", stream_id, size);
+            }
+        }
+        Err(e) => error!("Failed to open cache file {}: {:?}", cache_path, e),
+    }
+}
+
+/// Build the head of a download response. Metadata that cannot go into a
+/// header safely is replaced: a content type that is not a valid MIME type by
+/// the default one, and quotes and line breaks in the file name by `_`.
+fn response_header(content_type: &str, length: u64, file_name: &str) -> String {
+    let content_type = match is_valid_content_type(content_type) {
+        true => content_type,
+        false => DEFAULT_CONTENT_TYPE,
+    };
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\nConnection: close\r\n\r\n",
+        content_type,
+        length,
+        file_name.replace(['"', '\\', '\r', '\n'], "_")
+    )
+}
+
+/// Check whether `content_type` is a MIME type that can go into a header
+/// as-is, e.g. `audio/pcm; rate=48000; channels=2`: a `type/subtype` of token
+/// characters, optionally followed by parameters, without control characters.
+pub fn is_valid_content_type(content_type: &str) -> bool {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    let (essence, parameters) = content_type.split_once(';').unwrap_or((content_type, ""));
+    let Some((kind, subtype)) = essence.trim_end().split_once('/') else {
+        return false;
+    };
+    content_type.len() <= MAX_CONTENT_TYPE_LEN
+        && !kind.is_empty()
+        && !subtype.is_empty()
+        && kind.chars().all(is_token)
+        && subtype.chars().all(is_token)
+        && parameters
+            .chars()
+            .all(|c| is_token(c) || matches!(c, ';' | '=' | ' '))
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics(stream: &mut TcpStream, is_head: bool) {
+    let body = crate::server::events::metrics_collector::StreamMetrics::instance().render();
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if stream.write_all(header.as_bytes()).is_err() || is_head {
+        return;
+    }
+    let _ = stream.write_all(body.as_bytes());
+}
+
+fn head_length(stream: &TcpStream) -> usize {
+    let mut buffer = vec![0u8; MAX_REQUEST_HEAD];
+    match stream.peek(&mut buffer) {
+        Ok(n) => find_head_end(&buffer[..n]).unwrap_or(n),
+        Err(_) => 0,
+    }
+}
+
+/// Get the URL of `path` on the HTTP port of the node at WebSocket URI
+/// `owner`.
+fn download_location(owner: &str, path: &str) -> Option<String> {
+    let mut url = url::Url::parse(owner).ok()?;
+    let scheme = match url.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        _ => return None,
+    };
+    url.set_scheme(scheme).ok()?;
+    Some(format!("{}{}", url.origin().ascii_serialization(), path))
+}
+
+fn write_redirect(stream: &mut TcpStream, location: &str) {
+    let response = format!(
+        "HTTP/1.1 307 Temporary Redirect\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+pub(crate) fn write_status(stream: &mut TcpStream, code: u16, reason: &str) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        code, reason
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_types_are_mime_types() {
+        assert!(is_valid_content_type("audio/mpeg"));
+        assert!(is_valid_content_type("audio/pcm; rate=48000; channels=2"));
+        assert!(is_valid_content_type("application/vnd.ms-excel"));
+        assert!(!is_valid_content_type("audio"));
+        assert!(!is_valid_content_type("/mpeg"));
+        assert!(!is_valid_content_type("audio/mpeg\r\nSet-Cookie: a=b"));
+        assert!(!is_valid_content_type("audio/mpeg; a=\"b\""));
+        assert!(!is_valid_content_type(&format!(
+            "audio/{}",
+            "x".repeat(300)
+        )));
+    }
+
+    #[test]
+    fn header_injection_is_not_served() {
+        let header = response_header(
+            "audio/mpeg\r\nSet-Cookie: session=stolen",
+            100,
+            "a\r\nb\".mp3",
+        );
+        assert!(header.starts_with(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 100\r\n"
+        ));
+        assert!(!header.contains("Set-Cookie"));
+        assert!(header.contains("filename=\"a__b_.mp3\""));
+        assert_eq!(header.matches("\r\n").count(), 6);
+    }
+
+    #[test]
+    fn redirects_point_at_the_http_port_of_the_owner() {
+        assert_eq!(
+            download_location("ws://node-b:8081/audio", "/streams/a.mp3?x=1").as_deref(),
+            Some("http://node-b:8081/streams/a.mp3?x=1")
+        );
+        assert_eq!(
+            download_location("wss://node-b/audio", "/streams/a").as_deref(),
+            Some("https://node-b/streams/a")
+        );
+        assert_eq!(download_location("tcp://node-b:9000", "/streams/a"), None);
+    }
+}
@@ -0,0 +1,159 @@
+// Address filtering of accepted connections (--allow, --deny).
+// Rules are CIDR blocks checked when a connection is accepted, before it takes
+// a connection slot: a connection from a denied block is closed, and once any
+// block is allowed, connections from outside the allowed blocks are closed too.
+// IPv4 clients reaching a dual-stack listener match IPv4 blocks.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use thiserror::Error;
+
+/// Invalid CIDR block.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CidrError {
+    #[error("invalid address in '{0}'")]
+    Address(String),
+    #[error("invalid prefix length in '{0}'")]
+    PrefixLength(String),
+}
+
+/// Block of addresses such as `10.0.0.0/8` or `fd00::/8`; a bare address is
+/// a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidrBlock {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl CidrBlock {
+    pub fn parse(spec: &str) -> Result<Self, CidrError> {
+        let spec = spec.trim();
+        let (address, prefix_len) = match spec.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (spec, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| CidrError::Address(spec.to_string()))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| CidrError::PrefixLength(spec.to_string()))?,
+            None => max_len,
+        };
+
+        // Clear host bits so `10.1.2.3/8` means 10.0.0.0/8
+        let network = match network {
+            IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & Self::mask_v4(prefix_len)).into()),
+            IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & Self::mask_v6(prefix_len)).into()),
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Check whether `addr` lies within the block.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                u32::from(addr) & Self::mask_v4(self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                u128::from(addr) & Self::mask_v6(self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+
+    fn mask_v4(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+    }
+
+    fn mask_v6(prefix_len: u8) -> u128 {
+        u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for CidrBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Allow and deny rules of the listener.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<CidrBlock>,
+    deny: Vec<CidrBlock>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<CidrBlock>, deny: Vec<CidrBlock>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Check whether there are no rules, so every address is admitted.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check whether a connection from `addr` may be served. Deny rules take
+    /// precedence over allow rules.
+    pub fn admits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|block| block.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|block| block.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let block = CidrBlock::parse("10.1.2.3/8").unwrap();
+        assert_eq!(block.to_string(), "10.0.0.0/8");
+        assert!(block.contains(ip("10.255.0.1")));
+        assert!(!block.contains(ip("11.0.0.1")));
+        assert!(block.contains(ip("::ffff:10.0.0.1")));
+
+        assert_eq!(CidrBlock::parse("::1").unwrap().to_string(), "::1/128");
+        assert!(CidrBlock::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        assert!(CidrBlock::parse("fd00::/8")
+            .unwrap()
+            .contains(ip("fd12::1")));
+        assert!(matches!(
+            CidrBlock::parse("10.0.0.0/33"),
+            Err(CidrError::PrefixLength(_))
+        ));
+        assert!(matches!(
+            CidrBlock::parse("example.com/8"),
+            Err(CidrError::Address(_))
+        ));
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let filter = IpFilter::new(
+            vec![CidrBlock::parse("192.168.0.0/16").unwrap()],
+            vec![CidrBlock::parse("192.168.1.0/24").unwrap()],
+        );
+        assert!(filter.admits(ip("192.168.2.1")));
+        assert!(!filter.admits(ip("192.168.1.1")));
+        assert!(!filter.admits(ip("10.0.0.1")));
+        assert!(IpFilter::default().admits(ip("10.0.0.1")));
+    }
+}
@@ -1,4 +1,14 @@
 // Server network module - WebSocket communication
 pub mod audio_websocket_server;
+pub mod connection;
+pub mod connection_limiter;
+pub mod drain;
+pub mod http_download;
+pub mod ip_filter;
+pub mod token_quota;
 
-pub use audio_websocket_server::AudioWebSocketServer;
+pub use audio_websocket_server::{AudioWebSocketServer, ShutdownHandle};
+pub use connection::{Connection, FaultyConnection};
+pub use connection_limiter::{ConnectionLimiter, ConnectionSlot};
+pub use ip_filter::{CidrBlock, CidrError, IpFilter};
+pub use token_quota::TokenQuotas;
@@ -0,0 +1,415 @@
+// Access tokens and per-token usage accounting for multi-team deployments.
+// Installed at startup when --token NAME=SECRET is given. Connections then
+// send AUTH with a secret before any stream operation, and the bytes they
+// upload and download are attributed to the token's name. Bytes uploaded to a
+// stream count as stored until the stream is deleted or expires, or until the
+// generation they were uploaded to is dropped beyond --keep-versions, and bytes
+// written again after a SEEK are not stored twice. A stream is changed only
+// through the token that created it. Uploads past
+// a token's --storage-quota, and uploads and GETs once it has used up its
+// --transfer-quota, are refused with QUOTA_EXCEEDED. A token given a
+// --namespace only reaches the streams whose IDs start with `namespace/`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::protocol::TokenUsage;
+use crate::server::error::ServerError;
+
+static TOKEN_QUOTAS: OnceLock<TokenQuotas> = OnceLock::new();
+
+// Bytes stored in one stream, by generation and token
+type StreamCharges = HashMap<(u64, String), u64>;
+
+/// Usage counters and quotas of one token.
+#[derive(Default)]
+struct Account {
+    stored: AtomicU64,
+    transferred: AtomicU64,
+    storage_quota: Option<u64>,
+    transfer_quota: Option<u64>,
+    namespace: Option<String>,
+}
+
+/// Configured access tokens and their usage.
+pub struct TokenQuotas {
+    /// Token name by secret.
+    names: HashMap<String, String>,
+    /// Accounts by token name.
+    accounts: BTreeMap<String, Account>,
+    /// Bytes stored by each token, by stream and generation.
+    streams: Mutex<HashMap<String, StreamCharges>>,
+}
+
+impl TokenQuotas {
+    /// Create the accounts of `tokens` (name, secret) with their quotas
+    /// (name, bytes) and namespaces (name, namespace).
+    pub fn new(
+        tokens: &[(String, String)],
+        storage_quotas: &[(String, u64)],
+        transfer_quotas: &[(String, u64)],
+        namespaces: &[(String, String)],
+    ) -> Result<Self, ServerError> {
+        let mut names = HashMap::new();
+        let mut accounts = BTreeMap::new();
+        for (name, secret) in tokens {
+            if accounts.insert(name.clone(), Account::default()).is_some() {
+                return Err(ServerError::Config(format!(
+                    "Duplicate token name: {}",
+                    name
+                )));
+            }
+            if names.insert(secret.clone(), name.clone()).is_some() {
+                return Err(ServerError::Config(format!(
+                    "Token {} reuses the secret of another token",
+                    name
+                )));
+            }
+        }
+
+        let unknown =
+            |name: &str| ServerError::Config(format!("Quota for unknown token: {}", name));
+        for (name, quota) in storage_quotas {
+            let account = accounts.get_mut(name).ok_or_else(|| unknown(name))?;
+            account.storage_quota = Some(*quota);
+        }
+        for (name, quota) in transfer_quotas {
+            let account = accounts.get_mut(name).ok_or_else(|| unknown(name))?;
+            account.transfer_quota = Some(*quota);
+        }
+        for (name, namespace) in namespaces {
+            let account = accounts.get_mut(name).ok_or_else(|| {
+                ServerError::Config(format!("Namespace for unknown token: {}", name))
+            })?;
+            account.namespace = Some(namespace.clone());
+        }
+
+        Ok(Self {
+            names,
+            accounts,
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Install the process-wide tokens. Only the first call has an effect.
+    pub fn install(quotas: TokenQuotas) -> &'static Self {
+        TOKEN_QUOTAS.get_or_init(|| quotas)
+    }
+
+    /// Get the installed tokens, if clients must authenticate.
+    pub fn current() -> Option<&'static Self> {
+        TOKEN_QUOTAS.get()
+    }
+
+    /// Get the name of the token with `secret`.
+    pub fn authenticate(&self, secret: &str) -> Result<&str, ServerError> {
+        self.names
+            .get(secret)
+            .map(String::as_str)
+            .ok_or_else(|| ServerError::Unauthorized("invalid access token".to_string()))
+    }
+
+    /// Get the usage of the token named `token`.
+    pub fn usage(&self, token: &str) -> Option<TokenUsage> {
+        self.accounts.get(token).map(|account| TokenUsage {
+            token: token.to_string(),
+            stored_bytes: account.stored.load(Ordering::Relaxed),
+            transferred_bytes: account.transferred.load(Ordering::Relaxed),
+            storage_quota: account.storage_quota,
+            transfer_quota: account.transfer_quota,
+        })
+    }
+
+    /// Get the namespace the streams of `token` are confined to, if any.
+    pub fn namespace(&self, token: &str) -> Option<&str> {
+        self.accounts.get(token)?.namespace.as_deref()
+    }
+
+    /// Get the usage of every token, in name order.
+    pub fn usages(&self) -> Vec<TokenUsage> {
+        self.accounts
+            .keys()
+            .filter_map(|token| self.usage(token))
+            .collect()
+    }
+
+    /// Check that `token` can store `bytes` more, e.g. the declared size of
+    /// an upload before it starts.
+    pub fn check_storage(&self, token: &str, bytes: u64) -> Result<(), ServerError> {
+        let account = self.account(token)?;
+        let stored = account.stored.load(Ordering::Relaxed);
+        match account.storage_quota {
+            Some(quota) if stored.saturating_add(bytes) > quota => {
+                Err(Self::storage_exceeded(token, quota))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Charge `bytes` uploaded to generation `generation` of `stream_id` to
+    /// `token` as transferred, of which the `stored` bytes the stream did not
+    /// have yet count as stored. Nothing is charged when either quota would
+    /// be exceeded.
+    pub fn charge_upload(
+        &self,
+        token: &str,
+        stream_id: &str,
+        generation: u64,
+        bytes: u64,
+        stored: u64,
+    ) -> Result<(), ServerError> {
+        let account = self.account(token)?;
+        self.check_transfer(token)?;
+        let new_bytes = stored;
+        account
+            .stored
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stored| {
+                let stored = stored.saturating_add(new_bytes);
+                account
+                    .storage_quota
+                    .is_none_or(|quota| stored <= quota)
+                    .then_some(stored)
+            })
+            .map_err(|_| Self::storage_exceeded(token, account.storage_quota.unwrap_or(0)))?;
+        account.transferred.fetch_add(bytes, Ordering::Relaxed);
+
+        *self
+            .streams
+            .lock()
+            .unwrap()
+            .entry(stream_id.to_string())
+            .or_default()
+            .entry((generation, token.to_string()))
+            .or_default() += stored;
+        Ok(())
+    }
+
+    /// Give back `bytes` and `stored` bytes charged by
+    /// [`charge_upload`](Self::charge_upload) for data that was not written
+    /// after all.
+    pub fn refund_upload(
+        &self,
+        token: &str,
+        stream_id: &str,
+        generation: u64,
+        bytes: u64,
+        stored: u64,
+    ) {
+        let Some(account) = self.accounts.get(token) else {
+            return;
+        };
+        for (counter, refund) in [(&account.stored, stored), (&account.transferred, bytes)] {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                Some(value.saturating_sub(refund))
+            });
+        }
+        if let Some(charged) = self
+            .streams
+            .lock()
+            .unwrap()
+            .get_mut(stream_id)
+            .and_then(|owners| owners.get_mut(&(generation, token.to_string())))
+        {
+            *charged = charged.saturating_sub(stored);
+        }
+    }
+
+    /// Check that `token` has not used up its transfer quota.
+    pub fn check_transfer(&self, token: &str) -> Result<(), ServerError> {
+        let account = self.account(token)?;
+        match account.transfer_quota {
+            Some(quota) if account.transferred.load(Ordering::Relaxed) >= quota => {
+                Err(ServerError::QuotaExceeded(format!(
+                    "token {} used up its transfer quota of {} bytes",
+                    token, quota
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Charge `bytes` downloaded to `token`.
+    pub fn charge_download(&self, token: &str, bytes: u64) {
+        if let Some(account) = self.accounts.get(token) {
+            account.transferred.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Stop counting the bytes of a deleted stream as stored, in every
+    /// generation.
+    pub fn release(&self, stream_id: &str) {
+        let owners = self.streams.lock().unwrap().remove(stream_id);
+        self.release_charges(owners.into_iter().flatten());
+    }
+
+    /// Stop counting the bytes of a deleted generation of a stream as stored.
+    pub fn release_version(&self, stream_id: &str, generation: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(owners) = streams.get_mut(stream_id) else {
+            return;
+        };
+        let released: Vec<_> = owners
+            .keys()
+            .filter(|(charged, _)| *charged == generation)
+            .cloned()
+            .collect();
+        let charges = released
+            .into_iter()
+            .filter_map(|key| owners.remove_entry(&key))
+            .collect::<Vec<_>>();
+        if owners.is_empty() {
+            streams.remove(stream_id);
+        }
+        drop(streams);
+        self.release_charges(charges);
+    }
+
+    fn release_charges(&self, charges: impl IntoIterator<Item = ((u64, String), u64)>) {
+        for ((_, token), bytes) in charges {
+            if let Some(account) = self.accounts.get(&token) {
+                let _ =
+                    account
+                        .stored
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stored| {
+                            Some(stored.saturating_sub(bytes))
+                        });
+            }
+        }
+    }
+
+    fn account(&self, token: &str) -> Result<&Account, ServerError> {
+        self.accounts
+            .get(token)
+            .ok_or_else(|| ServerError::Unauthorized(format!("unknown token {}", token)))
+    }
+
+    fn storage_exceeded(token: &str, quota: u64) -> ServerError {
+        ServerError::QuotaExceeded(format!(
+            "token {} would exceed its storage quota of {} bytes",
+            token, quota
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas() -> TokenQuotas {
+        TokenQuotas::new(
+            &[
+                ("team-a".to_string(), "secret-a".to_string()),
+                ("team-b".to_string(), "secret-b".to_string()),
+            ],
+            &[("team-a".to_string(), 100)],
+            &[("team-b".to_string(), 50)],
+            &[("team-a".to_string(), "tenant-a".to_string())],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn authenticates_by_secret() {
+        let quotas = quotas();
+        assert_eq!(quotas.authenticate("secret-b").unwrap(), "team-b");
+        assert_eq!(
+            quotas.authenticate("team-b").unwrap_err().code(),
+            "UNAUTHORIZED"
+        );
+    }
+
+    #[test]
+    fn enforces_storage_quota_until_streams_are_released() {
+        let quotas = quotas();
+        quotas.charge_upload("team-a", "s1", 1, 60, 60).unwrap();
+        assert!(quotas.check_storage("team-a", 40).is_ok());
+        assert_eq!(
+            quotas
+                .charge_upload("team-a", "s2", 1, 41, 41)
+                .unwrap_err()
+                .code(),
+            "QUOTA_EXCEEDED"
+        );
+        assert_eq!(quotas.usage("team-a").unwrap().stored_bytes, 60);
+
+        quotas.release("s1");
+        quotas.charge_upload("team-a", "s2", 1, 41, 41).unwrap();
+        let usage = quotas.usage("team-a").unwrap();
+        assert_eq!(usage.stored_bytes, 41);
+        assert_eq!(usage.transferred_bytes, 101);
+    }
+
+    #[test]
+    fn refunded_uploads_are_not_counted() {
+        let quotas = quotas();
+        quotas.charge_upload("team-a", "s1", 1, 60, 60).unwrap();
+        quotas.refund_upload("team-a", "s1", 1, 20, 20);
+        quotas.charge_upload("team-a", "s1", 1, 60, 60).unwrap();
+        let usage = quotas.usage("team-a").unwrap();
+        assert_eq!(usage.stored_bytes, 100);
+        assert_eq!(usage.transferred_bytes, 100);
+
+        quotas.release("s1");
+        assert_eq!(quotas.usage("team-a").unwrap().stored_bytes, 0);
+    }
+
+    #[test]
+    fn rewritten_bytes_are_transferred_but_not_stored_again() {
+        let quotas = quotas();
+        quotas.charge_upload("team-a", "s1", 1, 60, 60).unwrap();
+        // Rewriting the first 60 bytes after a SEEK fits the quota of 100
+        quotas.charge_upload("team-a", "s1", 1, 60, 0).unwrap();
+        quotas.charge_upload("team-a", "s1", 1, 50, 30).unwrap();
+        let usage = quotas.usage("team-a").unwrap();
+        assert_eq!(usage.stored_bytes, 90);
+        assert_eq!(usage.transferred_bytes, 170);
+
+        quotas.refund_upload("team-a", "s1", 1, 50, 30);
+        quotas.release("s1");
+        let usage = quotas.usage("team-a").unwrap();
+        assert_eq!(usage.stored_bytes, 0);
+        assert_eq!(usage.transferred_bytes, 120);
+    }
+
+    #[test]
+    fn dropped_versions_release_only_their_bytes() {
+        let quotas = quotas();
+        quotas.charge_upload("team-a", "s1", 1, 30, 30).unwrap();
+        quotas.charge_upload("team-a", "s1", 2, 40, 40).unwrap();
+        quotas.release_version("s1", 1);
+        assert_eq!(quotas.usage("team-a").unwrap().stored_bytes, 40);
+        quotas.release_version("s1", 1);
+        assert_eq!(quotas.usage("team-a").unwrap().stored_bytes, 40);
+
+        quotas.release("s1");
+        assert_eq!(quotas.usage("team-a").unwrap().stored_bytes, 0);
+    }
+
+    #[test]
+    fn enforces_transfer_quota() {
+        let quotas = quotas();
+        quotas.charge_upload("team-b", "s1", 1, 30, 30).unwrap();
+        quotas.charge_download("team-b", 30);
+        assert_eq!(
+            quotas.check_transfer("team-b").unwrap_err().code(),
+            "QUOTA_EXCEEDED"
+        );
+        assert!(quotas.check_transfer("team-a").is_ok());
+    }
+
+    #[test]
+    fn rejects_quotas_of_unknown_tokens() {
+        let tokens = [("team-a".to_string(), "secret-a".to_string())];
+        assert!(TokenQuotas::new(&tokens, &[("team-c".to_string(), 1)], &[], &[]).is_err());
+        let namespaces = [("team-c".to_string(), "tenant-c".to_string())];
+        assert!(TokenQuotas::new(&tokens, &[], &[], &namespaces).is_err());
+    }
+
+    #[test]
+    fn namespaces_are_per_token() {
+        let quotas = quotas();
+        assert_eq!(quotas.namespace("team-a"), Some("tenant-a"));
+        assert_eq!(quotas.namespace("team-b"), None);
+    }
+}
@@ -0,0 +1,256 @@
+// Audio header probing for finalized streams.
+// Parses WAV (RIFF) and MP3 (MPEG audio layer III) headers to determine
+// duration, sample rate, and channel count without decoding the audio.
+
+use std::collections::HashMap;
+
+use crate::protocol::WavHeader;
+
+/// Number of leading bytes read from a stream for probing.
+pub const PROBE_HEADER_BYTES: usize = 256 * 1024;
+
+/// Audio properties extracted from a stream header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    pub format: &'static str,
+    pub duration_secs: f64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: Option<u16>,
+    pub bitrate_kbps: Option<u32>,
+    /// WAV only: sample encoding (1 = integer PCM, 3 = IEEE float)
+    pub sample_format: Option<u16>,
+    /// WAV only: byte offset and length of the sample data
+    pub data_offset: Option<u64>,
+    pub data_size: Option<u64>,
+}
+
+impl AudioInfo {
+    /// Convert to stream metadata entries.
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert("format".to_string(), self.format.to_string());
+        metadata.insert("duration".to_string(), format!("{:.3}", self.duration_secs));
+        metadata.insert("sampleRate".to_string(), self.sample_rate.to_string());
+        metadata.insert("channels".to_string(), self.channels.to_string());
+        if let Some(bits) = self.bits_per_sample {
+            metadata.insert("bitsPerSample".to_string(), bits.to_string());
+        }
+        if let Some(bitrate) = self.bitrate_kbps {
+            metadata.insert("bitrate".to_string(), bitrate.to_string());
+        }
+        if let Some(data_offset) = self.data_offset {
+            metadata.insert("dataOffset".to_string(), data_offset.to_string());
+        }
+        metadata
+    }
+}
+
+/// Probe the header of an audio stream.
+/// `header` holds the leading bytes of the stream, `total_size` its full length.
+pub fn probe(header: &[u8], total_size: u64) -> Option<AudioInfo> {
+    if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" {
+        probe_wav(header, total_size)
+    } else {
+        probe_mp3(header, total_size)
+    }
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Probe a RIFF/WAVE header.
+fn probe_wav(header: &[u8], total_size: u64) -> Option<AudioInfo> {
+    let wav = WavHeader::parse(header, total_size)?;
+    if wav.byte_rate == 0 {
+        return None;
+    }
+    // Streamed WAVs may carry a placeholder size, capped to the real length
+    let data_size = wav.data.end - wav.data.start;
+    Some(AudioInfo {
+        format: "wav",
+        duration_secs: data_size as f64 / wav.byte_rate as f64,
+        sample_rate: wav.sample_rate,
+        channels: wav.channels,
+        bits_per_sample: Some(wav.bits_per_sample),
+        bitrate_kbps: Some((u64::from(wav.byte_rate) * 8 / 1000) as u32),
+        sample_format: Some(wav.encoding),
+        data_offset: Some(wav.data.start),
+        data_size: Some(data_size),
+    })
+}
+
+const MP3_BITRATES_V1_L3: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MP3_BITRATES_V2_L3: [u32; 15] =
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MP3_SAMPLE_RATES_V1: [u32; 3] = [44100, 48000, 32000];
+
+/// Probe an MPEG audio layer III stream, honoring ID3v2 tags and Xing/Info VBR headers.
+fn probe_mp3(header: &[u8], total_size: u64) -> Option<AudioInfo> {
+    let mut offset = 0;
+
+    // Skip ID3v2 tag (syncsafe size, optional footer)
+    if header.len() >= 10 && &header[0..3] == b"ID3" {
+        let size = header[6..10]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7F));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset = 10 + size + footer;
+    }
+
+    // Find the first frame sync
+    while offset + 4 <= header.len() {
+        if header[offset] == 0xFF && header[offset + 1] & 0xE0 == 0xE0 {
+            if let Some(info) = parse_mp3_frame(header, offset, total_size) {
+                return Some(info);
+            }
+        }
+        offset += 1;
+    }
+
+    None
+}
+
+fn parse_mp3_frame(header: &[u8], offset: usize, total_size: u64) -> Option<AudioInfo> {
+    let b1 = header[offset + 1];
+    let b2 = header[offset + 2];
+    let b3 = header[offset + 3];
+
+    let version = (b1 >> 3) & 0x03; // 0 = MPEG 2.5, 2 = MPEG 2, 3 = MPEG 1
+    let layer = (b1 >> 1) & 0x03; // 1 = Layer III
+    let bitrate_index = (b2 >> 4) as usize;
+    let sample_rate_index = ((b2 >> 2) & 0x03) as usize;
+
+    if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15 {
+        return None;
+    }
+    if sample_rate_index == 3 {
+        return None;
+    }
+
+    let is_mpeg1 = version == 3;
+    let bitrate_kbps = if is_mpeg1 {
+        MP3_BITRATES_V1_L3[bitrate_index]
+    } else {
+        MP3_BITRATES_V2_L3[bitrate_index]
+    };
+    let sample_rate = match version {
+        3 => MP3_SAMPLE_RATES_V1[sample_rate_index],
+        2 => MP3_SAMPLE_RATES_V1[sample_rate_index] / 2,
+        _ => MP3_SAMPLE_RATES_V1[sample_rate_index] / 4,
+    };
+    // Require the next frame to start where this one ends, to reject
+    // random 0xFFE sync patterns inside non-MP3 data
+    let padding = ((b2 >> 1) & 0x01) as usize;
+    let frame_length = if is_mpeg1 {
+        144_000 * bitrate_kbps as usize / sample_rate as usize + padding
+    } else {
+        72_000 * bitrate_kbps as usize / sample_rate as usize + padding
+    };
+    let next = offset + frame_length;
+    if next + 1 < header.len() && (header[next] != 0xFF || header[next + 1] & 0xE0 != 0xE0) {
+        return None;
+    }
+
+    let mono = (b3 >> 6) == 3;
+    let channels = if mono { 1 } else { 2 };
+    let samples_per_frame: u64 = if is_mpeg1 { 1152 } else { 576 };
+
+    // Xing/Info header lives right after the side information
+    let side_info = match (is_mpeg1, mono) {
+        (true, true) => 17,
+        (true, false) => 32,
+        (false, true) => 9,
+        (false, false) => 17,
+    };
+    let xing = offset + 4 + side_info;
+    let vbr_frames = match header.get(xing..xing + 4) {
+        Some(b"Xing") | Some(b"Info") => {
+            let flags = read_u32_be(header, xing + 4)?;
+            if flags & 0x01 != 0 {
+                read_u32_be(header, xing + 8)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    let duration_secs = match vbr_frames {
+        Some(frames) => (frames as u64 * samples_per_frame) as f64 / sample_rate as f64,
+        None => {
+            let audio_bytes = total_size.saturating_sub(offset as u64);
+            (audio_bytes * 8) as f64 / (bitrate_kbps as f64 * 1000.0)
+        }
+    };
+
+    Some(AudioInfo {
+        format: "mp3",
+        duration_secs,
+        sample_rate,
+        channels,
+        bits_per_sample: None,
+        bitrate_kbps: Some(bitrate_kbps),
+        sample_format: None,
+        data_offset: None,
+        data_size: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PcmFormat;
+
+    #[test]
+    fn probes_pcm_wav() {
+        let header = PcmFormat::new(44100, 2, 16).unwrap().wav_header(176_400);
+        let info = probe(&header, 44 + 176_400).unwrap();
+        assert_eq!(info.format, "wav");
+        assert_eq!(info.duration_secs, 1.0);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.bitrate_kbps, Some(1411));
+        assert_eq!(info.sample_format, Some(1));
+        assert_eq!(info.data_offset, Some(44));
+
+        // A placeholder data size is capped to the stream length
+        let info = probe(&header, 44 + 88_200).unwrap();
+        assert_eq!(info.data_size, Some(88_200));
+        assert_eq!(info.duration_secs, 0.5);
+    }
+
+    #[test]
+    fn wav_byte_rate_does_not_overflow_the_bitrate() {
+        let mut header = PcmFormat::new(8000, 1, 8).unwrap().wav_header(100);
+        header[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        let info = probe(&header, 144).unwrap();
+        assert_eq!(info.bitrate_kbps, Some(34_359_738));
+
+        header[28..32].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(probe(&header, 144), None);
+    }
+
+    #[test]
+    fn probes_mp3_frames() {
+        // MPEG-1 layer III, 128kbps, 44.1kHz, stereo: 417-byte frames
+        let mut data = vec![0u8; 834];
+        data[0..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        data[417..421].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+
+        let info = probe(&data, 834).unwrap();
+        assert_eq!(info.format, "mp3");
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.bitrate_kbps, Some(128));
+        assert_eq!(info.duration_secs, 834.0 * 8.0 / 128_000.0);
+
+        // A sync pattern not followed by another frame is not MP3
+        data[417] = 0;
+        assert_eq!(probe(&data, 834), None);
+    }
+}
@@ -0,0 +1,102 @@
+// EBU R128 loudness normalization hook.
+// Runs ffmpeg's loudnorm filter on finalized streams and stores the result
+// as a derived stream, for pipelines that need consistent playback levels.
+
+use std::sync::Arc;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use super::transcoder::{self, DERIVED_FROM_KEY, RENDITION_KEY_PREFIX};
+use crate::logger;
+use crate::server::events::{StreamEvent, StreamEventBus};
+use crate::server::memory::StreamManager;
+
+/// Rendition name used for normalized streams.
+pub const NORMALIZED_RENDITION: &str = "normalized";
+
+/// Loudness normalization configuration.
+#[derive(Debug, Clone)]
+pub struct LoudnessConfig {
+    pub ffmpeg_path: String,
+    /// Integrated loudness target in LUFS (EBU R128 recommends -23)
+    pub target_lufs: f64,
+    /// Maximum true peak in dBTP
+    pub true_peak_db: f64,
+}
+
+/// Spawn a task that normalizes every finalized source stream.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    config: LoudnessConfig,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let config = Arc::new(config);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized { stream_id, .. }) => {
+                    let stream_manager = stream_manager.clone();
+                    let config = config.clone();
+                    tokio::task::spawn_blocking(move || {
+                        normalize_stream(&stream_manager, &config, &stream_id)
+                    });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!(
+                        "Loudness normalizer lagged, {} events skipped",
+                        skipped
+                    ));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Normalize a single stream into a derived WAV stream.
+fn normalize_stream(stream_manager: &StreamManager, config: &LoudnessConfig, stream_id: &str) {
+    let cache_path = match stream_manager.get_stream(stream_id) {
+        Some(stream) => {
+            let ctx = stream.lock().unwrap();
+            if ctx.get_metadata().contains_key(DERIVED_FROM_KEY) {
+                return;
+            }
+            ctx.get_cache_path().to_string()
+        }
+        None => return,
+    };
+
+    let filter = format!(
+        "loudnorm=I={}:TP={}:LRA=11",
+        config.target_lufs, config.true_peak_db
+    );
+    let args = ["-af", filter.as_str(), "-c:a", "pcm_s16le", "-f", "wav"];
+    let derived_id = format!("{}.{}", stream_id, NORMALIZED_RENDITION);
+
+    match transcoder::run_ffmpeg_with(
+        &config.ffmpeg_path,
+        &cache_path,
+        &args,
+        NORMALIZED_RENDITION,
+    )
+    .and_then(|output| transcoder::import_file(stream_manager, stream_id, &derived_id, &output))
+    {
+        Ok(size) => {
+            if let Some(stream) = stream_manager.get_stream(stream_id) {
+                stream.lock().unwrap().set_metadata(
+                    &format!("{}{}", RENDITION_KEY_PREFIX, NORMALIZED_RENDITION),
+                    derived_id.clone(),
+                );
+            }
+            logger::log_info(&format!(
+                "Normalized stream {} to {} LUFS ({} bytes) as {}",
+                stream_id, config.target_lufs, size, derived_id
+            ));
+        }
+        Err(e) => logger::log_error(&format!("Failed to normalize stream {}: {}", stream_id, e)),
+    }
+}
@@ -0,0 +1,17 @@
+// Server processing module - post-upload analysis of stream contents
+pub mod audio_probe;
+#[cfg(feature = "loudness")]
+pub mod loudness;
+#[cfg(feature = "rtp")]
+pub mod rtp_egress;
+pub mod silence_trim;
+pub mod stream_processor;
+pub mod transcoder;
+pub mod waveform_peaks;
+
+pub use audio_probe::AudioInfo;
+pub use silence_trim::SilenceTrimProcessor;
+pub use stream_processor::{AudioProbeProcessor, StreamProcessor};
+#[cfg(feature = "transcode")]
+pub use transcoder::{TranscodeFormat, TranscoderConfig};
+pub use waveform_peaks::PeaksConfig;
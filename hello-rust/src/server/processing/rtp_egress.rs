@@ -0,0 +1,268 @@
+// RTP/UDP egress of finalized streams (--rtp-destination).
+// Every finalized raw PCM or PCM WAV stream is sent, paced in real time, as
+// an RTP session (RFC 3550) of L8, L16, or L24 audio (RFC 3551, RFC 3190) to a
+// UDP destination, so VoIP and broadcast tooling such as ffmpeg, GStreamer,
+// or VLC can pick up stored audio. The SDP describing each session is kept in
+// the stream metadata for receivers to load.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use super::transcoder::DERIVED_FROM_KEY;
+use crate::logger;
+use crate::protocol::PcmFormat;
+use crate::server::events::{StreamEvent, StreamEventBus};
+use crate::server::memory::StreamManager;
+
+/// Metadata key holding the SDP of the RTP session of a stream.
+pub const SDP_KEY: &str = "rtpSdp";
+
+// Audio carried per packet, unless a packet would outgrow the payload limit
+const PACKET_MILLIS: u32 = 20;
+// Largest payload, keeping packets below a 1500-byte Ethernet MTU
+const MAX_PAYLOAD: usize = 1400;
+// Bytes read from the stream at a time
+const READ_BYTES: usize = 64 * 1024;
+const RTP_HEADER_LEN: usize = 12;
+const RTP_VERSION: u8 = 2;
+
+/// RTP egress configuration.
+#[derive(Debug, Clone)]
+pub struct RtpEgressConfig {
+    pub destination: SocketAddr,
+    /// Payload type of formats without a static one (96-127)
+    pub payload_type: u8,
+}
+
+/// Spawn a task that sends every finalized PCM stream to the destination.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    config: RtpEgressConfig,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let config = Arc::new(config);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized { stream_id, .. }) => {
+                    let stream_manager = stream_manager.clone();
+                    let config = config.clone();
+                    tokio::task::spawn_blocking(move || {
+                        send_stream(&stream_manager, &config, &stream_id)
+                    });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!("RTP egress lagged, {} events skipped", skipped));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Send the samples of a single stream as one RTP session.
+fn send_stream(stream_manager: &StreamManager, config: &RtpEgressConfig, stream_id: &str) {
+    let Some(stream) = stream_manager.get_stream(stream_id) else {
+        return;
+    };
+    let (format, data) = {
+        let ctx = stream.lock().unwrap();
+        if ctx.get_metadata().contains_key(DERIVED_FROM_KEY) {
+            return;
+        }
+        match ctx.sample_layout() {
+            Some(layout) => layout,
+            None => return,
+        }
+    };
+    let Some(encoding) = encoding_name(format.bits_per_sample) else {
+        warn!(
+            "RTP egress skips stream {}: no RTP payload format for {}-bit samples",
+            stream_id, format.bits_per_sample
+        );
+        return;
+    };
+    let payload_type = static_payload_type(&format).unwrap_or(config.payload_type);
+
+    let local: SocketAddr = if config.destination.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = match UdpSocket::bind(local) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("RTP egress of stream {} failed to bind: {}", stream_id, e);
+            return;
+        }
+    };
+
+    // Random SSRC, sequence number, and timestamp origin, as RFC 3550 asks
+    let random = RandomState::new().hash_one(stream_id);
+    let ssrc = random as u32;
+    let first_sequence = (random >> 32) as u16;
+    let first_timestamp = (random >> 16) as u32;
+
+    let sdp = session_description(
+        stream_id,
+        config.destination,
+        &format,
+        encoding,
+        payload_type,
+    );
+    stream.lock().unwrap().set_metadata(SDP_KEY, sdp);
+    info!(
+        "RTP egress of stream {} to {}: {}/{}/{}, payload type {}, SSRC {:#010x}",
+        stream_id,
+        config.destination,
+        encoding,
+        format.sample_rate,
+        format.channels,
+        payload_type,
+        ssrc
+    );
+
+    let frame_size = format.frame_size();
+    let frames_per_packet = std::cmp::min(
+        (format.sample_rate * PACKET_MILLIS / 1000) as usize,
+        MAX_PAYLOAD / frame_size,
+    )
+    .max(1);
+    let packet_bytes = frames_per_packet * frame_size;
+    let read_bytes = (READ_BYTES / packet_bytes).max(1) * packet_bytes;
+
+    let start = Instant::now();
+    let mut frames_sent = 0u64;
+    let mut packets = 0u64;
+    let mut offset = data.start;
+    while offset < data.end {
+        let length = std::cmp::min(read_bytes as u64, data.end - offset) as usize;
+        let block = match stream_manager.read_chunk(stream_id, offset, length) {
+            Ok(block) if !block.is_empty() => block,
+            Ok(_) => break,
+            Err(e) => {
+                error!("RTP egress of stream {} stopped: {}", stream_id, e);
+                return;
+            }
+        };
+
+        for samples in block.chunks(packet_bytes) {
+            // A trailing partial frame is not sent
+            let samples = &samples[..samples.len() - samples.len() % frame_size];
+            if samples.is_empty() {
+                continue;
+            }
+
+            // Send each packet when its first frame is due
+            let due = Duration::from_secs_f64(frames_sent as f64 / format.sample_rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+            let header = RtpHeader {
+                marker: packets == 0,
+                payload_type,
+                sequence: first_sequence.wrapping_add(packets as u16),
+                timestamp: first_timestamp.wrapping_add(frames_sent as u32),
+                ssrc,
+            };
+            let packet = header.packet(samples, format.bits_per_sample);
+            if let Err(e) = socket.send_to(&packet, config.destination) {
+                error!("RTP egress of stream {} stopped: {}", stream_id, e);
+                return;
+            }
+            frames_sent += (samples.len() / frame_size) as u64;
+            packets += 1;
+        }
+        offset += block.len() as u64;
+    }
+
+    info!(
+        "RTP egress of stream {} finished: {} packets, {:.1}s of audio",
+        stream_id,
+        packets,
+        frames_sent as f64 / format.sample_rate as f64
+    );
+}
+
+/// Fixed header of an RTP packet, without CSRCs or extensions.
+struct RtpHeader {
+    marker: bool,
+    payload_type: u8,
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+}
+
+impl RtpHeader {
+    /// Build a packet carrying little-endian `samples`, converted to the
+    /// network byte order of the L16 and L24 formats.
+    fn packet(&self, samples: &[u8], bits_per_sample: u16) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(RTP_HEADER_LEN + samples.len());
+        packet.push(RTP_VERSION << 6);
+        packet.push((self.marker as u8) << 7 | self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for sample in samples.chunks_exact((bits_per_sample / 8) as usize) {
+            packet.extend(sample.iter().rev());
+        }
+        packet
+    }
+}
+
+/// Get the RTP encoding name of a sample width. L8 is unsigned with an
+/// offset of 128, like 8-bit WAV.
+fn encoding_name(bits_per_sample: u16) -> Option<&'static str> {
+    match bits_per_sample {
+        8 => Some("L8"),
+        16 => Some("L16"),
+        24 => Some("L24"),
+        _ => None,
+    }
+}
+
+/// Get the static payload type RFC 3551 assigns to a format, if any.
+fn static_payload_type(format: &PcmFormat) -> Option<u8> {
+    match (format.bits_per_sample, format.sample_rate, format.channels) {
+        (16, 44100, 2) => Some(10),
+        (16, 44100, 1) => Some(11),
+        _ => None,
+    }
+}
+
+/// Describe the session of a stream in SDP (RFC 8866).
+fn session_description(
+    stream_id: &str,
+    destination: SocketAddr,
+    format: &PcmFormat,
+    encoding: &str,
+    payload_type: u8,
+) -> String {
+    let address_type = if destination.is_ipv4() { "IP4" } else { "IP6" };
+    let address = destination.ip();
+    [
+        "v=0".to_string(),
+        format!("o=- 0 0 IN {} {}", address_type, address),
+        format!("s={}", stream_id),
+        format!("c=IN {} {}", address_type, address),
+        "t=0 0".to_string(),
+        format!("m=audio {} RTP/AVP {}", destination.port(), payload_type),
+        format!(
+            "a=rtpmap:{} {}/{}/{}",
+            payload_type, encoding, format.sample_rate, format.channels
+        ),
+        format!("a=ptime:{}", PACKET_MILLIS),
+        String::new(),
+    ]
+    .join("\r\n")
+}
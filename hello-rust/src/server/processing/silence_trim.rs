@@ -0,0 +1,161 @@
+// Silence trimming on finalize (--trim-silence).
+// Leading and trailing frames whose samples all stay below a threshold are cut
+// from raw PCM and PCM WAV streams before they become Ready, which saves
+// storage for voice recordings padded with room noise. The checksum, block
+// index, and capture timestamps are rebuilt for the trimmed data, and the
+// original and trimmed sizes are kept in stream metadata.
+
+use std::ops::Range;
+
+use tracing::info;
+
+use super::stream_processor::StreamProcessor;
+use crate::protocol::PcmFormat;
+use crate::server::memory::{CacheError, MemoryMappedCache, StreamContext, TimestampIndex};
+
+// Bytes read or moved per cache file access
+const SCAN_BYTES: usize = 64 * 1024;
+
+/// Processor trimming leading and trailing silence of PCM streams.
+pub struct SilenceTrimProcessor {
+    /// Largest silent sample level, as a fraction of full scale.
+    threshold: f64,
+}
+
+impl SilenceTrimProcessor {
+    /// Create a processor treating frames below `threshold_db` dBFS as silence.
+    pub fn new(threshold_db: f64) -> Self {
+        Self {
+            threshold: 10f64.powf(threshold_db / 20.0),
+        }
+    }
+
+    fn is_silent(&self, frame: &[u8], sample_size: usize) -> bool {
+        frame
+            .chunks_exact(sample_size)
+            .all(|sample| PcmFormat::sample_value(sample).abs() <= self.threshold)
+    }
+
+    /// Find the frames between the first and the last audible frame of the
+    /// sample data; empty when every frame is silent.
+    fn audible_range(
+        &self,
+        mmap: &MemoryMappedCache,
+        format: &PcmFormat,
+        data: &Range<u64>,
+    ) -> Result<Range<u64>, CacheError> {
+        let frame_size = format.frame_size();
+        let sample_size = frame_size / format.channels as usize;
+        let step = (SCAN_BYTES / frame_size).max(1) * frame_size;
+        // A partial frame at the end counts as silence
+        let frames_end = data.end - (data.end - data.start) % frame_size as u64;
+
+        let mut start = frames_end;
+        let mut offset = data.start;
+        while offset < frames_end {
+            let length = std::cmp::min(step as u64, frames_end - offset) as usize;
+            let frames = mmap.read(offset, length)?;
+            if let Some(index) = frames
+                .chunks_exact(frame_size)
+                .position(|frame| !self.is_silent(frame, sample_size))
+            {
+                start = offset + (index * frame_size) as u64;
+                break;
+            }
+            offset += length as u64;
+        }
+
+        let mut end = start;
+        let mut offset = frames_end;
+        while offset > start {
+            let length = std::cmp::min(step as u64, offset - start) as usize;
+            let frames = mmap.read(offset - length as u64, length)?;
+            if let Some(index) = frames
+                .chunks_exact(frame_size)
+                .rposition(|frame| !self.is_silent(frame, sample_size))
+            {
+                end = offset - length as u64 + ((index + 1) * frame_size) as u64;
+                break;
+            }
+            offset -= length as u64;
+        }
+        Ok(start..end)
+    }
+}
+
+impl StreamProcessor for SilenceTrimProcessor {
+    fn name(&self) -> &str {
+        "silence-trim"
+    }
+
+    fn on_finalize(&self, ctx: &mut StreamContext) -> anyhow::Result<()> {
+        let (Some((format, data)), Some(mmap)) =
+            (ctx.sample_layout(), ctx.get_mmap_file().cloned())
+        else {
+            return Ok(());
+        };
+        let original_size = ctx.get_total_size();
+        ctx.set_metadata("originalSize", original_size.to_string());
+        ctx.set_metadata("trimmedSize", original_size.to_string());
+
+        let audible = self.audible_range(&mmap, &format, &data)?;
+        if audible == data {
+            return Ok(());
+        }
+
+        // Close the gaps: samples move to the start of the data, followed by
+        // any WAV chunks that came after it
+        let kept = audible.end - audible.start;
+        move_bytes(&mmap, audible.clone(), data.start)?;
+        move_bytes(&mmap, data.end..original_size, data.start + kept)?;
+        let size = original_size - (data.end - data.start) + kept;
+        if data.start > 0 {
+            // Patch the RIFF and data chunk sizes of the WAV header
+            mmap.write(4, &((size - 8).min(u32::MAX as u64) as u32).to_le_bytes())?;
+            mmap.write(
+                data.start - 4,
+                &(kept.min(u32::MAX as u64) as u32).to_le_bytes(),
+            )?;
+        }
+        mmap.finalize(size)?;
+        ctx.set_total_size(size);
+        ctx.set_current_offset(size);
+
+        let (checksum, block_index) = mmap.compute_digests()?;
+        ctx.set_checksum(Some(checksum));
+        block_index.save(ctx.get_cache_path())?;
+        ctx.set_block_index(Some(std::sync::Arc::new(block_index)));
+        if !ctx.get_timestamps().is_empty() {
+            let timestamps = ctx.get_timestamps_mut();
+            timestamps.remove_range(audible.end..data.end);
+            timestamps.remove_range(data.start..audible.start);
+            ctx.get_timestamps().save(ctx.get_cache_path())?;
+            // Drop records of chunks that were entirely trailing silence
+            let timestamps = TimestampIndex::load(ctx.get_cache_path(), size);
+            timestamps.save(ctx.get_cache_path())?;
+            ctx.set_timestamps(timestamps);
+        }
+
+        ctx.set_metadata("trimmedSize", size.to_string());
+        info!(
+            "Trimmed silence of stream {}: {} -> {} bytes",
+            ctx.get_stream_id(),
+            original_size,
+            size
+        );
+        Ok(())
+    }
+}
+
+/// Copy the bytes in `from` down to `to`, chunk by chunk; `to` must not be
+/// past `from.start`.
+fn move_bytes(mmap: &MemoryMappedCache, from: Range<u64>, to: u64) -> Result<(), CacheError> {
+    let mut offset = 0;
+    while from.start + offset < from.end {
+        let length = std::cmp::min(SCAN_BYTES as u64, from.end - from.start - offset) as usize;
+        let data = mmap.read(from.start + offset, length)?;
+        mmap.write(to + offset, &data)?;
+        offset += length as u64;
+    }
+    Ok(())
+}
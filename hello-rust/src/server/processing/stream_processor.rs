@@ -0,0 +1,68 @@
+// Stream processing plugin trait.
+// Deployments implement StreamProcessor and register it with StreamManager to
+// run custom server-side processing (virus scan, fingerprinting, re-encoding)
+// without forking the message handler.
+
+use tracing::info;
+
+use crate::server::memory::StreamContext;
+
+use super::audio_probe;
+
+/// Hooks invoked by StreamManager while a stream is uploaded and finalized.
+///
+/// Hooks run synchronously on the connection thread; long-running work should
+/// be handed off (e.g. to an event bus subscriber) instead.
+pub trait StreamProcessor: Send + Sync {
+    /// Processor name used in logs.
+    fn name(&self) -> &str;
+
+    /// Called before a chunk is written to the stream cache.
+    /// Returning an error rejects the chunk, which is then never written.
+    fn on_chunk(&self, _stream_id: &str, _offset: u64, _data: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called after the cache file has been finalized, before the stream
+    /// becomes Ready. Returning an error marks the stream as failed.
+    fn on_finalize(&self, _ctx: &mut StreamContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Built-in processor storing WAV/MP3 header properties in stream metadata.
+pub struct AudioProbeProcessor;
+
+impl StreamProcessor for AudioProbeProcessor {
+    fn name(&self) -> &str {
+        "audio-probe"
+    }
+
+    fn on_finalize(&self, ctx: &mut StreamContext) -> anyhow::Result<()> {
+        let header = match ctx.get_mmap_file() {
+            Some(mmap) => mmap.read(0, audio_probe::PROBE_HEADER_BYTES)?,
+            None => return Ok(()),
+        };
+
+        match audio_probe::probe(&header, ctx.get_total_size()) {
+            Some(info) => {
+                info!(
+                    "Probed stream {}: {} {:.3}s {}Hz {}ch",
+                    ctx.get_stream_id(),
+                    info.format,
+                    info.duration_secs,
+                    info.sample_rate,
+                    info.channels
+                );
+                for (key, value) in info.to_metadata() {
+                    ctx.set_metadata(&key, value);
+                }
+            }
+            None => info!(
+                "Stream {} is not a recognized audio format",
+                ctx.get_stream_id()
+            ),
+        }
+        Ok(())
+    }
+}
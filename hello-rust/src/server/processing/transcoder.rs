@@ -0,0 +1,227 @@
+// Post-finalize transcoding pipeline.
+// Produces compressed renditions (e.g. WAV -> Opus) of finalized streams as
+// derived streams, linked to the original through stream metadata.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::logger;
+use crate::server::events::{StreamEvent, StreamEventBus};
+use crate::server::memory::StreamManager;
+
+/// Metadata key on a derived stream pointing back to its source stream.
+pub const DERIVED_FROM_KEY: &str = "derivedFrom";
+/// Metadata key prefix on a source stream listing its renditions.
+pub const RENDITION_KEY_PREFIX: &str = "rendition.";
+
+const IMPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Supported rendition formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscodeFormat {
+    Opus,
+    Mp3,
+}
+
+impl TranscodeFormat {
+    /// Parse a format name as given on the command line.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "opus" => Some(TranscodeFormat::Opus),
+            "mp3" => Some(TranscodeFormat::Mp3),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "opus",
+            TranscodeFormat::Mp3 => "mp3",
+        }
+    }
+
+    fn ffmpeg_args(&self) -> &'static [&'static str] {
+        match self {
+            TranscodeFormat::Opus => &["-c:a", "libopus", "-b:a", "64k", "-f", "ogg"],
+            TranscodeFormat::Mp3 => &["-c:a", "libmp3lame", "-b:a", "128k", "-f", "mp3"],
+        }
+    }
+
+    /// ID of the derived stream holding this rendition.
+    pub fn derived_stream_id(&self, stream_id: &str) -> String {
+        format!("{}.{}", stream_id, self.as_str())
+    }
+}
+
+/// Transcoder configuration.
+#[derive(Debug, Clone)]
+pub struct TranscoderConfig {
+    pub ffmpeg_path: String,
+    pub formats: Vec<TranscodeFormat>,
+}
+
+/// Spawn a task that transcodes every finalized source stream.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    config: TranscoderConfig,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let config = Arc::new(config);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized { stream_id, .. }) => {
+                    let stream_manager = stream_manager.clone();
+                    let config = config.clone();
+                    tokio::task::spawn_blocking(move || {
+                        transcode_stream(&stream_manager, &config, &stream_id)
+                    });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!("Transcoder lagged, {} events skipped", skipped));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Produce all configured renditions for a single stream.
+fn transcode_stream(stream_manager: &StreamManager, config: &TranscoderConfig, stream_id: &str) {
+    let (cache_path, source_format) = match stream_manager.get_stream(stream_id) {
+        Some(stream) => {
+            let ctx = stream.lock().unwrap();
+            // Never transcode renditions themselves
+            if ctx.get_metadata().contains_key(DERIVED_FROM_KEY) {
+                return;
+            }
+            (
+                ctx.get_cache_path().to_string(),
+                ctx.get_metadata().get("format").cloned(),
+            )
+        }
+        None => return,
+    };
+
+    for format in &config.formats {
+        if source_format.as_deref() == Some(format.as_str()) {
+            continue;
+        }
+
+        let derived_id = format.derived_stream_id(stream_id);
+        match run_ffmpeg(config, &cache_path, *format)
+            .and_then(|output| import_file(stream_manager, stream_id, &derived_id, &output))
+        {
+            Ok(size) => {
+                if let Some(stream) = stream_manager.get_stream(stream_id) {
+                    stream.lock().unwrap().set_metadata(
+                        &format!("{}{}", RENDITION_KEY_PREFIX, format.as_str()),
+                        derived_id.clone(),
+                    );
+                }
+                logger::log_info(&format!(
+                    "Transcoded stream {} to {} ({} bytes) as {}",
+                    stream_id,
+                    format.as_str(),
+                    size,
+                    derived_id
+                ));
+            }
+            Err(e) => logger::log_error(&format!(
+                "Failed to transcode stream {} to {}: {}",
+                stream_id,
+                format.as_str(),
+                e
+            )),
+        }
+    }
+}
+
+/// Run ffmpeg on a cache file, returning the path of the transcoded output.
+fn run_ffmpeg(
+    config: &TranscoderConfig,
+    input: &str,
+    format: TranscodeFormat,
+) -> anyhow::Result<PathBuf> {
+    run_ffmpeg_with(
+        &config.ffmpeg_path,
+        input,
+        format.ffmpeg_args(),
+        format.as_str(),
+    )
+}
+
+/// Run ffmpeg with the given output arguments, writing next to the input file.
+pub(crate) fn run_ffmpeg_with(
+    ffmpeg_path: &str,
+    input: &str,
+    args: &[&str],
+    suffix: &str,
+) -> anyhow::Result<PathBuf> {
+    let output = PathBuf::from(format!("{}.{}.tmp", input, suffix));
+
+    let status = Command::new(ffmpeg_path)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i", input])
+        .args(args)
+        .arg(&output)
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", ffmpeg_path, e))?;
+
+    if !status.success() {
+        let _ = std::fs::remove_file(&output);
+        anyhow::bail!("ffmpeg exited with {}", status);
+    }
+
+    Ok(output)
+}
+
+/// Copy a transcoded file into a new derived stream and finalize it.
+/// The file is removed afterwards.
+pub(crate) fn import_file(
+    stream_manager: &StreamManager,
+    source_id: &str,
+    derived_id: &str,
+    path: &Path,
+) -> anyhow::Result<u64> {
+    let result = (|| {
+        // Replace any rendition left over from a previous upload
+        if stream_manager.get_stream(derived_id).is_some() {
+            stream_manager.delete_stream(derived_id)?;
+        }
+        stream_manager.create_stream(derived_id.to_string(), None, false)?;
+
+        let mut file = std::fs::File::open(path)?;
+        let mut buffer = vec![0u8; IMPORT_CHUNK_SIZE];
+        let mut total = 0u64;
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            stream_manager.write_chunk(derived_id, &buffer[..n])?;
+            total += n as u64;
+        }
+
+        // Link before finalizing so the finalize event already sees the marker
+        if let Some(stream) = stream_manager.get_stream(derived_id) {
+            stream
+                .lock()
+                .unwrap()
+                .set_metadata(DERIVED_FROM_KEY, source_id.to_string());
+        }
+
+        stream_manager.finalize_stream(derived_id)?;
+        Ok(total)
+    })();
+
+    let _ = std::fs::remove_file(path);
+    result
+}
@@ -0,0 +1,281 @@
+// Waveform peak generation for finalized streams.
+// Computes per-bucket min/max sample values and caches them in a sidecar
+// file next to the stream cache, so UIs can render waveforms without
+// downloading the full audio.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use super::audio_probe::{self, AudioInfo};
+use super::transcoder;
+use crate::logger;
+use crate::server::events::{StreamEvent, StreamEventBus};
+use crate::server::memory::StreamManager;
+
+pub const DEFAULT_PEAK_BUCKETS: usize = 1000;
+
+// Decode parameters for formats that need ffmpeg (mono 16-bit at 8kHz is
+// plenty of resolution for a waveform overview)
+const DECODE_SAMPLE_RATE: u32 = 8000;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+pub use crate::protocol::Peak;
+
+/// Waveform peak generator configuration.
+#[derive(Debug, Clone)]
+pub struct PeaksConfig {
+    pub buckets: usize,
+    /// ffmpeg used to decode non-WAV formats; WAV PCM is handled natively
+    pub ffmpeg_path: Option<String>,
+}
+
+/// Get the sidecar path holding the peaks of a cache file.
+pub fn peaks_path(cache_path: &str) -> PathBuf {
+    Path::new(cache_path).with_extension("peaks")
+}
+
+/// Load cached peaks for a stream.
+pub fn load_peaks(cache_path: &str) -> Option<Vec<Peak>> {
+    let data = std::fs::read(peaks_path(cache_path)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Spawn a task computing peaks for every finalized stream.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    config: PeaksConfig,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let config = Arc::new(config);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized { stream_id, .. }) => {
+                    let stream_manager = stream_manager.clone();
+                    let config = config.clone();
+                    tokio::task::spawn_blocking(move || {
+                        generate_for_stream(&stream_manager, &config, &stream_id)
+                    });
+                }
+                Ok(StreamEvent::StreamDeleted { stream_id, .. }) => {
+                    let cache_path = stream_manager.get_cache_path(&stream_id);
+                    let _ = std::fs::remove_file(peaks_path(&cache_path));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!(
+                        "Peak generator lagged, {} events skipped",
+                        skipped
+                    ));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn generate_for_stream(stream_manager: &StreamManager, config: &PeaksConfig, stream_id: &str) {
+    let (cache_path, total_size) = match stream_manager.get_stream(stream_id) {
+        Some(stream) => {
+            let ctx = stream.lock().unwrap();
+            (ctx.get_cache_path().to_string(), ctx.get_total_size())
+        }
+        None => return,
+    };
+
+    match compute_peaks(&cache_path, total_size, config) {
+        Ok(peaks) => {
+            let json = match serde_json::to_vec(&peaks) {
+                Ok(json) => json,
+                Err(e) => {
+                    logger::log_error(&format!("Failed to serialize peaks: {}", e));
+                    return;
+                }
+            };
+            if let Err(e) = std::fs::write(peaks_path(&cache_path), json) {
+                logger::log_error(&format!(
+                    "Failed to write peaks for stream {}: {}",
+                    stream_id, e
+                ));
+                return;
+            }
+            if let Some(stream) = stream_manager.get_stream(stream_id) {
+                stream
+                    .lock()
+                    .unwrap()
+                    .set_metadata("peaks", peaks.len().to_string());
+            }
+            logger::log_info(&format!(
+                "Generated {} waveform peaks for stream {}",
+                peaks.len(),
+                stream_id
+            ));
+        }
+        Err(e) => logger::log_warn(&format!(
+            "No waveform peaks for stream {}: {}",
+            stream_id, e
+        )),
+    }
+}
+
+/// Compute peaks for a cache file, natively for PCM WAV and via ffmpeg otherwise.
+fn compute_peaks(
+    cache_path: &str,
+    total_size: u64,
+    config: &PeaksConfig,
+) -> anyhow::Result<Vec<Peak>> {
+    let mut file = File::open(cache_path)?;
+    let mut header = vec![0u8; audio_probe::PROBE_HEADER_BYTES];
+    let n = read_fully(&mut file, &mut header)?;
+    header.truncate(n);
+
+    if let Some(info) = audio_probe::probe(&header, total_size) {
+        if info.format == "wav" {
+            return peaks_from_wav(&mut file, &info, config.buckets);
+        }
+    }
+
+    let ffmpeg_path = config
+        .ffmpeg_path
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("not a PCM WAV file and no ffmpeg configured"))?;
+    let rate = DECODE_SAMPLE_RATE.to_string();
+    let args = [
+        "-ac",
+        "1",
+        "-ar",
+        rate.as_str(),
+        "-c:a",
+        "pcm_s16le",
+        "-f",
+        "s16le",
+    ];
+    let pcm_path = transcoder::run_ffmpeg_with(ffmpeg_path, cache_path, &args, "pcm")?;
+
+    let result = (|| {
+        let size = std::fs::metadata(&pcm_path)?.len();
+        let mut pcm = File::open(&pcm_path)?;
+        peaks_from_pcm(&mut pcm, size, 1, SampleEncoding::Int(2), config.buckets)
+    })();
+    let _ = std::fs::remove_file(&pcm_path);
+    result
+}
+
+fn peaks_from_wav(file: &mut File, info: &AudioInfo, buckets: usize) -> anyhow::Result<Vec<Peak>> {
+    let bits = info.bits_per_sample.unwrap_or(0);
+    let bytes_per_sample = (bits as usize).div_ceil(8);
+    let encoding = match (info.sample_format, bits) {
+        (Some(1), 8 | 16 | 24 | 32) => SampleEncoding::Int(bytes_per_sample),
+        (Some(3), 32) => SampleEncoding::Float32,
+        _ => anyhow::bail!("unsupported WAV sample format"),
+    };
+
+    let data_offset = info
+        .data_offset
+        .ok_or_else(|| anyhow::anyhow!("missing WAV data chunk"))?;
+    file.seek(SeekFrom::Start(data_offset))?;
+
+    peaks_from_pcm(
+        file,
+        info.data_size.unwrap_or(0),
+        info.channels as usize,
+        encoding,
+        buckets,
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SampleEncoding {
+    /// Little-endian integer PCM with the given byte width (8-bit is unsigned)
+    Int(usize),
+    Float32,
+}
+
+impl SampleEncoding {
+    fn width(&self) -> usize {
+        match self {
+            SampleEncoding::Int(width) => *width,
+            SampleEncoding::Float32 => 4,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> f32 {
+        match self {
+            SampleEncoding::Int(1) => (bytes[0] as f32 - 128.0) / 128.0,
+            SampleEncoding::Int(2) => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            SampleEncoding::Int(3) => {
+                let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+                value as f32 / 8_388_608.0
+            }
+            SampleEncoding::Int(_) => {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32
+                    / 2_147_483_648.0
+            }
+            SampleEncoding::Float32 => {
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).clamp(-1.0, 1.0)
+            }
+        }
+    }
+}
+
+/// Stream interleaved PCM from `reader`, folding all channels into `buckets` min/max pairs.
+fn peaks_from_pcm(
+    reader: &mut impl Read,
+    data_size: u64,
+    channels: usize,
+    encoding: SampleEncoding,
+    buckets: usize,
+) -> anyhow::Result<Vec<Peak>> {
+    let frame_size = encoding.width() * channels.max(1);
+    let total_frames = data_size / frame_size as u64;
+    if total_frames == 0 || buckets == 0 {
+        anyhow::bail!("no audio samples");
+    }
+
+    let bucket_count = std::cmp::min(buckets as u64, total_frames) as usize;
+    let mut peaks = vec![[0.0f32, 0.0f32]; bucket_count];
+    // Keep reads frame-aligned so no sample straddles two chunks
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE - READ_CHUNK_SIZE % frame_size];
+    let mut frame_index = 0u64;
+
+    while frame_index < total_frames {
+        let n = read_fully(reader, &mut buffer)?;
+        if n < frame_size {
+            break;
+        }
+
+        for frame in buffer[..n - n % frame_size].chunks_exact(frame_size) {
+            if frame_index >= total_frames {
+                break;
+            }
+            let bucket = (frame_index * bucket_count as u64 / total_frames) as usize;
+            let peak = &mut peaks[bucket];
+            for sample in frame.chunks_exact(encoding.width()) {
+                let value = encoding.decode(sample);
+                peak[0] = peak[0].min(value);
+                peak[1] = peak[1].max(value);
+            }
+            frame_index += 1;
+        }
+    }
+
+    Ok(peaks)
+}
+
+fn read_fully(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,4 @@
+// Server replication module - warm standby of the audio cache
+pub mod replicator;
+
+pub use replicator::ReplicatorConfig;
@@ -0,0 +1,158 @@
+// Asynchronous replication of finalized streams to a peer cache server.
+// The replicator acts as a regular client of the peer: it re-uploads every
+// finalized stream with START / binary chunks / STOP under the same stream ID.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::client::file_manager::CHUNK_SIZE;
+use crate::client::websocket_client::WebSocketClient;
+use crate::protocol::ControlMessage;
+use crate::logger;
+use crate::server::events::{StreamEvent, StreamEventBus};
+use crate::server::memory::StreamManager;
+
+const REPLICATION_MAX_ATTEMPTS: u32 = 3;
+const REPLICATION_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Replicator configuration.
+#[derive(Debug, Clone)]
+pub struct ReplicatorConfig {
+    /// WebSocket URI of the peer server, e.g. ws://standby:8080/audio
+    pub peer_uri: String,
+}
+
+/// Spawn the replicator: finalized streams are queued and forwarded one at a time.
+pub fn spawn(
+    bus: &StreamEventBus,
+    stream_manager: Arc<StreamManager>,
+    config: ReplicatorConfig,
+) -> JoinHandle<()> {
+    let mut receiver = bus.subscribe();
+    let (queue, mut pending) = mpsc::unbounded_channel::<String>();
+
+    // Queue stream IDs as they finalize, so slow replication never lags the bus
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StreamEvent::StreamFinalized { stream_id, .. }) => {
+                    if queue.send(stream_id).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::log_warn(&format!("Replicator lagged, {} events skipped", skipped));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(stream_id) = pending.recv().await {
+            for attempt in 1..=REPLICATION_MAX_ATTEMPTS {
+                match replicate_stream(&stream_manager, &config, &stream_id).await {
+                    Ok(size) => {
+                        logger::log_info(&format!(
+                            "Replicated stream {} ({} bytes) to {}",
+                            stream_id, size, config.peer_uri
+                        ));
+                        break;
+                    }
+                    Err(e) => {
+                        logger::log_warn(&format!(
+                            "Replication attempt {}/{} of stream {} failed: {}",
+                            attempt, REPLICATION_MAX_ATTEMPTS, stream_id, e
+                        ));
+                        if attempt < REPLICATION_MAX_ATTEMPTS {
+                            tokio::time::sleep(REPLICATION_RETRY_DELAY * attempt).await;
+                        } else {
+                            logger::log_error(&format!(
+                                "Giving up replication of stream {} to {}",
+                                stream_id, config.peer_uri
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Upload one finalized stream to the peer.
+async fn replicate_stream(
+    stream_manager: &StreamManager,
+    config: &ReplicatorConfig,
+    stream_id: &str,
+) -> Result<u64> {
+    let (cache_path, size, content_type, file_name, tags) = {
+        let stream = stream_manager
+            .get_stream(stream_id)
+            .ok_or_else(|| anyhow::anyhow!("Stream no longer exists"))?;
+        let ctx = stream.lock().unwrap();
+        let metadata = ctx.get_metadata();
+        (
+            ctx.get_cache_path().to_string(),
+            ctx.get_total_size(),
+            metadata.get("contentType").cloned(),
+            metadata.get("fileName").cloned(),
+            ctx.get_tags().clone(),
+        )
+    };
+
+    let mut ws_client = WebSocketClient::new(&config.peer_uri);
+    ws_client.connect(&config.peer_uri).await?;
+
+    let result = async {
+        ws_client
+            .send_control_message(ControlMessage::Start {
+                stream_id: stream_id.to_string(),
+                content_type,
+                file_name,
+                size: Some(size),
+                append: false,
+                resume: false,
+                tags,
+                acks: false,
+                exclusive: false,
+            })
+            .await?;
+        let chunk_size = match ws_client.receive_reply("START", &["STARTED"]).await? {
+            ControlMessage::Started { max_chunk_size, .. } => {
+                max_chunk_size.map_or(CHUNK_SIZE, |limit| CHUNK_SIZE.min(limit))
+            }
+            response => anyhow::bail!("Peer rejected START: {:?}", response),
+        };
+
+        let mut file = tokio::fs::File::open(&cache_path).await?;
+        let mut remaining = size;
+        while remaining > 0 {
+            let mut chunk = vec![0u8; std::cmp::min(chunk_size as u64, remaining) as usize];
+            file.read_exact(&mut chunk).await?;
+            remaining -= chunk.len() as u64;
+            ws_client.send_binary(chunk).await?;
+        }
+
+        ws_client
+            .send_control_message(ControlMessage::Stop {
+                stream_id: stream_id.to_string(),
+            })
+            .await?;
+        let response = ws_client.receive_reply("STOP", &["STOPPED"]).await?;
+        if !matches!(response, ControlMessage::Stopped { .. }) {
+            anyhow::bail!("Peer rejected STOP: {:?}", response);
+        }
+        Ok(size)
+    }
+    .await;
+
+    let _ = ws_client.close().await;
+    result
+}